@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+use crate::core::NodeOutput;
+use crate::missing::{fill_previous, MissingPolicy};
+
+// Collapses the elements of all inputs of an aggregate node into a single number
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AggregateOp {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+}
+
+impl FromStr for AggregateOp {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim() {
+            "sum" => Ok(AggregateOp::Sum),
+            "mean" => Ok(AggregateOp::Mean),
+            "min" => Ok(AggregateOp::Min),
+            "max" => Ok(AggregateOp::Max),
+            "count" => Ok(AggregateOp::Count),
+            name => Err(anyhow!("unknown aggregation {}", name)),
+        }
+    }
+}
+
+impl AggregateOp {
+    // A missing element makes the result missing, except for the count. Nothing to aggregate
+    // gives a sum and count of 0 and a missing value otherwise.
+    pub(crate) fn reduce(&self, values: &[f64]) -> f64 {
+        let is_missing = values.iter().any(|x| x.is_nan());
+        match self {
+            AggregateOp::Count => values.len() as f64,
+            _ if is_missing => f64::NAN,
+            AggregateOp::Sum => values.iter().sum(),
+            AggregateOp::Mean if values.is_empty() => f64::NAN,
+            AggregateOp::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            AggregateOp::Min => values.iter().copied().reduce(f64::min).unwrap_or(f64::NAN),
+            AggregateOp::Max => values.iter().copied().reduce(f64::max).unwrap_or(f64::NAN),
+        }
+    }
+
+    pub(crate) fn apply(
+        &self,
+        inputs: &[NodeOutput],
+        missing: MissingPolicy,
+    ) -> Result<NodeOutput> {
+        let mut values = Vec::new();
+        for input in inputs {
+            let mut elements = input.elements()?;
+            match missing {
+                MissingPolicy::Propagate => {}
+                MissingPolicy::Skip => elements.retain(|x| !x.is_nan()),
+                MissingPolicy::Substitute(v) => elements
+                    .iter_mut()
+                    .filter(|x| x.is_nan())
+                    .for_each(|x| *x = v),
+                MissingPolicy::Previous => fill_previous(&mut elements),
+            }
+            values.extend(elements);
+        }
+
+        Ok(match self {
+            AggregateOp::Count => NodeOutput::Integer(values.len() as i64),
+            op => NodeOutput::Number(op.reduce(&values)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, NodeId, Tree};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_aggregate() {
+        let mut node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+        ];
+        let mut edge_defs = Vec::new();
+        for (node_id, op) in [
+            (2, "sum"),
+            (3, "mean"),
+            (4, "min"),
+            (5, "max"),
+            (6, "count"),
+        ] {
+            node_defs.push(NodeDefinition {
+                node_id,
+                kind: 2,
+                value: op.into(),
+                default: None,
+            });
+            for input_id in [0, 1] {
+                edge_defs.push(EdgeDefinition { node_id, input_id });
+            }
+        }
+        // Aggregates are inputs of formulas like any other node
+        node_defs.push(NodeDefinition {
+            node_id: 7,
+            kind: 1,
+            value: "$0 - $3".into(),
+            default: None,
+        });
+        for input_id in [0, 3] {
+            edge_defs.push(EdgeDefinition {
+                node_id: 7,
+                input_id,
+            });
+        }
+        let tree = Tree::new(node_defs.clone(), edge_defs.clone()).unwrap();
+
+        let values: HashMap<NodeId, NodeOutput> = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![1., 5., 3.])),
+            (1, NodeOutput::Number(7.)),
+        ]);
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(16.));
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(4.));
+        assert_eq!(tree.eval(4, &values).unwrap(), NodeOutput::Number(1.));
+        assert_eq!(tree.eval(5, &values).unwrap(), NodeOutput::Number(7.));
+        assert_eq!(tree.eval(6, &values).unwrap(), NodeOutput::Integer(4));
+        assert_eq!(
+            tree.eval(7, &values).unwrap(),
+            NodeOutput::NumberArray(vec![-3., 1., -1.])
+        );
+
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![1., f64::NAN, 3.])),
+            (1, NodeOutput::Number(8.)),
+        ]);
+        assert!(tree.eval(2, &values).unwrap().is_missing());
+        let skipping = tree
+            .clone()
+            .with_missing_policy(&[2, 6], MissingPolicy::Skip)
+            .unwrap();
+        assert_eq!(skipping.eval(2, &values).unwrap(), NodeOutput::Number(12.));
+        assert_eq!(skipping.eval(6, &values).unwrap(), NodeOutput::Integer(3));
+
+        // The compiled tree aggregates scalars
+        let compiled = tree.compile(3).unwrap();
+        assert_eq!(compiled.eval(&[1., 4.]).unwrap(), 2.5);
+
+        node_defs[2].value = "median".into();
+        assert!(Tree::new(node_defs, edge_defs).is_err());
+    }
+}
@@ -0,0 +1,543 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::thread;
+
+use crate::core::{CacheStats, EvalCache, NodeId, NodeOutput, Tree};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scenario {
+    pub probability: f64,
+    pub values: HashMap<NodeId, NodeOutput>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TornadoBar {
+    pub node_id: NodeId,
+    pub low: f64,
+    pub high: f64,
+}
+
+impl TornadoBar {
+    pub fn swing(&self) -> f64 {
+        (self.high - self.low).abs()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SweepPoint {
+    pub values: Vec<f64>,
+    pub output: NodeOutput,
+}
+
+// Walks the cartesian grid like an odometer, the last axis changes fastest. Between two points
+// only the nodes depending on a changed variable are evaluated again.
+pub struct Sweep<'a> {
+    tree: &'a Tree,
+    node_id: NodeId,
+    values: HashMap<NodeId, NodeOutput>,
+    grid: &'a [(NodeId, Vec<f64>)],
+    indices: Vec<usize>,
+    previous: Option<Vec<f64>>,
+    cache: EvalCache,
+    done: bool,
+}
+
+impl Sweep<'_> {
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats
+    }
+}
+
+impl Iterator for Sweep<'_> {
+    type Item = Result<SweepPoint>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut point = Vec::with_capacity(self.grid.len());
+        for ((variable_id, axis), idx) in self.grid.iter().zip(&self.indices) {
+            point.push(axis[*idx]);
+            self.values
+                .insert(*variable_id, NodeOutput::Number(axis[*idx]));
+        }
+
+        let changed = match &self.previous {
+            Some(previous) => changed_variables(self.grid, previous, &point),
+            None => Vec::new(),
+        };
+        self.cache.invalidate(self.tree, &changed);
+        self.previous = Some(point.clone());
+
+        self.done = true;
+        for (idx, (_, axis)) in self.indices.iter_mut().zip(self.grid).rev() {
+            *idx += 1;
+            if *idx < axis.len() {
+                self.done = false;
+                break;
+            }
+            *idx = 0;
+        }
+
+        Some(
+            self.tree
+                .eval_cached(self.node_id, &self.values, &mut self.cache)
+                .map(|output| SweepPoint {
+                    values: point,
+                    output,
+                }),
+        )
+    }
+}
+
+// The variables of the grid whose value differs between the two points
+fn changed_variables(grid: &[(NodeId, Vec<f64>)], previous: &[f64], point: &[f64]) -> Vec<NodeId> {
+    grid.iter()
+        .zip(previous.iter().zip(point))
+        .filter(|(_, (a, b))| a != b)
+        .map(|((variable_id, _), _)| *variable_id)
+        .collect()
+}
+
+// The point at `idx` in the order the sweep walks the grid
+fn grid_point(grid: &[(NodeId, Vec<f64>)], mut idx: usize) -> Vec<f64> {
+    let mut point = vec![0.; grid.len()];
+    for (value, (_, axis)) in point.iter_mut().zip(grid).rev() {
+        *value = axis[idx % axis.len()];
+        idx /= axis.len();
+    }
+    point
+}
+
+fn to_output(mut values: Vec<f64>) -> NodeOutput {
+    match values.len() {
+        1 => NodeOutput::Number(values.remove(0)),
+        _ => NodeOutput::NumberArray(values),
+    }
+}
+
+impl Tree {
+    pub fn expected_value(&self, node_id: NodeId, scenarios: &[Scenario]) -> Result<NodeOutput> {
+        let (mean, _) = self.scenario_moments(node_id, scenarios, &mut EvalCache::new())?;
+        Ok(to_output(mean))
+    }
+
+    pub fn variance(&self, node_id: NodeId, scenarios: &[Scenario]) -> Result<NodeOutput> {
+        let (_, variance) = self.scenario_moments(node_id, scenarios, &mut EvalCache::new())?;
+        Ok(to_output(variance))
+    }
+
+    // Bars are sorted by decreasing swing, the order a tornado chart is drawn in
+    pub fn tornado(
+        &self,
+        node_id: NodeId,
+        base_values: &HashMap<NodeId, NodeOutput>,
+        variable_ranges: &HashMap<NodeId, (f64, f64)>,
+    ) -> Result<Vec<TornadoBar>> {
+        let eval_scalar = |values: &HashMap<NodeId, NodeOutput>| -> Result<f64> {
+            let output = self.eval(node_id, values)?;
+            match output {
+                NodeOutput::Number(v) => Ok(v),
+                NodeOutput::Integer(v) => Ok(v as f64),
+                NodeOutput::Bool(_) | NodeOutput::String(_) => Err(anyhow!(
+                    "tornado analysis needs a number, node {} returned {:?}",
+                    node_id,
+                    output
+                )),
+                NodeOutput::NumberArray(_)
+                | NodeOutput::BoolArray(_)
+                | NodeOutput::StringArray(_)
+                | NodeOutput::Matrix(_)
+                | NodeOutput::Series { .. }
+                | NodeOutput::TimeSeries(_) => Err(anyhow!(
+                    "tornado analysis needs a scalar output, node {} returned an array",
+                    node_id
+                )),
+                NodeOutput::Custom(v) => Err(anyhow!(
+                    "tornado analysis needs a scalar output, node {} returned a {}",
+                    node_id,
+                    v.type_name()
+                )),
+            }
+        };
+
+        let mut bars = Vec::new();
+        for (variable_id, (low, high)) in variable_ranges {
+            let mut values = base_values.clone();
+            values.insert(*variable_id, NodeOutput::Number(*low));
+            let low = eval_scalar(&values)?;
+            values.insert(*variable_id, NodeOutput::Number(*high));
+            let high = eval_scalar(&values)?;
+
+            bars.push(TornadoBar {
+                node_id: *variable_id,
+                low,
+                high,
+            });
+        }
+
+        bars.sort_by(|a, b| {
+            b.swing()
+                .total_cmp(&a.swing())
+                .then(a.node_id.cmp(&b.node_id))
+        });
+        Ok(bars)
+    }
+
+    pub fn sweep<'a>(
+        &'a self,
+        node_id: NodeId,
+        base_values: &HashMap<NodeId, NodeOutput>,
+        grid: &'a [(NodeId, Vec<f64>)],
+    ) -> Sweep<'a> {
+        Sweep {
+            tree: self,
+            node_id,
+            values: base_values.clone(),
+            grid,
+            indices: vec![0; grid.len()],
+            previous: None,
+            cache: EvalCache::new(),
+            done: grid.iter().any(|(_, axis)| axis.is_empty()),
+        }
+    }
+
+    // Same points in the same order as `sweep`, with the grid cut into contiguous chunks that
+    // are swept on all cores. Every chunk reuses the outputs between its own points.
+    pub fn sweep_parallel(
+        &self,
+        node_id: NodeId,
+        base_values: &HashMap<NodeId, NodeOutput>,
+        grid: &[(NodeId, Vec<f64>)],
+    ) -> Result<Vec<SweepPoint>> {
+        let count: usize = grid.iter().map(|(_, axis)| axis.len()).product();
+        let threads = thread::available_parallelism().map_or(1, |x| x.get());
+        let chunk_len = count.div_ceil(threads).max(1);
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..count)
+                .step_by(chunk_len)
+                .map(|start| {
+                    scope.spawn(move || -> Result<Vec<SweepPoint>> {
+                        let mut values = base_values.clone();
+                        let mut cache = EvalCache::new();
+                        let mut previous: Option<Vec<f64>> = None;
+                        let mut points = Vec::new();
+                        for idx in start..count.min(start + chunk_len) {
+                            let point = grid_point(grid, idx);
+                            for ((variable_id, _), value) in grid.iter().zip(&point) {
+                                values.insert(*variable_id, NodeOutput::Number(*value));
+                            }
+                            if let Some(previous) = &previous {
+                                cache.invalidate(self, &changed_variables(grid, previous, &point));
+                            }
+                            previous = Some(point.clone());
+
+                            let output = self.eval_cached(node_id, &values, &mut cache)?;
+                            points.push(SweepPoint {
+                                values: point,
+                                output,
+                            });
+                        }
+                        Ok(points)
+                    })
+                })
+                .collect();
+
+            let mut points = Vec::with_capacity(count);
+            for handle in handles {
+                points.extend(
+                    handle
+                        .join()
+                        .map_err(|_| anyhow!("sweep thread panicked"))??,
+                );
+            }
+            Ok(points)
+        })
+    }
+
+    // Between two scenarios only the nodes depending on a variable whose value differs are
+    // evaluated again
+    fn scenario_moments(
+        &self,
+        node_id: NodeId,
+        scenarios: &[Scenario],
+        cache: &mut EvalCache,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        if scenarios.iter().any(|x| x.probability < 0.) {
+            return Err(anyhow!("scenario probabilities must not be negative"));
+        }
+        let total: f64 = scenarios.iter().map(|x| x.probability).sum();
+        if (total - 1.).abs() > 1e-9 {
+            return Err(anyhow!(
+                "scenario probabilities sum up to {} instead of 1",
+                total
+            ));
+        }
+
+        let mut outputs: Vec<(f64, Vec<f64>)> = Vec::new();
+        let mut previous: Option<&HashMap<NodeId, NodeOutput>> = None;
+        for scenario in scenarios {
+            if let Some(previous) = previous {
+                let changed: Vec<NodeId> = previous
+                    .keys()
+                    .chain(scenario.values.keys())
+                    .filter(|id| previous.get(id) != scenario.values.get(id))
+                    .copied()
+                    .collect();
+                cache.invalidate(self, &changed);
+            }
+            previous = Some(&scenario.values);
+
+            let output = self
+                .eval_cached(node_id, &scenario.values, cache)?
+                .elements()?;
+            if let Some((_, first)) = outputs.first() {
+                if first.len() != output.len() {
+                    return Err(anyhow!(
+                        "scenario outputs have different lengths ({} and {})",
+                        first.len(),
+                        output.len()
+                    ));
+                }
+            }
+            outputs.push((scenario.probability, output));
+        }
+
+        let len = outputs.first().map(|(_, x)| x.len()).unwrap_or(0);
+        let mut mean = vec![0.; len];
+        for (probability, output) in &outputs {
+            for (m, v) in mean.iter_mut().zip(output) {
+                *m += probability * v;
+            }
+        }
+        let mut variance = vec![0.; len];
+        for (probability, output) in &outputs {
+            for ((var, m), v) in variance.iter_mut().zip(&mean).zip(output) {
+                *var += probability * (v - m).powi(2);
+            }
+        }
+
+        Ok((mean, variance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    fn test_tree() -> Tree {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        Tree::new(node_defs, edge_defs).unwrap()
+    }
+
+    #[test]
+    fn test_expected_value() {
+        let tree = test_tree();
+        let scenarios = vec![
+            Scenario {
+                probability: 0.25,
+                values: HashMap::from([
+                    (0, NodeOutput::Number(2.)),
+                    (1, NodeOutput::NumberArray(vec![1., 2.])),
+                ]),
+            },
+            Scenario {
+                probability: 0.75,
+                values: HashMap::from([
+                    (0, NodeOutput::Number(6.)),
+                    (1, NodeOutput::NumberArray(vec![1., 2.])),
+                ]),
+            },
+        ];
+
+        assert_eq!(
+            tree.expected_value(2, &scenarios).unwrap(),
+            NodeOutput::NumberArray(vec![5., 10.])
+        );
+        assert_eq!(
+            tree.variance(2, &scenarios).unwrap(),
+            NodeOutput::NumberArray(vec![3., 12.])
+        );
+        assert!(tree.expected_value(2, &scenarios[..1]).is_err());
+    }
+
+    #[test]
+    fn test_scenarios_reuse_cache() {
+        let tree = test_tree();
+        let scenario = |probability, a| Scenario {
+            probability,
+            values: HashMap::from([(0, NodeOutput::Number(a)), (1, NodeOutput::Number(3.))]),
+        };
+        let scenarios = vec![scenario(0.5, 1.), scenario(0.25, 1.), scenario(0.25, 5.)];
+
+        // The second scenario takes the output of the first one, the third one only evaluates
+        // what depends on the changed variable
+        let mut cache = EvalCache::new();
+        let (mean, variance) = tree.scenario_moments(2, &scenarios, &mut cache).unwrap();
+        assert_eq!(mean, vec![6.]);
+        assert_eq!(variance, vec![27.]);
+        assert_eq!(cache.stats, CacheStats { hits: 2, misses: 5 });
+    }
+
+    #[test]
+    fn test_sweep() {
+        let tree = test_tree();
+        let base_values = HashMap::from([(0, NodeOutput::Number(2.))]);
+        let grid = vec![(0, vec![1., 2.]), (1, vec![10., 20., 30.])];
+
+        let outputs: Vec<_> = tree
+            .sweep(2, &base_values, &grid)
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(outputs.len(), 6);
+        assert_eq!(
+            outputs[2],
+            SweepPoint {
+                values: vec![1., 30.],
+                output: NodeOutput::Number(30.),
+            }
+        );
+        assert_eq!(outputs[3].values, vec![2., 10.]);
+        assert_eq!(
+            tree.sweep_parallel(2, &base_values, &grid).unwrap(),
+            outputs
+        );
+
+        let grid = vec![(1, vec![10.])];
+        let outputs: Vec<_> = tree.sweep(2, &base_values, &grid).collect();
+        assert_eq!(outputs.len(), 1);
+
+        let grid = vec![(0, vec![1., 2.]), (1, vec![])];
+        assert_eq!(tree.sweep(2, &base_values, &grid).count(), 0);
+        assert!(tree
+            .sweep_parallel(2, &base_values, &grid)
+            .unwrap()
+            .is_empty());
+
+        // Larger grids are split across threads in the same order
+        let grid = vec![(0, (0..7).map(f64::from).collect()), (1, vec![1., -1., 2.])];
+        let outputs: Vec<_> = tree
+            .sweep(2, &base_values, &grid)
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(
+            tree.sweep_parallel(2, &base_values, &grid).unwrap(),
+            outputs
+        );
+    }
+
+    #[test]
+    fn test_sweep_reuses_cache() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 + $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let base_values = HashMap::from([(0, NodeOutput::Number(2.))]);
+        let grid = vec![(1, vec![1., 2., 3.])];
+
+        let mut sweep = tree.sweep(3, &base_values, &grid);
+        let outputs: Vec<_> = sweep.by_ref().map(|x| x.unwrap().output).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                NodeOutput::Number(5.),
+                NodeOutput::Number(6.),
+                NodeOutput::Number(7.)
+            ]
+        );
+        assert_eq!(sweep.cache_stats(), CacheStats { hits: 2, misses: 8 });
+    }
+
+    #[test]
+    fn test_tornado() {
+        let tree = test_tree();
+        let base_values = HashMap::from([(0, NodeOutput::Number(2.)), (1, NodeOutput::Number(3.))]);
+        let ranges = HashMap::from([(0, (1., 4.)), (1, (-3., 1.))]);
+
+        let bars = tree.tornado(2, &base_values, &ranges).unwrap();
+        assert_eq!(
+            bars,
+            vec![
+                TornadoBar {
+                    node_id: 0,
+                    low: 3.,
+                    high: 12.,
+                },
+                TornadoBar {
+                    node_id: 1,
+                    low: -6.,
+                    high: 2.,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::core::{NodeId, NodeKind, NodeOutput, Tree};
+
+impl Tree {
+    // Evaluates like `eval`, but awaits the custom nodes that hand out a future for their output
+    // (see `CustomNode::eval_future`). Everything else runs inline, the caller picks the executor.
+    pub async fn eval_async(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<NodeOutput> {
+        let values = self.hooks.before(&[node_id], values)?;
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        for id in self.eval_order(node_id)?.iter() {
+            let node = self.node(*id)?;
+            if let NodeKind::Variable(_) = node.kind() {
+                outputs.insert(*id, node.eval(&values)?);
+                continue;
+            }
+
+            let mut input_outputs = Vec::new();
+            for input in node.inputs.iter() {
+                let output = outputs
+                    .get(input)
+                    .ok_or(anyhow!("node {} was not evaluated", input))?;
+                input_outputs.push(output.clone());
+            }
+            let future = match node.kind() {
+                NodeKind::Custom(custom) => custom.node.eval_future(&input_outputs),
+                _ => None,
+            };
+            let output = match future {
+                Some(future) => future.await?,
+                None => self.apply(node, input_outputs)?,
+            };
+            outputs.insert(*id, output);
+        }
+
+        let output = outputs
+            .remove(&node_id)
+            .ok_or(anyhow!("node {} was not evaluated", node_id))?;
+        self.hooks.after(node_id, &output)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+    use crate::registry::{CustomNode, NodeFactory, NodeFuture, NodeRegistry};
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::task::{Context, Poll, Waker};
+
+    // Answers on the second poll, like a query that is not done right away
+    struct Delayed {
+        output: f64,
+        polled: bool,
+    }
+
+    impl Future for Delayed {
+        type Output = Result<NodeOutput>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.polled {
+                return Poll::Ready(Ok(NodeOutput::Number(self.output)));
+            }
+            self.polled = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[derive(Debug)]
+    struct Query;
+
+    impl CustomNode for Query {
+        fn eval(&self, _inputs: &[NodeOutput]) -> Result<NodeOutput> {
+            Err(anyhow!("the query can only be awaited"))
+        }
+
+        fn value(&self) -> String {
+            String::new()
+        }
+
+        fn eval_future(&self, inputs: &[NodeOutput]) -> Option<NodeFuture> {
+            let NodeOutput::Number(v) = inputs[0] else {
+                return None;
+            };
+            Some(Box::pin(Delayed {
+                output: v * 10.,
+                polled: false,
+            }))
+        }
+    }
+
+    struct QueryFactory;
+
+    impl NodeFactory for QueryFactory {
+        fn name(&self) -> &str {
+            "query"
+        }
+
+        fn create(&self, _value: &str) -> Result<Box<dyn CustomNode>> {
+            Ok(Box::new(Query))
+        }
+    }
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_async() {
+        let mut registry = NodeRegistry::new();
+        registry.register(7, QueryFactory).unwrap();
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 7,
+                value: "".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(2.))]);
+
+        assert!(tree.eval(2, &values).is_err());
+        assert_eq!(
+            block_on(tree.eval_async(2, &values)).unwrap(),
+            NodeOutput::Number(21.)
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use evalexpr::{Context, EvalexprResult, HashMapContext, Node, Value};
+
+use crate::formula::referenced_input;
+
+// Maps every variable a formula reads to the index of the input that provides it, so
+// evaluation can fill in values by position instead of formatting and hashing "$id" keys
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct BindingPlan {
+    identifiers: Vec<String>,
+    inputs: Vec<usize>,
+}
+
+impl BindingPlan {
+    pub(crate) fn new(formula: &Node, input_ids: &[usize]) -> Self {
+        let mut plan = Self::default();
+        for identifier in formula.iter_read_variable_identifiers() {
+            if plan.identifiers.iter().any(|x| x == identifier) {
+                continue;
+            }
+            let input =
+                referenced_input(identifier).and_then(|id| input_ids.iter().position(|x| *x == id));
+
+            // Unbound variables are left out and fail at evaluation like before
+            if let Some(input) = input {
+                plan.identifiers.push(identifier.to_string());
+                plan.inputs.push(input);
+            }
+        }
+        plan
+    }
+
+    pub(crate) fn inputs(&self) -> &[usize] {
+        &self.inputs
+    }
+
+    // The index of the input the variable reads
+    pub(crate) fn input(&self, identifier: &str) -> Option<usize> {
+        let slot = self.identifiers.iter().position(|x| x == identifier)?;
+        Some(self.inputs[slot])
+    }
+}
+
+// Formulas read only a handful of variables, a linear scan over them beats hashing
+pub(crate) struct BoundContext<'a> {
+    plan: &'a BindingPlan,
+    values: Vec<Value>,
+    functions: HashMapContext,
+}
+
+impl<'a> BoundContext<'a> {
+    pub(crate) fn new(plan: &'a BindingPlan, functions: HashMapContext) -> Self {
+        Self {
+            plan,
+            values: vec![Value::Empty; plan.identifiers.len()],
+            functions,
+        }
+    }
+
+    // `slot` is the position of the binding in `BindingPlan::inputs`
+    pub(crate) fn set(&mut self, slot: usize, value: f64) {
+        self.values[slot] = Value::Float(value);
+    }
+
+    pub(crate) fn set_value(&mut self, slot: usize, value: Value) {
+        self.values[slot] = value;
+    }
+}
+
+impl Context for BoundContext<'_> {
+    fn get_value(&self, identifier: &str) -> Option<&Value> {
+        let slot = self.plan.identifiers.iter().position(|x| x == identifier)?;
+        self.values.get(slot)
+    }
+
+    fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        self.functions.call_function(identifier, argument)
+    }
+
+    fn are_builtin_functions_disabled(&self) -> bool {
+        self.functions.are_builtin_functions_disabled()
+    }
+
+    fn set_builtin_functions_disabled(&mut self, disabled: bool) -> EvalexprResult<()> {
+        self.functions.set_builtin_functions_disabled(disabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evalexpr::build_operator_tree;
+
+    #[test]
+    fn test_binding_plan() {
+        let formula = build_operator_tree("$4 * $2 + max($4, $7)").unwrap();
+        let plan = BindingPlan::new(&formula, &[2, 4]);
+        assert_eq!(plan.identifiers, vec!["$4", "$2"]);
+        assert_eq!(plan.inputs(), &[1, 0]);
+
+        let mut context = BoundContext::new(&plan, HashMapContext::new());
+        context.set(0, 3.);
+        context.set(1, 0.5);
+        assert_eq!(context.get_value("$2"), Some(&Value::Float(0.5)));
+        assert_eq!(context.get_value("$7"), None);
+    }
+}
@@ -0,0 +1,1091 @@
+use anyhow::{anyhow, Result};
+use rusqlite::types::FromSql;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+
+use crate::core::{EdgeDefinition, NodeDefinition, NodeId, NodeOutput, TreeDraft};
+use crate::examples::Example;
+use crate::formula::canonical_references;
+use crate::history::Edit;
+use crate::permissions::EditContext;
+
+// Same as `defintions_from_sqlite`, but on rusqlite without any async executor
+pub fn definitions_from_sqlite_blocking(
+    file_name: String,
+    root_node_id: usize,
+) -> Result<(Vec<NodeDefinition>, Vec<EdgeDefinition>)> {
+    let conn = Connection::open(file_name)?;
+
+    let mut edge_query = conn.prepare(
+        "
+        WITH RECURSIVE child_tree AS (
+            SELECT node_id, input_id
+            FROM edge
+            WHERE node_id = ?
+
+            UNION ALL
+
+            SELECT c.node_id, c.input_id
+            FROM edge c
+            JOIN child_tree ct ON c.node_id = ct.input_id
+        )
+
+        SELECT node_id, input_id FROM child_tree
+        ",
+    )?;
+    let edges = edge_query.query_map([root_node_id as i64], |row| {
+        Ok((
+            row.get::<_, i64>("node_id")?,
+            row.get::<_, i64>("input_id")?,
+        ))
+    })?;
+
+    let mut node_ids = HashSet::new();
+    let mut edge_definitions = Vec::new();
+    for edge in edges {
+        let (node_id, input_id) = edge?;
+        node_ids.insert(node_id);
+        node_ids.insert(input_id);
+        edge_definitions.push(EdgeDefinition {
+            node_id: node_id as usize,
+            input_id: input_id as usize,
+        });
+    }
+    node_ids.insert(root_node_id as i64);
+
+    let placeholders = "?,".repeat(node_ids.len());
+    let query = format!(
+        "SELECT * FROM node WHERE node_id IN ({})",
+        placeholders.trim_matches(',')
+    );
+    let mut node_query = conn.prepare(&query)?;
+    let nodes = node_query.query_map(params_from_iter(node_ids), |row| {
+        // Tables from before the column was added have no defaults
+        let default = match row.get::<_, Option<String>>("default_value") {
+            Err(rusqlite::Error::InvalidColumnName(_)) => None,
+            default => default?,
+        };
+        Ok((
+            row.get::<_, i64>("node_id")? as usize,
+            row.get::<_, i64>("type")? as usize,
+            row.get("operation")?,
+            default,
+        ))
+    })?;
+
+    let mut nodes_definitions = Vec::new();
+    for node in nodes {
+        let (node_id, kind, value, default) = node?;
+        nodes_definitions.push(NodeDefinition {
+            node_id,
+            kind,
+            value,
+            default: match default {
+                Some(default) => Some(serde_json::from_str(&default)?),
+                None => None,
+            },
+        });
+    }
+    Ok((nodes_definitions, edge_definitions))
+}
+
+// Reads the graph below `root_node_id` for editing, with the roles owning its nodes. The draft
+// remembers the nodes as they were read, see `write_draft_sqlite_blocking`.
+pub fn draft_from_sqlite_blocking(file_name: String, root_node_id: NodeId) -> Result<TreeDraft> {
+    let (node_defs, _) = definitions_from_sqlite_blocking(file_name.clone(), root_node_id)?;
+    let mut node_ids: Vec<NodeId> = node_defs.iter().map(|x| x.node_id).collect();
+    node_ids.sort();
+
+    let conn = Connection::open(file_name)?;
+    let mut nodes = Vec::new();
+    for node_id in node_ids {
+        let node = stored_node(&conn, node_id)?.ok_or(anyhow!("no node with id {}", node_id))?;
+        nodes.push(node);
+    }
+
+    let mut draft = TreeDraft::new();
+    for node in &nodes {
+        draft.add_node(node.node_def.clone())?;
+    }
+    for node in nodes {
+        let node_id = node.node_def.node_id;
+        for input_id in &node.inputs {
+            draft.connect(node_id, *input_id)?;
+        }
+        draft.set_owner(node_id, node.owner.as_deref())?;
+        draft.set_read_node(node);
+    }
+    Ok(draft)
+}
+
+// A node changed in a draft and by someone else since the draft was read
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConflictingNode {
+    pub node_id: NodeId,
+    // None if the node was added to the draft
+    pub read_version: Option<u64>,
+    pub stored_version: u64,
+    pub ours: NodeDefinition,
+    pub our_inputs: Vec<NodeId>,
+    pub theirs: NodeDefinition,
+    pub their_inputs: Vec<NodeId>,
+}
+
+// The error of `write_draft_sqlite_blocking` if nodes were changed concurrently, found with
+// `downcast_ref` on the returned error
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conflict {
+    pub nodes: Vec<ConflictingNode>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node_ids: Vec<_> = self.nodes.iter().map(|x| x.node_id.to_string()).collect();
+        write!(
+            f,
+            "nodes with ids {} were changed since the draft was read",
+            node_ids.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+// Writes back the nodes changed in the draft since it was read, with their inputs. Changing a
+// stored node needs the role owning it and the stored version must still be the one the draft
+// was read at, otherwise nothing is written. Nodes only changed by someone else are left alone.
+// Every write counts up the version of the node, its edges get the same one. Removed nodes keep
+// their rows, other graphs may still read them. Returns the written nodes.
+pub fn write_draft_sqlite_blocking(
+    file_name: String,
+    draft: &mut TreeDraft,
+    context: &EditContext,
+) -> Result<Vec<NodeId>> {
+    let mut conn = Connection::open(file_name)?;
+    add_column(&conn, "node", "default_value", "TEXT")?;
+    add_column(&conn, "node", "owner", "TEXT")?;
+    add_column(&conn, "node", "version", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(&conn, "edge", "version", "INTEGER NOT NULL DEFAULT 0")?;
+
+    let tx = conn.transaction()?;
+    let mut written = Vec::new();
+    let mut stored_nodes = Vec::new();
+    let mut conflicts = Vec::new();
+    for node_def in draft.node_definitions() {
+        let node_id = node_def.node_id;
+        let ours = StoredNode {
+            node_def: node_def.clone(),
+            // The order of the inputs matters, e.g. for `id0` and `id1` or custom nodes
+            inputs: draft
+                .edge_definitions()
+                .iter()
+                .filter(|x| x.node_id == node_id)
+                .map(|x| x.input_id)
+                .collect(),
+            owner: draft.owner(node_id).map(|x| x.to_string()),
+            version: 0,
+        };
+        let read = draft.read_node(node_id);
+        if read.is_some_and(|x| x.same_as(&ours)) {
+            continue;
+        }
+
+        let mut version = read.map_or(1, |x| x.version + 1);
+        if let Some(stored) = stored_node(&tx, node_id)? {
+            // Someone else already made the same change
+            if stored.same_as(&ours) {
+                stored_nodes.push(stored);
+                continue;
+            }
+            context.check(node_id, stored.owner.as_deref())?;
+            if read.map(|x| x.version) != Some(stored.version) {
+                conflicts.push(ConflictingNode {
+                    node_id,
+                    read_version: read.map(|x| x.version),
+                    stored_version: stored.version,
+                    ours: ours.node_def,
+                    our_inputs: ours.inputs,
+                    theirs: stored.node_def,
+                    their_inputs: stored.inputs,
+                });
+                continue;
+            }
+            version = stored.version + 1;
+        }
+
+        let default = match &node_def.default {
+            Some(default) => Some(serde_json::to_string(default)?),
+            None => None,
+        };
+        tx.execute(
+            r#"
+            INSERT INTO node (node_id, type, operation, default_value, owner, version)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (node_id) DO UPDATE SET
+                type = excluded.type,
+                operation = excluded.operation,
+                default_value = excluded.default_value,
+                owner = excluded.owner,
+                version = excluded.version
+            "#,
+            params![
+                node_id as i64,
+                node_def.kind as i64,
+                node_def.value,
+                default,
+                ours.owner,
+                version as i64
+            ],
+        )?;
+        tx.execute("DELETE FROM edge WHERE node_id = ?", [node_id as i64])?;
+        for input_id in &ours.inputs {
+            tx.execute(
+                "INSERT INTO edge (node_id, input_id, version) VALUES (?, ?, ?)",
+                params![node_id as i64, *input_id as i64, version as i64],
+            )?;
+        }
+        written.push(node_id);
+        stored_nodes.push(StoredNode { version, ..ours });
+    }
+    if !conflicts.is_empty() {
+        return Err(Conflict { nodes: conflicts }.into());
+    }
+    tx.commit()?;
+
+    for node in stored_nodes {
+        draft.set_read_node(node);
+    }
+    Ok(written)
+}
+
+// A node as stored, with its inputs in the order of the edge ids. Tables from before the
+// columns were added have no defaults, owners and versions.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct StoredNode {
+    pub(crate) node_def: NodeDefinition,
+    pub(crate) inputs: Vec<NodeId>,
+    pub(crate) owner: Option<String>,
+    pub(crate) version: u64,
+}
+
+impl StoredNode {
+    fn same_as(&self, other: &StoredNode) -> bool {
+        self.node_def == other.node_def && self.inputs == other.inputs && self.owner == other.owner
+    }
+}
+
+fn stored_node(conn: &Connection, node_id: NodeId) -> Result<Option<StoredNode>> {
+    let row = conn
+        .query_row(
+            "SELECT * FROM node WHERE node_id = ?",
+            [node_id as i64],
+            |row| {
+                Ok((
+                    row.get::<_, i64>("type")? as usize,
+                    row.get::<_, String>("operation")?,
+                    column_or(row, "default_value", None::<String>)?,
+                    column_or(row, "owner", None::<String>)?,
+                    column_or(row, "version", 0i64)? as u64,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((kind, value, default, owner, version)) = row else {
+        return Ok(None);
+    };
+
+    let mut input_query =
+        conn.prepare("SELECT input_id FROM edge WHERE node_id = ? ORDER BY edge_id")?;
+    let inputs = input_query
+        .query_map([node_id as i64], |row| {
+            Ok(row.get::<_, i64>("input_id")? as NodeId)
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let node_def = NodeDefinition {
+        node_id,
+        kind,
+        value,
+        default: match default {
+            Some(default) => Some(serde_json::from_str(&default)?),
+            None => None,
+        },
+    };
+    Ok(Some(StoredNode {
+        node_def,
+        inputs,
+        owner,
+        version,
+    }))
+}
+
+fn column_or<T: FromSql>(row: &Row, column: &str, default: T) -> rusqlite::Result<T> {
+    match row.get(column) {
+        Err(rusqlite::Error::InvalidColumnName(_)) => Ok(default),
+        value => value,
+    }
+}
+
+// Tables from before a column was added get it on the first write
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut query = conn.prepare(&format!(r#"PRAGMA table_info("{}")"#, table))?;
+    let columns = query
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if !columns.iter().any(|x| x == column) {
+        conn.execute(
+            &format!(
+                r#"ALTER TABLE "{}" ADD COLUMN "{}" {}"#,
+                table, column, definition
+            ),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+// Reads the markdown documentation of the nodes from the `doc` column of the node table, nodes
+// without one are left out
+pub fn docs_from_sqlite_blocking(
+    file_name: String,
+    node_ids: &[NodeId],
+) -> Result<HashMap<NodeId, String>> {
+    let conn = Connection::open(file_name)?;
+    let placeholders = "?,".repeat(node_ids.len());
+    let query = format!(
+        "SELECT node_id, doc FROM node WHERE doc IS NOT NULL AND node_id IN ({})",
+        placeholders.trim_matches(',')
+    );
+    let mut doc_query = conn.prepare(&query)?;
+    let docs = doc_query.query_map(
+        params_from_iter(node_ids.iter().map(|x| *x as i64)),
+        |row| Ok((row.get::<_, i64>("node_id")? as NodeId, row.get("doc")?)),
+    )?;
+    Ok(docs.collect::<rusqlite::Result<_>>()?)
+}
+
+// Stores every element of the outputs as one row of the result table, which is created
+// if needed. Writing the same evaluation again replaces its earlier values.
+pub fn write_results(
+    file_name: String,
+    graph_id: usize,
+    eval_id: usize,
+    outputs: &HashMap<NodeId, NodeOutput>,
+) -> Result<()> {
+    let mut conn = Connection::open(file_name)?;
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS "result" (
+            "graph_id"	INTEGER NOT NULL,
+            "eval_id"	INTEGER NOT NULL,
+            "node_id"	INTEGER NOT NULL,
+            "index"	INTEGER NOT NULL,
+            "value"	REAL NOT NULL,
+            "timestamp"	INTEGER NOT NULL,
+            PRIMARY KEY("graph_id", "eval_id", "node_id", "index")
+        );
+        "#,
+    )?;
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        r#"DELETE FROM "result" WHERE "graph_id" = ? AND "eval_id" = ?"#,
+        params![graph_id as i64, eval_id as i64],
+    )?;
+    {
+        let mut insert = tx.prepare(
+            r#"
+            INSERT INTO "result" ("graph_id", "eval_id", "node_id", "index", "value", "timestamp")
+            VALUES (?, ?, ?, ?, ?, unixepoch())
+            "#,
+        )?;
+        let mut node_ids: Vec<_> = outputs.keys().collect();
+        node_ids.sort();
+        for node_id in node_ids {
+            let values = outputs[node_id].elements()?;
+            for (idx, value) in values.iter().enumerate() {
+                insert.execute(params![
+                    graph_id as i64,
+                    eval_id as i64,
+                    *node_id as i64,
+                    idx as i64,
+                    value
+                ])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Rewrites the stored formulas reading their inputs as `id0`, `id1`, ... to read
+// `$<node id>`, where the inputs are in the order of their edge ids. Returns the rewritten nodes.
+pub fn canonicalize_formulas_sqlite_blocking(file_name: String) -> Result<Vec<NodeId>> {
+    let mut conn = Connection::open(file_name)?;
+    let tx = conn.transaction()?;
+
+    let mut inputs: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+    let mut formulas = Vec::new();
+    {
+        let mut edge_query = tx.prepare("SELECT node_id, input_id FROM edge ORDER BY edge_id")?;
+        let edges = edge_query.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>("node_id")? as NodeId,
+                row.get::<_, i64>("input_id")? as NodeId,
+            ))
+        })?;
+        for edge in edges {
+            let (node_id, input_id) = edge?;
+            inputs.entry(node_id).or_default().push(input_id);
+        }
+
+        let mut node_query = tx.prepare("SELECT node_id, operation FROM node WHERE type = 1")?;
+        let nodes = node_query.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>("node_id")? as NodeId,
+                row.get::<_, String>("operation")?,
+            ))
+        })?;
+        for node in nodes {
+            formulas.push(node?);
+        }
+    }
+
+    let mut rewritten = Vec::new();
+    for (node_id, formula) in formulas {
+        let node_inputs = inputs.get(&node_id).map_or(&[][..], |x| x.as_slice());
+        let canonical = canonical_references(&formula, node_inputs)?;
+        if canonical != formula {
+            tx.execute(
+                "UPDATE node SET operation = ? WHERE node_id = ?",
+                params![canonical, node_id as i64],
+            )?;
+            rewritten.push(node_id);
+        }
+    }
+    tx.commit()?;
+    rewritten.sort();
+    Ok(rewritten)
+}
+
+const EXAMPLE_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS "example" (
+        "root_id"	INTEGER NOT NULL,
+        "name"	TEXT NOT NULL,
+        "node_id"	INTEGER NOT NULL,
+        "expected"	INTEGER NOT NULL,
+        "value"	TEXT NOT NULL,
+        PRIMARY KEY("root_id", "name", "node_id", "expected")
+    );
+"#;
+
+// Stores the example for the graph below `root_node_id`, replacing an example of the same name.
+// Values are kept as the JSON of `NodeOutput`.
+pub fn write_example(file_name: String, root_node_id: NodeId, example: &Example) -> Result<()> {
+    let mut conn = Connection::open(file_name)?;
+    conn.execute_batch(EXAMPLE_TABLE)?;
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        r#"DELETE FROM "example" WHERE "root_id" = ? AND "name" = ?"#,
+        params![root_node_id as i64, example.name],
+    )?;
+    {
+        let mut insert = tx.prepare(
+            r#"
+            INSERT INTO "example" ("root_id", "name", "node_id", "expected", "value")
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )?;
+        for (expected, values) in [(false, &example.inputs), (true, &example.expected)] {
+            for (node_id, value) in values {
+                insert.execute(params![
+                    root_node_id as i64,
+                    example.name,
+                    *node_id as i64,
+                    expected,
+                    serde_json::to_string(value)?
+                ])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Reads the examples of the graph below `root_node_id` in the order they were written
+pub fn examples_from_sqlite_blocking(
+    file_name: String,
+    root_node_id: NodeId,
+) -> Result<Vec<Example>> {
+    let conn = Connection::open(file_name)?;
+    conn.execute_batch(EXAMPLE_TABLE)?;
+
+    let mut query = conn.prepare(
+        r#"
+        SELECT "name", "node_id", "expected", "value" FROM "example"
+        WHERE "root_id" = ? ORDER BY rowid
+        "#,
+    )?;
+    let rows = query.query_map([root_node_id as i64], |row| {
+        Ok((
+            row.get::<_, String>("name")?,
+            row.get::<_, i64>("node_id")? as NodeId,
+            row.get::<_, bool>("expected")?,
+            row.get::<_, String>("value")?,
+        ))
+    })?;
+
+    let mut examples: Vec<Example> = Vec::new();
+    for row in rows {
+        let (name, node_id, expected, value) = row?;
+        let idx = match examples.iter().position(|x| x.name == name) {
+            Some(idx) => idx,
+            None => {
+                examples.push(Example {
+                    name,
+                    ..Default::default()
+                });
+                examples.len() - 1
+            }
+        };
+        let values = match expected {
+            true => &mut examples[idx].expected,
+            false => &mut examples[idx].inputs,
+        };
+        values.insert(node_id, serde_json::from_str(&value)?);
+    }
+    Ok(examples)
+}
+
+const EDIT_LOG_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS "edit_log" (
+        "root_id"	INTEGER NOT NULL,
+        "role"	TEXT,
+        "edit"	TEXT NOT NULL,
+        "timestamp"	INTEGER NOT NULL
+    );
+"#;
+
+// Appends edits made to the graph below `root_node_id` to the edit log, e.g. the ones taken from
+// `History::take_log`, together with the role making them. Edits are kept as JSON.
+pub fn write_edit_log(
+    file_name: String,
+    root_node_id: NodeId,
+    role: Option<&str>,
+    edits: &[Edit],
+) -> Result<()> {
+    let mut conn = Connection::open(file_name)?;
+    conn.execute_batch(EDIT_LOG_TABLE)?;
+
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare(
+            r#"
+            INSERT INTO "edit_log" ("root_id", "role", "edit", "timestamp")
+            VALUES (?, ?, ?, unixepoch())
+            "#,
+        )?;
+        for edit in edits {
+            insert.execute(params![
+                root_node_id as i64,
+                role,
+                serde_json::to_string(edit)?
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Reads the edits of the graph below `root_node_id` in the order they were written
+pub fn edit_log_from_sqlite_blocking(file_name: String, root_node_id: NodeId) -> Result<Vec<Edit>> {
+    let conn = Connection::open(file_name)?;
+    conn.execute_batch(EDIT_LOG_TABLE)?;
+
+    let mut query =
+        conn.prepare(r#"SELECT "edit" FROM "edit_log" WHERE "root_id" = ? ORDER BY rowid"#)?;
+    let edits = query.query_map([root_node_id as i64], |row| row.get::<_, String>("edit"))?;
+    let mut res = Vec::new();
+    for edit in edits {
+        res.push(serde_json::from_str(&edit?)?);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Tree;
+    use crate::history::History;
+
+    #[test]
+    fn test_definitions_from_sqlite_blocking() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_blocking.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let conn = Connection::open(&file_name).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE "node" (
+                "node_id"	INTEGER NOT NULL UNIQUE,
+                "type"	INTEGER NOT NULL,
+                "operation"	BLOB NOT NULL,
+                "doc"	TEXT,
+                "default_value"	TEXT,
+                PRIMARY KEY("node_id" AUTOINCREMENT)
+            );
+
+            CREATE TABLE "edge" (
+                "edge_id"	INTEGER NOT NULL UNIQUE,
+                "node_id"	INTEGER NOT NULL,
+                "input_id"	INTEGER NOT NULL,
+                PRIMARY KEY("edge_id" AUTOINCREMENT)
+            );
+
+            INSERT INTO "node"("node_id","type","operation","default_value") VALUES (1,0,'a','{"Number":2.0}');
+            INSERT INTO "node"("node_id","type","operation","doc") VALUES (2,1,'$1 * 2','Twice *a*');
+            INSERT INTO "node"("node_id","type","operation") VALUES (3,1,'$2 + 1');
+            INSERT INTO "node"("node_id","type","operation") VALUES (4,0,'b');
+            INSERT INTO "edge"("edge_id","node_id","input_id") VALUES (1,2,1);
+            INSERT INTO "edge"("edge_id","node_id","input_id") VALUES (2,3,2);
+        "#,
+        )
+        .unwrap();
+
+        let (mut node_defs, edge_defs) =
+            definitions_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        node_defs.sort_by_key(|x| x.node_id);
+        assert_eq!(
+            node_defs.iter().map(|x| x.node_id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(node_defs[1].value, "$1 * 2");
+        assert_eq!(node_defs[0].default, Some(NodeOutput::Number(2.)));
+        assert_eq!(edge_defs.len(), 2);
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        assert_eq!(
+            tree.eval(3, &HashMap::new()).unwrap(),
+            NodeOutput::Number(5.)
+        );
+
+        let docs = docs_from_sqlite_blocking(file_name.clone(), &[1, 2, 3]).unwrap();
+        assert_eq!(docs, HashMap::from([(2, "Twice *a*".to_string())]));
+
+        // Node 3 reads its input in the old dialect
+        conn.execute(
+            "UPDATE node SET operation = 'id0 + 1' WHERE node_id = 3",
+            [],
+        )
+        .unwrap();
+        let (node_defs, edge_defs) =
+            definitions_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        assert_eq!(
+            tree.eval(3, &HashMap::new()).unwrap(),
+            NodeOutput::Number(5.)
+        );
+        assert_eq!(
+            canonicalize_formulas_sqlite_blocking(file_name.clone()).unwrap(),
+            vec![3]
+        );
+        let (node_defs, _) = definitions_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        let node_def = node_defs.iter().find(|x| x.node_id == 3).unwrap();
+        assert_eq!(node_def.value, "$2 + 1");
+        assert!(canonicalize_formulas_sqlite_blocking(file_name)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_write_results() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_results.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2.]))]);
+        let outputs = tree.eval_all(1, &values).unwrap();
+        write_results(file_name.clone(), 1, 1, &outputs).unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::Number(5.))]);
+        let outputs = tree.eval_all(1, &values).unwrap();
+        write_results(file_name.clone(), 1, 1, &outputs).unwrap();
+        write_results(file_name.clone(), 1, 2, &outputs).unwrap();
+
+        let conn = Connection::open(&file_name).unwrap();
+        let rows: Vec<(i64, i64, i64, f64)> = conn
+            .prepare(
+                r#"SELECT "eval_id", "node_id", "index", "value" FROM "result"
+                ORDER BY "eval_id", "node_id", "index""#,
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![(1, 0, 0, 5.), (1, 1, 0, 10.), (2, 0, 0, 5.), (2, 1, 0, 10.),]
+        );
+    }
+
+    #[test]
+    fn test_examples() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_examples.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let mut example = Example {
+            name: "double".into(),
+            inputs: HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2.]))]),
+            expected: HashMap::from([(1, NodeOutput::NumberArray(vec![2., 4.]))]),
+        };
+        write_example(file_name.clone(), 1, &example).unwrap();
+        let other = Example {
+            name: "zero".into(),
+            inputs: HashMap::from([(0, NodeOutput::Number(0.))]),
+            expected: HashMap::new(),
+        };
+        write_example(file_name.clone(), 2, &other).unwrap();
+        example
+            .expected
+            .insert(1, NodeOutput::NumberArray(vec![2., 5.]));
+        write_example(file_name.clone(), 1, &example).unwrap();
+
+        let examples = examples_from_sqlite_blocking(file_name.clone(), 1).unwrap();
+        assert_eq!(examples, vec![example.clone()]);
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs)
+            .unwrap()
+            .with_examples(examples)
+            .unwrap();
+        let mismatches = tree.self_test();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            mismatches[0].actual,
+            Ok(NodeOutput::NumberArray(vec![2., 4.]))
+        );
+    }
+
+    #[test]
+    fn test_write_draft() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_draft.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        // Tables from before the default and owner columns were added
+        let conn = Connection::open(&file_name).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE "node" (
+                "node_id"	INTEGER NOT NULL UNIQUE,
+                "type"	INTEGER NOT NULL,
+                "operation"	BLOB NOT NULL,
+                "doc"	TEXT,
+                PRIMARY KEY("node_id" AUTOINCREMENT)
+            );
+
+            CREATE TABLE "edge" (
+                "edge_id"	INTEGER NOT NULL UNIQUE,
+                "node_id"	INTEGER NOT NULL,
+                "input_id"	INTEGER NOT NULL,
+                PRIMARY KEY("edge_id" AUTOINCREMENT)
+            );
+
+            INSERT INTO "node"("node_id","type","operation","doc") VALUES (1,0,'a','The *a*');
+            INSERT INTO "node"("node_id","type","operation") VALUES (2,1,'$1 * 2');
+            INSERT INTO "edge"("node_id","input_id") VALUES (2,1);
+        "#,
+        )
+        .unwrap();
+
+        let admin = EditContext::new(&["admin"]);
+        let guest = EditContext::default();
+        let mut draft = draft_from_sqlite_blocking(file_name.clone(), 2).unwrap();
+        draft
+            .add_node(NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 + 1".into(),
+                default: None,
+            })
+            .unwrap();
+        draft.connect(3, 2).unwrap();
+        draft.editor(&admin).set_owner(2, Some("admin")).unwrap();
+        assert_eq!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut draft, &guest).unwrap(),
+            vec![2, 3]
+        );
+        assert!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut draft, &guest)
+                .unwrap()
+                .is_empty()
+        );
+
+        let mut draft = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        assert_eq!(draft.owner(2), Some("admin"));
+        draft.set_value(2, "$1 * 3".into()).unwrap();
+        draft.set_value(3, "$2 + 2".into()).unwrap();
+        assert!(write_draft_sqlite_blocking(file_name.clone(), &mut draft, &guest).is_err());
+        let tree = draft_from_sqlite_blocking(file_name.clone(), 3)
+            .unwrap()
+            .freeze()
+            .unwrap();
+        let values = HashMap::from([(1, NodeOutput::Number(1.))]);
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(3.));
+
+        assert_eq!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut draft, &admin).unwrap(),
+            vec![2, 3]
+        );
+        let tree = draft_from_sqlite_blocking(file_name.clone(), 3)
+            .unwrap()
+            .freeze()
+            .unwrap();
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(5.));
+
+        // Swapping the inputs changes what `id0` and `id1` read
+        let mut draft = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        draft
+            .add_node(NodeDefinition {
+                node_id: 4,
+                kind: 1,
+                value: "id0 - id1".into(),
+                default: None,
+            })
+            .unwrap();
+        draft.connect(4, 1).unwrap();
+        draft.connect(4, 2).unwrap();
+        write_draft_sqlite_blocking(file_name.clone(), &mut draft, &admin).unwrap();
+        let mut draft = draft_from_sqlite_blocking(file_name.clone(), 4).unwrap();
+        assert!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut draft, &admin)
+                .unwrap()
+                .is_empty()
+        );
+        draft.disconnect(4, 1).unwrap();
+        draft.connect(4, 1).unwrap();
+        assert_eq!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut draft, &admin).unwrap(),
+            vec![4]
+        );
+        let tree = draft_from_sqlite_blocking(file_name.clone(), 4)
+            .unwrap()
+            .freeze()
+            .unwrap();
+        assert_eq!(tree.eval(4, &values).unwrap(), NodeOutput::Number(2.));
+
+        let docs = docs_from_sqlite_blocking(file_name, &[1]).unwrap();
+        assert_eq!(docs, HashMap::from([(1, "The *a*".to_string())]));
+    }
+
+    #[test]
+    fn test_write_draft_conflict() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_draft_conflict.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let conn = Connection::open(&file_name).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE "node" (
+                "node_id"	INTEGER NOT NULL UNIQUE,
+                "type"	INTEGER NOT NULL,
+                "operation"	BLOB NOT NULL,
+                PRIMARY KEY("node_id" AUTOINCREMENT)
+            );
+
+            CREATE TABLE "edge" (
+                "edge_id"	INTEGER NOT NULL UNIQUE,
+                "node_id"	INTEGER NOT NULL,
+                "input_id"	INTEGER NOT NULL,
+                PRIMARY KEY("edge_id" AUTOINCREMENT)
+            );
+
+            INSERT INTO "node"("node_id","type","operation") VALUES (1,0,'a');
+            INSERT INTO "node"("node_id","type","operation") VALUES (2,1,'$1 * 2');
+            INSERT INTO "node"("node_id","type","operation") VALUES (3,1,'$2 + 1');
+            INSERT INTO "edge"("node_id","input_id") VALUES (2,1);
+            INSERT INTO "edge"("node_id","input_id") VALUES (3,2);
+        "#,
+        )
+        .unwrap();
+
+        let context = EditContext::default();
+        let mut ours = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        let mut theirs = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        assert_eq!(ours.version(3), Some(0));
+
+        theirs.set_value(3, "$2 + 2".into()).unwrap();
+        write_draft_sqlite_blocking(file_name.clone(), &mut theirs, &context).unwrap();
+        assert_eq!(theirs.version(3), Some(1));
+
+        // Node 2 alone could be written, but nothing is on a conflict
+        ours.set_value(2, "$1 * 3".into()).unwrap();
+        ours.set_value(3, "$2 + 3".into()).unwrap();
+        let err = write_draft_sqlite_blocking(file_name.clone(), &mut ours, &context).unwrap_err();
+        let conflict = err.downcast_ref::<Conflict>().unwrap();
+        assert_eq!(conflict.nodes.len(), 1);
+        assert_eq!(conflict.nodes[0].node_id, 3);
+        assert_eq!(conflict.nodes[0].read_version, Some(0));
+        assert_eq!(conflict.nodes[0].stored_version, 1);
+        assert_eq!(conflict.nodes[0].ours.value, "$2 + 3");
+        assert_eq!(conflict.nodes[0].theirs.value, "$2 + 2");
+        assert_eq!(conflict.nodes[0].their_inputs, vec![2]);
+        let stored = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        assert_eq!(stored.node_definitions(), theirs.node_definitions());
+
+        // Back at the value it was read with, node 3 is theirs alone
+        ours.set_value(3, "$2 + 1".into()).unwrap();
+        assert_eq!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut ours, &context).unwrap(),
+            vec![2]
+        );
+        assert_eq!(ours.version(2), Some(1));
+        let edge_version: i64 = conn
+            .query_row("SELECT version FROM edge WHERE node_id = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(edge_version, 1);
+        let stored = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        assert_eq!(stored.node_definitions()[1].value, "$1 * 3");
+        assert_eq!(stored.node_definitions()[2].value, "$2 + 2");
+
+        // Disjoint edits are both written, also when the other node is owned by a role the
+        // caller does not hold
+        let admin = EditContext::new(&["admin"]);
+        let mut theirs = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        theirs.editor(&admin).set_owner(3, Some("admin")).unwrap();
+        write_draft_sqlite_blocking(file_name.clone(), &mut theirs, &admin).unwrap();
+        let mut ours = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        theirs.set_value(3, "$2 + 4".into()).unwrap();
+        ours.set_value(2, "$1 * 4".into()).unwrap();
+        assert_eq!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut theirs, &admin).unwrap(),
+            vec![3]
+        );
+        assert_eq!(
+            write_draft_sqlite_blocking(file_name.clone(), &mut ours, &context).unwrap(),
+            vec![2]
+        );
+        let tree = draft_from_sqlite_blocking(file_name.clone(), 3)
+            .unwrap()
+            .freeze()
+            .unwrap();
+        let values = HashMap::from([(1, NodeOutput::Number(1.))]);
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(8.));
+
+        // A node added by both is a conflict as well
+        let mut theirs = draft_from_sqlite_blocking(file_name.clone(), 3).unwrap();
+        for draft in [&mut ours, &mut theirs] {
+            draft
+                .add_node(NodeDefinition {
+                    node_id: 4,
+                    kind: 0,
+                    value: "b".into(),
+                    default: None,
+                })
+                .unwrap();
+        }
+        theirs.set_value(4, "c".into()).unwrap();
+        write_draft_sqlite_blocking(file_name.clone(), &mut theirs, &context).unwrap();
+        let err = write_draft_sqlite_blocking(file_name, &mut ours, &context).unwrap_err();
+        let conflict = err.downcast_ref::<Conflict>().unwrap();
+        assert_eq!(conflict.nodes[0].read_version, None);
+    }
+
+    #[test]
+    fn test_edit_log() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_edit_log.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let mut draft = TreeDraft::new();
+        let mut history = History::new();
+        let context = EditContext::default();
+        history
+            .add_node(
+                &mut draft,
+                &context,
+                NodeDefinition {
+                    node_id: 0,
+                    kind: 0,
+                    value: "a".into(),
+                    default: None,
+                },
+            )
+            .unwrap();
+        history
+            .set_value(&mut draft, &context, 0, "b".into())
+            .unwrap();
+        write_edit_log(file_name.clone(), 0, Some("admin"), &history.take_log()).unwrap();
+        history.undo(&mut draft, &context).unwrap();
+        write_edit_log(file_name.clone(), 0, None, &history.take_log()).unwrap();
+
+        let edits = edit_log_from_sqlite_blocking(file_name.clone(), 0).unwrap();
+        assert_eq!(edits.len(), 3);
+        assert_eq!(edits[2], edits[1].inverse());
+        let mut replayed = TreeDraft::new();
+        for edit in &edits {
+            edit.apply(&mut replayed, &context).unwrap();
+        }
+        assert_eq!(replayed, draft);
+        assert!(edit_log_from_sqlite_blocking(file_name, 1)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+
+use crate::core::{NodeId, NodeKind, Tree};
+
+// How a formula node combines array inputs of different lengths. Inputs of a single element,
+// e.g. scalars, are repeated for every element under all policies.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum BroadcastPolicy {
+    // Shorter arrays repeat their last element up to the longest array
+    #[default]
+    RepeatLast,
+    // Shorter arrays start over from their first element up to the longest array
+    Cycle,
+    // The output ends with the shortest array
+    ZipShortest,
+    // Arrays of different lengths are an error
+    Error,
+}
+
+impl BroadcastPolicy {
+    // The number of output elements for inputs of the given lengths. An empty array has no
+    // element to pair with the others.
+    pub(crate) fn output_len(&self, lens: impl Iterator<Item = usize>) -> Result<usize> {
+        let mut shortest: Option<usize> = None;
+        let mut longest = 0;
+        let mut empty = false;
+        for len in lens {
+            empty |= len == 0;
+            if len > 1 {
+                shortest = Some(shortest.map_or(len, |x| x.min(len)));
+            }
+            longest = longest.max(len);
+        }
+        if empty && longest > 0 {
+            return Err(anyhow!(
+                "an empty array does not match an input of {} elements",
+                longest
+            ));
+        }
+        match (self, shortest) {
+            (BroadcastPolicy::ZipShortest, Some(shortest)) => Ok(shortest),
+            (BroadcastPolicy::Error, Some(shortest)) if shortest != longest => Err(anyhow!(
+                "arrays of {} and {} elements do not match",
+                shortest,
+                longest
+            )),
+            _ => Ok(longest),
+        }
+    }
+
+    pub(crate) fn element<'a, T>(&self, values: &'a [T], idx: usize) -> &'a T {
+        let idx = match self {
+            BroadcastPolicy::Cycle => idx % values.len(),
+            _ => idx.min(values.len() - 1),
+        };
+        &values[idx]
+    }
+}
+
+impl Tree {
+    pub fn with_broadcast_policy(
+        mut self,
+        node_ids: &[NodeId],
+        policy: BroadcastPolicy,
+    ) -> Result<Self> {
+        for node_id in node_ids {
+            if !matches!(self.node(*node_id)?.kind(), NodeKind::Formula(_)) {
+                return Err(anyhow!("node {} is not a formula node", node_id));
+            }
+        }
+        for node_id in node_ids {
+            self.broadcast.insert(*node_id, policy);
+        }
+        Ok(self)
+    }
+
+    pub fn broadcast_policy(&self, node_id: NodeId) -> BroadcastPolicy {
+        self.broadcast.get(&node_id).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, NodeOutput};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_broadcast_policy() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 + $1 * 10".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![1., 2., 3., 4.])),
+            (1, NodeOutput::NumberArray(vec![1., 2.])),
+        ]);
+        let eval = |policy| {
+            let tree = tree.clone().with_broadcast_policy(&[2], policy).unwrap();
+            tree.eval(2, &values)
+        };
+
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::NumberArray(vec![11., 22., 23., 24.])
+        );
+        assert_eq!(
+            eval(BroadcastPolicy::Cycle).unwrap(),
+            NodeOutput::NumberArray(vec![11., 22., 13., 24.])
+        );
+        assert_eq!(
+            eval(BroadcastPolicy::ZipShortest).unwrap(),
+            NodeOutput::NumberArray(vec![11., 22.])
+        );
+        assert!(eval(BroadcastPolicy::Error).is_err());
+
+        // Scalars are repeated under every policy
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![1., 2.])),
+            (1, NodeOutput::Number(1.)),
+        ]);
+        let tree = tree
+            .with_broadcast_policy(&[2], BroadcastPolicy::Error)
+            .unwrap();
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::NumberArray(vec![11., 12.])
+        );
+        assert!(tree
+            .clone()
+            .with_broadcast_policy(&[0], BroadcastPolicy::Cycle)
+            .is_err());
+
+        // An empty array has nothing to pair with, whatever the policy
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![])),
+            (1, NodeOutput::NumberArray(vec![1., 2.])),
+        ]);
+        for policy in [
+            BroadcastPolicy::RepeatLast,
+            BroadcastPolicy::Cycle,
+            BroadcastPolicy::ZipShortest,
+            BroadcastPolicy::Error,
+        ] {
+            let tree = tree.clone().with_broadcast_policy(&[2], policy).unwrap();
+            assert!(tree.eval(2, &values).is_err());
+        }
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![])),
+            (1, NodeOutput::Number(1.)),
+        ]);
+        assert!(tree.eval(2, &values).is_err());
+    }
+}
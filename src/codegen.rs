@@ -0,0 +1,653 @@
+use anyhow::{anyhow, Result};
+use evalexpr::{build_operator_tree, Node as Formula, Operator, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::compile::function_arguments;
+use crate::contract::{Contract, ValueType};
+use crate::core::{NodeId, NodeKind, Tree};
+use crate::formula::input_identifier;
+
+fn check_identifier(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(anyhow!("{} is not a valid identifier", name));
+    }
+    Ok(())
+}
+
+fn rust_type(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Number => "f64",
+        ValueType::NumberArray => "Vec<f64>",
+        ValueType::Integer => "i64",
+        ValueType::Bool => "bool",
+        ValueType::String => "String",
+        ValueType::BoolArray => "Vec<bool>",
+        ValueType::StringArray => "Vec<String>",
+        ValueType::Matrix => "Vec<Vec<f64>>",
+    }
+}
+
+impl Contract {
+    // Emits an input and an output struct named after `type_name` together with an `eval`
+    // calling `Tree::eval_contract`, meant to be written to a file from a build script
+    pub fn to_rust(&self, type_name: &str) -> Result<String> {
+        check_identifier(type_name)?;
+        let mut code = String::new();
+
+        writeln!(
+            code,
+            "// Generated from the contract of a graph, do not edit"
+        )?;
+        writeln!(code, "#[derive(Debug, PartialEq, Clone)]")?;
+        writeln!(code, "pub struct {}Inputs {{", type_name)?;
+        for input in &self.inputs {
+            check_identifier(&input.name)?;
+            let field_type = match input.default {
+                Some(_) => format!("Option<{}>", rust_type(input.value_type)),
+                None => rust_type(input.value_type).to_string(),
+            };
+            if let Some(unit) = &input.unit {
+                writeln!(code, "    // {}", unit)?;
+            }
+            writeln!(code, "    pub {}: {},", input.name, field_type)?;
+        }
+        writeln!(code, "}}\n")?;
+
+        writeln!(code, "#[derive(Debug, PartialEq, Clone)]")?;
+        writeln!(code, "pub struct {}Outputs {{", type_name)?;
+        for output in &self.outputs {
+            check_identifier(&output.name)?;
+            if let Some(unit) = &output.unit {
+                writeln!(code, "    // {}", unit)?;
+            }
+            writeln!(
+                code,
+                "    pub {}: {},",
+                output.name,
+                rust_type(output.value_type)
+            )?;
+        }
+        writeln!(code, "}}\n")?;
+
+        writeln!(code, "impl {}Inputs {{", type_name)?;
+        writeln!(
+            code,
+            "    pub fn eval(&self, tree: &graph::Tree) -> anyhow::Result<{}Outputs> {{",
+            type_name
+        )?;
+        writeln!(
+            code,
+            "        let mut inputs = std::collections::HashMap::new();"
+        )?;
+        for input in &self.inputs {
+            let value = match input.value_type {
+                ValueType::Number => "graph::NodeOutput::Number(*x)",
+                ValueType::NumberArray => "graph::NodeOutput::NumberArray(x.clone())",
+                ValueType::Integer => "graph::NodeOutput::Integer(*x)",
+                ValueType::Bool => "graph::NodeOutput::Bool(*x)",
+                ValueType::String => "graph::NodeOutput::String(x.clone())",
+                ValueType::BoolArray => "graph::NodeOutput::BoolArray(x.clone())",
+                ValueType::StringArray => "graph::NodeOutput::StringArray(x.clone())",
+                ValueType::Matrix => "graph::NodeOutput::Matrix(x.clone())",
+            };
+            match input.default {
+                Some(_) => writeln!(code, "        if let Some(x) = &self.{} {{", input.name)?,
+                None => writeln!(
+                    code,
+                    "        {{\n            let x = &self.{};",
+                    input.name
+                )?,
+            }
+            writeln!(
+                code,
+                "            inputs.insert(\"{}\".to_string(), {});\n        }}",
+                input.name, value
+            )?;
+        }
+        writeln!(
+            code,
+            "        let mut outputs = tree.eval_contract(&inputs)?;"
+        )?;
+        writeln!(code, "        Ok({}Outputs {{", type_name)?;
+        for output in &self.outputs {
+            let variant = match output.value_type {
+                ValueType::Number => "Number",
+                ValueType::NumberArray => "NumberArray",
+                ValueType::Integer => "Integer",
+                ValueType::Bool => "Bool",
+                ValueType::String => "String",
+                ValueType::BoolArray => "BoolArray",
+                ValueType::StringArray => "StringArray",
+                ValueType::Matrix => "Matrix",
+            };
+            writeln!(
+                code,
+                "            {}: match outputs.remove(\"{}\") {{\n                \
+                Some(graph::NodeOutput::{}(x)) => x,\n                \
+                _ => return Err(anyhow::anyhow!(\"invalid output {}\")),\n            }},",
+                output.name, output.name, variant, output.name
+            )?;
+        }
+        writeln!(code, "        }})\n    }}\n}}")?;
+        Ok(code)
+    }
+}
+
+impl Tree {
+    // Emits a python function computing the composed expression of the node with numpy, taking
+    // the variables it depends on as arguments named like the variables
+    pub fn to_python(&self, node_id: NodeId, function_name: &str) -> Result<String> {
+        check_identifier(function_name)?;
+        let formula = build_operator_tree(&self.composed_expression(node_id)?)?;
+
+        let mut arguments = Vec::new();
+        let mut names = HashMap::new();
+        for &id in self.eval_order(node_id)?.iter() {
+            let NodeKind::Variable(name) = self.node(id)?.kind() else {
+                continue;
+            };
+            check_identifier(name)?;
+            if arguments.contains(name) {
+                return Err(anyhow!("more than one variable is named {}", name));
+            }
+            arguments.push(name.clone());
+            names.insert(input_identifier(id), name.clone());
+        }
+
+        let mut code = String::new();
+        writeln!(
+            code,
+            "# Generated from node {} of a graph, do not edit",
+            node_id
+        )?;
+        writeln!(code, "import numpy as np\n\n")?;
+        writeln!(code, "def {}({}):", function_name, arguments.join(", "))?;
+        writeln!(code, "    return {}", python_expression(&formula, &names)?)?;
+        Ok(code)
+    }
+}
+
+// Every operation is parenthesized, as the precedence of evalexpr and python differ
+fn python_expression(formula: &Formula, names: &HashMap<String, String>) -> Result<String> {
+    // Constant subexpressions are folded by evalexpr itself to keep its integer semantics
+    let is_read = matches!(formula.operator(), Operator::VariableIdentifierRead { .. });
+    if !is_read && formula.iter_variable_identifiers().next().is_none() {
+        return match formula.eval()? {
+            Value::Boolean(v) => Ok(if v { "True" } else { "False" }.to_string()),
+            value => Ok(python_number(value.as_number()?)),
+        };
+    }
+
+    let children = formula.children();
+    let child = |idx: usize| python_expression(&children[idx], names);
+    let binary =
+        |op: &str| -> Result<String> { Ok(format!("({} {} {})", child(0)?, op, child(1)?)) };
+
+    match formula.operator() {
+        Operator::RootNode if children.len() == 1 => child(0),
+        Operator::VariableIdentifierRead { identifier } => names
+            .get(identifier)
+            .cloned()
+            .ok_or(anyhow!("unknown input {}", identifier)),
+        Operator::Add => binary("+"),
+        Operator::Sub => binary("-"),
+        Operator::Mul => binary("*"),
+        Operator::Div => binary("/"),
+        Operator::Exp => binary("**"),
+        Operator::Eq => binary("=="),
+        Operator::Neq => binary("!="),
+        Operator::Gt => binary(">"),
+        Operator::Lt => binary("<"),
+        Operator::Geq => binary(">="),
+        Operator::Leq => binary("<="),
+        // The remainder keeps the sign of the dividend like in rust
+        Operator::Mod => Ok(format!("np.fmod({}, {})", child(0)?, child(1)?)),
+        Operator::And => Ok(format!("np.logical_and({}, {})", child(0)?, child(1)?)),
+        Operator::Or => Ok(format!("np.logical_or({}, {})", child(0)?, child(1)?)),
+        Operator::Neg => Ok(format!("(-{})", child(0)?)),
+        Operator::Not => Ok(format!("np.logical_not({})", child(0)?)),
+        Operator::FunctionIdentifier { identifier } => {
+            let args = function_arguments(formula)
+                .iter()
+                .map(|x| python_expression(x, names))
+                .collect::<Result<Vec<_>>>()?;
+            let numpy_function = match (identifier.as_str(), args.len()) {
+                ("floor", 1) => "np.floor",
+                ("ceil", 1) => "np.ceil",
+                ("math::abs", 1) => "np.abs",
+                ("math::sqrt", 1) => "np.sqrt",
+                ("math::cbrt", 1) => "np.cbrt",
+                ("math::exp", 1) => "np.exp",
+                ("math::ln", 1) => "np.log",
+                ("math::log2", 1) => "np.log2",
+                ("math::log10", 1) => "np.log10",
+                ("math::sin", 1) => "np.sin",
+                ("math::cos", 1) => "np.cos",
+                ("math::tan", 1) => "np.tan",
+                ("if", 3) => "np.where",
+                // Halves are rounded away from zero, numpy would round them to even
+                ("round", 1) => {
+                    return Ok(format!(
+                        "np.copysign(np.floor(np.abs({}) + 0.5), {})",
+                        args[0], args[0]
+                    ))
+                }
+                // Like f64::min and f64::max, missing values are ignored
+                ("min" | "max", 1..) => {
+                    let function = if identifier == "min" {
+                        "np.fmin"
+                    } else {
+                        "np.fmax"
+                    };
+                    let mut args = args.into_iter();
+                    let first = args.next().unwrap();
+                    return Ok(args.fold(first, |a, b| format!("{}({}, {})", function, a, b)));
+                }
+                ("approx_eq", 4) => {
+                    let (a, b) = (&args[0], &args[1]);
+                    return Ok(format!(
+                        "(({} == {}) | (np.abs({} - {}) <= np.fmax({}, {} * np.fmax(np.abs({}), np.abs({})))))",
+                        a, b, a, b, args[2], args[3], a, b
+                    ));
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "cannot export function {} with {} arguments",
+                        identifier,
+                        args.len()
+                    ))
+                }
+            };
+            Ok(format!("{}({})", numpy_function, args.join(", ")))
+        }
+        operator => Err(anyhow!("cannot export operator {:?}", operator)),
+    }
+}
+
+fn python_number(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "np.nan".to_string(),
+        f64::INFINITY => "np.inf".to_string(),
+        f64::NEG_INFINITY => "-np.inf".to_string(),
+        v => format!("{:?}", v),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SqlDialect {
+    Sqlite,
+    Postgres,
+}
+
+impl Tree {
+    // Emits a select computing the composed expression of the node for every row of the table,
+    // reading the variables it depends on from the columns named like the variables
+    pub fn to_sql(&self, node_id: NodeId, table: &str, dialect: SqlDialect) -> Result<String> {
+        let formula = build_operator_tree(&self.composed_expression(node_id)?)?;
+
+        let mut columns = HashMap::new();
+        for &id in self.eval_order(node_id)?.iter() {
+            if let NodeKind::Variable(name) = self.node(id)?.kind() {
+                columns.insert(input_identifier(id), quote_identifier(name)?);
+            }
+        }
+
+        Ok(format!(
+            "SELECT {} AS value FROM {}",
+            sql_expression(&formula, &columns, dialect)?,
+            quote_identifier(table)?
+        ))
+    }
+}
+
+fn quote_identifier(name: &str) -> Result<String> {
+    if name.is_empty() || name.contains('"') {
+        return Err(anyhow!("{} cannot be used as a sql identifier", name));
+    }
+    Ok(format!("\"{}\"", name))
+}
+
+// Every operation is parenthesized like in the python export. Missing values are NULL in sql.
+fn sql_expression(
+    formula: &Formula,
+    columns: &HashMap<String, String>,
+    dialect: SqlDialect,
+) -> Result<String> {
+    // Constant subexpressions are folded by evalexpr itself to keep its integer semantics
+    let is_read = matches!(formula.operator(), Operator::VariableIdentifierRead { .. });
+    if !is_read && formula.iter_variable_identifiers().next().is_none() {
+        return match (formula.eval()?, dialect) {
+            (Value::Boolean(v), SqlDialect::Sqlite) => Ok((v as u8).to_string()),
+            (Value::Boolean(v), SqlDialect::Postgres) => Ok(v.to_string().to_uppercase()),
+            (value, _) => Ok(sql_number(value.as_number()?, dialect)),
+        };
+    }
+
+    let children = formula.children();
+    let child = |idx: usize| sql_expression(&children[idx], columns, dialect);
+    let binary =
+        |op: &str| -> Result<String> { Ok(format!("({} {} {})", child(0)?, op, child(1)?)) };
+
+    match formula.operator() {
+        Operator::RootNode if children.len() == 1 => child(0),
+        Operator::VariableIdentifierRead { identifier } => columns
+            .get(identifier)
+            .cloned()
+            .ok_or(anyhow!("unknown input {}", identifier)),
+        Operator::Add => binary("+"),
+        Operator::Sub => binary("-"),
+        Operator::Mul => binary("*"),
+        Operator::Div => binary("/"),
+        Operator::Eq => binary("="),
+        Operator::Neq => binary("<>"),
+        Operator::Gt => binary(">"),
+        Operator::Lt => binary("<"),
+        Operator::Geq => binary(">="),
+        Operator::Leq => binary("<="),
+        Operator::And => binary("AND"),
+        Operator::Or => binary("OR"),
+        // Sqlite needs its math functions for powers, remainders and most functions, which are
+        // not part of every build
+        Operator::Exp => match dialect {
+            SqlDialect::Sqlite => Ok(format!("pow({}, {})", child(0)?, child(1)?)),
+            SqlDialect::Postgres => Ok(format!("power({}, {})", child(0)?, child(1)?)),
+        },
+        // The remainder keeps the sign of the dividend like in rust, postgres has no remainder
+        // of floats
+        Operator::Mod => match dialect {
+            SqlDialect::Sqlite => Ok(format!("mod({}, {})", child(0)?, child(1)?)),
+            SqlDialect::Postgres => {
+                let (a, b) = (child(0)?, child(1)?);
+                Ok(format!("({} - {} * trunc({} / {}))", a, b, a, b))
+            }
+        },
+        Operator::Neg => Ok(format!("(-{})", child(0)?)),
+        Operator::Not => Ok(format!("(NOT {})", child(0)?)),
+        Operator::FunctionIdentifier { identifier } => {
+            let args = function_arguments(formula)
+                .iter()
+                .map(|x| sql_expression(x, columns, dialect))
+                .collect::<Result<Vec<_>>>()?;
+            let sql_function = match (identifier.as_str(), args.len(), dialect) {
+                ("floor", 1, _) => "floor",
+                ("ceil", 1, _) => "ceil",
+                ("math::abs", 1, _) => "abs",
+                ("math::sqrt", 1, _) => "sqrt",
+                ("math::cbrt", 1, SqlDialect::Postgres) => "cbrt",
+                ("math::exp", 1, _) => "exp",
+                ("math::ln", 1, _) => "ln",
+                ("math::log2", 1, SqlDialect::Sqlite) => "log2",
+                ("math::log10", 1, SqlDialect::Sqlite) => "log10",
+                ("math::log10", 1, SqlDialect::Postgres) => "log",
+                ("math::sin", 1, _) => "sin",
+                ("math::cos", 1, _) => "cos",
+                ("math::tan", 1, _) => "tan",
+                ("min", 1.., SqlDialect::Sqlite) => "min",
+                ("max", 1.., SqlDialect::Sqlite) => "max",
+                ("min", 1.., SqlDialect::Postgres) => "LEAST",
+                ("max", 1.., SqlDialect::Postgres) => "GREATEST",
+                ("round", 1, SqlDialect::Sqlite) => "round",
+                // Halves of doubles are rounded to even by postgres, but away from zero as numeric
+                ("round", 1, SqlDialect::Postgres) => {
+                    return Ok(format!(
+                        "CAST(round(CAST({} AS numeric)) AS double precision)",
+                        args[0]
+                    ))
+                }
+                ("math::log2", 1, SqlDialect::Postgres) => {
+                    return Ok(format!("(ln({}) / ln(2.0))", args[0]))
+                }
+                ("approx_eq", 4, _) => {
+                    let (a, b) = (&args[0], &args[1]);
+                    let max = match dialect {
+                        SqlDialect::Sqlite => "max",
+                        SqlDialect::Postgres => "GREATEST",
+                    };
+                    return Ok(format!(
+                        "(({} = {}) OR (abs({} - {}) <= {}({}, {} * {}(abs({}), abs({})))))",
+                        a, b, a, b, max, args[2], args[3], max, a, b
+                    ));
+                }
+                ("if", 3, _) => {
+                    return Ok(format!(
+                        "(CASE WHEN {} THEN {} ELSE {} END)",
+                        args[0], args[1], args[2]
+                    ))
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "cannot export function {} with {} arguments to {:?}",
+                        identifier,
+                        args.len(),
+                        dialect
+                    ))
+                }
+            };
+            Ok(format!("{}({})", sql_function, args.join(", ")))
+        }
+        operator => Err(anyhow!("cannot export operator {:?}", operator)),
+    }
+}
+
+fn sql_number(value: f64, dialect: SqlDialect) -> String {
+    match (value, dialect) {
+        (v, _) if v.is_nan() => "NULL".to_string(),
+        (f64::INFINITY, SqlDialect::Sqlite) => "9e999".to_string(),
+        (f64::NEG_INFINITY, SqlDialect::Sqlite) => "-9e999".to_string(),
+        (f64::INFINITY, SqlDialect::Postgres) => "CAST('Infinity' AS double precision)".to_string(),
+        (f64::NEG_INFINITY, SqlDialect::Postgres) => {
+            "CAST('-Infinity' AS double precision)".to_string()
+        }
+        (v, _) => format!("{:?}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{ContractInput, ContractOutput};
+    use crate::core::{EdgeDefinition, NodeDefinition, NodeOutput};
+
+    #[test]
+    fn test_to_rust() {
+        let mut contract = Contract {
+            inputs: vec![
+                ContractInput {
+                    name: "length".into(),
+                    node_id: 0,
+                    value_type: ValueType::NumberArray,
+                    unit: Some("m".into()),
+                    default: None,
+                },
+                ContractInput {
+                    name: "width".into(),
+                    node_id: 1,
+                    value_type: ValueType::Number,
+                    unit: None,
+                    default: Some(NodeOutput::Number(2.)),
+                },
+            ],
+            outputs: vec![ContractOutput {
+                name: "area".into(),
+                node_id: 2,
+                value_type: ValueType::NumberArray,
+                unit: Some("m2".into()),
+            }],
+        };
+
+        let code = contract.to_rust("Area").unwrap();
+        assert!(code.contains("pub struct AreaInputs {\n    // m\n    pub length: Vec<f64>,"));
+        assert!(code.contains("    pub width: Option<f64>,\n}"));
+        assert!(code.contains("pub struct AreaOutputs {\n    // m2\n    pub area: Vec<f64>,\n}"));
+        assert!(code.contains("if let Some(x) = &self.width {"));
+        assert!(code.contains("Some(graph::NodeOutput::NumberArray(x)) => x,"));
+
+        assert!(contract.to_rust("area type").is_err());
+        contract.outputs[0].name = "1area".into();
+        assert!(contract.to_rust("Area").is_err());
+    }
+
+    #[test]
+    fn test_to_python() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "length".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "width".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * (7 / 2) + max($1, 1.5)".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "if($2 > 10, round($2), -$0) % 3".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 0,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let code = tree.to_python(3, "area").unwrap();
+        let x = "((length * 3.0) + np.fmax(width, 1.5))";
+        assert_eq!(
+            code,
+            format!(
+                "# Generated from node 3 of a graph, do not edit\n\
+                import numpy as np\n\n\n\
+                def area(length, width):\n    \
+                return np.fmod(np.where(({} > 10.0), \
+                np.copysign(np.floor(np.abs({}) + 0.5), {}), (-length)), 3.0)\n",
+                x, x, x
+            )
+        );
+        assert!(tree.to_python(3, "not valid").is_err());
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "length".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "width".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * (7 / 2) + max($1, 1.5)".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "if($2 > 10, round($2), -$0) % 3".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 0,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let x = "((\"length\" * 3.0) + max(\"width\", 1.5))";
+        let sql = tree.to_sql(3, "plots", SqlDialect::Sqlite).unwrap();
+        assert_eq!(
+            sql,
+            format!(
+                "SELECT mod((CASE WHEN ({} > 10.0) THEN round({}) ELSE (-\"length\") END), 3.0) \
+                AS value FROM \"plots\"",
+                x, x
+            )
+        );
+        let postgres = tree.to_sql(3, "plots", SqlDialect::Postgres).unwrap();
+        assert!(postgres.contains("GREATEST(\"width\", 1.5)"));
+        assert!(tree.to_sql(3, "pl\"ots", SqlDialect::Sqlite).is_err());
+
+        // The select gives the same results as the tree, the bundled sqlite has no math
+        // functions so the check uses a node without them
+        #[cfg(feature = "sqlite-blocking")]
+        {
+            let sql = tree.to_sql(2, "plots", SqlDialect::Sqlite).unwrap();
+            let conn = rusqlite::Connection::open_in_memory().unwrap();
+            conn.execute("CREATE TABLE plots (length REAL, width REAL)", [])
+                .unwrap();
+            let rows = [(1., 2.), (4., 0.25), (-3.5, 9.)];
+            for (length, width) in rows {
+                conn.execute("INSERT INTO plots VALUES (?1, ?2)", (length, width))
+                    .unwrap();
+            }
+            let mut stmt = conn.prepare(&format!("{} ORDER BY rowid", sql)).unwrap();
+            let results: Vec<f64> = stmt
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .map(|x| x.unwrap())
+                .collect();
+            for ((length, width), result) in rows.into_iter().zip(results) {
+                let values = HashMap::from([
+                    (0, NodeOutput::Number(length)),
+                    (1, NodeOutput::Number(width)),
+                ]);
+                assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(result));
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+use anyhow::{anyhow, Result};
+use evalexpr::{Node as Formula, Operator, Value};
+use std::collections::HashMap;
+
+use crate::core::{NodeId, NodeKind, Tree};
+use crate::formula::input_identifier;
+use crate::functions::{approx_eq, FunctionSet};
+use crate::missing::MissingPolicy;
+
+// Every step reads the tree inputs and the outputs of the steps before it
+type Step = Box<dyn Fn(&[f64], &[f64]) -> f64>;
+
+pub struct CompiledTree {
+    variables: Vec<NodeId>,
+    steps: Vec<Step>,
+}
+
+impl CompiledTree {
+    // Variable node ids in the order `eval` expects their values
+    pub fn variables(&self) -> &[NodeId] {
+        &self.variables
+    }
+
+    pub fn eval(&self, inputs: &[f64]) -> Result<f64> {
+        if inputs.len() != self.variables.len() {
+            return Err(anyhow!(
+                "expected {} input values, got {}",
+                self.variables.len(),
+                inputs.len()
+            ));
+        }
+        let mut outputs = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            let output = step(inputs, &outputs);
+            outputs.push(output);
+        }
+        outputs
+            .last()
+            .copied()
+            .ok_or(anyhow!("compiled tree has no steps"))
+    }
+}
+
+impl Tree {
+    // Only trees evaluating the same compiled and interpreted compile, e.g. the steps only know
+    // the default functions, every variable needs a value and every formula a number output
+    pub fn compile(&self, node_id: NodeId) -> Result<CompiledTree> {
+        if self.function_set() != FunctionSet::default() {
+            return Err(anyhow!(
+                "cannot compile with function set {:?}",
+                self.function_set()
+            ));
+        }
+        let order = self.eval_order(node_id)?;
+
+        let mut variables = Vec::new();
+        let mut slots = HashMap::new();
+        let mut steps: Vec<Step> = Vec::new();
+        for &node_id in order.iter() {
+            let node = self.node(node_id)?;
+            let step: Step = match node.kind() {
+                NodeKind::Variable(_) => {
+                    if node.default().is_some() {
+                        return Err(anyhow!("variable node {} has a default", node_id));
+                    }
+                    let idx = variables.len();
+                    variables.push(node_id);
+                    Box::new(move |inputs, _| inputs[idx])
+                }
+                NodeKind::Formula(formula) => {
+                    let inputs = &node.inputs;
+                    if inputs.is_empty() {
+                        return Err(anyhow!("formula node {} has no inputs", node_id));
+                    }
+                    // Compiled steps always propagate missing values
+                    if self.missing_policy(node_id) != MissingPolicy::Propagate {
+                        return Err(anyhow!("node {} has a missing value policy", node_id));
+                    }
+                    let input_slots: HashMap<String, usize> = inputs
+                        .iter()
+                        .map(|x| (input_identifier(*x), slots[x]))
+                        .collect();
+                    compile_formula(formula, &input_slots)?
+                }
+                // Compiled steps have scalar inputs, so the aggregate is over the inputs
+                NodeKind::Aggregate(op) => {
+                    if self.missing_policy(node_id) != MissingPolicy::Propagate {
+                        return Err(anyhow!("node {} has a missing value policy", node_id));
+                    }
+                    let op = *op;
+                    let input_slots: Vec<usize> = node.inputs.iter().map(|x| slots[x]).collect();
+                    Box::new(move |_, o| {
+                        let values: Vec<f64> = input_slots.iter().map(|x| o[*x]).collect();
+                        op.reduce(&values)
+                    })
+                }
+                NodeKind::SqlQuery(_) | NodeKind::Custom(_) => {
+                    return Err(anyhow!("node {} is not a static node", node_id));
+                }
+            };
+            slots.insert(node_id, steps.len());
+            steps.push(step);
+        }
+
+        Ok(CompiledTree { variables, steps })
+    }
+}
+
+// What a part of a formula evaluates to when interpreted. Integers only come from constants,
+// everything reading an input is a float.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Integer,
+    Bool,
+}
+
+impl Type {
+    fn is_number(self) -> bool {
+        self != Type::Bool
+    }
+}
+
+// Node outputs of compiled trees are always numbers, so formulas ending in a boolean are left
+// to the interpreter
+fn compile_formula(formula: &Formula, slots: &HashMap<String, usize>) -> Result<Step> {
+    match compile_expression(formula, slots)? {
+        (_, Type::Bool) => Err(anyhow!("cannot compile formula with a boolean output")),
+        (step, _) => Ok(step),
+    }
+}
+
+fn compile_expression(formula: &Formula, slots: &HashMap<String, usize>) -> Result<(Step, Type)> {
+    // Constant subexpressions are folded by evalexpr itself to keep its integer semantics.
+    // `iter` skips the node itself, so a bare input read has to be checked separately.
+    let is_read = matches!(formula.operator(), Operator::VariableIdentifierRead { .. });
+    if !is_read && formula.iter_variable_identifiers().next().is_none() {
+        let (value, ty) = match formula.eval()? {
+            Value::Float(v) => (v, Type::Number),
+            Value::Int(v) => (v as f64, Type::Integer),
+            Value::Boolean(v) => (truth(v), Type::Bool),
+            value => return Err(anyhow!("cannot compile constant {}", value)),
+        };
+        return Ok((Box::new(move |_, _| value), ty));
+    }
+
+    let children = formula.children();
+    // The interpreter refuses operands of the wrong type, so the compiled step has to as well
+    let binary = |op: fn(f64, f64) -> f64, operands: Type, output: Type| -> Result<(Step, Type)> {
+        let (a, a_type) = compile_expression(&children[0], slots)?;
+        let (b, b_type) = compile_expression(&children[1], slots)?;
+        let matches = match operands {
+            Type::Bool => a_type == Type::Bool && b_type == Type::Bool,
+            _ => a_type.is_number() && b_type.is_number(),
+        };
+        if !matches {
+            return Err(anyhow!(
+                "cannot compile {:?} of {:?} and {:?}",
+                formula.operator(),
+                a_type,
+                b_type
+            ));
+        }
+        let output = match output {
+            Type::Bool => Type::Bool,
+            _ => Type::Number,
+        };
+        Ok((Box::new(move |x, o| op(a(x, o), b(x, o))), output))
+    };
+    // A float never equals an integer when interpreted, the types have to be the same
+    let equality = |op: fn(f64, f64) -> f64| -> Result<(Step, Type)> {
+        let (a, a_type) = compile_expression(&children[0], slots)?;
+        let (b, b_type) = compile_expression(&children[1], slots)?;
+        if a_type != b_type {
+            return Err(anyhow!("cannot compare {:?} with {:?}", a_type, b_type));
+        }
+        Ok((Box::new(move |x, o| op(a(x, o), b(x, o))), Type::Bool))
+    };
+    let unary = |ty: Type| -> Result<Step> {
+        let (a, a_type) = compile_expression(&children[0], slots)?;
+        if a_type.is_number() != ty.is_number() {
+            return Err(anyhow!(
+                "cannot compile {:?} of {:?}",
+                formula.operator(),
+                a_type
+            ));
+        }
+        Ok(a)
+    };
+
+    match formula.operator() {
+        Operator::RootNode if children.len() == 1 => compile_expression(&children[0], slots),
+        Operator::VariableIdentifierRead { identifier } => {
+            let slot = *slots
+                .get(identifier)
+                .ok_or(anyhow!("unknown input {}", identifier))?;
+            Ok((Box::new(move |_, o| o[slot]), Type::Number))
+        }
+        Operator::Add => binary(|a, b| a + b, Type::Number, Type::Number),
+        Operator::Sub => binary(|a, b| a - b, Type::Number, Type::Number),
+        Operator::Mul => binary(|a, b| a * b, Type::Number, Type::Number),
+        Operator::Div => binary(|a, b| a / b, Type::Number, Type::Number),
+        Operator::Mod => binary(|a, b| a % b, Type::Number, Type::Number),
+        Operator::Exp => binary(f64::powf, Type::Number, Type::Number),
+        Operator::Eq => equality(|a, b| truth(a == b)),
+        Operator::Neq => equality(|a, b| truth(a != b)),
+        Operator::Gt => binary(|a, b| truth(a > b), Type::Number, Type::Bool),
+        Operator::Lt => binary(|a, b| truth(a < b), Type::Number, Type::Bool),
+        Operator::Geq => binary(|a, b| truth(a >= b), Type::Number, Type::Bool),
+        Operator::Leq => binary(|a, b| truth(a <= b), Type::Number, Type::Bool),
+        Operator::And => binary(|a, b| truth(a != 0. && b != 0.), Type::Bool, Type::Bool),
+        Operator::Or => binary(|a, b| truth(a != 0. || b != 0.), Type::Bool, Type::Bool),
+        Operator::Neg => {
+            let a = unary(Type::Number)?;
+            Ok((Box::new(move |x, o| -a(x, o)), Type::Number))
+        }
+        Operator::Not => {
+            let a = unary(Type::Bool)?;
+            Ok((Box::new(move |x, o| truth(a(x, o) == 0.)), Type::Bool))
+        }
+        Operator::FunctionIdentifier { identifier } => {
+            let args = function_arguments(formula)
+                .iter()
+                .map(|x| compile_expression(x, slots))
+                .collect::<Result<Vec<_>>>()?;
+            compile_function(identifier, args)
+        }
+        Operator::Const { value } => Err(anyhow!("cannot compile constant {}", value)),
+        operator => Err(anyhow!("cannot compile operator {:?}", operator)),
+    }
+}
+
+fn truth(x: bool) -> f64 {
+    if x {
+        1.
+    } else {
+        0.
+    }
+}
+
+pub(crate) fn function_arguments(call: &Formula) -> Vec<&Formula> {
+    let Some(mut arg) = call.children().first() else {
+        return Vec::new();
+    };
+    while arg.operator() == &Operator::RootNode && arg.children().len() == 1 {
+        arg = &arg.children()[0];
+    }
+    match arg.operator() {
+        Operator::Tuple => arg.children().iter().collect(),
+        Operator::RootNode if arg.children().is_empty() => Vec::new(),
+        _ => vec![arg],
+    }
+}
+
+fn compile_function(identifier: &str, args: Vec<(Step, Type)>) -> Result<(Step, Type)> {
+    let types: Vec<Type> = args.iter().map(|x| x.1).collect();
+    let mut args: Vec<Step> = args.into_iter().map(|x| x.0).collect();
+    let numbers = types.iter().all(|x| x.is_number());
+    let unary = |op: fn(f64) -> f64, mut args: Vec<Step>| -> Result<(Step, Type)> {
+        if args.len() != 1 || !numbers {
+            return Err(anyhow!("{} expects 1 number", identifier));
+        }
+        let a = args.remove(0);
+        Ok((Box::new(move |x, o| op(a(x, o))), Type::Number))
+    };
+
+    match identifier {
+        "floor" => unary(f64::floor, args),
+        "round" => unary(f64::round, args),
+        "ceil" => unary(f64::ceil, args),
+        "math::abs" => unary(f64::abs, args),
+        "math::sqrt" => unary(f64::sqrt, args),
+        "math::cbrt" => unary(f64::cbrt, args),
+        "math::exp" => unary(f64::exp, args),
+        "math::ln" => unary(f64::ln, args),
+        "math::log2" => unary(f64::log2, args),
+        "math::log10" => unary(f64::log10, args),
+        "math::sin" => unary(f64::sin, args),
+        "math::cos" => unary(f64::cos, args),
+        "math::tan" => unary(f64::tan, args),
+        // A single argument is not a tuple to the interpreter
+        "min" | "max" if args.len() > 1 && numbers => {
+            let op = if identifier == "min" {
+                f64::min
+            } else {
+                f64::max
+            };
+            Ok((
+                Box::new(move |x, o| args.iter().map(|a| a(x, o)).reduce(op).unwrap()),
+                Type::Number,
+            ))
+        }
+        "approx_eq" if args.len() == 4 && numbers => {
+            let relative = args.pop().unwrap();
+            let absolute = args.pop().unwrap();
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            Ok((
+                Box::new(move |x, o| {
+                    truth(approx_eq(a(x, o), b(x, o), absolute(x, o), relative(x, o)))
+                }),
+                Type::Bool,
+            ))
+        }
+        // Both branches need the same type, an integer one would stay an integer
+        "if" if args.len() == 3 && types[0] == Type::Bool && types[1] == types[2] => {
+            let if_false = args.pop().unwrap();
+            let if_true = args.pop().unwrap();
+            let cond = args.pop().unwrap();
+            Ok((
+                Box::new(move |x, o| {
+                    if cond(x, o) != 0. {
+                        if_true(x, o)
+                    } else {
+                        if_false(x, o)
+                    }
+                }),
+                types[1],
+            ))
+        }
+        _ => Err(anyhow!(
+            "cannot compile function {} with arguments {:?}",
+            identifier,
+            types
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, NodeOutput};
+
+    #[test]
+    fn test_compile() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * (7 / 2) + max($1, 1.5, -$0)".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "if($2 > 10, $2, -$2) + $2 ^ 2 % 7 - math::sqrt($1)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let compiled = tree.compile(3).unwrap();
+        assert_eq!(compiled.variables(), &[0, 1]);
+
+        for (a, b) in [(1., 2.), (4., 0.25), (-3.5, 9.)] {
+            let values = HashMap::from([(0, NodeOutput::Number(a)), (1, NodeOutput::Number(b))]);
+            let NodeOutput::Number(expected) = tree.eval(3, &values).unwrap() else {
+                unreachable!()
+            };
+            assert_eq!(compiled.eval(&[a, b]).unwrap(), expected);
+        }
+        assert!(compiled.eval(&[1.]).is_err());
+
+        let excel = tree.clone().with_function_set(FunctionSet::Excel);
+        assert!(excel.compile(3).is_err());
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: Some(NodeOutput::Number(1.)),
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        assert!(tree.compile(1).is_err());
+
+        // Formulas the interpreter answers with a boolean or refuses do not compile
+        let compile = |formula: &str| {
+            let node_defs = vec![
+                NodeDefinition {
+                    node_id: 0,
+                    kind: 0,
+                    value: "a".into(),
+                    default: None,
+                },
+                NodeDefinition {
+                    node_id: 1,
+                    kind: 0,
+                    value: "b".into(),
+                    default: None,
+                },
+                NodeDefinition {
+                    node_id: 2,
+                    kind: 1,
+                    value: formula.into(),
+                    default: None,
+                },
+            ];
+            let edge_defs = vec![
+                EdgeDefinition {
+                    node_id: 2,
+                    input_id: 0,
+                },
+                EdgeDefinition {
+                    node_id: 2,
+                    input_id: 1,
+                },
+            ];
+            let tree = Tree::new(node_defs, edge_defs).unwrap();
+            let compiled = tree.compile(2);
+            (tree, compiled)
+        };
+        for formula in [
+            "$0 > $1",
+            "$0 == $1",
+            "$0 && $1",
+            "!$0",
+            "if($0, 1, 2)",
+            "min($0)",
+            "$0 == 1",
+            "approx_eq($0, $1, 0.1, 0.1)",
+            "$0 + true",
+        ] {
+            let (tree, compiled) = compile(formula);
+            assert!(compiled.is_err(), "{}", formula);
+            let values = HashMap::from([(0, NodeOutput::Number(1.)), (1, NodeOutput::Number(1.))]);
+            assert!(!matches!(tree.eval(2, &values), Ok(NodeOutput::Number(_))));
+        }
+        // The branch taken decides whether the interpreter has an integer or a float
+        assert!(compile("if($0 > 1, 1, $0) == $0").1.is_err());
+
+        for formula in [
+            "if($0 > 1 && !($1 < 0), $0, $1)",
+            "if(($0 > 1) == ($1 > 1), min($0, $1), 2.5)",
+            "if($0 == 1.0, 1, 2) + max($0, 2)",
+            "if(approx_eq($0, $1, 0.5, 0), -$0, $1 % 2)",
+        ] {
+            let (tree, compiled) = compile(formula);
+            let compiled = compiled.unwrap();
+            for (a, b) in [(1., 1.), (1.2, 1.), (-3., 2.)] {
+                let values =
+                    HashMap::from([(0, NodeOutput::Number(a)), (1, NodeOutput::Number(b))]);
+                assert_eq!(
+                    tree.eval(2, &values).unwrap(),
+                    NodeOutput::Number(compiled.eval(&[a, b]).unwrap())
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{EvalCache, NodeId, NodeKind, NodeOutput, Tree};
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum ValueType {
+    Number,
+    NumberArray,
+    Integer,
+    Bool,
+    String,
+    BoolArray,
+    StringArray,
+    Matrix,
+}
+
+impl ValueType {
+    fn matches(&self, value: &NodeOutput) -> bool {
+        matches!(
+            (self, value),
+            (ValueType::Number, NodeOutput::Number(_))
+                | (ValueType::NumberArray, NodeOutput::NumberArray(_))
+                | (ValueType::Integer, NodeOutput::Integer(_))
+                | (ValueType::Bool, NodeOutput::Bool(_))
+                | (ValueType::String, NodeOutput::String(_))
+                | (ValueType::BoolArray, NodeOutput::BoolArray(_))
+                | (ValueType::StringArray, NodeOutput::StringArray(_))
+                | (ValueType::Matrix, NodeOutput::Matrix(_))
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ContractInput {
+    pub name: String,
+    pub node_id: NodeId,
+    pub value_type: ValueType,
+    pub unit: Option<String>,
+    pub default: Option<NodeOutput>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ContractOutput {
+    pub name: String,
+    pub node_id: NodeId,
+    pub value_type: ValueType,
+    pub unit: Option<String>,
+}
+
+// The public interface of a tree, callers only get to see these names
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Contract {
+    pub inputs: Vec<ContractInput>,
+    pub outputs: Vec<ContractOutput>,
+}
+
+impl Tree {
+    pub fn with_contract(mut self, contract: Contract) -> Result<Self> {
+        let mut names = HashSet::new();
+        for input in &contract.inputs {
+            if !names.insert(&input.name) {
+                return Err(anyhow!("contract name {} is used twice", input.name));
+            }
+            if !matches!(self.node(input.node_id)?.kind(), NodeKind::Variable(_)) {
+                return Err(anyhow!(
+                    "contract input {} is not a variable node",
+                    input.name
+                ));
+            }
+            if let Some(default) = &input.default {
+                if !input.value_type.matches(default) {
+                    return Err(anyhow!(
+                        "default of contract input {} does not match its type",
+                        input.name
+                    ));
+                }
+            }
+        }
+        for output in &contract.outputs {
+            if !names.insert(&output.name) {
+                return Err(anyhow!("contract name {} is used twice", output.name));
+            }
+            self.node(output.node_id)?;
+        }
+
+        self.contract = Some(contract);
+        Ok(self)
+    }
+
+    pub fn contract(&self) -> Option<&Contract> {
+        self.contract.as_ref()
+    }
+
+    // Evaluates every output of the contract from the inputs given by name. Missing inputs take
+    // their default, inputs the contract does not declare are rejected.
+    pub fn eval_contract(
+        &self,
+        inputs: &HashMap<String, NodeOutput>,
+    ) -> Result<HashMap<String, NodeOutput>> {
+        let contract = self
+            .contract
+            .as_ref()
+            .ok_or(anyhow!("the tree has no contract"))?;
+
+        for name in inputs.keys() {
+            if !contract.inputs.iter().any(|x| &x.name == name) {
+                return Err(anyhow!("input {} is not part of the contract", name));
+            }
+        }
+
+        let mut values = HashMap::new();
+        for input in &contract.inputs {
+            let value = inputs
+                .get(&input.name)
+                .or(input.default.as_ref())
+                .ok_or(anyhow!("missing contract input {}", input.name))?;
+            if !input.value_type.matches(value) {
+                return Err(anyhow!(
+                    "contract input {} has to be a {:?}",
+                    input.name,
+                    input.value_type
+                ));
+            }
+            values.insert(input.node_id, value.clone());
+        }
+
+        let mut cache = EvalCache::new();
+        let mut outputs = HashMap::new();
+        for output in &contract.outputs {
+            let value = self.eval_cached(output.node_id, &values, &mut cache)?;
+            if !output.value_type.matches(&value) {
+                return Err(anyhow!(
+                    "contract output {} has to be a {:?}",
+                    output.name,
+                    output.value_type
+                ));
+            }
+            outputs.insert(output.name.clone(), value);
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_eval_contract() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "length".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "width".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let contract = Contract {
+            inputs: vec![
+                ContractInput {
+                    name: "length".into(),
+                    node_id: 0,
+                    value_type: ValueType::NumberArray,
+                    unit: Some("m".into()),
+                    default: None,
+                },
+                ContractInput {
+                    name: "width".into(),
+                    node_id: 1,
+                    value_type: ValueType::Number,
+                    unit: Some("m".into()),
+                    default: Some(NodeOutput::Number(2.)),
+                },
+            ],
+            outputs: vec![ContractOutput {
+                name: "area".into(),
+                node_id: 2,
+                value_type: ValueType::NumberArray,
+                unit: Some("m2".into()),
+            }],
+        };
+
+        let mut invalid = contract.clone();
+        invalid.inputs[1].node_id = 2;
+        assert!(tree.clone().with_contract(invalid).is_err());
+        assert!(tree.eval_contract(&HashMap::new()).is_err());
+
+        let tree = tree.with_contract(contract).unwrap();
+        let inputs = HashMap::from([("length".into(), NodeOutput::NumberArray(vec![1., 3.]))]);
+        let outputs = tree.eval_contract(&inputs).unwrap();
+        assert_eq!(outputs["area"], NodeOutput::NumberArray(vec![2., 6.]));
+
+        let inputs = HashMap::from([("length".into(), NodeOutput::Number(1.))]);
+        assert!(tree.eval_contract(&inputs).is_err());
+        let inputs = HashMap::from([
+            ("length".into(), NodeOutput::NumberArray(vec![1.])),
+            ("height".into(), NodeOutput::Number(1.)),
+        ]);
+        assert!(tree.eval_contract(&inputs).is_err());
+    }
+}
@@ -1,153 +1,990 @@
 use anyhow::{anyhow, Result};
-use evalexpr::{build_operator_tree, ContextWithMutableVariables, HashMapContext, Value};
-use std::borrow::BorrowMut;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use evalexpr::{build_operator_tree, Value};
+use serde::{Deserialize, Serialize};
+use smallvec::{smallvec, SmallVec};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-type NodeId = usize;
-type EvalId = u64;
+use crate::aggregate::AggregateOp;
+use crate::binding::{BindingPlan, BoundContext};
+use crate::broadcast::BroadcastPolicy;
+use crate::contract::Contract;
+use crate::dialect::FormulaDialect;
+use crate::examples::Example;
+use crate::formula::{
+    approximate_equality, bind_parameters, canonical_references, eval_condition, input_identifier,
+    input_ids, split_top_level, substitute_input, to_formula_string, FormulaAliases,
+    FormulaMetrics, Tolerance, INPUT_PREFIX,
+};
+use crate::functions::FunctionSet;
+use crate::hooks::Hooks;
+use crate::missing::{
+    fill_previous, nullable, nullable_array, nullable_matrix, nullable_time_series, MissingPolicy,
+};
+use crate::registry::{CustomKind, NodeRegistry};
+use crate::session::Session;
+use crate::trigger::{Trigger, TriggeredNodes};
+use crate::value::{apply_custom, CustomValue};
+
+pub type NodeId = usize;
+
+// Most nodes have a handful of inputs and scalar outputs, which stay on the stack this way
+type InputVec<T> = SmallVec<[T; 4]>;
+type Values = SmallVec<[f64; 1]>;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum NodeKind {
     Variable(String),
     Formula(evalexpr::Node),
     SqlQuery(String),
+    Custom(CustomKind),
+    Aggregate(AggregateOp),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum NodeOutput {
-    NumberArray(Vec<f64>),
-    Number(f64),
+    // Missing values are NaN, written as null
+    NumberArray(#[serde(deserialize_with = "nullable_array")] Vec<f64>),
+    Number(#[serde(deserialize_with = "nullable")] f64),
+    Integer(i64),
+    Bool(bool),
+    String(String),
+    BoolArray(Vec<bool>),
+    StringArray(Vec<String>),
+    // Rows of the same length
+    Matrix(#[serde(deserialize_with = "nullable_matrix")] Vec<Vec<f64>>),
+    // An array with a label for its elements, e.g. a column name
+    Series {
+        name: String,
+        #[serde(deserialize_with = "nullable_array")]
+        values: Vec<f64>,
+    },
+    // Pairs of a timestamp and a number, formulas join them by timestamp
+    TimeSeries(#[serde(deserialize_with = "nullable_time_series")] Vec<(i64, f64)>),
+    // Cannot be serialized
+    #[serde(skip)]
+    Custom(Arc<dyn CustomValue>),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl NodeOutput {
+    // Scalars as a single element, integers as floats and booleans as 1 or 0
+    pub(crate) fn elements(&self) -> Result<Vec<f64>> {
+        match self {
+            NodeOutput::Number(v) => Ok(vec![*v]),
+            NodeOutput::Integer(v) => Ok(vec![*v as f64]),
+            NodeOutput::NumberArray(v) => Ok(v.clone()),
+            NodeOutput::Bool(v) => Ok(vec![*v as u8 as f64]),
+            NodeOutput::BoolArray(v) => Ok(v.iter().map(|x| *x as u8 as f64).collect()),
+            NodeOutput::String(_) | NodeOutput::StringArray(_) => {
+                Err(anyhow!("strings have no numbers"))
+            }
+            NodeOutput::Matrix(v) => Ok(v.concat()),
+            NodeOutput::Series { values, .. } => Ok(values.clone()),
+            NodeOutput::TimeSeries(v) => Ok(v.iter().map(|(_, x)| *x).collect()),
+            NodeOutput::Custom(v) => Err(anyhow!("a {} has no numbers", v.type_name())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct EdgeDefinition {
     pub node_id: usize,
     pub input_id: usize,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NodeDefinition {
     pub node_id: usize,
     pub value: String,
     pub kind: usize,
+    // Output of a variable node when the caller supplies no value for it
+    #[serde(default)]
+    pub default: Option<NodeOutput>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ParameterDefinition {
+    pub name: String,
+    pub default: f64,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Node {
     pub id: usize,
-    pub inputs: RefCell<Vec<Rc<Self>>>,
-    pub outputs: RefCell<Vec<Rc<Self>>>,
+    pub inputs: Vec<NodeId>,
     kind: NodeKind,
+    plan: Option<BindingPlan>,
+    default: Option<NodeOutput>,
 }
 
 impl Node {
     pub fn from_variable(node_id: NodeId, variable_name: String) -> Result<Self> {
         Ok(Node {
             id: node_id,
-            inputs: RefCell::new(Vec::new()),
-            outputs: RefCell::new(Vec::new()),
+            inputs: Vec::new(),
             kind: NodeKind::Variable(variable_name),
+            plan: None,
+            default: None,
         })
     }
 
-    pub fn from_formula(node_id: NodeId, formula: &str) -> Result<Self> {
-        let formula = build_operator_tree(&formula).unwrap();
+    pub fn from_custom(node_id: NodeId, custom: CustomKind) -> Result<Self> {
         Ok(Node {
             id: node_id,
-            inputs: RefCell::new(Vec::new()),
-            outputs: RefCell::new(Vec::new()),
-            kind: NodeKind::Formula(formula),
+            inputs: Vec::new(),
+            kind: NodeKind::Custom(custom),
+            plan: None,
+            default: None,
         })
     }
 
-    pub fn inputs(&self) -> Vec<NodeId> {
-        let inputs = self.inputs.borrow();
-        if inputs.len() == 0 {
-            return vec![self.id];
-        }
+    pub fn from_aggregate(node_id: NodeId, op: AggregateOp) -> Result<Self> {
+        Ok(Node {
+            id: node_id,
+            inputs: Vec::new(),
+            kind: NodeKind::Aggregate(op),
+            plan: None,
+            default: None,
+        })
+    }
 
-        let mut ids = Vec::new();
-        for input in inputs.iter() {
-            let id = &input.inputs();
-            ids.extend_from_slice(id);
-        }
+    pub fn from_formula(node_id: NodeId, formula: &str) -> Result<Self> {
+        let formula = build_operator_tree(formula)?;
+        let mut node = Node {
+            id: node_id,
+            inputs: Vec::new(),
+            kind: NodeKind::Formula(formula),
+            plan: None,
+            default: None,
+        };
+        node.bind();
+        Ok(node)
+    }
 
-        return ids;
+    // Nodes built outside of a tree, e.g. variants of a tree node, get their inputs this way
+    pub fn with_inputs(mut self, inputs: Vec<NodeId>) -> Self {
+        self.inputs = inputs;
+        self.bind();
+        self
+    }
+
+    pub fn with_default(mut self, default: Option<NodeOutput>) -> Self {
+        self.default = default;
+        self
+    }
+
+    pub fn kind(&self) -> &NodeKind {
+        &self.kind
+    }
+
+    pub fn default(&self) -> Option<&NodeOutput> {
+        self.default.as_ref()
     }
 
     pub fn eval(&self, values: &HashMap<NodeId, NodeOutput>) -> Result<NodeOutput> {
-        match &self.kind {
-            NodeKind::Variable(var_name) => {
-                let val = values.get(&self.id).ok_or(anyhow!(
+        self.eval_with_functions(values, FunctionSet::default())
+    }
+
+    pub fn eval_with_functions(
+        &self,
+        values: &HashMap<NodeId, NodeOutput>,
+        function_set: FunctionSet,
+    ) -> Result<NodeOutput> {
+        if let NodeKind::Variable(var_name) = &self.kind {
+            let val = values
+                .get(&self.id)
+                .or(self.default.as_ref())
+                .ok_or(anyhow!(
                     "missing variable value for {} (node id = {})",
                     var_name,
                     self.id
                 ))?;
-                return Ok(val.clone());
-            }
-            _ => (),
-        }
-        let mut input_vals = Vec::new();
-        let mut node_ids = Vec::new();
-        let mut max_len = 0;
-        let inputs = self.inputs.borrow_mut();
-        for node in inputs.iter() {
-            let val = node.eval(values)?;
-            let val = match val {
-                NodeOutput::Number(v) => vec![v],
-                NodeOutput::NumberArray(v) => v,
-            };
-            max_len = max_len.max(val.len());
-            node_ids.push(format!("${}", node.id));
-            input_vals.push(val);
+            return Ok(val.clone());
         }
 
-        let mut output_vals = Vec::new();
-        for idx_arr in 0..max_len {
-            match &self.kind {
-                NodeKind::Variable(_) => unreachable!(),
-                NodeKind::Formula(formula) => {
-                    let mut args = HashMapContext::new();
-                    for idx_node in 0..node_ids.len() {
-                        let id = node_ids.get(idx_node).ok_or(anyhow!("indexing error"))?;
-
-                        let node_vals = input_vals
-                            .get(idx_node)
-                            .ok_or(anyhow!("invalid node index"))?;
-
-                        // Shorter arrays repeat the last value
-                        let val = node_vals.get(idx_arr).unwrap_or(
-                            node_vals
-                                .last()
-                                .expect("The value array from a node was empty"),
-                        );
-
-                        args.set_value(id.to_string(), Value::Float(*val))?;
-                    }
+        // Other nodes read the outputs of their inputs from the values as well
+        let mut input_outputs = InputVec::new();
+        for input_id in self.inputs.iter() {
+            let output = values
+                .get(input_id)
+                .ok_or(anyhow!("missing output of input node {}", input_id))?;
+            input_outputs.push(output.clone());
+        }
 
-                    let Ok(res) = formula.eval_float_with_context(&args) else {
-                        return Err(anyhow!("Formula evaluation failed"));
-                    };
+        self.apply(
+            input_outputs,
+            function_set,
+            None,
+            MissingPolicy::default(),
+            BroadcastPolicy::default(),
+        )
+    }
+
+    // Resolves the formula variables against the current inputs, done whenever they are set
+    fn bind(&mut self) {
+        if let NodeKind::Formula(formula) = &self.kind {
+            self.plan = Some(BindingPlan::new(formula, &self.inputs));
+        }
+    }
+
+    // Evaluates this node from the outputs of its inputs, given in the order of `self.inputs`
+    fn apply(
+        &self,
+        input_outputs: InputVec<NodeOutput>,
+        function_set: FunctionSet,
+        parallel_threshold: Option<usize>,
+        missing: MissingPolicy,
+        broadcast: BroadcastPolicy,
+    ) -> Result<NodeOutput> {
+        match &self.kind {
+            NodeKind::Custom(custom) => return custom.node.eval(&input_outputs),
+            NodeKind::Aggregate(op) => return op.apply(&input_outputs, missing),
+            _ => {}
+        }
+
+        let plan = self
+            .plan
+            .as_ref()
+            .ok_or(anyhow!("node {} is not bound", self.id))?;
+
+        let formula = match &self.kind {
+            NodeKind::Formula(formula) => formula,
+            NodeKind::SqlQuery(_q) => todo!(),
+            NodeKind::Variable(_) | NodeKind::Custom(_) | NodeKind::Aggregate(_) => unreachable!(),
+        };
+        apply_formula(
+            formula,
+            plan,
+            input_outputs,
+            function_set,
+            parallel_threshold,
+            missing,
+            broadcast,
+        )
+    }
+}
+
+// Holds nothing but the formula and its plan, so it can run on any thread
+fn apply_formula(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_outputs: InputVec<NodeOutput>,
+    function_set: FunctionSet,
+    parallel_threshold: Option<usize>,
+    missing: MissingPolicy,
+    broadcast: BroadcastPolicy,
+) -> Result<NodeOutput> {
+    if input_outputs
+        .iter()
+        .any(|x| matches!(x, NodeOutput::Custom(_)))
+    {
+        return apply_custom(formula, plan, &input_outputs);
+    }
+    if input_outputs
+        .iter()
+        .any(|x| matches!(x, NodeOutput::TimeSeries(_)))
+    {
+        return apply_time_series(
+            formula,
+            plan,
+            input_outputs,
+            function_set,
+            parallel_threshold,
+            missing,
+            broadcast,
+        );
+    }
+    if input_outputs
+        .iter()
+        .any(|x| matches!(x, NodeOutput::Series { .. }))
+    {
+        return apply_series(
+            formula,
+            plan,
+            input_outputs,
+            function_set,
+            parallel_threshold,
+            missing,
+            broadcast,
+        );
+    }
+    if input_outputs
+        .iter()
+        .any(|x| matches!(x, NodeOutput::Matrix(_)))
+    {
+        return apply_matrix(
+            formula,
+            plan,
+            input_outputs,
+            function_set,
+            parallel_threshold,
+            missing,
+            broadcast,
+        );
+    }
+
+    // Integers keep the overflow checked integer arithmetic of evalexpr, arrays have no
+    // integer elements so integers are broadcast as floats. Missing numbers take the float path,
+    // which applies the missing policy.
+    if input_outputs
+        .iter()
+        .any(|x| matches!(x, NodeOutput::Integer(_)))
+        && input_outputs.iter().all(|x| match x {
+            NodeOutput::NumberArray(_)
+            | NodeOutput::BoolArray(_)
+            | NodeOutput::StringArray(_)
+            | NodeOutput::Matrix(_) => false,
+            NodeOutput::Number(v) => !v.is_nan(),
+            _ => true,
+        })
+    {
+        return eval_formula_scalar(formula, plan, &input_outputs, function_set);
+    }
+
+    let mut input_vals = InputVec::new();
+    for val in input_outputs {
+        let val = match val {
+            NodeOutput::Number(v) => Elements::Numbers(smallvec![v]),
+            NodeOutput::Integer(v) => Elements::Numbers(smallvec![v as f64]),
+            NodeOutput::NumberArray(mut v) => {
+                if missing == MissingPolicy::Previous {
+                    fill_previous(&mut v);
+                }
+                Elements::Numbers(Values::from_vec(v))
+            }
+            NodeOutput::Bool(v) => Elements::Bools(vec![v]),
+            NodeOutput::BoolArray(v) => Elements::Bools(v),
+            NodeOutput::String(v) => Elements::Strings(vec![v]),
+            NodeOutput::StringArray(v) => Elements::Strings(v),
+            NodeOutput::Matrix(_)
+            | NodeOutput::Series { .. }
+            | NodeOutput::TimeSeries(_)
+            | NodeOutput::Custom(_) => unreachable!(),
+        };
+        input_vals.push(val);
+    }
+    let len = broadcast.output_len(input_vals.iter().map(|x| x.len()))?;
+
+    let output_vals = match parallel_threshold {
+        Some(threshold) if len >= threshold => eval_formula_parallel(
+            formula,
+            plan,
+            &input_vals,
+            function_set,
+            missing,
+            broadcast,
+            len,
+        )?,
+        _ => eval_formula_range(
+            formula,
+            plan,
+            &input_vals,
+            function_set,
+            missing,
+            broadcast,
+            0..len,
+        )?,
+    };
+
+    match output_vals.into_output() {
+        Some(output) => Ok(output),
+        // Every element was skipped, which leaves nothing but a missing value
+        None if len > 0 && missing == MissingPolicy::Skip => Ok(NodeOutput::Number(f64::NAN)),
+        None => Err(anyhow!("The computation resulted in no output")),
+    }
+}
+
+// Time series are joined on the union of their timestamps. A timestamp missing from one of them
+// is a missing element, which the missing policy decides on, e.g. skipping gives an inner join.
+fn apply_time_series(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_outputs: InputVec<NodeOutput>,
+    function_set: FunctionSet,
+    parallel_threshold: Option<usize>,
+    missing: MissingPolicy,
+    broadcast: BroadcastPolicy,
+) -> Result<NodeOutput> {
+    let mut timestamps = Vec::new();
+    for output in &input_outputs {
+        let NodeOutput::TimeSeries(v) = output else {
+            continue;
+        };
+        if v.windows(2).any(|x| x[0].0 >= x[1].0) {
+            return Err(anyhow!("time series timestamps have to be increasing"));
+        }
+        timestamps.extend(v.iter().map(|(t, _)| *t));
+    }
+    timestamps.sort_unstable();
+    timestamps.dedup();
+
+    let mut aligned = InputVec::new();
+    for output in input_outputs {
+        aligned.push(match output {
+            NodeOutput::TimeSeries(v) => {
+                let mut values = vec![f64::NAN; timestamps.len()];
+                for (t, x) in v {
+                    values[timestamps.binary_search(&t).unwrap_or_default()] = x;
+                }
+                NodeOutput::NumberArray(values)
+            }
+            NodeOutput::Number(_) | NodeOutput::Integer(_) => output,
+            _ => return Err(anyhow!("time series can only be combined with numbers")),
+        });
+    }
+
+    // Skipped timestamps are dropped here, so the remaining ones still match the output
+    if missing == MissingPolicy::Skip {
+        let kept: Vec<bool> = (0..timestamps.len())
+            .map(|idx| {
+                !aligned.iter().any(|x| match x {
+                    NodeOutput::NumberArray(v) => v[idx].is_nan(),
+                    NodeOutput::Number(v) => v.is_nan(),
+                    _ => false,
+                })
+            })
+            .collect();
+        for output in aligned.iter_mut() {
+            if let NodeOutput::NumberArray(v) = output {
+                let mut keep = kept.iter();
+                v.retain(|_| *keep.next().unwrap());
+            }
+        }
+        let mut keep = kept.iter();
+        timestamps.retain(|_| *keep.next().unwrap());
+    }
+    if timestamps.is_empty() {
+        return Ok(NodeOutput::TimeSeries(Vec::new()));
+    }
+
+    let values = match apply_formula(
+        formula,
+        plan,
+        aligned,
+        function_set,
+        parallel_threshold,
+        missing,
+        broadcast,
+    )? {
+        NodeOutput::NumberArray(v) if v.len() == timestamps.len() => v,
+        NodeOutput::Number(v) if timestamps.len() == 1 => vec![v],
+        _ => {
+            return Err(anyhow!(
+                "formulas on time series have to give a number for every timestamp"
+            ))
+        }
+    };
+    Ok(NodeOutput::TimeSeries(
+        timestamps.into_iter().zip(values).collect(),
+    ))
+}
+
+// Series are evaluated like arrays. The output keeps the label if all series inputs share it,
+// differently labeled inputs give an unlabeled array.
+fn apply_series(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_outputs: InputVec<NodeOutput>,
+    function_set: FunctionSet,
+    parallel_threshold: Option<usize>,
+    missing: MissingPolicy,
+    broadcast: BroadcastPolicy,
+) -> Result<NodeOutput> {
+    let mut label: Option<Option<String>> = None;
+    let mut unlabeled = InputVec::new();
+    for output in input_outputs {
+        unlabeled.push(match output {
+            NodeOutput::Series { name, values } => {
+                label = match label {
+                    None => Some(Some(name)),
+                    Some(Some(x)) if x == name => Some(Some(x)),
+                    Some(_) => Some(None),
+                };
+                NodeOutput::NumberArray(values)
+            }
+            output => output,
+        });
+    }
+
+    let output = apply_formula(
+        formula,
+        plan,
+        unlabeled,
+        function_set,
+        parallel_threshold,
+        missing,
+        broadcast,
+    )?;
+    Ok(match (output, label.flatten()) {
+        (NodeOutput::NumberArray(values), Some(name)) => NodeOutput::Series { name, values },
+        (output, _) => output,
+    })
+}
+
+// Matrices are evaluated element by element. Other matrices need the same shape, arrays are
+// repeated for every row and scalars for every element.
+fn apply_matrix(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_outputs: InputVec<NodeOutput>,
+    function_set: FunctionSet,
+    parallel_threshold: Option<usize>,
+    missing: MissingPolicy,
+    broadcast: BroadcastPolicy,
+) -> Result<NodeOutput> {
+    let mut shape = None;
+    for output in &input_outputs {
+        let NodeOutput::Matrix(rows) = output else {
+            continue;
+        };
+        let cols = rows.first().map_or(0, |x| x.len());
+        if cols == 0 || rows.iter().any(|x| x.len() != cols) {
+            return Err(anyhow!(
+                "matrix rows have to be of the same length, and not empty"
+            ));
+        }
+        match shape {
+            Some((r, c)) if (r, c) != (rows.len(), cols) => {
+                return Err(anyhow!(
+                    "matrices of {}x{} and {}x{} elements do not fit",
+                    r,
+                    c,
+                    rows.len(),
+                    cols
+                ))
+            }
+            _ => shape = Some((rows.len(), cols)),
+        }
+    }
+    let (rows, cols) = shape.ok_or(anyhow!("no matrix input"))?;
+
+    let mut flattened = InputVec::new();
+    for output in input_outputs {
+        flattened.push(match output {
+            NodeOutput::Matrix(v) => NodeOutput::NumberArray(v.concat()),
+            NodeOutput::NumberArray(v) if v.len() == cols => {
+                NodeOutput::NumberArray(v.repeat(rows))
+            }
+            NodeOutput::NumberArray(v) => {
+                return Err(anyhow!(
+                    "an array of {} elements does not fit matrix rows of {}",
+                    v.len(),
+                    cols
+                ))
+            }
+            NodeOutput::Number(_) | NodeOutput::Integer(_) => output,
+            _ => return Err(anyhow!("matrices can only be combined with numbers")),
+        });
+    }
+
+    let elements = match apply_formula(
+        formula,
+        plan,
+        flattened,
+        function_set,
+        parallel_threshold,
+        missing,
+        broadcast,
+    )? {
+        NodeOutput::NumberArray(v) if v.len() == rows * cols => v,
+        NodeOutput::Number(v) if rows * cols == 1 => vec![v],
+        _ => {
+            return Err(anyhow!(
+                "formulas on matrices have to give a number for every element"
+            ))
+        }
+    };
+    Ok(NodeOutput::Matrix(
+        elements.chunks(cols).map(|x| x.to_vec()).collect(),
+    ))
+}
+
+// The elements of a formula input, which formulas broadcast over
+enum Elements {
+    Numbers(Values),
+    Bools(Vec<bool>),
+    Strings(Vec<String>),
+}
+
+impl Elements {
+    fn len(&self) -> usize {
+        match self {
+            Elements::Numbers(v) => v.len(),
+            Elements::Bools(v) => v.len(),
+            Elements::Strings(v) => v.len(),
+        }
+    }
+}
+
+// The elements of a formula output, whose type is decided by the first element
+enum Column {
+    Numbers(Vec<f64>),
+    Bools(Vec<bool>),
+    Strings(Vec<String>),
+}
+
+impl Column {
+    fn len(&self) -> usize {
+        match self {
+            Column::Numbers(v) => v.len(),
+            Column::Bools(v) => v.len(),
+            Column::Strings(v) => v.len(),
+        }
+    }
+
+    fn push(&mut self, value: Value) -> Result<()> {
+        let empty = self.len() == 0;
+        match (&mut *self, value) {
+            (Column::Numbers(v), Value::Float(x)) => v.push(x),
+            (Column::Numbers(v), Value::Int(x)) => v.push(x as f64),
+            (Column::Bools(v), Value::Boolean(x)) => v.push(x),
+            (Column::Strings(v), Value::String(x)) => v.push(x),
+            (_, Value::Boolean(x)) if empty => *self = Column::Bools(vec![x]),
+            (_, Value::String(x)) if empty => *self = Column::Strings(vec![x]),
+            (_, value) => {
+                return Err(anyhow!(
+                    "formula evaluated to {}, which does not match its other elements",
+                    value
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, other: Column) -> Result<()> {
+        if self.len() == 0 {
+            *self = other;
+            return Ok(());
+        }
+        match (self, other) {
+            (Column::Numbers(v), Column::Numbers(x)) => v.extend(x),
+            (Column::Bools(v), Column::Bools(x)) => v.extend(x),
+            (Column::Strings(v), Column::Strings(x)) => v.extend(x),
+            (_, other) if other.len() == 0 => {}
+            _ => return Err(anyhow!("formula elements evaluated to different types")),
+        }
+        Ok(())
+    }
+
+    fn into_output(self) -> Option<NodeOutput> {
+        let output = match self {
+            Column::Numbers(v) if v.len() == 1 => NodeOutput::Number(v[0]),
+            Column::Numbers(v) if !v.is_empty() => NodeOutput::NumberArray(v),
+            Column::Bools(v) if v.len() == 1 => NodeOutput::Bool(v[0]),
+            Column::Bools(v) if !v.is_empty() => NodeOutput::BoolArray(v),
+            Column::Strings(mut v) if v.len() == 1 => NodeOutput::String(v.remove(0)),
+            Column::Strings(v) if !v.is_empty() => NodeOutput::StringArray(v),
+            _ => return None,
+        };
+        Some(output)
+    }
+}
 
-                    output_vals.push(res);
+fn eval_formula_range(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_vals: &[Elements],
+    function_set: FunctionSet,
+    missing: MissingPolicy,
+    broadcast: BroadcastPolicy,
+    range: Range<usize>,
+) -> Result<Column> {
+    let mut args = BoundContext::new(plan, function_set.context()?);
+    let mut output_vals = Column::Numbers(Vec::with_capacity(range.len()));
+    for idx_arr in range {
+        let mut is_missing = false;
+        for (slot, input) in plan.inputs().iter().enumerate() {
+            let node_vals = input_vals
+                .get(*input)
+                .ok_or(anyhow!("invalid node index"))?;
+
+            match node_vals {
+                Elements::Numbers(vals) => {
+                    let val = broadcast.element(vals, idx_arr);
+                    match (val.is_nan(), missing) {
+                        (true, MissingPolicy::Substitute(v)) => args.set(slot, v),
+                        (true, _) => is_missing = true,
+                        (false, _) => args.set(slot, *val),
+                    }
                 }
-                NodeKind::SqlQuery(_q) => todo!(),
+                Elements::Bools(vals) => {
+                    args.set_value(slot, Value::Boolean(*broadcast.element(vals, idx_arr)))
+                }
+                Elements::Strings(vals) => args.set_value(
+                    slot,
+                    Value::String(broadcast.element(vals, idx_arr).clone()),
+                ),
+            }
+        }
+        if is_missing {
+            if missing != MissingPolicy::Skip {
+                output_vals.push(Value::Float(f64::NAN))?;
+            }
+            continue;
+        }
+
+        let Ok(res) = formula.eval_with_context(&args) else {
+            return Err(anyhow!("Formula evaluation failed"));
+        };
+
+        output_vals.push(res)?;
+    }
+    Ok(output_vals)
+}
+
+fn eval_formula_scalar(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_outputs: &[NodeOutput],
+    function_set: FunctionSet,
+) -> Result<NodeOutput> {
+    let mut args = BoundContext::new(plan, function_set.context()?);
+    for (slot, input) in plan.inputs().iter().enumerate() {
+        let val = match input_outputs.get(*input) {
+            Some(NodeOutput::Integer(v)) => Value::Int(*v),
+            Some(NodeOutput::Number(v)) => Value::Float(*v),
+            Some(NodeOutput::Bool(v)) => Value::Boolean(*v),
+            Some(NodeOutput::String(v)) => Value::String(v.clone()),
+            _ => return Err(anyhow!("invalid node index")),
+        };
+        args.set_value(slot, val);
+    }
+
+    match formula.eval_with_context(&args) {
+        Ok(Value::Int(v)) => Ok(NodeOutput::Integer(v)),
+        Ok(Value::Float(v)) => Ok(NodeOutput::Number(v)),
+        Ok(Value::Boolean(v)) => Ok(NodeOutput::Bool(v)),
+        Ok(Value::String(v)) => Ok(NodeOutput::String(v)),
+        Ok(v) => Err(anyhow!("formula evaluated to {}, not a number", v)),
+        Err(e) => Err(anyhow!("Formula evaluation failed: {}", e)),
+    }
+}
+
+// Splits the elements into one contiguous chunk per available core
+fn eval_formula_parallel(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_vals: &[Elements],
+    function_set: FunctionSet,
+    missing: MissingPolicy,
+    broadcast: BroadcastPolicy,
+    len: usize,
+) -> Result<Column> {
+    let threads = thread::available_parallelism().map_or(1, |x| x.get());
+    let chunk_len = len.div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..len)
+            .step_by(chunk_len)
+            .map(|start| {
+                let range = start..(start + chunk_len).min(len);
+                scope.spawn(move || {
+                    eval_formula_range(
+                        formula,
+                        plan,
+                        input_vals,
+                        function_set,
+                        missing,
+                        broadcast,
+                        range,
+                    )
+                })
+            })
+            .collect();
+
+        let mut output_vals = Column::Numbers(Vec::with_capacity(len));
+        for handle in handles {
+            let chunk = handle
+                .join()
+                .map_err(|_| anyhow!("formula evaluation thread panicked"))??;
+            output_vals.extend(chunk)?;
+        }
+        Ok(output_vals)
+    })
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct EvalCache {
+    outputs: HashMap<NodeId, NodeOutput>,
+    computed_at: HashMap<NodeId, Instant>,
+    ttls: HashMap<NodeId, Duration>,
+    pub stats: CacheStats,
+}
+
+impl EvalCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Drops the cached outputs of every node that depends on one of the changed variables
+    pub fn invalidate(&mut self, tree: &Tree, changed: &[NodeId]) {
+        self.outputs
+            .retain(|node_id, _| match tree.leaves(*node_id) {
+                Ok(leaves) => !leaves.iter().any(|x| changed.contains(x)),
+                Err(_) => false,
+            });
+        self.computed_at
+            .retain(|id, _| self.outputs.contains_key(id));
+    }
+
+    // Drops the cached output of the node and of every node depending on it
+    pub fn invalidate_node(&mut self, tree: &Tree, node_id: NodeId) {
+        self.outputs.retain(|id, _| match tree.eval_order(*id) {
+            Ok(order) => !order.contains(&node_id),
+            Err(_) => false,
+        });
+        self.computed_at
+            .retain(|id, _| self.outputs.contains_key(id));
+    }
+
+    // Outputs of the node are reused for at most `ttl`, meant for nodes reading external data
+    pub fn set_ttl(&mut self, node_id: NodeId, ttl: Duration) {
+        self.ttls.insert(node_id, ttl);
+    }
+
+    pub fn get(&self, node_id: NodeId) -> Option<&NodeOutput> {
+        self.outputs.get(&node_id)
+    }
+
+    pub fn clear(&mut self) {
+        self.outputs.clear();
+        self.computed_at.clear();
+    }
+
+    fn expire(&mut self, tree: &Tree) {
+        let expired: Vec<NodeId> = self
+            .ttls
+            .iter()
+            .filter(|(id, ttl)| match self.computed_at.get(id) {
+                Some(computed_at) => computed_at.elapsed() >= **ttl,
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for node_id in expired {
+            self.invalidate_node(tree, node_id);
+        }
+    }
+
+    fn insert(&mut self, node_id: NodeId, output: NodeOutput) {
+        if self.ttls.contains_key(&node_id) {
+            self.computed_at.insert(node_id, Instant::now());
+        }
+        self.outputs.insert(node_id, output);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub current: usize,
+    pub peak: usize,
+}
+
+impl MemoryStats {
+    fn allocate(&mut self, bytes: usize, limit: Option<usize>, node_id: NodeId) -> Result<()> {
+        self.current += bytes;
+        if let Some(limit) = limit {
+            if self.current > limit {
+                return Err(anyhow!(
+                    "memory limit of {} bytes exceeded at node {} ({} bytes needed)",
+                    limit,
+                    node_id,
+                    self.current
+                ));
             }
         }
+        self.peak = self.peak.max(self.current);
+        Ok(())
+    }
+
+    fn free(&mut self, bytes: usize) {
+        self.current -= bytes;
+    }
+}
+
+fn output_len(output: &NodeOutput) -> usize {
+    match output {
+        NodeOutput::Number(_)
+        | NodeOutput::Integer(_)
+        | NodeOutput::Bool(_)
+        | NodeOutput::String(_)
+        | NodeOutput::Custom(_) => 1,
+        NodeOutput::NumberArray(v) => v.len(),
+        NodeOutput::BoolArray(v) => v.len(),
+        NodeOutput::StringArray(v) => v.len(),
+        NodeOutput::Matrix(v) => v.iter().map(|x| x.len()).sum(),
+        NodeOutput::Series { values, .. } => values.len(),
+        NodeOutput::TimeSeries(v) => v.len(),
+    }
+}
+
+fn output_bytes(output: &NodeOutput) -> usize {
+    output_len(output) * std::mem::size_of::<f64>()
+}
+
+// Traversal results per node, computed on first use. A Tree does not change after it is
+// built, so they stay valid for its whole lifetime.
+#[derive(Debug, Default)]
+struct Traversals {
+    orders: Mutex<HashMap<NodeId, Arc<[NodeId]>>>,
+    leaves: Mutex<HashMap<NodeId, Arc<[NodeId]>>>,
+}
 
-        match output_vals.len() {
-            0 => Err(anyhow!("The computation resulted in no output")),
-            1 => Ok(NodeOutput::Number(output_vals.first().unwrap().clone())),
-            _ => Ok(NodeOutput::NumberArray(output_vals)),
+impl Clone for Traversals {
+    fn clone(&self) -> Self {
+        Self {
+            orders: Mutex::new(lock(&self.orders).clone()),
+            leaves: Mutex::new(lock(&self.leaves).clone()),
         }
     }
 }
 
+// Only a cache, which traversals a tree has done does not make it a different tree
+impl PartialEq for Traversals {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+// Cached traversals and node states stay usable even if a thread panicked while holding them
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Tree {
-    nodes: HashMap<usize, Rc<Node>>,
+    // Every node once, `positions` maps the node ids to their index
+    nodes: Vec<Node>,
+    positions: HashMap<NodeId, usize>,
+    node_definitions: Vec<NodeDefinition>,
+    edge_definitions: Vec<EdgeDefinition>,
+    function_set: FunctionSet,
+    memory_limit: Option<usize>,
+    parallel_threshold: Option<usize>,
+    traversals: Traversals,
+    registry: NodeRegistry,
+    pub(crate) contract: Option<Contract>,
+    tolerance: Option<Tolerance>,
+    pub(crate) docs: HashMap<NodeId, String>,
+    pub(crate) examples: Vec<Example>,
+    pub(crate) rerun: HashSet<NodeId>,
+    pub(crate) missing: HashMap<NodeId, MissingPolicy>,
+    pub(crate) broadcast: HashMap<NodeId, BroadcastPolicy>,
+    pub(crate) triggers: HashMap<NodeId, Trigger>,
+    pub(crate) dialect: FormulaDialect,
+    pub(crate) hooks: Hooks,
+    // The draft an instantiated tree was built from, with its parameters and conditions
+    template: Option<Arc<TreeDraft>>,
 }
 
 impl Tree {
@@ -155,120 +992,2730 @@ impl Tree {
         nodes_definitions: Vec<NodeDefinition>,
         edge_definitions: Vec<EdgeDefinition>,
     ) -> Result<Self> {
-        let mut nodes = HashMap::new();
+        Self::new_with_registry(nodes_definitions, edge_definitions, NodeRegistry::default())
+    }
+
+    // Node kinds other than variables and formulas are created by the registered factories
+    pub fn new_with_registry(
+        nodes_definitions: Vec<NodeDefinition>,
+        edge_definitions: Vec<EdgeDefinition>,
+        registry: NodeRegistry,
+    ) -> Result<Self> {
+        // Formulas may still read their inputs by position, as `id0`, `id1`, ...
+        let mut edge_inputs: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        for edge_def in &edge_definitions {
+            edge_inputs
+                .entry(edge_def.node_id)
+                .or_default()
+                .push(edge_def.input_id);
+        }
+
+        let mut nodes = Vec::new();
+        let mut positions = HashMap::new();
         for node_def in &nodes_definitions {
-            if let None = nodes.get_mut(&node_def.node_id) {
+            if let Entry::Vacant(entry) = positions.entry(node_def.node_id) {
                 let node = match node_def.kind {
-                    0 => Rc::new(Node::from_variable(
-                        node_def.node_id,
-                        node_def.value.clone(),
-                    )?),
-                    1 => Rc::new(Node::from_formula(node_def.node_id, &node_def.value)?),
-                    _ => Err(anyhow!("Invalid node type"))?,
+                    0 => Node::from_variable(node_def.node_id, node_def.value.clone())?
+                        .with_default(node_def.default.clone()),
+                    _ if node_def.default.is_some() => {
+                        return Err(anyhow!(
+                            "only variable nodes can have a default (node id = {})",
+                            node_def.node_id
+                        ))
+                    }
+                    1 => {
+                        let inputs = edge_inputs.get(&node_def.node_id);
+                        let formula = canonical_references(
+                            &node_def.value,
+                            inputs.map_or(&[], |x| x.as_slice()),
+                        )?;
+                        Node::from_formula(node_def.node_id, &formula)?
+                    }
+                    2 => Node::from_aggregate(node_def.node_id, node_def.value.parse()?)?,
+                    kind => {
+                        let inputs = edge_inputs.get(&node_def.node_id);
+                        Node::from_custom(
+                            node_def.node_id,
+                            registry.create(
+                                kind,
+                                &node_def.value,
+                                inputs.map_or(&[], |x| x.as_slice()),
+                            )?,
+                        )?
+                    }
                 };
 
-                nodes.insert(node_def.node_id, Rc::clone(&node));
+                entry.insert(nodes.len());
+                nodes.push(node);
             }
         }
 
+        // A cycle has no evaluation order
+        if let Some(cycle) = find_cycle(&edge_definitions) {
+            let path: Vec<_> = cycle.iter().map(|x| x.to_string()).collect();
+            return Err(anyhow!("the edges form a cycle: {}", path.join(" -> ")));
+        }
+
         for edge_def in &edge_definitions {
-            let Some(node) = nodes.get(&edge_def.node_id) else {
+            let Some(position) = positions.get(&edge_def.node_id) else {
                 return Err(anyhow!("node not found"));
             };
-
-            let Some(input_node) = nodes.get(&edge_def.input_id) else {
+            if !positions.contains_key(&edge_def.input_id) {
                 return Err(anyhow!("input node not found"));
-            };
+            }
+            nodes[*position].inputs.push(edge_def.input_id);
+        }
+
+        for node in nodes.iter_mut() {
+            node.bind();
+        }
+
+        let tree = Self {
+            nodes,
+            positions,
+            node_definitions: nodes_definitions,
+            edge_definitions,
+            function_set: FunctionSet::default(),
+            memory_limit: None,
+            parallel_threshold: None,
+            traversals: Traversals::default(),
+            registry,
+            contract: None,
+            tolerance: None,
+            docs: HashMap::new(),
+            examples: Vec::new(),
+            rerun: HashSet::new(),
+            missing: HashMap::new(),
+            broadcast: HashMap::new(),
+            triggers: HashMap::new(),
+            dialect: FormulaDialect::default(),
+            hooks: Hooks::default(),
+            template: None,
+        };
+
+        Ok(tree)
+    }
+
+    pub fn with_function_set(mut self, function_set: FunctionSet) -> Self {
+        self.function_set = function_set;
+        self
+    }
+
+    // Limits the estimated size of the intermediate arrays held at the same time during eval
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
 
-            {
-                let mut inputs = node.inputs.borrow_mut();
-                inputs.borrow_mut().push(Rc::clone(input_node));
+    // Formula nodes broadcasting over at least `len` elements spread them across all cores
+    pub fn with_parallel_threshold(mut self, len: usize) -> Self {
+        self.parallel_threshold = Some(len);
+        self
+    }
+
+    // Nodes reading external data, e.g. custom nodes running a query, are evaluated again for
+    // every node reading them instead of sharing one output per evaluation. Nodes keeping state
+    // in the session run once per evaluation all the same.
+    pub fn with_rerun(mut self, node_ids: &[NodeId]) -> Result<Self> {
+        for node_id in node_ids {
+            if let NodeKind::Variable(_) = self.node(*node_id)?.kind {
+                return Err(anyhow!("variable node {} cannot be re-run", node_id));
+            }
+            if self.triggers.contains_key(node_id) {
+                return Err(anyhow!("node {} has a trigger", node_id));
+            }
+        }
+        self.rerun.extend(node_ids);
+        Ok(self)
+    }
+
+    // Refuses trees that could evaluate differently from one run to the next with the same
+    // inputs, i.e. with custom nodes depending on more than their inputs and state. Everything
+    // else already evaluates in the fixed order of the edges with plain f64 arithmetic.
+    pub fn with_strict_mode(self) -> Result<Self> {
+        for node in &self.nodes {
+            if let NodeKind::Custom(custom) = &node.kind {
+                if !custom.node.deterministic(node.inputs.len()) {
+                    return Err(anyhow!("node {} is not deterministic", node.id));
+                }
             }
+            if let Trigger::Every(_) = self.trigger(node.id) {
+                return Err(anyhow!("node {} is refreshed on a timer", node.id));
+            }
+        }
+        Ok(self)
+    }
 
-            nodes.insert(node.id, Rc::clone(&node));
+    // Rebuilds the formula nodes with `==` and `!=` comparing within the tolerance, the node
+    // definitions keep the formulas as they were written
+    pub fn with_tolerance(self, tolerance: Tolerance) -> Result<Self> {
+        let mut node_definitions = self.node_definitions.clone();
+        for node_def in node_definitions.iter_mut().filter(|x| x.kind == 1) {
+            node_def.value = approximate_equality(&node_def.value, tolerance)?;
         }
 
-        let tree = Self { nodes };
+        let mut tree = Tree::new_with_registry(
+            node_definitions,
+            self.edge_definitions.clone(),
+            self.registry.clone(),
+        )?;
+        tree.node_definitions = self.node_definitions;
+        tree.function_set = self.function_set;
+        tree.memory_limit = self.memory_limit;
+        tree.parallel_threshold = self.parallel_threshold;
+        tree.contract = self.contract;
+        tree.tolerance = Some(tolerance);
+        tree.docs = self.docs;
+        tree.examples = self.examples;
+        tree.rerun = self.rerun;
+        tree.missing = self.missing;
+        tree.broadcast = self.broadcast;
+        tree.triggers = self.triggers;
+        tree.dialect = self.dialect;
+        tree.hooks = self.hooks;
+        tree.template = self.template;
+        Ok(tree)
+    }
 
-        return Ok(tree);
+    // The same tree with other node definitions, keeping its settings, docs and examples
+    pub(crate) fn with_node_definitions(
+        &self,
+        node_definitions: Vec<NodeDefinition>,
+    ) -> Result<Tree> {
+        let mut tree = Tree::new_with_registry(
+            node_definitions,
+            self.edge_definitions.clone(),
+            self.registry.clone(),
+        )?
+        .with_function_set(self.function_set);
+        tree.memory_limit = self.memory_limit;
+        tree.parallel_threshold = self.parallel_threshold;
+        tree.docs = self.docs.clone();
+        tree.examples = self.examples.clone();
+        tree.rerun = self.rerun.clone();
+        tree.missing = self.missing.clone();
+        tree.broadcast = self.broadcast.clone();
+        tree.triggers = self.triggers.clone();
+        tree.dialect = self.dialect.clone();
+        tree.hooks = self.hooks.clone();
+        if let Some(tolerance) = self.tolerance {
+            tree = tree.with_tolerance(tolerance)?;
+        }
+        match &self.contract {
+            Some(contract) => tree.with_contract(contract.clone()),
+            None => Ok(tree),
+        }
     }
 
-    pub fn node_inputs(&self, node_id: NodeId) -> Result<Vec<String>> {
-        let node = self
-            .nodes
+    pub fn tolerance(&self) -> Option<Tolerance> {
+        self.tolerance
+    }
+
+    pub fn node(&self, node_id: NodeId) -> Result<&Node> {
+        let position = self
+            .positions
             .get(&node_id)
             .ok_or(anyhow!("no node with id {}", node_id))?;
-        let inputs = node.inputs();
-        let res = inputs
-            .iter()
-            .filter_map(|x| match self.nodes.get(x) {
-                Some(node) => match &node.kind {
-                    NodeKind::Variable(var_name) => Some(var_name.clone()),
-                    _ => None,
-                },
-                None => None,
-            })
-            .collect();
-        return Ok(res);
+        Ok(&self.nodes[*position])
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn eval(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<NodeOutput> {
+        let (output, _) = self.eval_with_memory(node_id, values)?;
+        Ok(output)
+    }
 
-    #[test]
-    fn test_tree() {
-        let edge_defs = vec![
-            EdgeDefinition {
-                node_id: 2,
-                input_id: 0,
-            },
-            EdgeDefinition {
-                node_id: 2,
-                input_id: 1,
+    // Takes the values by variable name instead of node id, every variable node with the name
+    // gets the value
+    pub fn eval_with_vars(
+        &self,
+        node_id: NodeId,
+        variables: &HashMap<String, NodeOutput>,
+    ) -> Result<NodeOutput> {
+        let mut values = HashMap::new();
+        for (name, value) in variables {
+            let mut found = false;
+            for node in self.nodes.iter() {
+                if let NodeKind::Variable(var_name) = &node.kind {
+                    if var_name == name {
+                        values.insert(node.id, value.clone());
+                        found = true;
+                    }
+                }
+            }
+            if !found {
+                return Err(anyhow!("no variable named {}", name));
+            }
+        }
+        self.eval(node_id, &values)
+    }
+
+    pub fn eval_with_memory(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<(NodeOutput, MemoryStats)> {
+        let values = self.hooks.before(&[node_id], values)?;
+        let mut stats = MemoryStats::default();
+        let output = self.eval_tracked(node_id, &values, &mut stats, &mut Session::new())?;
+        self.hooks.after(node_id, &output)?;
+        Ok((output, stats))
+    }
+
+    // Evaluates the nodes in the order of `eval_order` with the outputs kept until their last
+    // consumer ran, so neither deep trees nor shared nodes are evaluated recursively
+    pub(crate) fn eval_tracked(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        stats: &mut MemoryStats,
+        session: &mut Session,
+    ) -> Result<NodeOutput> {
+        let now = Instant::now();
+        let TriggeredNodes { mut kept, needed } = self.triggered_nodes(node_id, session, now)?;
+        let order: Arc<[NodeId]> = match needed {
+            Some(needed) => self
+                .eval_order(node_id)?
+                .iter()
+                .filter(|x| needed.contains(x))
+                .copied()
+                .collect(),
+            None => self.eval_order(node_id)?,
+        };
+        let mut consumers: HashMap<NodeId, usize> = HashMap::new();
+        for id in order.iter().filter(|x| !kept.contains_key(x)) {
+            for input in self.node(*id)?.inputs.iter() {
+                *consumers.entry(*input).or_default() += 1;
+            }
+        }
+
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        // Nodes that re-run keep their inputs instead of their output
+        let mut rerun_inputs: HashMap<NodeId, InputVec<NodeOutput>> = HashMap::new();
+        for id in order.iter() {
+            let node = self.node(*id)?;
+            if let NodeKind::Variable(_) = node.kind {
+                let output = node.eval(values)?;
+                stats.allocate(output_bytes(&output), self.memory_limit, node.id)?;
+                outputs.insert(node.id, output);
+                continue;
+            }
+            if let Some(output) = kept.remove(id) {
+                stats.allocate(output_bytes(&output), self.memory_limit, node.id)?;
+                outputs.insert(node.id, output);
+                continue;
+            }
+
+            let mut input_outputs = InputVec::new();
+            let mut input_bytes = 0;
+            for input in node.inputs.iter() {
+                let remaining = consumers
+                    .get_mut(input)
+                    .ok_or(anyhow!("node {} has no consumers", input))?;
+                *remaining -= 1;
+                let last = *remaining == 0;
+
+                let output = match rerun_inputs.get(input) {
+                    Some(inputs) => {
+                        let output =
+                            self.apply_in_session(self.node(*input)?, inputs.clone(), session)?;
+                        stats.allocate(output_bytes(&output), self.memory_limit, *input)?;
+                        // A node keeping state in the session would advance it again for every
+                        // consumer, the others get the output of the first one instead
+                        let shared = !last && session.has_state(*input);
+                        match shared {
+                            true => {
+                                outputs.insert(*input, output.clone());
+                            }
+                            false => input_bytes += output_bytes(&output),
+                        }
+                        if last || shared {
+                            let inputs = rerun_inputs.remove(input).unwrap_or_default();
+                            input_bytes += inputs.iter().map(output_bytes).sum::<usize>();
+                        }
+                        output
+                    }
+                    None => {
+                        let output = match last {
+                            true => outputs.remove(input),
+                            false => outputs.get(input).cloned(),
+                        }
+                        .ok_or(anyhow!("node {} was not evaluated", input))?;
+                        if last {
+                            input_bytes += output_bytes(&output);
+                        }
+                        output
+                    }
+                };
+                input_outputs.push(output);
+            }
+
+            if self.rerun.contains(&node.id) && node.id != node_id {
+                rerun_inputs.insert(node.id, input_outputs);
+                continue;
+            }
+
+            // The output is expected to be as long as the longest input, checked before it gets
+            // allocated. Afterwards the estimate is replaced by the actual size.
+            let len = input_outputs.iter().map(output_len).max().unwrap_or(0);
+            let estimate = len * std::mem::size_of::<f64>();
+            stats.allocate(estimate, self.memory_limit, node.id)?;
+
+            let output = self.apply_in_session(node, input_outputs, session)?;
+            stats.free(estimate);
+            stats.allocate(output_bytes(&output), self.memory_limit, node.id)?;
+            stats.free(input_bytes);
+            if self.triggers.contains_key(&node.id) {
+                session.set_output(node.id, now, output.clone());
+            }
+            outputs.insert(node.id, output);
+        }
+        outputs
+            .remove(&node_id)
+            .ok_or(anyhow!("node {} was not evaluated", node_id))
+    }
+
+    // Custom nodes keep their state in the session, every other node is applied as usual
+    fn apply_in_session(
+        &self,
+        node: &Node,
+        input_outputs: InputVec<NodeOutput>,
+        session: &mut Session,
+    ) -> Result<NodeOutput> {
+        if let NodeKind::Custom(custom) = &node.kind {
+            return custom
+                .node
+                .eval_with_state(&input_outputs, session.state(node.id));
+        }
+        node.apply(
+            input_outputs,
+            self.function_set,
+            self.parallel_threshold,
+            self.missing_policy(node.id),
+            self.broadcast_policy(node.id),
+        )
+    }
+
+    // Evaluates the nodes level by level, where a level holds the nodes whose inputs are all in
+    // earlier levels. The formula nodes of a level are spread across all cores, custom nodes
+    // stay on the calling thread, and with a parallel threshold the formulas spread their
+    // elements instead. The memory limit is not tracked and nodes marked to re-run are evaluated
+    // once, like every other node.
+    pub fn eval_parallel(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<NodeOutput> {
+        self.run_hooks(node_id, values, |values| self.eval_levels(node_id, values))
+    }
+
+    fn eval_levels(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<NodeOutput> {
+        let mut depths: HashMap<NodeId, usize> = HashMap::new();
+        let mut levels: Vec<Vec<&Node>> = Vec::new();
+        for id in self.eval_order(node_id)?.iter() {
+            let node = self.node(*id)?;
+            let depth = node
+                .inputs
+                .iter()
+                .filter_map(|x| depths.get(x))
+                .map(|x| x + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(*id, depth);
+            if levels.len() <= depth {
+                levels.resize(depth + 1, Vec::new());
+            }
+            levels[depth].push(node);
+        }
+
+        let threads = thread::available_parallelism().map_or(1, |x| x.get());
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        for level in levels {
+            let mut jobs = Vec::new();
+            for node in level {
+                if let NodeKind::Variable(_) = node.kind {
+                    outputs.insert(node.id, node.eval(values)?);
+                    continue;
+                }
+
+                let mut input_outputs = InputVec::new();
+                for input in node.inputs.iter() {
+                    let output = outputs
+                        .get(input)
+                        .ok_or(anyhow!("node {} was not evaluated", input))?;
+                    input_outputs.push(output.clone());
+                }
+                match (&node.kind, &node.plan) {
+                    (NodeKind::Formula(formula), Some(plan))
+                        if self.parallel_threshold.is_none() =>
+                    {
+                        let policies =
+                            (self.missing_policy(node.id), self.broadcast_policy(node.id));
+                        jobs.push((node.id, formula, plan, input_outputs, policies));
+                    }
+                    _ => {
+                        let output = node.apply(
+                            input_outputs,
+                            self.function_set,
+                            self.parallel_threshold,
+                            self.missing_policy(node.id),
+                            self.broadcast_policy(node.id),
+                        )?;
+                        outputs.insert(node.id, output);
+                    }
+                }
+            }
+
+            let function_set = self.function_set;
+            let chunk_len = jobs.len().div_ceil(threads).max(1);
+            let mut chunks = Vec::new();
+            let mut jobs = jobs.into_iter().peekable();
+            while jobs.peek().is_some() {
+                chunks.push(jobs.by_ref().take(chunk_len).collect::<Vec<_>>());
+            }
+            let results = thread::scope(|scope| {
+                let handles: Vec<_> = chunks
+                    .into_iter()
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .into_iter()
+                                .map(|(id, formula, plan, input_outputs, (missing, broadcast))| {
+                                    let output = apply_formula(
+                                        formula,
+                                        plan,
+                                        input_outputs,
+                                        function_set,
+                                        None,
+                                        missing,
+                                        broadcast,
+                                    )?;
+                                    Ok((id, output))
+                                })
+                                .collect::<Result<Vec<_>>>()
+                        })
+                    })
+                    .collect();
+
+                let mut results = Vec::new();
+                for handle in handles {
+                    results.extend(
+                        handle
+                            .join()
+                            .map_err(|_| anyhow!("node evaluation thread panicked"))??,
+                    );
+                }
+                Ok::<_, anyhow::Error>(results)
+            })?;
+            outputs.extend(results);
+        }
+
+        outputs
+            .remove(&node_id)
+            .ok_or(anyhow!("node {} was not evaluated", node_id))
+    }
+
+    // Evaluates a single node from the outputs of its inputs with the settings of this tree
+    pub(crate) fn apply(&self, node: &Node, input_outputs: Vec<NodeOutput>) -> Result<NodeOutput> {
+        node.apply(
+            input_outputs.into_iter().collect(),
+            self.function_set,
+            self.parallel_threshold,
+            self.missing_policy(node.id),
+            self.broadcast_policy(node.id),
+        )
+    }
+
+    pub fn formula_metrics(&self) -> HashMap<NodeId, FormulaMetrics> {
+        self.nodes
+            .iter()
+            .filter_map(|node| match &node.kind {
+                NodeKind::Formula(formula) => {
+                    Some((node.id, FormulaMetrics::from_formula(formula)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // One formula for the node in terms of the variables it depends on, with every formula
+    // input substituted by its own composed expression
+    pub fn composed_expression(&self, node_id: NodeId) -> Result<String> {
+        let mut expressions: HashMap<NodeId, String> = HashMap::new();
+        for &id in self.eval_order(node_id)?.iter() {
+            let node = self.node(id)?;
+            let expression = match node.kind() {
+                NodeKind::Variable(_) => input_identifier(id),
+                NodeKind::Formula(formula) => {
+                    let mut expression = to_formula_string(formula);
+                    for input_id in &node.inputs {
+                        expression =
+                            substitute_input(&expression, *input_id, &expressions[input_id])?;
+                    }
+                    expression
+                }
+                NodeKind::Aggregate(_) => {
+                    return Err(anyhow!("node {} is an aggregate, which has no formula", id));
+                }
+                NodeKind::SqlQuery(_) | NodeKind::Custom(_) => {
+                    return Err(anyhow!("node {} is not a static node", id));
+                }
+            };
+            expressions.insert(id, expression);
+        }
+        expressions
+            .remove(&node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))
+    }
+
+    pub fn eval_cached(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        cache: &mut EvalCache,
+    ) -> Result<NodeOutput> {
+        let node = self.node(node_id)?;
+        cache.expire(self);
+        self.run_hooks(node_id, values, |values| {
+            self.eval_cached_node(node, values, cache)
+        })
+    }
+
+    // Evaluates the node and returns the output of every node it depends on as well
+    pub fn eval_all(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<HashMap<NodeId, NodeOutput>> {
+        let mut cache = EvalCache::new();
+        self.eval_cached(node_id, values, &mut cache)?;
+        Ok(cache.outputs)
+    }
+
+    // Evaluates several nodes at once, the nodes they share are evaluated only once. The nodes
+    // are walked in the order of `eval_order` of every root with one map of outputs for all of
+    // them, so deep trees are not evaluated recursively.
+    pub fn eval_many(
+        &self,
+        node_ids: &[NodeId],
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<HashMap<NodeId, NodeOutput>> {
+        let values = self.hooks.before(node_ids, values)?;
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        // Nodes that re-run keep their inputs and are applied again for every consumer
+        let mut rerun_inputs: HashMap<NodeId, InputVec<NodeOutput>> = HashMap::new();
+        let mut roots = HashMap::new();
+        for node_id in node_ids {
+            for id in self.eval_order(*node_id)?.iter() {
+                if outputs.contains_key(id) || rerun_inputs.contains_key(id) {
+                    continue;
+                }
+                let node = self.node(*id)?;
+                if let NodeKind::Variable(_) = node.kind {
+                    outputs.insert(node.id, node.eval(&values)?);
+                    continue;
+                }
+
+                let mut input_outputs = InputVec::new();
+                for input in node.inputs.iter() {
+                    let output = match rerun_inputs.get(input) {
+                        Some(inputs) => self.apply(self.node(*input)?, inputs.to_vec())?,
+                        None => outputs
+                            .get(input)
+                            .cloned()
+                            .ok_or(anyhow!("node {} was not evaluated", input))?,
+                    };
+                    input_outputs.push(output);
+                }
+                match self.rerun.contains(&node.id) {
+                    true => {
+                        rerun_inputs.insert(node.id, input_outputs);
+                    }
+                    false => {
+                        let output = self.apply(node, input_outputs.into_vec())?;
+                        outputs.insert(node.id, output);
+                    }
+                }
+            }
+
+            let output = match rerun_inputs.get(node_id) {
+                Some(inputs) => self.apply(self.node(*node_id)?, inputs.to_vec())?,
+                None => outputs
+                    .get(node_id)
+                    .cloned()
+                    .ok_or(anyhow!("node {} was not evaluated", node_id))?,
+            };
+            self.hooks.after(*node_id, &output)?;
+            roots.insert(*node_id, output);
+        }
+        Ok(roots)
+    }
+
+    // Walks the inputs depth first with an explicit stack of the nodes waiting on their inputs,
+    // so deep trees do not overflow the call stack. Every request of a node counts as a cache
+    // hit or miss, and nodes that re-run are evaluated again for every consumer.
+    fn eval_cached_node(
+        &self,
+        node: &Node,
+        values: &HashMap<NodeId, NodeOutput>,
+        cache: &mut EvalCache,
+    ) -> Result<NodeOutput> {
+        let mut stack: Vec<(&Node, InputVec<NodeOutput>)> = Vec::new();
+        let mut next = node;
+        loop {
+            let rerun = self.rerun.contains(&next.id);
+            let mut output = None;
+            if let (Some(cached), false) = (cache.outputs.get(&next.id), rerun) {
+                cache.stats.hits += 1;
+                output = Some(cached.clone());
+            } else {
+                cache.stats.misses += 1;
+                match next.kind {
+                    NodeKind::Variable(_) => {
+                        let evaluated = next.eval(values)?;
+                        if !rerun {
+                            cache.insert(next.id, evaluated.clone());
+                        }
+                        output = Some(evaluated);
+                    }
+                    _ => stack.push((next, InputVec::new())),
+                }
+            }
+
+            // Hands outputs to the waiting nodes until one of them needs another input
+            loop {
+                if let Some(output) = output.take() {
+                    match stack.last_mut() {
+                        Some((_, input_outputs)) => input_outputs.push(output),
+                        None => return Ok(output),
+                    }
+                }
+                let (waiting, input_outputs) = stack
+                    .last()
+                    .ok_or(anyhow!("node {} was not evaluated", node.id))?;
+                if let Some(input) = waiting.inputs.get(input_outputs.len()) {
+                    next = self.node(*input)?;
+                    break;
+                }
+
+                let (waiting, input_outputs) = stack
+                    .pop()
+                    .ok_or(anyhow!("node {} was not evaluated", node.id))?;
+                let evaluated = waiting.apply(
+                    input_outputs,
+                    self.function_set,
+                    self.parallel_threshold,
+                    self.missing_policy(waiting.id),
+                    self.broadcast_policy(waiting.id),
+                )?;
+                if !self.rerun.contains(&waiting.id) {
+                    cache.insert(waiting.id, evaluated.clone());
+                }
+                output = Some(evaluated);
+            }
+        }
+    }
+
+    pub fn inline(&self, node_id: NodeId) -> Result<Tree> {
+        let mut draft = self.definitions_draft();
+        draft.inline(node_id)?;
+        let mut tree = draft
+            .freeze_with_registry(self.registry.clone())?
+            .with_function_set(self.function_set);
+        tree.memory_limit = self.memory_limit;
+        tree.parallel_threshold = self.parallel_threshold;
+        tree.docs = self.docs.clone();
+        tree.docs.retain(|id, _| tree.positions.contains_key(id));
+        tree.examples = self.examples.clone();
+        for example in &mut tree.examples {
+            example
+                .expected
+                .retain(|id, _| tree.positions.contains_key(id));
+        }
+        tree.rerun = self.rerun.clone();
+        tree.rerun.retain(|id| tree.positions.contains_key(id));
+        tree.missing = self.missing.clone();
+        tree.missing.retain(|id, _| tree.positions.contains_key(id));
+        tree.broadcast = self.broadcast.clone();
+        tree.broadcast
+            .retain(|id, _| tree.positions.contains_key(id));
+        tree.triggers = self.triggers.clone();
+        tree.triggers
+            .retain(|id, _| tree.positions.contains_key(id));
+        tree.dialect = self.dialect.clone();
+        tree.hooks = self.hooks.clone();
+        if let Some(tolerance) = self.tolerance {
+            tree = tree.with_tolerance(tolerance)?;
+        }
+        match &self.contract {
+            Some(contract) => tree.with_contract(contract.clone()),
+            None => Ok(tree),
+        }
+    }
+
+    // Every node below `node_id` exactly once, each one after all of its inputs
+    pub fn eval_order(&self, node_id: NodeId) -> Result<Arc<[NodeId]>> {
+        if let Some(order) = lock(&self.traversals.orders).get(&node_id) {
+            return Ok(Arc::clone(order));
+        }
+
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        self.visit(node_id, &mut visited, &mut order)?;
+        let order: Arc<[NodeId]> = order.into();
+
+        lock(&self.traversals.orders).insert(node_id, Arc::clone(&order));
+        Ok(order)
+    }
+
+    // The nodes without inputs `node_id` depends on, sorted by id
+    pub fn leaves(&self, node_id: NodeId) -> Result<Arc<[NodeId]>> {
+        if let Some(leaves) = lock(&self.traversals.leaves).get(&node_id) {
+            return Ok(Arc::clone(leaves));
+        }
+
+        let mut leaves = Vec::new();
+        for id in self.eval_order(node_id)?.iter() {
+            if self.node(*id)?.inputs.is_empty() {
+                leaves.push(*id);
+            }
+        }
+        leaves.sort();
+        let leaves: Arc<[NodeId]> = leaves.into();
+
+        lock(&self.traversals.leaves).insert(node_id, Arc::clone(&leaves));
+        Ok(leaves)
+    }
+
+    pub fn function_set(&self) -> FunctionSet {
+        self.function_set
+    }
+
+    pub fn node_definitions(&self) -> &[NodeDefinition] {
+        &self.node_definitions
+    }
+
+    pub fn edge_definitions(&self) -> &[EdgeDefinition] {
+        &self.edge_definitions
+    }
+
+    // An instantiated tree gives back the draft it was built from, parameters and all
+    pub fn to_draft(&self) -> TreeDraft {
+        match &self.template {
+            Some(template) => TreeDraft::clone(template),
+            None => self.definitions_draft(),
+        }
+    }
+
+    fn definitions_draft(&self) -> TreeDraft {
+        TreeDraft {
+            nodes: self.node_definitions.clone(),
+            edges: self.edge_definitions.clone(),
+            registry: self.registry.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn node_inputs(&self, node_id: NodeId) -> Result<Vec<String>> {
+        let inputs = self.input_leaves(node_id)?;
+        let res = inputs
+            .iter()
+            .filter_map(|x| match self.node(*x) {
+                Ok(node) => match &node.kind {
+                    NodeKind::Variable(var_name) => Some(var_name.clone()),
+                    _ => None,
+                },
+                Err(_) => None,
+            })
+            .collect();
+        Ok(res)
+    }
+
+    // The nodes without inputs reached from `node_id` along every path, in the order of the
+    // edges and once per path. A node without inputs is its own leaf.
+    pub(crate) fn input_leaves(&self, node_id: NodeId) -> Result<Vec<NodeId>> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![node_id];
+        while let Some(id) = stack.pop() {
+            let node = self.node(id)?;
+            if node.inputs.is_empty() {
+                leaves.push(id);
+            }
+            stack.extend(node.inputs.iter().rev());
+        }
+        Ok(leaves)
+    }
+
+    // Iterative, deep trees would overflow the stack otherwise
+    fn visit(
+        &self,
+        node_id: NodeId,
+        visited: &mut HashSet<NodeId>,
+        order: &mut Vec<NodeId>,
+    ) -> Result<()> {
+        if !visited.insert(node_id) {
+            return Ok(());
+        }
+        let mut stack = vec![(self.node(node_id)?, 0)];
+        while let Some((node, idx)) = stack.last_mut() {
+            let input = node.inputs.get(*idx).copied();
+            *idx += 1;
+            match input {
+                Some(input) => {
+                    if visited.insert(input) {
+                        stack.push((self.node(input)?, 0));
+                    }
+                }
+                None => {
+                    order.push(node.id);
+                    stack.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A path of node ids from a node through its inputs back to itself, if there is one
+fn find_cycle(edge_definitions: &[EdgeDefinition]) -> Option<Vec<NodeId>> {
+    let mut inputs: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+    for edge_def in edge_definitions {
+        inputs
+            .entry(edge_def.node_id)
+            .or_default()
+            .push(edge_def.input_id);
+    }
+    let mut starts: Vec<_> = inputs.keys().copied().collect();
+    starts.sort();
+
+    // Iterative, deep trees would overflow the stack otherwise
+    let mut done = HashSet::new();
+    for start in starts {
+        if done.contains(&start) {
+            continue;
+        }
+        let mut path = vec![start];
+        let mut on_path = HashSet::from([start]);
+        let mut next = vec![0];
+        while let (Some(node_id), Some(idx)) = (path.last().copied(), next.last_mut()) {
+            let Some(input_id) = inputs.get(&node_id).and_then(|x| x.get(*idx)).copied() else {
+                done.insert(node_id);
+                on_path.remove(&node_id);
+                path.pop();
+                next.pop();
+                continue;
+            };
+            *idx += 1;
+            if on_path.contains(&input_id) {
+                let pos = path.iter().position(|x| *x == input_id)?;
+                let mut cycle = path[pos..].to_vec();
+                cycle.push(input_id);
+                return Some(cycle);
+            }
+            if !done.contains(&input_id) {
+                path.push(input_id);
+                on_path.insert(input_id);
+                next.push(0);
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TreeDraft {
+    nodes: Vec<NodeDefinition>,
+    edges: Vec<EdgeDefinition>,
+    parameters: Vec<ParameterDefinition>,
+    conditions: HashMap<NodeId, String>,
+    // The role needed to edit a node, see `TreeDraft::editor`
+    owners: HashMap<NodeId, String>,
+    // The stored nodes as the draft was read from them, to tell its changes from concurrent ones
+    #[cfg(feature = "sqlite-blocking")]
+    read: HashMap<NodeId, crate::blocking::StoredNode>,
+    registry: NodeRegistry,
+}
+
+impl TreeDraft {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Node kinds other than variables, formulas and aggregates are created by the registered
+    // factories when the draft is frozen or instantiated
+    pub fn new_with_registry(registry: NodeRegistry) -> Self {
+        Self {
+            registry,
+            ..Default::default()
+        }
+    }
+
+    pub fn registry(&self) -> &NodeRegistry {
+        &self.registry
+    }
+
+    pub fn add_node(&mut self, node_def: NodeDefinition) -> Result<()> {
+        if self.has_node(node_def.node_id) {
+            return Err(anyhow!("node with id {} already exists", node_def.node_id));
+        }
+        self.nodes.push(node_def);
+        Ok(())
+    }
+
+    // Parameters are read by name in formulas and fixed when the draft is instantiated
+    pub fn add_parameter(&mut self, name: &str, default: f64) -> Result<()> {
+        if name.is_empty() || name.starts_with(INPUT_PREFIX) {
+            return Err(anyhow!("invalid parameter name {:?}", name));
+        }
+        if self.parameters.iter().any(|x| x.name == name) {
+            return Err(anyhow!("parameter {} already exists", name));
+        }
+        self.parameters.push(ParameterDefinition {
+            name: name.to_string(),
+            default,
+        });
+        Ok(())
+    }
+
+    pub fn parameters(&self) -> &[ParameterDefinition] {
+        &self.parameters
+    }
+
+    // The node is only part of instantiated trees where the condition over the parameters holds
+    pub fn set_condition(&mut self, node_id: NodeId, condition: &str) -> Result<()> {
+        if !self.has_node(node_id) {
+            return Err(anyhow!("no node with id {}", node_id));
+        }
+        build_operator_tree(condition)?;
+        self.conditions.insert(node_id, condition.to_string());
+        Ok(())
+    }
+
+    pub fn clear_condition(&mut self, node_id: NodeId) {
+        self.conditions.remove(&node_id);
+    }
+
+    // Builds a tree with the parameters bound to constants, missing values use the defaults.
+    // A disabled node takes every node depending on it along, so whole branches are left out.
+    pub fn instantiate(&self, values: &HashMap<String, f64>) -> Result<Tree> {
+        if let Some(name) = values
+            .keys()
+            .find(|name| !self.parameters.iter().any(|x| &x.name == *name))
+        {
+            return Err(anyhow!("unknown parameter {}", name));
+        }
+        let parameters: HashMap<String, f64> = self
+            .parameters
+            .iter()
+            .map(|x| (x.name.clone(), *values.get(&x.name).unwrap_or(&x.default)))
+            .collect();
+
+        let mut disabled = HashSet::new();
+        for (node_id, condition) in &self.conditions {
+            if !eval_condition(condition, &parameters)? {
+                disabled.insert(*node_id);
+            }
+        }
+        loop {
+            let dependents: Vec<NodeId> = self
+                .edges
+                .iter()
+                .filter(|x| disabled.contains(&x.input_id) && !disabled.contains(&x.node_id))
+                .map(|x| x.node_id)
+                .collect();
+            if dependents.is_empty() {
+                break;
+            }
+            disabled.extend(dependents);
+        }
+
+        let mut nodes: Vec<NodeDefinition> = self
+            .nodes
+            .iter()
+            .filter(|x| !disabled.contains(&x.node_id))
+            .cloned()
+            .collect();
+        for node_def in nodes.iter_mut().filter(|x| x.kind == 1) {
+            node_def.value = bind_parameters(&node_def.value, &parameters)?;
+        }
+        let edges = self
+            .edges
+            .iter()
+            .filter(|x| !disabled.contains(&x.node_id) && !disabled.contains(&x.input_id))
+            .cloned()
+            .collect();
+        let mut tree = Tree::new_with_registry(nodes, edges, self.registry.clone())?;
+        tree.template = Some(Arc::new(self.clone()));
+        Ok(tree)
+    }
+
+    pub fn remove_node(&mut self, node_id: NodeId) -> Result<NodeDefinition> {
+        let idx = self
+            .nodes
+            .iter()
+            .position(|x| x.node_id == node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))?;
+        self.edges
+            .retain(|x| x.node_id != node_id && x.input_id != node_id);
+        self.conditions.remove(&node_id);
+        self.owners.remove(&node_id);
+        Ok(self.nodes.remove(idx))
+    }
+
+    pub fn set_value(&mut self, node_id: NodeId, value: String) -> Result<()> {
+        let node_def = self
+            .nodes
+            .iter_mut()
+            .find(|x| x.node_id == node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))?;
+        node_def.value = value;
+        Ok(())
+    }
+
+    pub fn connect(&mut self, node_id: NodeId, input_id: NodeId) -> Result<()> {
+        if !self.has_node(node_id) {
+            return Err(anyhow!("no node with id {}", node_id));
+        }
+        if !self.has_node(input_id) {
+            return Err(anyhow!("no input node with id {}", input_id));
+        }
+        self.edges.push(EdgeDefinition { node_id, input_id });
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, node_id: NodeId, input_id: NodeId) -> Result<()> {
+        let idx = self
+            .edges
+            .iter()
+            .position(|x| x.node_id == node_id && x.input_id == input_id)
+            .ok_or(anyhow!("no edge from {} to {}", input_id, node_id))?;
+        self.edges.remove(idx);
+        Ok(())
+    }
+
+    // Rewrites the formulas reading `id0`, `id1`, ... to read `$<node id>`
+    pub fn canonicalize_references(&mut self) -> Result<()> {
+        for node_def in self.nodes.iter_mut().filter(|x| x.kind == 1) {
+            let inputs: Vec<_> = self
+                .edges
+                .iter()
+                .filter(|x| x.node_id == node_def.node_id)
+                .map(|x| x.input_id)
+                .collect();
+            node_def.value = canonical_references(&node_def.value, &inputs)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_aliases(&mut self, aliases: &FormulaAliases) -> Result<()> {
+        for node_def in self.nodes.iter_mut().filter(|x| x.kind == 1) {
+            node_def.value = aliases.normalize(&node_def.value)?;
+        }
+        Ok(())
+    }
+
+    pub fn split_formula(&mut self, node_id: NodeId, max_operators: usize) -> Result<Vec<NodeId>> {
+        let node_def = self
+            .nodes
+            .iter()
+            .find(|x| x.node_id == node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))?;
+        if node_def.kind != 1 {
+            return Err(anyhow!("node with id {} is not a formula", node_id));
+        }
+        let formula = node_def.value.clone();
+
+        let mut next_id = self.nodes.iter().map(|x| x.node_id).max().unwrap_or(0);
+        let (rewritten, parts) = split_top_level(&formula, &mut || {
+            next_id += 1;
+            next_id
+        })?;
+
+        let old_inputs = input_ids(&build_operator_tree(&formula)?);
+        let new_inputs = input_ids(&build_operator_tree(&rewritten)?);
+        self.edges.retain(|x| {
+            x.node_id != node_id
+                || !old_inputs.contains(&x.input_id)
+                || new_inputs.contains(&x.input_id)
+        });
+        self.set_value(node_id, rewritten)?;
+
+        let mut new_ids = Vec::new();
+        for (part_id, part) in &parts {
+            self.add_node(NodeDefinition {
+                node_id: *part_id,
+                kind: 1,
+                value: part.clone(),
+                default: None,
+            })?;
+            for input_id in input_ids(&build_operator_tree(part)?) {
+                self.connect(*part_id, input_id)?;
+            }
+            self.connect(node_id, *part_id)?;
+            new_ids.push(*part_id);
+        }
+
+        for (part_id, part) in &parts {
+            let metrics = FormulaMetrics::from_formula(&build_operator_tree(part)?);
+            if metrics.operator_count > max_operators {
+                new_ids.extend(self.split_formula(*part_id, max_operators)?);
+            }
+        }
+
+        Ok(new_ids)
+    }
+
+    pub fn inline(&mut self, node_id: NodeId) -> Result<()> {
+        let node_def = self
+            .nodes
+            .iter()
+            .find(|x| x.node_id == node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))?;
+        if node_def.kind != 1 {
+            return Err(anyhow!("node with id {} is not a formula", node_id));
+        }
+        let formula = node_def.value.clone();
+
+        let inputs: Vec<NodeId> = self
+            .edges
+            .iter()
+            .filter(|x| x.node_id == node_id)
+            .map(|x| x.input_id)
+            .collect();
+        let parents: Vec<NodeId> = self
+            .edges
+            .iter()
+            .filter(|x| x.input_id == node_id)
+            .map(|x| x.node_id)
+            .collect();
+
+        for parent_id in parents {
+            let parent = self
+                .nodes
+                .iter_mut()
+                .find(|x| x.node_id == parent_id)
+                .ok_or(anyhow!("no node with id {}", parent_id))?;
+            if parent.kind != 1 {
+                return Err(anyhow!(
+                    "cannot inline into node with id {}, it is not a formula",
+                    parent_id
+                ));
+            }
+            parent.value = substitute_input(&parent.value, node_id, &formula)?;
+
+            for input_id in &inputs {
+                let is_connected = self
+                    .edges
+                    .iter()
+                    .any(|x| x.node_id == parent_id && x.input_id == *input_id);
+                if !is_connected {
+                    self.connect(parent_id, *input_id)?;
+                }
+            }
+        }
+
+        self.remove_node(node_id)?;
+        Ok(())
+    }
+
+    // Parameters and conditions only take effect in `instantiate`, freezing would drop them
+    pub fn freeze(self) -> Result<Tree> {
+        let registry = self.registry.clone();
+        self.freeze_with_registry(registry)
+    }
+
+    pub fn freeze_with_registry(self, registry: NodeRegistry) -> Result<Tree> {
+        if !self.parameters.is_empty() || !self.conditions.is_empty() {
+            return Err(anyhow!(
+                "the draft has parameters or conditions, it has to be instantiated"
+            ));
+        }
+        Tree::new_with_registry(self.nodes, self.edges, registry)
+    }
+
+    pub fn node_definitions(&self) -> &[NodeDefinition] {
+        &self.nodes
+    }
+
+    pub fn edge_definitions(&self) -> &[EdgeDefinition] {
+        &self.edges
+    }
+
+    pub fn condition(&self, node_id: NodeId) -> Option<&str> {
+        self.conditions.get(&node_id).map(|x| x.as_str())
+    }
+
+    pub fn owner(&self, node_id: NodeId) -> Option<&str> {
+        self.owners.get(&node_id).map(|x| x.as_str())
+    }
+
+    // The version of the stored node the draft was read at
+    #[cfg(feature = "sqlite-blocking")]
+    pub fn version(&self, node_id: NodeId) -> Option<u64> {
+        self.read.get(&node_id).map(|x| x.version)
+    }
+
+    #[cfg(feature = "sqlite-blocking")]
+    pub(crate) fn read_node(&self, node_id: NodeId) -> Option<&crate::blocking::StoredNode> {
+        self.read.get(&node_id)
+    }
+
+    #[cfg(feature = "sqlite-blocking")]
+    pub(crate) fn set_read_node(&mut self, node: crate::blocking::StoredNode) {
+        self.read.insert(node.node_def.node_id, node);
+    }
+
+    pub(crate) fn set_owner(&mut self, node_id: NodeId, role: Option<&str>) -> Result<()> {
+        if !self.has_node(node_id) {
+            return Err(anyhow!("no node with id {}", node_id));
+        }
+        match role {
+            Some(role) => self.owners.insert(node_id, role.to_string()),
+            None => self.owners.remove(&node_id),
+        };
+        Ok(())
+    }
+
+    // Puts a node back where it was, see `History`
+    pub(crate) fn insert_node(&mut self, idx: usize, node_def: NodeDefinition) -> Result<()> {
+        if self.has_node(node_def.node_id) {
+            return Err(anyhow!("node with id {} already exists", node_def.node_id));
+        }
+        if idx > self.nodes.len() {
+            return Err(anyhow!("no node position {}", idx));
+        }
+        self.nodes.insert(idx, node_def);
+        Ok(())
+    }
+
+    pub(crate) fn insert_edge(&mut self, idx: usize, edge_def: EdgeDefinition) -> Result<()> {
+        if !self.has_node(edge_def.node_id) || !self.has_node(edge_def.input_id) {
+            return Err(anyhow!(
+                "no edge from {} to {} possible",
+                edge_def.input_id,
+                edge_def.node_id
+            ));
+        }
+        if idx > self.edges.len() {
+            return Err(anyhow!("no edge position {}", idx));
+        }
+        self.edges.insert(idx, edge_def);
+        Ok(())
+    }
+
+    pub(crate) fn remove_edge(&mut self, idx: usize, edge_def: &EdgeDefinition) -> Result<()> {
+        if self.edges.get(idx) != Some(edge_def) {
+            return Err(anyhow!(
+                "no edge from {} to {} at position {}",
+                edge_def.input_id,
+                edge_def.node_id,
+                idx
+            ));
+        }
+        self.edges.remove(idx);
+        Ok(())
+    }
+
+    pub(crate) fn has_node(&self, node_id: NodeId) -> bool {
+        self.nodes.iter().any(|x| x.node_id == node_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree() {
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 0,
+                input_id: 3,
+            },
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 4,
+            },
+        ];
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 3,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 4,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 0,
+                kind: 1,
+                value: "a + 1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "b * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 + $1".into(),
+                default: None,
+            },
+        ];
+
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let inputs = tree.node_inputs(2).unwrap();
+        assert_eq!(inputs, vec!["a", "b"]);
+        //let outputs = tree.node_ouputs(1);
+    }
+
+    #[test]
+    fn test_tree_formula_metrics() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "max($0, $1) + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+
+        // Variables have no metrics
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let metrics = tree.formula_metrics();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[&1].input_count, 1);
+        assert_eq!(metrics[&2].input_count, 2);
+        assert!(metrics[&2].operator_count > metrics[&1].operator_count);
+    }
+
+    #[test]
+    fn test_tree_draft() {
+        let mut draft = TreeDraft::new();
+        draft
+            .add_node(NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            })
+            .unwrap();
+        draft
+            .add_node(NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            })
+            .unwrap();
+        draft.connect(1, 0).unwrap();
+        assert!(draft.connect(1, 5).is_err());
+
+        let tree = draft.freeze().unwrap();
+        assert_eq!(tree.node_inputs(1).unwrap(), vec!["a"]);
+
+        let mut draft = tree.to_draft();
+        draft.set_value(1, "($0 * 2".into()).unwrap();
+        assert!(draft.clone().freeze().is_err());
+
+        draft.remove_node(0).unwrap();
+        draft.set_value(1, "2".into()).unwrap();
+        let tree = draft.freeze().unwrap();
+        assert_eq!(tree.node_inputs(1).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_split_formula() {
+        let mut draft = TreeDraft::new();
+        for (node_id, value) in [(0, "a"), (1, "b")] {
+            draft
+                .add_node(NodeDefinition {
+                    node_id,
+                    kind: 0,
+                    value: value.into(),
+                    default: None,
+                })
+                .unwrap();
+        }
+        draft
+            .add_node(NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "($0 + $1) * ($0 - 2) + max($1, 3 * $0 / $1)".into(),
+                default: None,
+            })
+            .unwrap();
+        draft.connect(2, 0).unwrap();
+        draft.connect(2, 1).unwrap();
+
+        let values = HashMap::from([
+            (0, NodeOutput::Number(2.)),
+            (1, NodeOutput::NumberArray(vec![3., 0.5])),
+        ]);
+        let expected = draft.clone().freeze().unwrap().eval(2, &values).unwrap();
+
+        let new_ids = draft.split_formula(2, 1).unwrap();
+        assert_eq!(new_ids, vec![3, 4, 5, 6, 7, 8]);
+
+        let tree = draft.freeze().unwrap();
+        assert_eq!(tree.eval(2, &values).unwrap(), expected);
+        assert!(tree
+            .formula_metrics()
+            .values()
+            .all(|x| x.operator_count <= 1));
+    }
+
+    #[test]
+    fn test_inline() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 + 1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 * $1 + max($2, 1.0)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+        ];
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![-4., 2.])),
+            (1, NodeOutput::Number(3.)),
+        ]);
+
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let inlined = tree.inline(2).unwrap();
+
+        assert_eq!(
+            inlined.eval(3, &values).unwrap(),
+            tree.eval(3, &values).unwrap()
+        );
+        assert!(inlined.eval(2, &values).is_err());
+        assert_eq!(inlined.node_inputs(3).unwrap(), vec!["b", "a"]);
+        assert_eq!(
+            inlined.node_definitions.last().unwrap().value,
+            "($0 + 1) * $1 + max($0 + 1, 1.0)"
+        );
+        assert!(tree.inline(0).is_err());
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(1.))]);
+
+        let mut cache = EvalCache::new();
+        cache.set_ttl(1, Duration::ZERO);
+        tree.eval_cached(2, &values, &mut cache).unwrap();
+        tree.eval_cached(2, &values, &mut cache).unwrap();
+        assert_eq!(cache.stats, CacheStats { hits: 1, misses: 5 });
+
+        cache.set_ttl(1, Duration::from_secs(3600));
+        tree.eval_cached(2, &values, &mut cache).unwrap();
+        assert_eq!(cache.stats, CacheStats { hits: 2, misses: 5 });
+
+        cache.invalidate_node(&tree, 1);
+        tree.eval_cached(2, &values, &mut cache).unwrap();
+        assert_eq!(cache.stats, CacheStats { hits: 3, misses: 7 });
+    }
+
+    #[test]
+    fn test_tree_function_set() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "ROUND($0 * 2, 1)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1.025, 2.]))]);
+
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        assert!(tree.eval(1, &values).is_err());
+
+        let tree = tree.with_function_set(FunctionSet::Excel);
+        assert_eq!(
+            tree.eval(1, &values).unwrap(),
+            NodeOutput::NumberArray(vec![2.1, 4.])
+        );
+    }
+
+    #[test]
+    fn test_parameters() {
+        let mut draft = TreeDraft::new();
+        draft
+            .add_node(NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "velocity".into(),
+                default: None,
+            })
+            .unwrap();
+        draft
+            .add_node(NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "diameter ^ 2 * 3.0 / 4 * $0".into(),
+                default: None,
+            })
+            .unwrap();
+        draft.connect(1, 0).unwrap();
+        draft.add_parameter("diameter", 2.).unwrap();
+        assert!(draft.add_parameter("diameter", 1.).is_err());
+        assert!(draft.add_parameter("$0", 1.).is_err());
+
+        let values = HashMap::from([(0, NodeOutput::Number(2.))]);
+        let tree = draft.instantiate(&HashMap::new()).unwrap();
+        assert_eq!(tree.node_definitions()[1].value, "3.0 * $0");
+        assert_eq!(tree.eval(1, &values).unwrap(), NodeOutput::Number(6.));
+
+        let parameters = HashMap::from([("diameter".to_string(), 4.)]);
+        let tree = draft.instantiate(&parameters).unwrap();
+        assert_eq!(tree.eval(1, &values).unwrap(), NodeOutput::Number(24.));
+
+        let parameters = HashMap::from([("length".to_string(), 4.)]);
+        assert!(draft.instantiate(&parameters).is_err());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut draft = TreeDraft::new();
+        for (node_id, kind, value) in [(0, 0, "a"), (1, 1, "$0 * 2"), (2, 1, "$1 + 1")] {
+            draft
+                .add_node(NodeDefinition {
+                    node_id,
+                    kind,
+                    value: value.into(),
+                    default: None,
+                })
+                .unwrap();
+        }
+        draft.connect(1, 0).unwrap();
+        draft.connect(2, 1).unwrap();
+        draft.add_parameter("size", 1.).unwrap();
+        draft.set_condition(1, "size > 2").unwrap();
+        assert!(draft.set_condition(5, "true").is_err());
+
+        let tree = draft.instantiate(&HashMap::new()).unwrap();
+        assert!(tree.node(1).is_err());
+        assert!(tree.node(2).is_err());
+        assert!(tree.node(0).is_ok());
+
+        let parameters = HashMap::from([("size".to_string(), 3.)]);
+        let tree = draft.instantiate(&parameters).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(2.))]);
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(5.));
+    }
+
+    #[test]
+    fn test_eval_order() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 + $0 + $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let order = tree.eval_order(3).unwrap();
+        assert_eq!(&*order, &[0, 2, 1, 3]);
+        assert!(Arc::ptr_eq(&order, &tree.eval_order(3).unwrap()));
+        assert_eq!(&*tree.leaves(3).unwrap(), &[0, 1]);
+        assert_eq!(&*tree.leaves(2).unwrap(), &[0]);
+        assert!(tree.eval_order(9).is_err());
+    }
+
+    #[test]
+    fn test_parallel_threshold() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let values = HashMap::from([
+            (
+                0,
+                NodeOutput::NumberArray((0..1001).map(|x| x as f64).collect()),
+            ),
+            (1, NodeOutput::Number(2.)),
+        ]);
+
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let expected = tree.eval(2, &values).unwrap();
+        let tree = tree.with_parallel_threshold(10);
+        assert_eq!(tree.eval(2, &values).unwrap(), expected);
+        assert_eq!(
+            expected,
+            NodeOutput::NumberArray((0..1001).map(|x| x as f64 * 2. + 1.).collect())
+        );
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1.; 100]))]);
+
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let (output, stats) = tree.eval_with_memory(2, &values).unwrap();
+        assert_eq!(output, NodeOutput::NumberArray(vec![3.; 100]));
+        assert_eq!(
+            stats,
+            MemoryStats {
+                current: 800,
+                peak: 1600
+            }
+        );
+
+        let tree = tree.with_memory_limit(1600);
+        assert!(tree.eval(2, &values).is_ok());
+        let tree = tree.with_memory_limit(1599);
+        assert!(tree.eval(2, &values).is_err());
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "if($0 + 0.2 == 0.3, 1.0, 0.0) + if($0 != 0.1, 2.0, 0.0)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![0.1, 0.1 + 1e-12, 0.2]))]);
+        assert_eq!(
+            tree.eval(1, &values).unwrap(),
+            NodeOutput::NumberArray(vec![0., 2., 2.])
+        );
+
+        let tolerance = Tolerance {
+            absolute: 1e-9,
+            relative: 0.,
+        };
+        let tree = tree.with_tolerance(tolerance).unwrap();
+        assert_eq!(tree.tolerance(), Some(tolerance));
+        assert_eq!(
+            tree.eval(1, &values).unwrap(),
+            NodeOutput::NumberArray(vec![1., 1., 2.])
+        );
+        assert_eq!(
+            tree.node_definitions()[1].value,
+            "if($0 + 0.2 == 0.3, 1.0, 0.0) + if($0 != 0.1, 2.0, 0.0)"
+        );
+        assert_eq!(tree.compile(1).unwrap().eval(&[0.1]).unwrap(), 1.);
+    }
+
+    #[test]
+    fn test_eval_deep() {
+        let depth = 20_000;
+        let mut node_defs = vec![NodeDefinition {
+            node_id: 0,
+            kind: 0,
+            value: "a".into(),
+            default: None,
+        }];
+        let mut edge_defs = Vec::new();
+        for node_id in 1..=depth {
+            node_defs.push(NodeDefinition {
+                node_id,
+                kind: 1,
+                value: format!("${} + 1", node_id - 1),
+                default: None,
+            });
+            edge_defs.push(EdgeDefinition {
+                node_id,
+                input_id: node_id - 1,
+            });
+        }
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(0.))]);
+        assert_eq!(
+            tree.eval(depth, &values).unwrap(),
+            NodeOutput::Number(depth as f64)
+        );
+        let outputs = tree.eval_many(&[depth, depth / 2], &values).unwrap();
+        assert_eq!(outputs[&depth], NodeOutput::Number(depth as f64));
+        assert_eq!(
+            outputs[&(depth / 2)],
+            NodeOutput::Number((depth / 2) as f64)
+        );
+
+        let outputs = tree.eval_all(depth, &values).unwrap();
+        assert_eq!(outputs.len(), depth + 1);
+        assert_eq!(outputs[&depth], NodeOutput::Number(depth as f64));
+    }
+
+    #[test]
+    fn test_eval_parallel() {
+        let mut node_defs = vec![NodeDefinition {
+            node_id: 0,
+            kind: 0,
+            value: "a".into(),
+            default: None,
+        }];
+        let mut edge_defs = Vec::new();
+        let mut sum = Vec::new();
+        for node_id in 1..=16 {
+            node_defs.push(NodeDefinition {
+                node_id,
+                kind: 1,
+                value: format!("$0 * {}", node_id),
+                default: None,
+            });
+            edge_defs.push(EdgeDefinition {
+                node_id,
+                input_id: 0,
+            });
+            edge_defs.push(EdgeDefinition {
+                node_id: 17,
+                input_id: node_id,
+            });
+            sum.push(format!("${}", node_id));
+        }
+        node_defs.push(NodeDefinition {
+            node_id: 17,
+            kind: 1,
+            value: sum.join(" + "),
+            default: None,
+        });
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2.]))]);
+        let output = tree.eval_parallel(17, &values).unwrap();
+        assert_eq!(output, NodeOutput::NumberArray(vec![136., 272.]));
+        assert_eq!(output, tree.eval(17, &values).unwrap());
+        let values = HashMap::from([(0, NodeOutput::Integer(1))]);
+        assert_eq!(
+            tree.eval_parallel(17, &values).unwrap(),
+            NodeOutput::Integer(136)
+        );
+        assert!(tree.eval_parallel(17, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_eval_with_vars() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "width".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "height".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let mut variables = HashMap::from([
+            ("width".to_string(), NodeOutput::Number(2.)),
+            ("height".to_string(), NodeOutput::NumberArray(vec![1., 3.])),
+        ]);
+        assert_eq!(
+            tree.eval_with_vars(2, &variables).unwrap(),
+            NodeOutput::NumberArray(vec![2., 6.])
+        );
+        variables.insert("depth".into(), NodeOutput::Number(1.));
+        assert!(tree.eval_with_vars(2, &variables).is_err());
+        variables.clear();
+        variables.insert("width".into(), NodeOutput::Number(1.));
+        assert!(tree.eval_with_vars(2, &variables).is_err());
+    }
+
+    #[test]
+    fn test_variable_default() {
+        let mut node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: Some(NodeOutput::Number(2.)),
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs.clone(), edge_defs.clone()).unwrap();
+
+        let values = HashMap::from([(1, NodeOutput::Number(3.))]);
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(6.));
+        let values = HashMap::from([(0, NodeOutput::Number(1.)), (1, NodeOutput::Number(3.))]);
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(3.));
+        assert!(tree.eval(2, &HashMap::new()).is_err());
+
+        node_defs[2].default = Some(NodeOutput::Number(1.));
+        assert!(Tree::new(node_defs, edge_defs).is_err());
+    }
+
+    #[test]
+    fn test_bool_string_outputs() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 > $1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "if($2, $0, 0.0)".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 4,
+                kind: 0,
+                value: "unit".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 5,
+                kind: 1,
+                value: "if($4 == \"kW\", $1 * 1000, $1)".into(),
+                default: None,
+            },
+        ];
+        let mut edge_defs = Vec::new();
+        for (node_id, input_id) in [(2, 0), (2, 1), (3, 2), (3, 0), (5, 4), (5, 1)] {
+            edge_defs.push(EdgeDefinition { node_id, input_id });
+        }
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![1., 3.])),
+            (1, NodeOutput::Integer(2)),
+            (4, NodeOutput::String("kW".into())),
+        ]);
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::BoolArray(vec![false, true])
+        );
+        assert_eq!(
+            tree.eval(3, &values).unwrap(),
+            NodeOutput::NumberArray(vec![0., 3.])
+        );
+        assert_eq!(tree.eval(5, &values).unwrap(), NodeOutput::Integer(2000));
+
+        let values = HashMap::from([
+            (1, NodeOutput::Number(2.)),
+            (4, NodeOutput::StringArray(vec!["W".into(), "kW".into()])),
+        ]);
+        assert_eq!(
+            tree.eval(5, &values).unwrap(),
+            NodeOutput::NumberArray(vec![2., 2000.])
+        );
+    }
+
+    #[test]
+    fn test_matrix() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * 2 + $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
             },
-            EdgeDefinition {
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let matrix = NodeOutput::Matrix(vec![vec![1., 2., 3.], vec![4., 5., 6.]]);
+
+        let mut values = HashMap::from([(0, matrix.clone()), (1, NodeOutput::Number(1.))]);
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::Matrix(vec![vec![3., 5., 7.], vec![9., 11., 13.]])
+        );
+        // Arrays are added to every row
+        values.insert(1, NodeOutput::NumberArray(vec![0., 10., 20.]));
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::Matrix(vec![vec![2., 14., 26.], vec![8., 20., 32.]])
+        );
+        values.insert(1, matrix.clone());
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::Matrix(vec![vec![3., 6., 9.], vec![12., 15., 18.]])
+        );
+
+        values.insert(1, NodeOutput::NumberArray(vec![1., 2.]));
+        assert!(tree.eval(2, &values).is_err());
+        values.insert(1, NodeOutput::Matrix(vec![vec![1., 2.], vec![3., 4.]]));
+        assert!(tree.eval(2, &values).is_err());
+        values.insert(1, NodeOutput::Matrix(vec![vec![1., 2.], vec![3.]]));
+        assert!(tree.eval(2, &values).is_err());
+
+        let output: NodeOutput = serde_json::from_str(r#"{"Matrix": [[1.0, null]]}"#).unwrap();
+        assert!(matches!(&output, NodeOutput::Matrix(v) if v[0][1].is_nan()));
+    }
+
+    #[test]
+    fn test_series() {
+        let node_defs = vec![
+            NodeDefinition {
                 node_id: 0,
-                input_id: 3,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
             },
             EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let price = NodeOutput::Series {
+            name: "price".into(),
+            values: vec![1., 2.],
+        };
+
+        let mut values = HashMap::from([(0, price.clone()), (1, NodeOutput::Number(3.))]);
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::Series {
+                name: "price".into(),
+                values: vec![3., 6.],
+            }
+        );
+        values.insert(1, price.clone());
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::Series {
+                name: "price".into(),
+                values: vec![1., 4.],
+            }
+        );
+        // Differently labeled inputs lose their labels
+        values.insert(
+            1,
+            NodeOutput::Series {
+                name: "amount".into(),
+                values: vec![2., 2.],
+            },
+        );
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::NumberArray(vec![2., 4.])
+        );
+
+        let output: NodeOutput =
+            serde_json::from_str(r#"{"Series": {"name": "price", "values": [1.0, null]}}"#)
+                .unwrap();
+        assert!(
+            matches!(&output, NodeOutput::Series { name, values } if name == "price" && values[1].is_nan())
+        );
+    }
+
+    #[test]
+    fn test_time_series() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
                 node_id: 1,
-                input_id: 4,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 + $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
             },
         ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([
+            (0, NodeOutput::TimeSeries(vec![(1, 1.), (2, 2.), (4, 4.)])),
+            (1, NodeOutput::TimeSeries(vec![(2, 10.), (3, 30.)])),
+        ]);
+
+        let NodeOutput::TimeSeries(output) = tree.eval(2, &values).unwrap() else {
+            panic!("expected a time series");
+        };
+        assert_eq!(
+            output.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(output[1].1, 12.);
+        assert!(output[0].1.is_nan() && output[2].1.is_nan() && output[3].1.is_nan());
+
+        let eval = |policy| {
+            let tree = tree.clone().with_missing_policy(&[2], policy).unwrap();
+            tree.eval(2, &values).unwrap()
+        };
+        assert_eq!(
+            eval(MissingPolicy::Skip),
+            NodeOutput::TimeSeries(vec![(2, 12.)])
+        );
+        assert_eq!(
+            eval(MissingPolicy::Substitute(0.)),
+            NodeOutput::TimeSeries(vec![(1, 1.), (2, 12.), (3, 30.), (4, 4.)])
+        );
+        let NodeOutput::TimeSeries(output) = eval(MissingPolicy::Previous) else {
+            panic!("expected a time series");
+        };
+        assert!(output[0].1.is_nan());
+        assert_eq!(&output[1..], &[(2, 12.), (3, 32.), (4, 34.)]);
+
+        let mut values = values.clone();
+        values.insert(1, NodeOutput::Number(1.));
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::TimeSeries(vec![(1, 2.), (2, 3.), (4, 5.)])
+        );
+        values.insert(1, NodeOutput::NumberArray(vec![1., 2., 3.]));
+        assert!(tree.eval(2, &values).is_err());
+        values.insert(1, NodeOutput::TimeSeries(vec![(2, 1.), (1, 1.)]));
+        assert!(tree.eval(2, &values).is_err());
+    }
+
+    #[test]
+    fn test_composed_expression() {
         let node_defs = vec![
             NodeDefinition {
-                node_id: 3,
+                node_id: 0,
                 kind: 0,
                 value: "a".into(),
+                default: None,
             },
             NodeDefinition {
-                node_id: 4,
+                node_id: 1,
                 kind: 0,
                 value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 + $1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "max($2, 1) * $2 - $0".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 0,
             },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let expression = tree.composed_expression(3).unwrap();
+        assert_eq!(expression, "max($0 + $1, 1) * ($0 + $1) - $0");
+        assert_eq!(tree.composed_expression(0).unwrap(), "$0");
+
+        // The composed expression gives the same result as the tree
+        let composed = Tree::new(
+            vec![
+                NodeDefinition {
+                    node_id: 0,
+                    kind: 0,
+                    value: "a".into(),
+                    default: None,
+                },
+                NodeDefinition {
+                    node_id: 1,
+                    kind: 0,
+                    value: "b".into(),
+                    default: None,
+                },
+                NodeDefinition {
+                    node_id: 2,
+                    kind: 1,
+                    value: expression,
+                    default: None,
+                },
+            ],
+            vec![
+                EdgeDefinition {
+                    node_id: 2,
+                    input_id: 0,
+                },
+                EdgeDefinition {
+                    node_id: 2,
+                    input_id: 1,
+                },
+            ],
+        )
+        .unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(2.)), (1, NodeOutput::Number(-3.))]);
+        assert_eq!(
+            tree.eval(3, &values).unwrap(),
+            composed.eval(2, &values).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_eval_many() {
+        let node_defs = vec![
             NodeDefinition {
                 node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
                 kind: 1,
-                value: "a + 1".into(),
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$1 - 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::Number(2.))]);
+        let outputs = tree.eval_many(&[2, 3], &values).unwrap();
+        assert_eq!(
+            outputs,
+            HashMap::from([(2, NodeOutput::Number(5.)), (3, NodeOutput::Number(3.))])
+        );
+        assert!(tree.eval_many(&[2, 4], &values).is_err());
+        assert!(tree.eval_many(&[], &values).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_shared_tree() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
             },
             NodeDefinition {
                 node_id: 1,
                 kind: 1,
-                value: "b * 2".into(),
+                value: "$0 * 2".into(),
+                default: None,
             },
             NodeDefinition {
                 node_id: 2,
                 kind: 1,
-                value: "$0 + $1".into(),
+                value: "$1 + $0".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+        ];
+        let tree = Arc::new(Tree::new(node_defs, edge_defs).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|x| {
+                let tree = Arc::clone(&tree);
+                thread::spawn(move || {
+                    let values = HashMap::from([(0, NodeOutput::Number(x as f64))]);
+                    tree.eval(2, &values).unwrap()
+                })
+            })
+            .collect();
+        for (x, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), NodeOutput::Number(x as f64 * 3.));
+        }
+        assert_eq!(&*tree.eval_order(2).unwrap(), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 + $3".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 - 1".into(),
+                default: None,
+            },
+        ];
+        let mut edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
             },
         ];
+        assert!(Tree::new(node_defs.clone(), edge_defs.clone()).is_ok());
 
+        edge_defs.push(EdgeDefinition {
+            node_id: 1,
+            input_id: 3,
+        });
+        let err = Tree::new(node_defs.clone(), edge_defs).unwrap_err();
+        assert_eq!(err.to_string(), "the edges form a cycle: 1 -> 3 -> 2 -> 1");
+
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 2,
+            input_id: 2,
+        }];
+        assert!(Tree::new(node_defs, edge_defs).is_err());
+    }
+
+    #[test]
+    fn test_integer_output() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "int($2 / 2.5)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+        ];
         let tree = Tree::new(node_defs, edge_defs).unwrap();
-        let inputs = tree.node_inputs(2).unwrap();
-        assert_eq!(inputs, vec!["a", "b"]);
-        //let outputs = tree.node_ouputs(1);
+
+        let values = HashMap::from([(0, NodeOutput::Integer(3)), (1, NodeOutput::Integer(4))]);
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Integer(12));
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Integer(4));
+
+        let values = HashMap::from([
+            (0, NodeOutput::Integer(i64::MAX)),
+            (1, NodeOutput::Integer(2)),
+        ]);
+        assert!(tree.eval(2, &values).is_err());
+
+        let values = HashMap::from([(0, NodeOutput::Integer(3)), (1, NodeOutput::Number(0.5))]);
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(1.5));
+
+        let values = HashMap::from([
+            (0, NodeOutput::Integer(3)),
+            (1, NodeOutput::NumberArray(vec![1., 2.])),
+        ]);
+        assert_eq!(
+            tree.eval(2, &values).unwrap(),
+            NodeOutput::NumberArray(vec![3., 6.])
+        );
     }
 
     // #[test]
@@ -0,0 +1,198 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::core::{EvalCache, Node, NodeId, NodeKind, NodeOutput, Tree};
+use crate::formula::conditions;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BranchCoverage {
+    pub node_id: NodeId,
+    pub condition: String,
+    // Number of elements the condition held or did not hold for
+    pub taken: usize,
+    pub not_taken: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Coverage {
+    // How often every node below the root was evaluated
+    pub evaluations: HashMap<NodeId, usize>,
+    pub branches: Vec<BranchCoverage>,
+    // Value sets that failed to evaluate, the nodes evaluated before the error still count
+    pub failures: usize,
+}
+
+impl Coverage {
+    pub fn uncovered_nodes(&self) -> Vec<NodeId> {
+        let mut node_ids: Vec<_> = self
+            .evaluations
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(id, _)| *id)
+            .collect();
+        node_ids.sort();
+        node_ids
+    }
+
+    // Conditions that did not go both ways
+    pub fn uncovered_branches(&self) -> Vec<&BranchCoverage> {
+        self.branches
+            .iter()
+            .filter(|x| x.taken == 0 || x.not_taken == 0)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.uncovered_nodes().is_empty() && self.uncovered_branches().is_empty()
+    }
+}
+
+impl Tree {
+    // Evaluates the node once per value set and records which nodes were evaluated and which way
+    // the `if` conditions of the formulas went. Like evalexpr, which evaluates all arguments of
+    // `if`, conditions in a branch that was not taken are counted as well.
+    pub fn coverage(
+        &self,
+        node_id: NodeId,
+        value_sets: &[HashMap<NodeId, NodeOutput>],
+    ) -> Result<Coverage> {
+        let mut coverage = Coverage::default();
+        let mut condition_nodes = Vec::new();
+        for id in self.eval_order(node_id)?.iter() {
+            coverage.evaluations.insert(*id, 0);
+
+            let node = self.node(*id)?;
+            let NodeKind::Formula(formula) = node.kind() else {
+                continue;
+            };
+            for condition in conditions(formula) {
+                // The condition is evaluated as a formula of its own on the inputs of the node
+                let condition_node =
+                    Node::from_formula(*id, &format!("if({}, 1.0, 0.0)", condition))?
+                        .with_inputs(node.inputs.clone());
+                condition_nodes.push((coverage.branches.len(), condition_node));
+                coverage.branches.push(BranchCoverage {
+                    node_id: *id,
+                    condition,
+                    taken: 0,
+                    not_taken: 0,
+                });
+            }
+        }
+
+        for values in value_sets {
+            let mut cache = EvalCache::new();
+            if self.eval_cached(node_id, values, &mut cache).is_err() {
+                coverage.failures += 1;
+            }
+            for (id, count) in coverage.evaluations.iter_mut() {
+                if cache.get(*id).is_some() {
+                    *count += 1;
+                }
+            }
+
+            for (idx, condition_node) in &condition_nodes {
+                let inputs: Option<Vec<_>> = condition_node
+                    .inputs
+                    .iter()
+                    .map(|x| cache.get(*x).cloned())
+                    .collect();
+                let Some(inputs) = inputs else {
+                    continue;
+                };
+                let branch = &mut coverage.branches[*idx];
+                let output = match self.apply(condition_node, inputs)? {
+                    NodeOutput::Matrix(v) => NodeOutput::NumberArray(v.concat()),
+                    NodeOutput::Series { values, .. } => NodeOutput::NumberArray(values),
+                    NodeOutput::TimeSeries(v) => {
+                        NodeOutput::NumberArray(v.into_iter().map(|(_, x)| x).collect())
+                    }
+                    output => output,
+                };
+                match output {
+                    NodeOutput::NumberArray(v) => {
+                        let taken = v.iter().filter(|x| **x != 0.).count();
+                        branch.taken += taken;
+                        branch.not_taken += v.len() - taken;
+                    }
+                    NodeOutput::Number(v) if v != 0. => branch.taken += 1,
+                    NodeOutput::Integer(v) if v != 0 => branch.taken += 1,
+                    _ => branch.not_taken += 1,
+                }
+            }
+        }
+        Ok(coverage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_coverage() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "if($0 > 1, $1, 0.0)".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "if($2 < 0, 0.0, $2) + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let value_sets = vec![
+            HashMap::from([
+                (0, NodeOutput::NumberArray(vec![0., 2., 3.])),
+                (1, NodeOutput::Number(5.)),
+            ]),
+            HashMap::from([(0, NodeOutput::Number(1.))]),
+        ];
+        let coverage = tree.coverage(3, &value_sets).unwrap();
+        assert_eq!(coverage.failures, 1);
+        assert_eq!(coverage.evaluations[&0], 2);
+        assert_eq!(coverage.evaluations[&3], 1);
+        assert_eq!(coverage.branches.len(), 2);
+        assert_eq!(
+            (coverage.branches[0].taken, coverage.branches[0].not_taken),
+            (2, 1)
+        );
+        assert_eq!(coverage.uncovered_branches()[0].condition, "$2 < 0");
+        assert!(coverage.uncovered_nodes().is_empty());
+        assert!(!coverage.is_complete());
+    }
+}
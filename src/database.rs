@@ -1,8 +1,9 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::executor;
+use sha2::{Digest, Sha256};
 use sqlx::Row;
 use sqlx::{Connection, SqliteConnection};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use crate::core::{EdgeDefinition, NodeDefinition};
 
@@ -42,9 +43,10 @@ pub fn defintions_from_sqlite(
         node_ids.insert(input_id);
         edge_definitions.push(EdgeDefinition {
             node_id: node_id as usize,
-            input_id: node_id as usize,
+            input_id: input_id as usize,
         });
     }
+    node_ids.insert(root_node_id as i32);
 
     let placeholders = "?,".repeat(node_ids.len()).trim_matches(',').to_string() + ")";
 
@@ -66,27 +68,263 @@ pub fn defintions_from_sqlite(
     for row in &node_query {
         let node_id: i32 = row.try_get("node_id")?;
         let kind: i32 = row.try_get("type")?;
+        // Tables from before the column was added have no defaults
+        let default: Option<String> = match row.try_get("default_value") {
+            Ok(default) => default,
+            Err(sqlx::Error::ColumnNotFound(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
         let node_def = NodeDefinition {
             node_id: node_id as usize,
             kind: kind as usize,
             value: row.try_get("operation")?,
+            default: match default {
+                Some(default) => Some(serde_json::from_str(&default)?),
+                None => None,
+            },
         };
         nodes_definitions.push(node_def);
     }
 
-    return Ok((nodes_definitions, edge_definitions));
+    Ok((nodes_definitions, edge_definitions))
+}
+
+pub fn definitions_from_sqlite_verified(
+    file_name: String,
+    root_node_id: usize,
+) -> Result<(Vec<NodeDefinition>, Vec<EdgeDefinition>)> {
+    verify_checksums(file_name.clone())?;
+    defintions_from_sqlite(file_name, root_node_id)
+}
+
+// Checksums are hex encoded SHA-256 hashes
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn node_checksum(node_id: usize, kind: usize, value: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update((node_id as u64).to_le_bytes());
+    hasher.update((kind as u64).to_le_bytes());
+    hasher.update(value.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+fn graph_checksum(node_checksums: &HashMap<usize, String>, edges: &EdgeSnapshot) -> String {
+    let mut node_ids: Vec<_> = node_checksums.keys().collect();
+    node_ids.sort();
+    let mut edges: Vec<_> = edges.iter().collect();
+    edges.sort();
+
+    let mut hasher = Sha256::new();
+    for node_id in node_ids {
+        hasher.update(node_checksums[node_id].as_bytes());
+    }
+    for (node_id, input_id) in edges {
+        hasher.update((*node_id as u64).to_le_bytes());
+        hasher.update((*input_id as u64).to_le_bytes());
+    }
+    to_hex(&hasher.finalize())
+}
+
+fn read_edges(conn: &mut SqliteConnection) -> Result<EdgeSnapshot> {
+    let edge_query = executor::block_on(
+        sqlx::query("SELECT node_id, input_id FROM edge").fetch_all(&mut *conn),
+    )?;
+    let mut edges = HashSet::new();
+    for row in &edge_query {
+        let node_id: i32 = row.try_get("node_id")?;
+        let input_id: i32 = row.try_get("input_id")?;
+        edges.insert((node_id as usize, input_id as usize));
+    }
+    Ok(edges)
+}
+
+pub fn write_checksums(file_name: String) -> Result<()> {
+    let mut conn = executor::block_on(SqliteConnection::connect(&file_name))?;
+
+    let has_column: i32 = executor::block_on(
+        sqlx::query("SELECT COUNT(*) FROM pragma_table_info('node') WHERE name = 'checksum'")
+            .fetch_one(&mut conn),
+    )?
+    .try_get(0)?;
+    if has_column == 0 {
+        executor::block_on(
+            sqlx::query("ALTER TABLE node ADD COLUMN checksum TEXT").execute(&mut conn),
+        )?;
+    }
+    executor::block_on(
+        sqlx::query("CREATE TABLE IF NOT EXISTS graph_checksum (checksum TEXT NOT NULL)")
+            .execute(&mut conn),
+    )?;
+
+    let mut tx = executor::block_on(conn.begin())?;
+    let node_query = executor::block_on(
+        sqlx::query("SELECT node_id, type, operation FROM node").fetch_all(&mut *tx),
+    )?;
+    let mut node_checksums = HashMap::new();
+    for row in &node_query {
+        let node_id: i32 = row.try_get("node_id")?;
+        let kind: i32 = row.try_get("type")?;
+        let operation: String = row.try_get("operation")?;
+        let checksum = node_checksum(node_id as usize, kind as usize, &operation);
+
+        executor::block_on(
+            sqlx::query("UPDATE node SET checksum = ? WHERE node_id = ?")
+                .bind(&checksum)
+                .bind(node_id)
+                .execute(&mut *tx),
+        )?;
+        node_checksums.insert(node_id as usize, checksum);
+    }
+
+    let edges = read_edges(&mut tx)?;
+    let checksum = graph_checksum(&node_checksums, &edges);
+    executor::block_on(sqlx::query("DELETE FROM graph_checksum").execute(&mut *tx))?;
+    executor::block_on(
+        sqlx::query("INSERT INTO graph_checksum (checksum) VALUES (?)")
+            .bind(checksum)
+            .execute(&mut *tx),
+    )?;
+
+    executor::block_on(tx.commit())?;
+    Ok(())
+}
+
+pub fn verify_checksums(file_name: String) -> Result<()> {
+    let mut conn = executor::block_on(SqliteConnection::connect(&file_name))?;
+
+    let node_query = executor::block_on(
+        sqlx::query("SELECT node_id, type, operation, checksum FROM node").fetch_all(&mut conn),
+    )?;
+    let mut node_checksums = HashMap::new();
+    let mut mismatches = Vec::new();
+    for row in &node_query {
+        let node_id: i32 = row.try_get("node_id")?;
+        let kind: i32 = row.try_get("type")?;
+        let operation: String = row.try_get("operation")?;
+        let stored: Option<String> = row.try_get("checksum")?;
+        let checksum = node_checksum(node_id as usize, kind as usize, &operation);
+        if stored.as_ref() != Some(&checksum) {
+            mismatches.push(node_id as usize);
+        }
+        node_checksums.insert(node_id as usize, checksum);
+    }
+
+    if !mismatches.is_empty() {
+        mismatches.sort();
+        return Err(anyhow!(
+            "checksum mismatch for nodes with ids {:?}",
+            mismatches
+        ));
+    }
+
+    let edges = read_edges(&mut conn)?;
+    let stored: Option<String> = executor::block_on(
+        sqlx::query("SELECT checksum FROM graph_checksum").fetch_optional(&mut conn),
+    )?
+    .map(|row| row.try_get(0))
+    .transpose()?;
+    if stored != Some(graph_checksum(&node_checksums, &edges)) {
+        return Err(anyhow!("graph checksum mismatch"));
+    }
+
+    Ok(())
+}
+
+type NodeSnapshot = HashMap<usize, (usize, String)>;
+type EdgeSnapshot = HashSet<(usize, usize)>;
+
+pub struct DefinitionWatcher {
+    conn: SqliteConnection,
+    data_version: i64,
+    nodes: NodeSnapshot,
+    edges: EdgeSnapshot,
+}
+
+impl DefinitionWatcher {
+    pub fn new(file_name: String) -> Result<Self> {
+        let conn = executor::block_on(SqliteConnection::connect(&file_name))?;
+        let mut watcher = Self {
+            conn,
+            data_version: 0,
+            nodes: HashMap::new(),
+            edges: HashSet::new(),
+        };
+        watcher.data_version = watcher.read_data_version()?;
+        (watcher.nodes, watcher.edges) = watcher.read_snapshot()?;
+
+        Ok(watcher)
+    }
+
+    /// Returns the ids of all nodes whose definition or inputs changed since the last poll.
+    pub fn poll(&mut self) -> Result<Vec<usize>> {
+        // `data_version` only changes when another connection commits to the file
+        let data_version = self.read_data_version()?;
+        if data_version == self.data_version {
+            return Ok(Vec::new());
+        }
+        self.data_version = data_version;
+
+        let (nodes, edges) = self.read_snapshot()?;
+
+        let mut changed = HashSet::new();
+        for (node_id, def) in &nodes {
+            if self.nodes.get(node_id) != Some(def) {
+                changed.insert(*node_id);
+            }
+        }
+        for node_id in self.nodes.keys() {
+            if !nodes.contains_key(node_id) {
+                changed.insert(*node_id);
+            }
+        }
+        for (node_id, _) in edges.symmetric_difference(&self.edges) {
+            changed.insert(*node_id);
+        }
+
+        self.nodes = nodes;
+        self.edges = edges;
+
+        let mut changed: Vec<usize> = changed.into_iter().collect();
+        changed.sort();
+        Ok(changed)
+    }
+
+    fn read_data_version(&mut self) -> Result<i64> {
+        let row = executor::block_on(sqlx::query("PRAGMA data_version").fetch_one(&mut self.conn))?;
+        Ok(row.try_get(0)?)
+    }
+
+    fn read_snapshot(&mut self) -> Result<(NodeSnapshot, EdgeSnapshot)> {
+        let node_query = executor::block_on(
+            sqlx::query("SELECT node_id, type, operation FROM node").fetch_all(&mut self.conn),
+        )?;
+        let mut nodes = HashMap::new();
+        for row in &node_query {
+            let node_id: i32 = row.try_get("node_id")?;
+            let kind: i32 = row.try_get("type")?;
+            let operation: String = row.try_get("operation")?;
+            nodes.insert(node_id as usize, (kind as usize, operation));
+        }
+
+        let edges = read_edges(&mut self.conn)?;
+
+        Ok((nodes, edges))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::NodeOutput;
     use futures::executor;
     use sqlx::sqlite::SqliteConnectOptions;
 
-    #[test]
-    fn test_definitions_from_sqlite() {
+    fn create_test_db(file_name: &str) -> SqliteConnection {
+        let _ = std::fs::remove_file(file_name);
         let options = SqliteConnectOptions::new()
-            .filename("_test_db.db")
+            .filename(file_name)
             .create_if_missing(true);
 
         let mut conn = executor::block_on(SqliteConnection::connect_with(&options)).unwrap();
@@ -98,6 +336,7 @@ mod tests {
                 "operation"	BLOB NOT NULL,
                 "name"	TEXT,
                 "symbol"	TEXT,
+                "default_value"	TEXT,
                 PRIMARY KEY("node_id" AUTOINCREMENT)
             );
 
@@ -111,11 +350,35 @@ mod tests {
             );
 
             INSERT INTO "main"."node"("node_id","type","operation","name","symbol") VALUES (1,0,'a + 2',NULL,NULL);
+            UPDATE "main"."node" SET "default_value" = '{"Number":1.5}' WHERE "node_id" = 1;
             INSERT INTO "main"."node"("node_id","type","operation","name","symbol") VALUES (2,1,'a * 2',NULL,NULL);
             INSERT INTO "main"."node"("node_id","type","operation","name","symbol") VALUES (3,2,'id0 + id1',NULL,NULL);
+            INSERT INTO "main"."edge"("edge_id","node_id","input_id") VALUES (1,3,1);
+            INSERT INTO "main"."edge"("edge_id","node_id","input_id") VALUES (2,3,2);
         "#).execute(&mut conn)).unwrap();
 
-        let (node_defs, edge_defs) = defintions_from_sqlite("_test_db.db".into(), 3).unwrap();
+        conn
+    }
+
+    fn test_db_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_definitions_from_sqlite() {
+        let file_name = test_db_path("delphy_test_definitions.db");
+        create_test_db(&file_name);
+
+        let (node_defs, edge_defs) = defintions_from_sqlite(file_name, 3).unwrap();
+
+        assert_eq!(edge_defs.len(), 2);
+        assert!(edge_defs.contains(&EdgeDefinition {
+            node_id: 3,
+            input_id: 1
+        }));
 
         assert_eq!(node_defs.len(), 3);
         for def in node_defs {
@@ -123,17 +386,75 @@ mod tests {
                 1 => {
                     assert_eq!(def.kind, 0);
                     assert_eq!(def.value, "a + 2");
+                    assert_eq!(def.default, Some(NodeOutput::Number(1.5)));
                 }
                 2 => {
                     assert_eq!(def.kind, 1);
                     assert_eq!(def.value, "a * 2");
+                    assert_eq!(def.default, None);
                 }
                 3 => {
                     assert_eq!(def.kind, 2);
                     assert_eq!(def.value, "id0 + id1");
                 }
-                _ => assert!(false),
+                _ => unreachable!(),
             };
         }
     }
+
+    #[test]
+    fn test_definition_watcher() {
+        let file_name = test_db_path("delphy_test_watcher.db");
+        let mut conn = create_test_db(&file_name);
+
+        let mut watcher = DefinitionWatcher::new(file_name).unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+
+        executor::block_on(
+            sqlx::query(
+                "
+                UPDATE node SET operation = 'a * 3' WHERE node_id = 2;
+                INSERT INTO edge(node_id, input_id) VALUES (1, 2);
+                ",
+            )
+            .execute(&mut conn),
+        )
+        .unwrap();
+
+        assert_eq!(watcher.poll().unwrap(), vec![1, 2]);
+        assert!(watcher.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_checksums() {
+        let file_name = test_db_path("delphy_test_checksums.db");
+        let mut conn = create_test_db(&file_name);
+
+        assert!(definitions_from_sqlite_verified(file_name.clone(), 3).is_err());
+
+        write_checksums(file_name.clone()).unwrap();
+        let (node_defs, _) = definitions_from_sqlite_verified(file_name.clone(), 3).unwrap();
+        assert_eq!(node_defs.len(), 3);
+        let checksum: String = executor::block_on(
+            sqlx::query("SELECT checksum FROM node WHERE node_id = 2").fetch_one(&mut conn),
+        )
+        .unwrap()
+        .get(0);
+        assert_eq!(checksum, node_checksum(2, 1, "a * 2"));
+        assert_eq!(checksum.len(), 64);
+        assert!(checksum.bytes().all(|x| x.is_ascii_hexdigit()));
+
+        executor::block_on(
+            sqlx::query("UPDATE node SET operation = 'a * 3' WHERE node_id = 2").execute(&mut conn),
+        )
+        .unwrap();
+        let err = verify_checksums(file_name.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "checksum mismatch for nodes with ids [2]");
+
+        write_checksums(file_name.clone()).unwrap();
+        executor::block_on(sqlx::query("DELETE FROM edge WHERE edge_id = 1").execute(&mut conn))
+            .unwrap();
+        let err = verify_checksums(file_name).unwrap_err();
+        assert_eq!(err.to_string(), "graph checksum mismatch");
+    }
 }
@@ -0,0 +1,236 @@
+use anyhow::{anyhow, Result};
+use evalexpr::build_operator_tree;
+use std::collections::HashMap;
+
+use crate::core::{EdgeDefinition, NodeDefinition, NodeId, Tree};
+use crate::formula::{input_identifier, referenced_input, to_formula_string, INPUT_PREFIX};
+use crate::registry::NodeRegistry;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum InputResolution {
+    // `<prefix><node id>`
+    #[default]
+    Id,
+    // `<prefix><variable name>` for variable inputs, inputs of other kinds keep their id
+    Name,
+}
+
+// How formulas refer to their inputs. Trees hold their formulas in the default dialect,
+// `$<node id>`, formulas of other dialects are rewritten when the tree is built and back when
+// they are shown.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FormulaDialect {
+    prefix: String,
+    resolution: InputResolution,
+}
+
+impl Default for FormulaDialect {
+    fn default() -> Self {
+        Self {
+            prefix: INPUT_PREFIX.to_string(),
+            resolution: InputResolution::Id,
+        }
+    }
+}
+
+// The input ids of a node together with the names of the variable inputs
+type NamedInputs<'a> = [(NodeId, Option<&'a str>)];
+
+impl FormulaDialect {
+    // The prefix has to be part of an identifier for evalexpr, without it only names work
+    pub fn new(prefix: &str, resolution: InputResolution) -> Result<Self> {
+        let invalid = |c: char| c.is_whitespace() || "+-*/%^(),;=!><&|\"".contains(c);
+        if prefix.chars().any(invalid) || prefix.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(anyhow!("{:?} cannot start an identifier", prefix));
+        }
+        if prefix.is_empty() && resolution == InputResolution::Id {
+            return Err(anyhow!("inputs read by id need a prefix"));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            resolution,
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn resolution(&self) -> InputResolution {
+        self.resolution
+    }
+
+    // Reads that are no input of the node are left as they are and fail at evaluation
+    pub(crate) fn to_canonical(&self, formula: &str, inputs: &NamedInputs) -> Result<String> {
+        if *self == Self::default() {
+            return Ok(formula.to_string());
+        }
+        let mut tree = build_operator_tree(formula)?;
+        for identifier in tree.iter_read_variable_identifiers_mut() {
+            if let Some(input_id) = self.resolve(identifier, inputs) {
+                *identifier = input_identifier(input_id);
+            }
+        }
+        Ok(to_formula_string(&tree))
+    }
+
+    pub(crate) fn render(&self, formula: &str, inputs: &NamedInputs) -> Result<String> {
+        if *self == Self::default() {
+            return Ok(formula.to_string());
+        }
+        let mut tree = build_operator_tree(formula)?;
+        for identifier in tree.iter_read_variable_identifiers_mut() {
+            let Some((input_id, name)) =
+                referenced_input(identifier).and_then(|id| inputs.iter().find(|(x, _)| *x == id))
+            else {
+                continue;
+            };
+            *identifier = match (self.resolution, name) {
+                (InputResolution::Name, Some(name)) => format!("{}{}", self.prefix, name),
+                _ => format!("{}{}", self.prefix, input_id),
+            };
+        }
+        Ok(to_formula_string(&tree))
+    }
+
+    fn resolve(&self, identifier: &str, inputs: &NamedInputs) -> Option<NodeId> {
+        let reference = identifier.strip_prefix(self.prefix.as_str())?;
+        if self.resolution == InputResolution::Name {
+            if let Some((input_id, _)) = inputs.iter().find(|(_, x)| *x == Some(reference)) {
+                return Some(*input_id);
+            }
+        }
+        if reference.is_empty() || !reference.bytes().all(|x| x.is_ascii_digit()) {
+            return None;
+        }
+        let input_id = reference.parse().ok()?;
+        inputs
+            .iter()
+            .any(|(x, _)| *x == input_id)
+            .then_some(input_id)
+    }
+}
+
+// The inputs of every node in the order of the edges, with the names of the variables
+fn named_inputs<'a>(
+    node_definitions: &'a [NodeDefinition],
+    edge_definitions: &[EdgeDefinition],
+) -> HashMap<NodeId, Vec<(NodeId, Option<&'a str>)>> {
+    let names: HashMap<NodeId, &str> = node_definitions
+        .iter()
+        .filter(|x| x.kind == 0)
+        .map(|x| (x.node_id, x.value.as_str()))
+        .collect();
+    let mut inputs: HashMap<NodeId, Vec<_>> = HashMap::new();
+    for edge_def in edge_definitions {
+        inputs
+            .entry(edge_def.node_id)
+            .or_default()
+            .push((edge_def.input_id, names.get(&edge_def.input_id).copied()));
+    }
+    inputs
+}
+
+impl Tree {
+    // Builds the tree from formulas written in the dialect, the definitions of the tree hold them
+    // in the default dialect
+    pub fn new_with_dialect(
+        mut nodes_definitions: Vec<NodeDefinition>,
+        edge_definitions: Vec<EdgeDefinition>,
+        registry: NodeRegistry,
+        dialect: FormulaDialect,
+    ) -> Result<Self> {
+        let inputs = named_inputs(&nodes_definitions, &edge_definitions);
+        let mut formulas = Vec::new();
+        for (idx, node_def) in nodes_definitions.iter().enumerate() {
+            if node_def.kind == 1 {
+                let node_inputs = inputs.get(&node_def.node_id).map_or(&[][..], |x| x);
+                formulas.push((idx, dialect.to_canonical(&node_def.value, node_inputs)?));
+            }
+        }
+        drop(inputs);
+        for (idx, formula) in formulas {
+            nodes_definitions[idx].value = formula;
+        }
+
+        let mut tree = Tree::new_with_registry(nodes_definitions, edge_definitions, registry)?;
+        tree.dialect = dialect;
+        Ok(tree)
+    }
+
+    pub fn dialect(&self) -> &FormulaDialect {
+        &self.dialect
+    }
+
+    // The formula of the node as written in the dialect of the tree
+    pub fn formula(&self, node_id: NodeId) -> Result<String> {
+        let node_def = self
+            .node_definitions()
+            .iter()
+            .find(|x| x.node_id == node_id && x.kind == 1)
+            .ok_or(anyhow!("node {} is not a formula node", node_id))?;
+        let inputs = named_inputs(self.node_definitions(), self.edge_definitions());
+        let node_inputs = inputs.get(&node_id).map_or(&[][..], |x| x);
+        self.dialect.render(&node_def.value, node_inputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::NodeOutput;
+
+    #[test]
+    fn test_formula_dialect() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "width".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "height".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "#width * #1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "#2 + #width".into(),
+                default: None,
+            },
+        ];
+        let mut edge_defs = Vec::new();
+        for (node_id, input_id) in [(2, 0), (2, 1), (3, 2), (3, 0)] {
+            edge_defs.push(EdgeDefinition { node_id, input_id });
+        }
+        let dialect = FormulaDialect::new("#", InputResolution::Name).unwrap();
+        let tree = Tree::new_with_dialect(
+            node_defs,
+            edge_defs,
+            NodeRegistry::default(),
+            dialect.clone(),
+        )
+        .unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::Number(2.)), (1, NodeOutput::Number(3.))]);
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(8.));
+        assert_eq!(tree.node_definitions()[3].value, "$2 + $0");
+        assert_eq!(tree.formula(2).unwrap(), "#width * #height");
+        assert_eq!(tree.formula(3).unwrap(), "#2 + #width");
+        assert_eq!(tree.dialect(), &dialect);
+        assert!(tree.formula(0).is_err());
+
+        assert!(FormulaDialect::new("", InputResolution::Name).is_ok());
+        assert!(FormulaDialect::new("", InputResolution::Id).is_err());
+        assert!(FormulaDialect::new("in-", InputResolution::Id).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{NodeId, NodeOutput, Tree};
+use crate::formula::Tolerance;
+use crate::functions::approx_eq;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OutputDifference {
+    pub node_id: NodeId,
+    // None if the node is not part of that version
+    pub before: Option<NodeOutput>,
+    pub after: Option<NodeOutput>,
+    // Largest absolute difference of the elements, infinite if the shapes do not match
+    pub max_difference: f64,
+}
+
+// The largest element difference and whether all elements are equal within the tolerance
+pub(crate) fn compare(
+    before: &NodeOutput,
+    after: &NodeOutput,
+    tolerance: Tolerance,
+) -> (f64, bool) {
+    // Custom values and strings have no elements, they are either equal or not
+    let (Ok(before_elements), Ok(after_elements)) = (before.elements(), after.elements()) else {
+        return match before == after {
+            true => (0., true),
+            false => (f64::INFINITY, false),
+        };
+    };
+    let (before, after) = (before_elements, after_elements);
+    if before.len() != after.len() {
+        return (f64::INFINITY, false);
+    }
+    before
+        .iter()
+        .zip(&after)
+        .fold((0., true), |(max, equal), (a, b)| {
+            let difference = if a == b { 0. } else { (a - b).abs() };
+            (
+                f64::max(max, difference),
+                equal && approx_eq(*a, *b, tolerance.absolute, tolerance.relative),
+            )
+        })
+}
+
+impl Tree {
+    // Evaluates the node in this and in the other version of the tree with the same values and
+    // lists every node below it whose outputs differ by more than the tolerance, by node id
+    pub fn diff(
+        &self,
+        other: &Tree,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        tolerance: Tolerance,
+    ) -> Result<Vec<OutputDifference>> {
+        let before = self.eval_all(node_id, values)?;
+        let after = other.eval_all(node_id, values)?;
+
+        let node_ids: HashSet<_> = before.keys().chain(after.keys()).collect();
+        let mut node_ids: Vec<_> = node_ids.into_iter().copied().collect();
+        node_ids.sort();
+
+        let mut differences = Vec::new();
+        for id in node_ids {
+            let (before, after) = (before.get(&id), after.get(&id));
+            let max_difference = match (before, after) {
+                (Some(before), Some(after)) => match compare(before, after, tolerance) {
+                    (_, true) => continue,
+                    (max_difference, false) => max_difference,
+                },
+                _ => f64::INFINITY,
+            };
+            differences.push(OutputDifference {
+                node_id: id,
+                before: before.cloned(),
+                after: after.cloned(),
+                max_difference,
+            });
+        }
+        Ok(differences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_diff() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let before = Tree::new(node_defs.clone(), edge_defs.clone()).unwrap();
+
+        let mut node_defs = node_defs;
+        node_defs[1].value = "$0 * 2.001".into();
+        let after = Tree::new(node_defs, edge_defs).unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 1000.]))]);
+        let tolerance = Tolerance {
+            absolute: 0.01,
+            relative: 0.,
+        };
+        let differences = before.diff(&after, 2, &values, tolerance).unwrap();
+        assert_eq!(
+            differences.iter().map(|x| x.node_id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!((differences[1].max_difference - 1.).abs() < 1e-9);
+
+        let tolerance = Tolerance {
+            absolute: 0.,
+            relative: 1e-3,
+        };
+        assert!(before
+            .diff(&after, 2, &values, tolerance)
+            .unwrap()
+            .is_empty());
+    }
+}
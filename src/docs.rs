@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+
+use crate::core::{NodeId, NodeOutput, Tree};
+
+const EXAMPLE_LEN: usize = 8;
+
+fn format_array<T: Display>(v: &[T]) -> String {
+    let mut values: Vec<_> = v.iter().take(EXAMPLE_LEN).map(|x| x.to_string()).collect();
+    if v.len() > EXAMPLE_LEN {
+        values.push(format!("... {} more", v.len() - EXAMPLE_LEN));
+    }
+    format!("[{}]", values.join(", "))
+}
+
+fn format_output(output: &NodeOutput) -> String {
+    match output {
+        NodeOutput::Number(v) => v.to_string(),
+        NodeOutput::Integer(v) => v.to_string(),
+        NodeOutput::Bool(v) => v.to_string(),
+        NodeOutput::String(v) => v.clone(),
+        NodeOutput::Custom(v) => v.to_string(),
+        NodeOutput::NumberArray(v) => format_array(v),
+        NodeOutput::BoolArray(v) => format_array(v),
+        NodeOutput::StringArray(v) => format_array(v),
+        NodeOutput::Matrix(v) => {
+            let rows: Vec<_> = v.iter().map(|x| format_array(x)).collect();
+            format_array(&rows)
+        }
+        NodeOutput::TimeSeries(v) => {
+            let pairs: Vec<_> = v.iter().map(|(t, x)| format!("{}: {}", t, x)).collect();
+            format_array(&pairs)
+        }
+        NodeOutput::Series { name, values } => format!("{}: {}", name, format_array(values)),
+    }
+}
+
+impl Tree {
+    // Attaches markdown documentation to the nodes, replacing earlier docs of the same nodes
+    pub fn with_docs(mut self, docs: HashMap<NodeId, String>) -> Result<Self> {
+        for node_id in docs.keys() {
+            self.node(*node_id)?;
+        }
+        self.docs.extend(docs);
+        Ok(self)
+    }
+
+    pub fn doc(&self, node_id: NodeId) -> Option<&str> {
+        self.docs.get(&node_id).map(|x| x.as_str())
+    }
+
+    pub fn docs(&self) -> &HashMap<NodeId, String> {
+        &self.docs
+    }
+
+    // Markdown document of every node below `node_id`, starting at the node itself. With
+    // `values` given, each section also shows the output the node evaluates to.
+    pub fn to_markdown(
+        &self,
+        node_id: NodeId,
+        values: Option<&HashMap<NodeId, NodeOutput>>,
+    ) -> Result<String> {
+        let outputs = match values {
+            Some(values) => self.eval_all(node_id, values)?,
+            None => HashMap::new(),
+        };
+        let mut doc = String::new();
+
+        writeln!(doc, "# Node {}", node_id)?;
+        for id in self.eval_order(node_id)?.iter().rev() {
+            let node_def = self
+                .node_definitions()
+                .iter()
+                .find(|x| x.node_id == *id)
+                .ok_or(anyhow!("no definition of node {}", id))?;
+
+            writeln!(doc, "\n## Node {}\n", id)?;
+            match node_def.kind {
+                0 => writeln!(doc, "Variable `{}`", node_def.value)?,
+                1 => writeln!(doc, "Formula `{}`", node_def.value)?,
+                kind => writeln!(doc, "Kind {} `{}`", kind, node_def.value)?,
+            }
+
+            let inputs: Vec<_> = self
+                .edge_definitions()
+                .iter()
+                .filter(|x| x.node_id == *id)
+                .map(|x| format!("[Node {}](#node-{})", x.input_id, x.input_id))
+                .collect();
+            if !inputs.is_empty() {
+                writeln!(doc, "\nInputs: {}", inputs.join(", "))?;
+            }
+            if let Some(output) = outputs.get(id) {
+                writeln!(doc, "\nExample: `{}`", format_output(output))?;
+            }
+            if let Some(node_doc) = self.doc(*id) {
+                writeln!(doc, "\n{}", node_doc.trim())?;
+            }
+        }
+        Ok(doc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_to_markdown() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "length".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        assert!(tree
+            .clone()
+            .with_docs(HashMap::from([(5, "missing".into())]))
+            .is_err());
+
+        let docs = HashMap::from([(1, "Twice the **length**.".into())]);
+        let tree = tree.with_docs(docs).unwrap();
+        assert_eq!(tree.doc(1), Some("Twice the **length**."));
+        assert_eq!(tree.doc(0), None);
+
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2.5]))]);
+        let doc = tree.to_markdown(1, Some(&values)).unwrap();
+        assert_eq!(
+            doc,
+            "# Node 1\n\n\
+            ## Node 1\n\nFormula `$0 * 2`\n\nInputs: [Node 0](#node-0)\n\nExample: `[2, 5]`\n\n\
+            Twice the **length**.\n\n\
+            ## Node 0\n\nVariable `length`\n\nExample: `[1, 2.5]`\n"
+        );
+        assert!(!tree.to_markdown(1, None).unwrap().contains("Example"));
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::core::{EvalCache, NodeId, NodeOutput, Tree};
+use crate::formula::Tolerance;
+use crate::functions::approx_eq;
+
+// Input values together with the outputs the author of the graph expects from them
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Example {
+    pub name: String,
+    pub inputs: HashMap<NodeId, NodeOutput>,
+    pub expected: HashMap<NodeId, NodeOutput>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub example: String,
+    pub node_id: NodeId,
+    pub expected: NodeOutput,
+    // The error message if the node could not be evaluated
+    pub actual: std::result::Result<NodeOutput, String>,
+}
+
+fn matches(expected: &NodeOutput, actual: &NodeOutput, tolerance: Option<Tolerance>) -> bool {
+    let equal = |a: f64, b: f64| match tolerance {
+        Some(tolerance) => approx_eq(a, b, tolerance.absolute, tolerance.relative),
+        None => a == b,
+    };
+    match (expected, actual) {
+        (NodeOutput::Number(a), NodeOutput::Number(b)) => equal(*a, *b),
+        (NodeOutput::Integer(a), NodeOutput::Integer(b)) => a == b,
+        (NodeOutput::Custom(_), NodeOutput::Custom(_))
+        | (NodeOutput::Bool(_), NodeOutput::Bool(_))
+        | (NodeOutput::String(_), NodeOutput::String(_))
+        | (NodeOutput::BoolArray(_), NodeOutput::BoolArray(_))
+        | (NodeOutput::StringArray(_), NodeOutput::StringArray(_)) => expected == actual,
+        (NodeOutput::NumberArray(a), NodeOutput::NumberArray(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(*a, *b))
+        }
+        (
+            NodeOutput::Series {
+                name: a_name,
+                values: a,
+            },
+            NodeOutput::Series {
+                name: b_name,
+                values: b,
+            },
+        ) => a_name == b_name && a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(*a, *b)),
+        (NodeOutput::TimeSeries(a), NodeOutput::TimeSeries(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((s, a), (t, b))| s == t && equal(*a, *b))
+        }
+        (NodeOutput::Matrix(a), NodeOutput::Matrix(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(a, b)| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(*a, *b)))
+        }
+        _ => false,
+    }
+}
+
+impl Tree {
+    pub fn with_examples(mut self, examples: Vec<Example>) -> Result<Self> {
+        for example in &examples {
+            for node_id in example.inputs.keys().chain(example.expected.keys()) {
+                self.node(*node_id)?;
+            }
+        }
+        self.examples = examples;
+        Ok(self)
+    }
+
+    pub fn examples(&self) -> &[Example] {
+        &self.examples
+    }
+
+    // Runs every example and lists the expected outputs the tree does not produce. Numbers are
+    // compared within the tolerance of the tree if it has one.
+    pub fn self_test(&self) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for example in &self.examples {
+            let mut node_ids: Vec<_> = example.expected.keys().collect();
+            node_ids.sort();
+
+            let mut cache = EvalCache::new();
+            for node_id in node_ids {
+                let expected = &example.expected[node_id];
+                let actual = self
+                    .eval_cached(*node_id, &example.inputs, &mut cache)
+                    .map_err(|e| e.to_string());
+                if let Ok(actual) = &actual {
+                    if matches(expected, actual, self.tolerance()) {
+                        continue;
+                    }
+                }
+                mismatches.push(Mismatch {
+                    example: example.name.clone(),
+                    node_id: *node_id,
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_self_test() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 / 3".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let examples = vec![
+            Example {
+                name: "third".into(),
+                inputs: HashMap::from([(0, NodeOutput::Number(1.))]),
+                expected: HashMap::from([(1, NodeOutput::Number(0.3333))]),
+            },
+            Example {
+                name: "missing input".into(),
+                inputs: HashMap::new(),
+                expected: HashMap::from([(1, NodeOutput::Number(0.))]),
+            },
+            Example {
+                name: "array".into(),
+                inputs: HashMap::from([(0, NodeOutput::NumberArray(vec![3., 6.]))]),
+                expected: HashMap::from([(1, NodeOutput::NumberArray(vec![1., 2.]))]),
+            },
+        ];
+        let mut invalid = examples.clone();
+        invalid[0].expected.insert(2, NodeOutput::Number(0.));
+        assert!(tree.clone().with_examples(invalid).is_err());
+
+        let tree = tree.with_examples(examples).unwrap();
+        let mismatches = tree.self_test();
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].example, "third");
+        assert_eq!(mismatches[0].actual, Ok(NodeOutput::Number(1. / 3.)));
+        assert_eq!(mismatches[1].example, "missing input");
+        assert!(mismatches[1].actual.is_err());
+
+        let tolerance = Tolerance {
+            absolute: 1e-3,
+            relative: 0.,
+        };
+        let tree = tree.with_tolerance(tolerance).unwrap();
+        assert_eq!(tree.self_test().len(), 1);
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{Node, NodeId, NodeKind, NodeOutput, Tree};
+use crate::fingerprint::{fnv1a, FNV_OFFSET};
+use crate::formula::input_ids;
+
+pub trait VariantStrategy {
+    // Picks one of the variants of the node for the evaluation identified by `key`, e.g. a user
+    // or request id. None keeps the formula of the tree.
+    fn select(&self, node_id: NodeId, key: &str, variants: &[&str]) -> Option<String>;
+}
+
+// Sends a stable share of the keys to the variant, the same key always gets the same answer
+pub struct PercentageRollout {
+    pub variant: String,
+    pub percent: f64,
+}
+
+impl VariantStrategy for PercentageRollout {
+    fn select(&self, node_id: NodeId, key: &str, variants: &[&str]) -> Option<String> {
+        if !variants.contains(&self.variant.as_str()) {
+            return None;
+        }
+        let hash = fnv1a(
+            fnv1a(FNV_OFFSET, key.as_bytes()),
+            &(node_id as u64).to_le_bytes(),
+        );
+        let bucket = (hash % 10_000) as f64 / 100.;
+        (bucket < self.percent).then(|| self.variant.clone())
+    }
+}
+
+pub struct AllowList {
+    pub variant: String,
+    pub keys: HashSet<String>,
+}
+
+impl VariantStrategy for AllowList {
+    fn select(&self, _node_id: NodeId, key: &str, variants: &[&str]) -> Option<String> {
+        (variants.contains(&self.variant.as_str()) && self.keys.contains(key))
+            .then(|| self.variant.clone())
+    }
+}
+
+// Alternative formulas for nodes of a tree, one of which the strategy picks per evaluation
+pub struct Experiment {
+    variants: HashMap<NodeId, Vec<(String, Node)>>,
+    strategy: Box<dyn VariantStrategy>,
+}
+
+impl Experiment {
+    pub fn new(strategy: impl VariantStrategy + 'static) -> Self {
+        Self {
+            variants: HashMap::new(),
+            strategy: Box::new(strategy),
+        }
+    }
+
+    // The formula of the variant can only reference the inputs of the node it replaces
+    pub fn add_variant(
+        &mut self,
+        tree: &Tree,
+        node_id: NodeId,
+        name: &str,
+        formula: &str,
+    ) -> Result<()> {
+        let node = tree.node(node_id)?;
+        if let NodeKind::Variable(_) = node.kind() {
+            return Err(anyhow!("variable node {} cannot have variants", node_id));
+        }
+
+        let variant = Node::from_formula(node_id, formula)?;
+        let NodeKind::Formula(parsed) = variant.kind() else {
+            unreachable!()
+        };
+        if let Some(id) = input_ids(parsed).iter().find(|x| !node.inputs.contains(x)) {
+            return Err(anyhow!("node {} is not an input of node {}", id, node_id));
+        }
+        let variant = variant.with_inputs(node.inputs.clone());
+
+        let variants = self.variants.entry(node_id).or_default();
+        if variants.iter().any(|(x, _)| x == name) {
+            return Err(anyhow!("node {} already has a variant {}", node_id, name));
+        }
+        variants.push((name.to_string(), variant));
+        Ok(())
+    }
+}
+
+impl Tree {
+    // Evaluates the node with the variants the strategy of the experiment picks for `key` and
+    // returns the picked variant of every node that has any
+    pub fn eval_experiment(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        experiment: &Experiment,
+        key: &str,
+    ) -> Result<(NodeOutput, HashMap<NodeId, String>)> {
+        let values = self.hooks.before(&[node_id], values)?;
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        let mut selected = HashMap::new();
+        for id in self.eval_order(node_id)?.iter() {
+            let mut node = self.node(*id)?;
+            if let NodeKind::Variable(_) = node.kind() {
+                outputs.insert(*id, node.eval(&values)?);
+                continue;
+            }
+
+            if let Some(variants) = experiment.variants.get(id) {
+                let names: Vec<&str> = variants.iter().map(|(x, _)| x.as_str()).collect();
+                if let Some(name) = experiment.strategy.select(*id, key, &names) {
+                    node = variants
+                        .iter()
+                        .find(|(x, _)| *x == name)
+                        .map(|(_, x)| x)
+                        .ok_or(anyhow!("node {} has no variant {}", id, name))?;
+                    selected.insert(*id, name);
+                }
+            }
+
+            let mut input_outputs = Vec::new();
+            for input in node.inputs.iter() {
+                let output = outputs
+                    .get(input)
+                    .ok_or(anyhow!("node {} was not evaluated", input))?;
+                input_outputs.push(output.clone());
+            }
+            outputs.insert(*id, self.apply(node, input_outputs)?);
+        }
+
+        let output = outputs
+            .remove(&node_id)
+            .ok_or(anyhow!("node {} was not evaluated", node_id))?;
+        self.hooks.after(node_id, &output)?;
+        Ok((output, selected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_eval_experiment() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(2.))]);
+
+        let mut experiment = Experiment::new(AllowList {
+            variant: "triple".into(),
+            keys: HashSet::from(["beta".to_string()]),
+        });
+        experiment
+            .add_variant(&tree, 1, "triple", "$0 * 3")
+            .unwrap();
+        assert!(experiment.add_variant(&tree, 1, "triple", "$0").is_err());
+        assert!(experiment.add_variant(&tree, 1, "other", "$2").is_err());
+        assert!(experiment.add_variant(&tree, 0, "other", "1").is_err());
+
+        let (output, selected) = tree
+            .eval_experiment(2, &values, &experiment, "beta")
+            .unwrap();
+        assert_eq!(output, NodeOutput::Number(7.));
+        assert_eq!(selected, HashMap::from([(1, "triple".to_string())]));
+        let (output, selected) = tree
+            .eval_experiment(2, &values, &experiment, "alpha")
+            .unwrap();
+        assert_eq!(output, NodeOutput::Number(5.));
+        assert!(selected.is_empty());
+
+        let rollout = PercentageRollout {
+            variant: "triple".into(),
+            percent: 30.,
+        };
+        let keys: Vec<String> = (0..1000).map(|x| x.to_string()).collect();
+        let picked = keys
+            .iter()
+            .filter(|x| rollout.select(1, x, &["triple"]).is_some())
+            .count();
+        assert!((250..350).contains(&picked));
+        assert_eq!(
+            rollout.select(1, "42", &["triple"]),
+            rollout.select(1, "42", &["triple"])
+        );
+        assert_eq!(rollout.select(1, "42", &["other"]), None);
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::broadcast::BroadcastPolicy;
+use crate::core::{NodeId, NodeKind, NodeOutput, Tree};
+use crate::missing::MissingPolicy;
+
+// FNV-1a, chosen because the stored values must stay stable across Rust versions
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn output_hash(hash: u64, output: &NodeOutput) -> u64 {
+    match output {
+        NodeOutput::Number(v) => fnv1a(fnv1a(hash, &[0]), &v.to_bits().to_le_bytes()),
+        NodeOutput::Integer(v) => fnv1a(fnv1a(hash, &[2]), &v.to_le_bytes()),
+        NodeOutput::Custom(v) => {
+            let hash = fnv1a(fnv1a(hash, &[3]), v.type_name().as_bytes());
+            fnv1a(hash, v.to_string().as_bytes())
+        }
+        NodeOutput::NumberArray(v) => v.iter().fold(
+            fnv1a(fnv1a(hash, &[1]), &(v.len() as u64).to_le_bytes()),
+            |hash, x| fnv1a(hash, &x.to_bits().to_le_bytes()),
+        ),
+        NodeOutput::Bool(v) => fnv1a(hash, &[4, *v as u8]),
+        NodeOutput::String(v) => string_hash(fnv1a(hash, &[5]), v),
+        NodeOutput::BoolArray(v) => v.iter().fold(
+            fnv1a(fnv1a(hash, &[6]), &(v.len() as u64).to_le_bytes()),
+            |hash, x| fnv1a(hash, &[*x as u8]),
+        ),
+        NodeOutput::StringArray(v) => v.iter().fold(
+            fnv1a(fnv1a(hash, &[7]), &(v.len() as u64).to_le_bytes()),
+            string_hash,
+        ),
+        // Every row is hashed like an array, which keeps the shape apart
+        NodeOutput::Matrix(v) => v.iter().fold(
+            fnv1a(fnv1a(hash, &[8]), &(v.len() as u64).to_le_bytes()),
+            |hash, x| output_hash(hash, &NodeOutput::NumberArray(x.clone())),
+        ),
+        NodeOutput::TimeSeries(v) => v.iter().fold(
+            fnv1a(fnv1a(hash, &[10]), &(v.len() as u64).to_le_bytes()),
+            |hash, (t, x)| fnv1a(fnv1a(hash, &t.to_le_bytes()), &x.to_bits().to_le_bytes()),
+        ),
+        NodeOutput::Series { name, values } => output_hash(
+            string_hash(fnv1a(hash, &[9]), name),
+            &NodeOutput::NumberArray(values.clone()),
+        ),
+    }
+}
+
+// The length keeps ["ab", "c"] and ["a", "bc"] apart
+fn string_hash(hash: u64, value: &String) -> u64 {
+    fnv1a(
+        fnv1a(hash, &(value.len() as u64).to_le_bytes()),
+        value.as_bytes(),
+    )
+}
+
+impl Tree {
+    // Hashes the definitions of the node and everything it depends on, so the fingerprint
+    // only changes when something that can change the output of the node changes
+    pub fn fingerprint(&self, node_id: NodeId) -> Result<u64> {
+        let definitions: HashMap<NodeId, (usize, &str)> = self
+            .node_definitions()
+            .iter()
+            .map(|x| (x.node_id, (x.kind, x.value.as_str())))
+            .collect();
+
+        let mut hash = fnv1a(FNV_OFFSET, &[self.function_set() as u8]);
+        if let Some(tolerance) = self.tolerance() {
+            hash = fnv1a(hash, &tolerance.absolute.to_bits().to_le_bytes());
+            hash = fnv1a(hash, &tolerance.relative.to_bits().to_le_bytes());
+        }
+        for id in self.eval_order(node_id)?.iter() {
+            let (kind, value) = definitions
+                .get(id)
+                .ok_or(anyhow!("no definition for node {}", id))?;
+            hash = fnv1a(hash, &(*id as u64).to_le_bytes());
+            hash = fnv1a(hash, &(*kind as u64).to_le_bytes());
+            hash = fnv1a(hash, value.as_bytes());
+            for input_id in self.node(*id)?.inputs.iter() {
+                hash = fnv1a(hash, &(*input_id as u64).to_le_bytes());
+            }
+            hash = match self.missing_policy(*id) {
+                MissingPolicy::Propagate => hash,
+                MissingPolicy::Skip => fnv1a(hash, &[1]),
+                MissingPolicy::Substitute(v) => {
+                    fnv1a(fnv1a(hash, &[2]), &v.to_bits().to_le_bytes())
+                }
+                MissingPolicy::Previous => fnv1a(hash, &[3]),
+            };
+            hash = match self.broadcast_policy(*id) {
+                BroadcastPolicy::RepeatLast => hash,
+                BroadcastPolicy::Cycle => fnv1a(hash, &[4]),
+                BroadcastPolicy::ZipShortest => fnv1a(hash, &[5]),
+                BroadcastPolicy::Error => fnv1a(hash, &[6]),
+            };
+        }
+        Ok(hash)
+    }
+
+    // Hashes the values of the variables the node depends on
+    pub fn input_hash(&self, node_id: NodeId, values: &HashMap<NodeId, NodeOutput>) -> Result<u64> {
+        let mut hash = FNV_OFFSET;
+        for id in self.leaves(node_id)?.iter() {
+            let node = self.node(*id)?;
+            if !matches!(node.kind(), NodeKind::Variable(_)) {
+                continue;
+            }
+            let value = values
+                .get(id)
+                .or(node.default())
+                .ok_or(anyhow!("missing value for node {}", id))?;
+            hash = fnv1a(hash, &(*id as u64).to_le_bytes());
+            hash = output_hash(hash, value);
+        }
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_fingerprint() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 + $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let mut draft = tree.to_draft();
+        draft.set_value(3, "$2 - $1".into()).unwrap();
+        let changed = draft.freeze().unwrap();
+        assert_eq!(
+            tree.fingerprint(2).unwrap(),
+            changed.fingerprint(2).unwrap()
+        );
+        assert_ne!(
+            tree.fingerprint(3).unwrap(),
+            changed.fingerprint(3).unwrap()
+        );
+
+        let values = HashMap::from([(0, NodeOutput::Number(1.)), (1, NodeOutput::Number(2.))]);
+        let other = HashMap::from([(0, NodeOutput::Number(1.)), (1, NodeOutput::Number(3.))]);
+        assert_eq!(
+            tree.input_hash(2, &values).unwrap(),
+            tree.input_hash(2, &other).unwrap()
+        );
+        assert_ne!(
+            tree.input_hash(3, &values).unwrap(),
+            tree.input_hash(3, &other).unwrap()
+        );
+        assert!(tree.input_hash(3, &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,661 @@
+use anyhow::{anyhow, Result};
+use evalexpr::{
+    build_operator_tree, ContextWithMutableVariables, HashMapContext, Node, Operator, Value,
+};
+use std::collections::HashMap;
+
+// Formulas of a tree read their inputs as `$<node id>`, see `FormulaDialect` for other ways
+pub(crate) const INPUT_PREFIX: &str = "$";
+
+pub(crate) fn input_identifier(node_id: usize) -> String {
+    format!("{}{}", INPUT_PREFIX, node_id)
+}
+
+pub(crate) fn referenced_input(identifier: &str) -> Option<usize> {
+    identifier.strip_prefix(INPUT_PREFIX)?.parse().ok()
+}
+
+pub(crate) fn to_formula_string(formula: &Node) -> String {
+    match formula.operator() {
+        Operator::RootNode => join_children(formula, ""),
+        _ => node_to_string(formula),
+    }
+}
+
+fn join_children(node: &Node, separator: &str) -> String {
+    node.children()
+        .iter()
+        .map(node_to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+// Each element of a tuple or chain is wrapped in its own root node by the parser
+fn join_elements(node: &Node, separator: &str) -> String {
+    node.children()
+        .iter()
+        .map(to_formula_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn node_to_string(node: &Node) -> String {
+    let children = node.children();
+    let binary = |op: &str| {
+        format!(
+            "{} {} {}",
+            node_to_string(&children[0]),
+            op,
+            node_to_string(&children[1])
+        )
+    };
+
+    match node.operator() {
+        // Nested root nodes are the parenthesized subexpressions
+        Operator::RootNode => format!("({})", join_children(node, "")),
+        Operator::Add => binary("+"),
+        Operator::Sub => binary("-"),
+        Operator::Mul => binary("*"),
+        Operator::Div => binary("/"),
+        Operator::Mod => binary("%"),
+        Operator::Exp => binary("^"),
+        Operator::Eq => binary("=="),
+        Operator::Neq => binary("!="),
+        Operator::Gt => binary(">"),
+        Operator::Lt => binary("<"),
+        Operator::Geq => binary(">="),
+        Operator::Leq => binary("<="),
+        Operator::And => binary("&&"),
+        Operator::Or => binary("||"),
+        Operator::Assign => binary("="),
+        Operator::AddAssign => binary("+="),
+        Operator::SubAssign => binary("-="),
+        Operator::MulAssign => binary("*="),
+        Operator::DivAssign => binary("/="),
+        Operator::ModAssign => binary("%="),
+        Operator::ExpAssign => binary("^="),
+        Operator::AndAssign => binary("&&="),
+        Operator::OrAssign => binary("||="),
+        Operator::Neg => format!("-{}", node_to_string(&children[0])),
+        Operator::Not => format!("!{}", node_to_string(&children[0])),
+        Operator::Tuple => join_elements(node, ", "),
+        Operator::Chain => join_elements(node, "; "),
+        Operator::Const { value } => value_to_string(value),
+        Operator::VariableIdentifierWrite { identifier }
+        | Operator::VariableIdentifierRead { identifier } => identifier.clone(),
+        Operator::FunctionIdentifier { identifier } => match children.first() {
+            Some(arg) if arg.operator() == &Operator::RootNode => {
+                format!("{}{}", identifier, node_to_string(arg))
+            }
+            Some(arg) => format!("{}({})", identifier, node_to_string(arg)),
+            None => format!("{}()", identifier),
+        },
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        // Debug keeps the decimal point, so floats are not re-parsed as ints
+        Value::Float(v) => format!("{:?}", v),
+        Value::String(v) => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+        Value::Tuple(v) => format!(
+            "({})",
+            v.iter().map(value_to_string).collect::<Vec<_>>().join(", ")
+        ),
+        _ => value.to_string(),
+    }
+}
+
+pub(crate) fn input_ids(formula: &Node) -> Vec<usize> {
+    let mut ids: Vec<usize> = formula
+        .iter_read_variable_identifiers()
+        .filter_map(referenced_input)
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+// The conditions of every `if` (or Excel `IF`) call in the formula, outermost first
+pub(crate) fn conditions(formula: &Node) -> Vec<String> {
+    let mut conditions = Vec::new();
+    if let Operator::FunctionIdentifier { identifier } = formula.operator() {
+        if let ("if" | "IF", Some(args)) = (identifier.as_str(), formula.children().first()) {
+            let args = unwrap_root(args);
+            if args.operator() == &Operator::Tuple {
+                conditions.push(to_formula_string(&args.children()[0]));
+            }
+        }
+    }
+    for child in formula.children() {
+        conditions.extend(self::conditions(child));
+    }
+    conditions
+}
+
+fn input_reference(node_id: usize) -> Result<Node> {
+    let reference = build_operator_tree(&input_identifier(node_id))?;
+    Ok(reference.children()[0].clone())
+}
+
+fn unwrap_root(mut node: &Node) -> &Node {
+    while node.operator() == &Operator::RootNode && node.children().len() == 1 {
+        node = &node.children()[0];
+    }
+    node
+}
+
+fn unwrap_root_mut(mut node: &mut Node) -> &mut Node {
+    while node.operator() == &Operator::RootNode && node.children().len() == 1 {
+        node = &mut node.children_mut()[0];
+    }
+    node
+}
+
+// Splits the operands of the outermost operator (or the arguments of the outermost function)
+// into separate formulas that are referenced by the ids `next_id` hands out. Operands that are
+// leaves or do not read any input are left in place.
+pub(crate) fn split_top_level(
+    formula: &str,
+    next_id: &mut impl FnMut() -> usize,
+) -> Result<(String, Vec<(usize, String)>)> {
+    let mut formula = build_operator_tree(formula)?;
+    let mut parts = Vec::new();
+
+    let top = unwrap_root_mut(&mut formula);
+    let targets: Vec<&mut Node> = match top.operator() {
+        Operator::FunctionIdentifier { .. } => {
+            let Some(arg) = top.children_mut().first_mut() else {
+                return Err(anyhow!("function call without arguments"));
+            };
+            let arg = unwrap_root_mut(arg);
+            match arg.operator() {
+                Operator::Tuple => arg.children_mut().iter_mut().collect(),
+                _ => vec![arg],
+            }
+        }
+        _ => top.children_mut().iter_mut().collect(),
+    };
+
+    for target in targets {
+        let is_leaf = matches!(
+            unwrap_root(target).operator(),
+            Operator::Const { .. } | Operator::VariableIdentifierRead { .. }
+        );
+        if is_leaf || target.iter_read_variable_identifiers().next().is_none() {
+            continue;
+        }
+
+        let id = next_id();
+        parts.push((id, to_formula_string(target)));
+        *target = input_reference(id)?;
+    }
+
+    Ok((to_formula_string(&formula), parts))
+}
+
+// Replaces every read of `$node_id` with the (parenthesized) replacement formula
+pub(crate) fn substitute_input(formula: &str, node_id: usize, replacement: &str) -> Result<String> {
+    let mut formula = build_operator_tree(formula)?;
+    let replacement = build_operator_tree(&format!("({})", replacement))?;
+    let replacement = &replacement.children()[0];
+    let replacement = match unwrap_root(replacement).operator() {
+        Operator::Const { .. }
+        | Operator::VariableIdentifierRead { .. }
+        | Operator::FunctionIdentifier { .. } => unwrap_root(replacement),
+        _ => replacement,
+    };
+
+    substitute(&mut formula, &input_identifier(node_id), replacement);
+    Ok(to_formula_string(&formula))
+}
+
+// The input position read as `id0`, `id1`, ... in the old formula dialect
+fn legacy_input(identifier: &str) -> Option<usize> {
+    let position = identifier.strip_prefix("id")?;
+    if position.is_empty() || !position.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    position.parse().ok()
+}
+
+// Rewrites the reads of the old dialect to read `$<node id>`, where `idN` is the N-th input in
+// the order of the edges. Formulas without them are returned as they are.
+pub(crate) fn canonical_references(formula: &str, input_ids: &[usize]) -> Result<String> {
+    // Spares most formulas a second parse
+    if !formula.contains("id") {
+        return Ok(formula.to_string());
+    }
+    let mut tree = build_operator_tree(formula)?;
+    if !tree
+        .iter_read_variable_identifiers()
+        .any(|x| legacy_input(x).is_some())
+    {
+        return Ok(formula.to_string());
+    }
+
+    for identifier in tree.iter_read_variable_identifiers_mut() {
+        if let Some(position) = legacy_input(identifier) {
+            let input_id = input_ids.get(position).ok_or(anyhow!(
+                "formula {} reads id{}, but has {} inputs",
+                formula,
+                position,
+                input_ids.len()
+            ))?;
+            *identifier = input_identifier(*input_id);
+        }
+    }
+    Ok(to_formula_string(&tree))
+}
+
+fn is_read_of(node: &Node, identifier: &str) -> bool {
+    matches!(node.operator(), Operator::VariableIdentifierRead { identifier: x } if x == identifier)
+}
+
+fn substitute(node: &mut Node, identifier: &str, replacement: &Node) {
+    if is_read_of(node, identifier) {
+        *node = replacement.clone();
+        return;
+    }
+
+    // Avoid doubled parentheses when the reference is already parenthesized
+    if node.operator() == &Operator::RootNode
+        && node.children().len() == 1
+        && is_read_of(&node.children()[0], identifier)
+    {
+        node.children_mut()[0] = unwrap_root(replacement).clone();
+        return;
+    }
+
+    for child in node.children_mut() {
+        substitute(child, identifier, replacement);
+    }
+}
+
+// Replaces the reads of the parameters with their values and folds every subexpression that
+// became constant
+pub(crate) fn bind_parameters(formula: &str, parameters: &HashMap<String, f64>) -> Result<String> {
+    let mut formula = build_operator_tree(formula)?;
+    replace_parameters(&mut formula, parameters)?;
+    fold_constants(&mut formula)?;
+    Ok(to_formula_string(&formula))
+}
+
+// Conditions are formulas over the parameters, numbers count as true when they are not 0
+pub(crate) fn eval_condition(condition: &str, parameters: &HashMap<String, f64>) -> Result<bool> {
+    let mut context = HashMapContext::new();
+    for (name, value) in parameters {
+        context.set_value(name.clone(), Value::Float(*value))?;
+    }
+    match build_operator_tree(condition)?.eval_with_context(&context)? {
+        Value::Boolean(v) => Ok(v),
+        Value::Float(v) => Ok(v != 0.),
+        Value::Int(v) => Ok(v != 0),
+        value => Err(anyhow!(
+            "condition {} returned {} instead of a boolean",
+            condition,
+            value
+        )),
+    }
+}
+
+// Equality in formulas holds when the values are at most `absolute` apart, or at most `relative`
+// of the larger magnitude
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Tolerance {
+    pub absolute: f64,
+    pub relative: f64,
+}
+
+// Turns `a == b` into `approx_eq(a, b, absolute, relative)` and `a != b` into its negation
+pub(crate) fn approximate_equality(formula: &str, tolerance: Tolerance) -> Result<String> {
+    let mut formula = build_operator_tree(formula)?;
+    replace_equality(&mut formula, tolerance)?;
+    Ok(to_formula_string(&formula))
+}
+
+fn replace_equality(node: &mut Node, tolerance: Tolerance) -> Result<()> {
+    for child in node.children_mut() {
+        replace_equality(child, tolerance)?;
+    }
+
+    let negation = match node.operator() {
+        Operator::Eq => "",
+        Operator::Neq => "!",
+        _ => return Ok(()),
+    };
+    let call = format!(
+        "{}approx_eq(__lhs, __rhs, {}, {})",
+        negation,
+        value_to_string(&Value::Float(tolerance.absolute)),
+        value_to_string(&Value::Float(tolerance.relative))
+    );
+    let mut replacement = unwrap_root(&build_operator_tree(&call)?).clone();
+    substitute(&mut replacement, "__lhs", &node.children()[0]);
+    substitute(&mut replacement, "__rhs", &node.children()[1]);
+    *node = replacement;
+    Ok(())
+}
+
+// Every formula that differs from `formula` by a single swapped operator or a single number
+// increased by one, in the order they appear in the formula
+pub(crate) fn mutants(formula: &str) -> Result<Vec<String>> {
+    let formula = build_operator_tree(formula)?;
+    let mut mutants = Vec::new();
+    for site in 0.. {
+        let mut mutant = formula.clone();
+        if !mutate(&mut mutant, &mut site.clone())? {
+            break;
+        }
+        mutants.push(to_formula_string(&mutant));
+    }
+    Ok(mutants)
+}
+
+fn swapped_operator(operator: &Operator) -> Option<&'static str> {
+    let swapped = match operator {
+        Operator::Add => "-",
+        Operator::Sub => "+",
+        Operator::Mul => "/",
+        Operator::Div => "*",
+        Operator::Gt => ">=",
+        Operator::Geq => ">",
+        Operator::Lt => "<=",
+        Operator::Leq => "<",
+        Operator::Eq => "!=",
+        Operator::Neq => "==",
+        Operator::And => "||",
+        Operator::Or => "&&",
+        _ => return None,
+    };
+    Some(swapped)
+}
+
+// Mutates the site with the index `site` counted in pre-order, false if there are fewer sites
+fn mutate(node: &mut Node, site: &mut usize) -> Result<bool> {
+    let mutation = match node.operator() {
+        Operator::Const {
+            value: Value::Int(v),
+        } => Some(constant(Value::Int(v.wrapping_add(1)))?),
+        Operator::Const {
+            value: Value::Float(v),
+        } => Some(constant(Value::Float(v + 1.))?),
+        operator => match swapped_operator(operator) {
+            Some(swapped) => {
+                let pattern = build_operator_tree(&format!("__lhs {} __rhs", swapped))?;
+                let mut replacement = unwrap_root(&pattern).clone();
+                substitute(&mut replacement, "__lhs", &node.children()[0]);
+                substitute(&mut replacement, "__rhs", &node.children()[1]);
+                Some(replacement)
+            }
+            None => None,
+        },
+    };
+    if let Some(mutation) = mutation {
+        if *site == 0 {
+            *node = mutation;
+            return Ok(true);
+        }
+        *site -= 1;
+    }
+
+    for child in node.children_mut() {
+        if mutate(child, site)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn constant(value: Value) -> Result<Node> {
+    let constant = build_operator_tree(&value_to_string(&value))?;
+    Ok(unwrap_root(&constant).clone())
+}
+
+fn replace_parameters(node: &mut Node, parameters: &HashMap<String, f64>) -> Result<()> {
+    if let Operator::VariableIdentifierRead { identifier } = node.operator() {
+        if let Some(value) = parameters.get(identifier) {
+            *node = constant(Value::Float(*value))?;
+        }
+        return Ok(());
+    }
+    for child in node.children_mut() {
+        replace_parameters(child, parameters)?;
+    }
+    Ok(())
+}
+
+fn fold_constants(node: &mut Node) -> Result<()> {
+    let is_foldable = !matches!(
+        node.operator(),
+        Operator::Const { .. }
+            | Operator::VariableIdentifierRead { .. }
+            | Operator::Tuple
+            | Operator::Chain
+    ) && node.iter_read_variable_identifiers().next().is_none();
+
+    // Only numbers are folded, everything else (or a failing function call) stays as it is
+    if is_foldable {
+        if let Ok(value @ (Value::Float(_) | Value::Int(_))) = node.eval() {
+            *node = constant(value)?;
+            return Ok(());
+        }
+    }
+    for child in node.children_mut() {
+        fold_constants(child)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FormulaMetrics {
+    pub depth: usize,
+    pub operator_count: usize,
+    pub input_count: usize,
+}
+
+impl FormulaMetrics {
+    pub fn from_formula(formula: &Node) -> Self {
+        let mut inputs: Vec<_> = formula.iter_read_variable_identifiers().collect();
+        inputs.sort();
+        inputs.dedup();
+
+        Self {
+            depth: depth(formula),
+            operator_count: formula.iter().filter(|x| is_operator(x.operator())).count(),
+            input_count: inputs.len(),
+        }
+    }
+}
+
+// Root nodes and tuples only group their children and do not add to the depth
+fn depth(node: &Node) -> usize {
+    let child_depth = node.children().iter().map(depth).max().unwrap_or(0);
+    match node.operator() {
+        Operator::RootNode | Operator::Tuple => child_depth,
+        _ => child_depth + 1,
+    }
+}
+
+fn is_operator(operator: &Operator) -> bool {
+    !matches!(
+        operator,
+        Operator::RootNode
+            | Operator::Tuple
+            | Operator::Chain
+            | Operator::Const { .. }
+            | Operator::VariableIdentifierRead { .. }
+            | Operator::VariableIdentifierWrite { .. }
+    )
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FormulaAliases {
+    aliases: HashMap<String, String>,
+}
+
+impl FormulaAliases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn german() -> Self {
+        let mut aliases = Self::new();
+        for (alias, canonical) in [
+            ("wenn", "if"),
+            ("runden", "round"),
+            ("abrunden", "floor"),
+            ("aufrunden", "ceil"),
+            ("wurzel", "math::sqrt"),
+            ("betrag", "math::abs"),
+            ("potenz", "math::pow"),
+            ("exp", "math::exp"),
+            ("ln", "math::ln"),
+            ("log10", "math::log10"),
+        ] {
+            aliases.add(alias, canonical);
+        }
+        aliases
+    }
+
+    // Aliases are matched case-insensitively, like spreadsheet function names
+    pub fn add(&mut self, alias: &str, canonical: &str) {
+        self.aliases
+            .insert(alias.to_lowercase(), canonical.to_string());
+    }
+
+    pub fn normalize(&self, formula: &str) -> Result<String> {
+        let mut formula = build_operator_tree(formula)?;
+        for identifier in formula.iter_function_identifiers_mut() {
+            if let Some(canonical) = self.aliases.get(&identifier.to_lowercase()) {
+                *identifier = canonical.clone();
+            }
+        }
+        Ok(to_formula_string(&formula))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formula_metrics() {
+        let formula = build_operator_tree("max(($0 + $1) * 2, -$0)").unwrap();
+        assert_eq!(
+            FormulaMetrics::from_formula(&formula),
+            FormulaMetrics {
+                depth: 4,
+                operator_count: 4,
+                input_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_canonical_references() {
+        assert_eq!(
+            canonical_references("id0 * 2 + max(id1, $4)", &[7, 4]).unwrap(),
+            "$7 * 2 + max($4, $4)"
+        );
+        assert_eq!(
+            canonical_references("$7 * 2 + idx", &[7]).unwrap(),
+            "$7 * 2 + idx"
+        );
+        assert!(canonical_references("id2", &[7, 4]).is_err());
+    }
+
+    #[test]
+    fn test_split_top_level() {
+        let mut next_id = 10;
+        let (formula, parts) = split_top_level("($0 + $1) * 2 - max($1, 3 * $0, 4)", &mut || {
+            next_id += 1;
+            next_id
+        })
+        .unwrap();
+        assert_eq!(formula, "$11 - $12");
+        assert_eq!(
+            parts,
+            vec![
+                (11, "($0 + $1) * 2".to_string()),
+                (12, "max($1, 3 * $0, 4)".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_substitute_input() {
+        let formula = substitute_input("$2 * $1 + max($2, 1.0) - ($2)", 2, "$0 + 1").unwrap();
+        assert_eq!(formula, "($0 + 1) * $1 + max($0 + 1, 1.0) - ($0 + 1)");
+
+        let formula = substitute_input("$2 * $1", 2, "(max($0, 1))").unwrap();
+        assert_eq!(formula, "max($0, 1) * $1");
+    }
+
+    #[test]
+    fn test_bind_parameters() {
+        let parameters = HashMap::from([("d".to_string(), 0.5)]);
+        let formula = bind_parameters("d ^ 2 * 3.0 / 4 * $0 + max($0, d)", &parameters).unwrap();
+        assert_eq!(formula, "0.1875 * $0 + max($0, 0.5)");
+
+        let formula = bind_parameters("$0 * (7 / 2) - e", &parameters).unwrap();
+        assert_eq!(formula, "$0 * 3 - e");
+    }
+
+    #[test]
+    fn test_normalize_aliases() {
+        let aliases = FormulaAliases::german();
+        let formula = aliases
+            .normalize("WENN($0 > 2.5, Wurzel($1), -($0 + 1) * 2)")
+            .unwrap();
+        assert_eq!(formula, "if($0 > 2.5, math::sqrt($1), -($0 + 1) * 2)");
+
+        let formula = aliases.normalize("min($0, \"a\\\"b\"); 3 ^ 2").unwrap();
+        assert_eq!(formula, "min($0, \"a\\\"b\"); 3 ^ 2");
+        assert_eq!(
+            build_operator_tree(&formula).unwrap(),
+            build_operator_tree("min($0, \"a\\\"b\"); 3 ^ 2").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_approximate_equality() {
+        let tolerance = Tolerance {
+            absolute: 1e-9,
+            relative: 0.,
+        };
+        assert_eq!(
+            approximate_equality("if($0 == $1 + 1, 1, 2)", tolerance).unwrap(),
+            "if(approx_eq($0, $1 + 1, 1e-9, 0.0), 1, 2)"
+        );
+        assert_eq!(
+            approximate_equality("$0 != 0.3", tolerance).unwrap(),
+            "!approx_eq($0, 0.3, 1e-9, 0.0)"
+        );
+    }
+
+    #[test]
+    fn test_mutants() {
+        assert_eq!(
+            mutants("$0 * 2 + 1").unwrap(),
+            vec!["$0 * 2 - 1", "$0 / 2 + 1", "$0 * 3 + 1", "$0 * 2 + 2"]
+        );
+        assert_eq!(
+            mutants("if($0 >= 0.5, $1, ($1 - $0))").unwrap(),
+            vec![
+                "if($0 > 0.5, $1, ($1 - $0))",
+                "if($0 >= 1.5, $1, ($1 - $0))",
+                "if($0 >= 0.5, $1, ($1 + $0))",
+            ]
+        );
+        assert!(mutants("$0").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let formula = build_operator_tree("if($0 > 1, IF($1 == 2, 1, 0), 3) + 1").unwrap();
+        assert_eq!(conditions(&formula), vec!["$0 > 1", "$1 == 2"]);
+        let formula = build_operator_tree("max($0, 1)").unwrap();
+        assert!(conditions(&formula).is_empty());
+    }
+}
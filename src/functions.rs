@@ -0,0 +1,515 @@
+use anyhow::Result;
+use evalexpr::{
+    ContextWithMutableFunctions, EvalexprError, EvalexprResult, Function, HashMapContext, Value,
+};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum FunctionSet {
+    #[default]
+    Evalexpr,
+    Excel,
+}
+
+impl FunctionSet {
+    pub fn context(&self) -> Result<HashMapContext> {
+        let mut context = HashMapContext::new();
+        for (name, function) in common_functions() {
+            context.set_function(name.to_string(), function)?;
+        }
+        match self {
+            FunctionSet::Evalexpr => (),
+            FunctionSet::Excel => {
+                for (name, function) in excel_functions() {
+                    context.set_function(name.to_string(), function)?;
+                }
+            }
+        }
+        Ok(context)
+    }
+}
+
+fn arguments(argument: &Value) -> Vec<Value> {
+    match argument {
+        Value::Tuple(values) => values.clone(),
+        Value::Empty => Vec::new(),
+        value => vec![value.clone()],
+    }
+}
+
+fn numbers(argument: &Value) -> EvalexprResult<Vec<f64>> {
+    arguments(argument).iter().map(Value::as_number).collect()
+}
+
+fn fixed_numbers<const N: usize>(argument: &Value) -> EvalexprResult<[f64; N]> {
+    let numbers = numbers(argument)?;
+    let len = numbers.len();
+    numbers
+        .try_into()
+        .map_err(|_| EvalexprError::wrong_function_argument_amount(len, N))
+}
+
+fn non_empty_numbers(argument: &Value) -> EvalexprResult<Vec<f64>> {
+    let numbers = numbers(argument)?;
+    if numbers.is_empty() {
+        return Err(EvalexprError::wrong_function_argument_amount_range(
+            0,
+            1..=usize::MAX,
+        ));
+    }
+    Ok(numbers)
+}
+
+fn truthy(value: &Value) -> EvalexprResult<bool> {
+    match value {
+        Value::Boolean(v) => Ok(*v),
+        value => Ok(value.as_number()? != 0.),
+    }
+}
+
+// Excel works with 15 significant digits, so 2.675 * 100 has to become 267.5 before rounding
+fn round_with(x: f64, digits: f64, round: fn(f64) -> f64) -> f64 {
+    let factor = 10f64.powi(digits as i32);
+    let scaled: f64 = format!("{:.14e}", x * factor).parse().unwrap_or(x * factor);
+    round(scaled) / factor
+}
+
+// Largest magnitude up to which every integer is exact in a f64
+const MAX_EXACT: f64 = 9007199254740992.;
+
+fn integer(x: f64) -> EvalexprResult<i64> {
+    if x.fract() != 0. || x.abs() > MAX_EXACT {
+        return Err(EvalexprError::CustomMessage(format!(
+            "{} is not an integer that fits into a float",
+            x
+        )));
+    }
+    Ok(x as i64)
+}
+
+fn exact(x: i64) -> EvalexprResult<Value> {
+    if (x as f64).abs() > MAX_EXACT {
+        return Err(EvalexprError::CustomMessage(format!(
+            "{} does not fit into a float",
+            x
+        )));
+    }
+    Ok(Value::Float(x as f64))
+}
+
+fn fixed_integers<const N: usize>(argument: &Value) -> EvalexprResult<[i64; N]> {
+    let numbers: [f64; N] = fixed_numbers(argument)?;
+    let mut integers = [0; N];
+    for (integer_ref, x) in integers.iter_mut().zip(numbers) {
+        *integer_ref = integer(x)?;
+    }
+    Ok(integers)
+}
+
+fn radix(base: i64) -> EvalexprResult<u32> {
+    if !(2..=36).contains(&base) {
+        return Err(EvalexprError::CustomMessage(format!(
+            "base {} is not between 2 and 36",
+            base
+        )));
+    }
+    Ok(base as u32)
+}
+
+fn shift(x: i64, by: i64, left: bool) -> EvalexprResult<Value> {
+    if !(0..64).contains(&by) {
+        return Err(EvalexprError::CustomMessage(format!(
+            "cannot shift by {} bits",
+            by
+        )));
+    }
+    match left {
+        true => exact(x.checked_shl(by as u32).filter(|v| v >> by == x).ok_or(
+            EvalexprError::CustomMessage(format!("{} << {} overflows", x, by)),
+        )?),
+        false => exact(x >> by),
+    }
+}
+
+pub(crate) fn approx_eq(a: f64, b: f64, absolute: f64, relative: f64) -> bool {
+    a == b || (a - b).abs() <= absolute.max(relative * a.abs().max(b.abs()))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xedb88320,
+            _ => crc >> 1,
+        })
+    });
+    !crc
+}
+
+// Available in every function set. The bit operations replace the evalexpr builtins of the same
+// name, which only take integer values, so they work on the floats nodes pass around.
+fn common_functions() -> Vec<(&'static str, Function)> {
+    vec![
+        (
+            "int",
+            Function::new(|argument| match argument {
+                Value::Int(x) => Ok(Value::Int(*x)),
+                argument => {
+                    let x = argument.as_number()?.trunc();
+                    // i64::MAX is not exact as a float, the bound has to be excluded
+                    if x.is_nan() || x < i64::MIN as f64 || x >= i64::MAX as f64 {
+                        return Err(EvalexprError::CustomMessage(format!(
+                            "{} does not fit into an integer",
+                            x
+                        )));
+                    }
+                    Ok(Value::Int(x as i64))
+                }
+            }),
+        ),
+        (
+            "float",
+            Function::new(|argument| Ok(Value::Float(argument.as_number()?))),
+        ),
+        (
+            "approx_eq",
+            Function::new(|argument| {
+                let args = arguments(argument);
+                let [a, b, absolute, relative] = args.as_slice() else {
+                    return Err(EvalexprError::wrong_function_argument_amount(args.len(), 4));
+                };
+                // Everything but numbers is still compared exactly
+                Ok(Value::Boolean(match (a.as_number(), b.as_number()) {
+                    (Ok(a), Ok(b)) => approx_eq(a, b, absolute.as_number()?, relative.as_number()?),
+                    _ => a == b,
+                }))
+            }),
+        ),
+        (
+            "bitand",
+            Function::new(|argument| {
+                let [x, y] = fixed_integers(argument)?;
+                exact(x & y)
+            }),
+        ),
+        (
+            "bitor",
+            Function::new(|argument| {
+                let [x, y] = fixed_integers(argument)?;
+                exact(x | y)
+            }),
+        ),
+        (
+            "bitxor",
+            Function::new(|argument| {
+                let [x, y] = fixed_integers(argument)?;
+                exact(x ^ y)
+            }),
+        ),
+        (
+            "bitnot",
+            Function::new(|argument| {
+                let [x] = fixed_integers(argument)?;
+                exact(!x)
+            }),
+        ),
+        (
+            "shl",
+            Function::new(|argument| {
+                let [x, by] = fixed_integers(argument)?;
+                shift(x, by, true)
+            }),
+        ),
+        (
+            "shr",
+            Function::new(|argument| {
+                let [x, by] = fixed_integers(argument)?;
+                shift(x, by, false)
+            }),
+        ),
+        (
+            "to_base",
+            Function::new(|argument| {
+                let [x, base] = fixed_integers(argument)?;
+                let base = radix(base)?;
+                let mut digits = Vec::new();
+                let mut rest = x.unsigned_abs();
+                loop {
+                    digits.push(std::char::from_digit((rest % base as u64) as u32, base).unwrap());
+                    rest /= base as u64;
+                    if rest == 0 {
+                        break;
+                    }
+                }
+                if x < 0 {
+                    digits.push('-');
+                }
+                Ok(Value::String(digits.iter().rev().collect()))
+            }),
+        ),
+        (
+            "from_base",
+            Function::new(|argument| {
+                let args = arguments(argument);
+                let [text, base] = args.as_slice() else {
+                    return Err(EvalexprError::wrong_function_argument_amount(args.len(), 2));
+                };
+                let base = radix(integer(base.as_number()?)?)?;
+                let x = i64::from_str_radix(&text.as_string()?, base).map_err(|e| {
+                    EvalexprError::CustomMessage(format!("invalid number {}: {}", text, e))
+                })?;
+                exact(x)
+            }),
+        ),
+        (
+            "crc32",
+            Function::new(|argument| {
+                Ok(Value::Float(crc32(argument.as_string()?.as_bytes()) as f64))
+            }),
+        ),
+        (
+            "sha256_prefix",
+            Function::new(|argument| {
+                // Six bytes are the most that stay exact as a float
+                let hash = Sha256::digest(argument.as_string()?.as_bytes());
+                let prefix = hash[..6].iter().fold(0u64, |x, byte| x << 8 | *byte as u64);
+                Ok(Value::Float(prefix as f64))
+            }),
+        ),
+    ]
+}
+
+fn excel_functions() -> Vec<(&'static str, Function)> {
+    vec![
+        (
+            "IF",
+            Function::new(|argument| {
+                let args = arguments(argument);
+                match args.as_slice() {
+                    [cond, if_true] => Ok(if truthy(cond)? {
+                        if_true.clone()
+                    } else {
+                        Value::Boolean(false)
+                    }),
+                    [cond, if_true, if_false] => Ok(if truthy(cond)? {
+                        if_true.clone()
+                    } else {
+                        if_false.clone()
+                    }),
+                    _ => Err(EvalexprError::wrong_function_argument_amount_range(
+                        args.len(),
+                        2..=3,
+                    )),
+                }
+            }),
+        ),
+        (
+            "AND",
+            Function::new(|argument| {
+                let args = arguments(argument);
+                Ok(Value::Boolean(
+                    args.iter()
+                        .map(truthy)
+                        .collect::<EvalexprResult<Vec<_>>>()?
+                        .iter()
+                        .all(|x| *x),
+                ))
+            }),
+        ),
+        (
+            "OR",
+            Function::new(|argument| {
+                let args = arguments(argument);
+                Ok(Value::Boolean(
+                    args.iter()
+                        .map(truthy)
+                        .collect::<EvalexprResult<Vec<_>>>()?
+                        .iter()
+                        .any(|x| *x),
+                ))
+            }),
+        ),
+        (
+            "NOT",
+            Function::new(|argument| Ok(Value::Boolean(!truthy(argument)?))),
+        ),
+        (
+            "ROUND",
+            Function::new(|argument| {
+                let [x, digits] = fixed_numbers(argument)?;
+                Ok(Value::Float(round_with(x, digits, f64::round)))
+            }),
+        ),
+        (
+            "ROUNDUP",
+            Function::new(|argument| {
+                let [x, digits] = fixed_numbers(argument)?;
+                Ok(Value::Float(round_with(x, digits, |x| {
+                    x.abs().ceil().copysign(x)
+                })))
+            }),
+        ),
+        (
+            "ROUNDDOWN",
+            Function::new(|argument| {
+                let [x, digits] = fixed_numbers(argument)?;
+                Ok(Value::Float(round_with(x, digits, f64::trunc)))
+            }),
+        ),
+        (
+            "INT",
+            Function::new(|argument| {
+                let [x] = fixed_numbers(argument)?;
+                Ok(Value::Float(x.floor()))
+            }),
+        ),
+        (
+            "MOD",
+            Function::new(|argument| {
+                let [x, y] = fixed_numbers(argument)?;
+                if y == 0. {
+                    return Err(EvalexprError::CustomMessage("MOD: division by zero".into()));
+                }
+                // The result takes the sign of the divisor
+                Ok(Value::Float(x - y * (x / y).floor()))
+            }),
+        ),
+        (
+            "ABS",
+            Function::new(|argument| {
+                let [x] = fixed_numbers(argument)?;
+                Ok(Value::Float(x.abs()))
+            }),
+        ),
+        (
+            "SQRT",
+            Function::new(|argument| {
+                let [x] = fixed_numbers(argument)?;
+                if x < 0. {
+                    return Err(EvalexprError::CustomMessage(
+                        "SQRT: negative argument".into(),
+                    ));
+                }
+                Ok(Value::Float(x.sqrt()))
+            }),
+        ),
+        (
+            "POWER",
+            Function::new(|argument| {
+                let [x, y] = fixed_numbers(argument)?;
+                Ok(Value::Float(x.powf(y)))
+            }),
+        ),
+        (
+            "SUM",
+            Function::new(|argument| Ok(Value::Float(numbers(argument)?.iter().sum()))),
+        ),
+        (
+            "AVERAGE",
+            Function::new(|argument| {
+                let numbers = non_empty_numbers(argument)?;
+                Ok(Value::Float(
+                    numbers.iter().sum::<f64>() / numbers.len() as f64,
+                ))
+            }),
+        ),
+        (
+            "MIN",
+            Function::new(|argument| {
+                let numbers = non_empty_numbers(argument)?;
+                Ok(Value::Float(
+                    numbers.into_iter().fold(f64::INFINITY, f64::min),
+                ))
+            }),
+        ),
+        (
+            "MAX",
+            Function::new(|argument| {
+                let numbers = non_empty_numbers(argument)?;
+                Ok(Value::Float(
+                    numbers.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                ))
+            }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evalexpr::{build_operator_tree, ContextWithMutableVariables};
+
+    #[test]
+    fn test_excel_functions() {
+        let mut context = FunctionSet::Excel.context().unwrap();
+        context.set_value("$0".into(), Value::Float(-3.)).unwrap();
+
+        let eval = |formula: &str| {
+            build_operator_tree(formula)
+                .unwrap()
+                .eval_float_with_context(&context)
+                .unwrap()
+        };
+
+        assert_eq!(eval("ROUND(2.675, 2)"), 2.68);
+        assert_eq!(eval("ROUND(-2.5, 0)"), -3.);
+        assert_eq!(eval("ROUNDUP(-2.01, 1)"), -2.1);
+        assert_eq!(eval("MOD($0, 2)"), 1.);
+        assert_eq!(eval("IF($0 < 0, SUM(1, 2, $0), 1.5)"), 0.);
+        assert_eq!(eval("AVERAGE(1, 2, 6)"), 3.);
+
+        let context = FunctionSet::Evalexpr.context().unwrap();
+        assert!(build_operator_tree("SUM(1, 2)")
+            .unwrap()
+            .eval_with_context(&context)
+            .is_err());
+    }
+
+    #[test]
+    fn test_common_functions() {
+        let mut context = FunctionSet::Evalexpr.context().unwrap();
+        context.set_value("$0".into(), Value::Float(12.)).unwrap();
+
+        let eval = |formula: &str| {
+            build_operator_tree(formula)
+                .unwrap()
+                .eval_with_context(&context)
+        };
+
+        assert_eq!(eval("int(-2.7)").unwrap(), Value::Int(-2));
+        assert_eq!(
+            eval("approx_eq(0.1 + 0.2, 0.3, 1e-12, 0)").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("approx_eq(100, 101, 0, 0.01)").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("approx_eq(1, 1.1, 0.01, 0)").unwrap(),
+            Value::Boolean(false)
+        );
+        assert_eq!(eval("float(int($0))").unwrap(), Value::Float(12.));
+        assert!(eval("int(1e19)").is_err());
+        assert_eq!(eval("bitand($0, 10)").unwrap(), Value::Float(8.));
+        assert_eq!(eval("bitor($0, 3)").unwrap(), Value::Float(15.));
+        assert_eq!(eval("bitxor($0, 4)").unwrap(), Value::Float(8.));
+        assert_eq!(eval("shl($0, 2)").unwrap(), Value::Float(48.));
+        assert_eq!(eval("shr($0, 2)").unwrap(), Value::Float(3.));
+        assert!(eval("bitand(1.5, 1)").is_err());
+        assert!(eval("shl($0, 60)").is_err());
+
+        assert_eq!(eval("to_base(255, 16)").unwrap(), Value::from("ff"));
+        assert_eq!(eval("to_base(-5, 2)").unwrap(), Value::from("-101"));
+        assert_eq!(eval("from_base(\"ff\", 16)").unwrap(), Value::Float(255.));
+        assert!(eval("from_base(\"fg\", 16)").is_err());
+
+        assert_eq!(
+            eval("crc32(\"123456789\")").unwrap(),
+            Value::Float(0xcbf43926u32 as f64)
+        );
+        assert_eq!(
+            eval("sha256_prefix(\"abc\")").unwrap(),
+            Value::Float(0xba7816bf8f01u64 as f64)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::core::{EvalCache, NodeId, NodeOutput, Tree};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Group {
+    pub key: f64,
+    pub output: NodeOutput,
+}
+
+impl Tree {
+    // Evaluates `node_id` once per distinct value of the key array. Every variable with as many
+    // elements as the key is cut down to the rows of the group, other values are passed as they
+    // are. Groups are returned in the order their keys first appear.
+    pub fn group_apply(
+        &self,
+        node_id: NodeId,
+        key_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<Vec<Group>> {
+        let keys = match values.get(&key_id) {
+            Some(NodeOutput::NumberArray(keys)) | Some(NodeOutput::Series { values: keys, .. }) => {
+                keys
+            }
+            Some(NodeOutput::Number(key)) => {
+                return Ok(vec![Group {
+                    key: *key,
+                    output: self.eval(node_id, values)?,
+                }]);
+            }
+            Some(NodeOutput::Integer(key)) => {
+                return Ok(vec![Group {
+                    key: *key as f64,
+                    output: self.eval(node_id, values)?,
+                }]);
+            }
+            Some(_) => return Err(anyhow!("the keys of node {} are not numbers", key_id)),
+            None => return Err(anyhow!("missing key values for node {}", key_id)),
+        };
+
+        let mut rows: Vec<(f64, Vec<usize>)> = Vec::new();
+        for (idx, key) in keys.iter().enumerate() {
+            match rows.iter_mut().find(|(x, _)| x.total_cmp(key).is_eq()) {
+                Some((_, group_rows)) => group_rows.push(idx),
+                None => rows.push((*key, vec![idx])),
+            }
+        }
+
+        let mut groups = Vec::with_capacity(rows.len());
+        for (key, group_rows) in rows {
+            let group_values = values
+                .iter()
+                .map(|(id, value)| {
+                    let value = match value {
+                        NodeOutput::NumberArray(v) if v.len() == keys.len() => {
+                            match group_rows.as_slice() {
+                                [row] => NodeOutput::Number(v[*row]),
+                                _ => NodeOutput::NumberArray(
+                                    group_rows.iter().map(|row| v[*row]).collect(),
+                                ),
+                            }
+                        }
+                        NodeOutput::Series { name, values: v } if v.len() == keys.len() => {
+                            match group_rows.as_slice() {
+                                [row] => NodeOutput::Number(v[*row]),
+                                _ => NodeOutput::Series {
+                                    name: name.clone(),
+                                    values: group_rows.iter().map(|row| v[*row]).collect(),
+                                },
+                            }
+                        }
+                        NodeOutput::BoolArray(v) if v.len() == keys.len() => {
+                            match group_rows.as_slice() {
+                                [row] => NodeOutput::Bool(v[*row]),
+                                _ => NodeOutput::BoolArray(
+                                    group_rows.iter().map(|row| v[*row]).collect(),
+                                ),
+                            }
+                        }
+                        NodeOutput::StringArray(v) if v.len() == keys.len() => {
+                            match group_rows.as_slice() {
+                                [row] => NodeOutput::String(v[*row].clone()),
+                                _ => NodeOutput::StringArray(
+                                    group_rows.iter().map(|row| v[*row].clone()).collect(),
+                                ),
+                            }
+                        }
+                        value => value.clone(),
+                    };
+                    (*id, value)
+                })
+                .collect();
+
+            groups.push(Group {
+                key,
+                output: self.eval(node_id, &group_values)?,
+            });
+        }
+        Ok(groups)
+    }
+
+    // Slides a window of `size` elements over `series` one step at a time and evaluates
+    // `node_id` with the window set as the array of the variable `window_id`
+    pub fn window_apply(
+        &self,
+        node_id: NodeId,
+        window_id: NodeId,
+        series: &[f64],
+        size: usize,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<Vec<NodeOutput>> {
+        if size == 0 {
+            return Err(anyhow!("windows need at least one element"));
+        }
+
+        let mut values = values.clone();
+        let mut cache = EvalCache::new();
+        let mut outputs = Vec::new();
+        for window in series.windows(size) {
+            values.insert(window_id, NodeOutput::NumberArray(window.to_vec()));
+            cache.invalidate(self, &[window_id]);
+            outputs.push(self.eval_cached(node_id, &values, &mut cache)?);
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_group_apply() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "batch".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "x".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 0,
+                value: "factor".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$1 * $2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![2., 1., 2., 3.])),
+            (1, NodeOutput::NumberArray(vec![1., 2., 3., 4.])),
+            (2, NodeOutput::Number(10.)),
+        ]);
+
+        let groups = tree.group_apply(3, 0, &values).unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                Group {
+                    key: 2.,
+                    output: NodeOutput::NumberArray(vec![10., 30.]),
+                },
+                Group {
+                    key: 1.,
+                    output: NodeOutput::Number(20.),
+                },
+                Group {
+                    key: 3.,
+                    output: NodeOutput::Number(40.),
+                },
+            ]
+        );
+        assert!(tree.group_apply(3, 5, &values).is_err());
+    }
+
+    #[test]
+    fn test_window_apply() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "window".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let series = [1., 2., 3., 4.];
+
+        let outputs = tree
+            .window_apply(1, 0, &series, 3, &HashMap::new())
+            .unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                NodeOutput::NumberArray(vec![2., 4., 6.]),
+                NodeOutput::NumberArray(vec![4., 6., 8.]),
+            ]
+        );
+        assert!(tree
+            .window_apply(1, 0, &series, 5, &HashMap::new())
+            .unwrap()
+            .is_empty());
+        assert!(tree
+            .window_apply(1, 0, &series, 0, &HashMap::new())
+            .is_err());
+    }
+}
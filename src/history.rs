@@ -0,0 +1,497 @@
+use anyhow::{anyhow, Result};
+use evalexpr::build_operator_tree;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{EdgeDefinition, NodeDefinition, NodeId, TreeDraft};
+use crate::permissions::EditContext;
+
+// A node with everything removing it takes along, at the positions they had in the draft
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub idx: usize,
+    pub node_def: NodeDefinition,
+    pub edges: Vec<(usize, EdgeDefinition)>,
+    pub condition: Option<String>,
+    pub owner: Option<String>,
+}
+
+// One change of a draft, holding enough to take it back
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum Edit {
+    AddNode(NodeSnapshot),
+    RemoveNode(NodeSnapshot),
+    SetValue {
+        node_id: NodeId,
+        before: String,
+        after: String,
+    },
+    Connect {
+        idx: usize,
+        edge_def: EdgeDefinition,
+    },
+    Disconnect {
+        idx: usize,
+        edge_def: EdgeDefinition,
+    },
+}
+
+impl Edit {
+    // Checked against the owners of the nodes it changes like the edits of a `DraftEditor`, and
+    // either applied as a whole or not at all
+    pub fn apply(&self, draft: &mut TreeDraft, context: &EditContext) -> Result<()> {
+        match self {
+            Edit::AddNode(snapshot) => {
+                let node_id = snapshot.node_def.node_id;
+                check_add(draft, context, snapshot)?;
+                draft.insert_node(snapshot.idx, snapshot.node_def.clone())?;
+                for (idx, edge_def) in &snapshot.edges {
+                    draft.insert_edge(*idx, edge_def.clone())?;
+                }
+                if let Some(condition) = &snapshot.condition {
+                    draft.set_condition(node_id, condition)?;
+                }
+                draft.set_owner(node_id, snapshot.owner.as_deref())
+            }
+            Edit::RemoveNode(snapshot) => {
+                let node_id = snapshot.node_def.node_id;
+                check(draft, context, node_id)?;
+                for edge_def in draft.edge_definitions().iter() {
+                    if edge_def.input_id == node_id {
+                        check(draft, context, edge_def.node_id)?;
+                    }
+                }
+                draft.remove_node(node_id)?;
+                Ok(())
+            }
+            Edit::SetValue {
+                node_id,
+                before,
+                after,
+            } => {
+                check(draft, context, *node_id)?;
+                let node_def = draft
+                    .node_definitions()
+                    .iter()
+                    .find(|x| x.node_id == *node_id)
+                    .ok_or(anyhow!("no node with id {}", node_id))?;
+                if &node_def.value != before {
+                    return Err(anyhow!(
+                        "node with id {} has the value {:?} instead of {:?}",
+                        node_id,
+                        node_def.value,
+                        before
+                    ));
+                }
+                draft.set_value(*node_id, after.clone())
+            }
+            Edit::Connect { idx, edge_def } => {
+                check(draft, context, edge_def.node_id)?;
+                draft.insert_edge(*idx, edge_def.clone())
+            }
+            Edit::Disconnect { idx, edge_def } => {
+                check(draft, context, edge_def.node_id)?;
+                draft.remove_edge(*idx, edge_def)
+            }
+        }
+    }
+
+    pub fn revert(&self, draft: &mut TreeDraft, context: &EditContext) -> Result<()> {
+        self.inverse().apply(draft, context)
+    }
+
+    pub fn inverse(&self) -> Edit {
+        match self {
+            Edit::AddNode(snapshot) => Edit::RemoveNode(snapshot.clone()),
+            Edit::RemoveNode(snapshot) => Edit::AddNode(snapshot.clone()),
+            Edit::SetValue {
+                node_id,
+                before,
+                after,
+            } => Edit::SetValue {
+                node_id: *node_id,
+                before: after.clone(),
+                after: before.clone(),
+            },
+            Edit::Connect { idx, edge_def } => Edit::Disconnect {
+                idx: *idx,
+                edge_def: edge_def.clone(),
+            },
+            Edit::Disconnect { idx, edge_def } => Edit::Connect {
+                idx: *idx,
+                edge_def: edge_def.clone(),
+            },
+        }
+    }
+}
+
+fn check(draft: &TreeDraft, context: &EditContext, node_id: NodeId) -> Result<()> {
+    if !draft.has_node(node_id) {
+        return Err(anyhow!("no node with id {}", node_id));
+    }
+    context.check(node_id, draft.owner(node_id))
+}
+
+// Everything adding the node back can fail on, before any of it is added
+fn check_add(draft: &TreeDraft, context: &EditContext, snapshot: &NodeSnapshot) -> Result<()> {
+    let node_id = snapshot.node_def.node_id;
+    if draft.has_node(node_id) {
+        return Err(anyhow!("node with id {} already exists", node_id));
+    }
+    if snapshot.idx > draft.node_definitions().len() {
+        return Err(anyhow!("no node position {}", snapshot.idx));
+    }
+    context.check(node_id, snapshot.owner.as_deref())?;
+    for (i, (idx, edge_def)) in snapshot.edges.iter().enumerate() {
+        if *idx > draft.edge_definitions().len() + i {
+            return Err(anyhow!("no edge position {}", idx));
+        }
+        if edge_def.node_id != node_id && edge_def.input_id != node_id {
+            return Err(anyhow!(
+                "the edge from {} to {} does not belong to node {}",
+                edge_def.input_id,
+                edge_def.node_id,
+                node_id
+            ));
+        }
+        for id in [edge_def.node_id, edge_def.input_id] {
+            if id != node_id && !draft.has_node(id) {
+                return Err(anyhow!("no node with id {}", id));
+            }
+        }
+        if edge_def.node_id != node_id {
+            check(draft, context, edge_def.node_id)?;
+        }
+    }
+    if let Some(condition) = &snapshot.condition {
+        build_operator_tree(condition)?;
+    }
+    Ok(())
+}
+
+// The edits made to a draft for undo and redo. The log keeps every change in the order it
+// happened, an undone edit as its inverse, e.g. to store it for audits.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    log: Vec<Edit>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A new edit drops the undone ones
+    pub fn apply(
+        &mut self,
+        draft: &mut TreeDraft,
+        context: &EditContext,
+        edit: Edit,
+    ) -> Result<()> {
+        edit.apply(draft, context)?;
+        self.redo.clear();
+        self.log.push(edit.clone());
+        self.undo.push(edit);
+        Ok(())
+    }
+
+    // Returns false if there is nothing to undo
+    pub fn undo(&mut self, draft: &mut TreeDraft, context: &EditContext) -> Result<bool> {
+        let Some(edit) = self.undo.pop() else {
+            return Ok(false);
+        };
+        if let Err(err) = edit.revert(draft, context) {
+            self.undo.push(edit);
+            return Err(err);
+        }
+        self.log.push(edit.inverse());
+        self.redo.push(edit);
+        Ok(true)
+    }
+
+    // Returns false if there is nothing to redo
+    pub fn redo(&mut self, draft: &mut TreeDraft, context: &EditContext) -> Result<bool> {
+        let Some(edit) = self.redo.pop() else {
+            return Ok(false);
+        };
+        if let Err(err) = edit.apply(draft, context) {
+            self.redo.push(edit);
+            return Err(err);
+        }
+        self.log.push(edit.clone());
+        self.undo.push(edit);
+        Ok(true)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    // The log since the last call
+    pub fn take_log(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.log)
+    }
+
+    pub fn add_node(
+        &mut self,
+        draft: &mut TreeDraft,
+        context: &EditContext,
+        node_def: NodeDefinition,
+    ) -> Result<()> {
+        let edit = Edit::AddNode(NodeSnapshot {
+            idx: draft.node_definitions().len(),
+            node_def,
+            edges: Vec::new(),
+            condition: None,
+            owner: None,
+        });
+        self.apply(draft, context, edit)
+    }
+
+    pub fn remove_node(
+        &mut self,
+        draft: &mut TreeDraft,
+        context: &EditContext,
+        node_id: NodeId,
+    ) -> Result<()> {
+        let idx = draft
+            .node_definitions()
+            .iter()
+            .position(|x| x.node_id == node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))?;
+        let edges = draft
+            .edge_definitions()
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.node_id == node_id || x.input_id == node_id)
+            .map(|(idx, x)| (idx, x.clone()))
+            .collect();
+        let edit = Edit::RemoveNode(NodeSnapshot {
+            idx,
+            node_def: draft.node_definitions()[idx].clone(),
+            edges,
+            condition: draft.condition(node_id).map(|x| x.to_string()),
+            owner: draft.owner(node_id).map(|x| x.to_string()),
+        });
+        self.apply(draft, context, edit)
+    }
+
+    pub fn set_value(
+        &mut self,
+        draft: &mut TreeDraft,
+        context: &EditContext,
+        node_id: NodeId,
+        value: String,
+    ) -> Result<()> {
+        let node_def = draft
+            .node_definitions()
+            .iter()
+            .find(|x| x.node_id == node_id)
+            .ok_or(anyhow!("no node with id {}", node_id))?;
+        let edit = Edit::SetValue {
+            node_id,
+            before: node_def.value.clone(),
+            after: value,
+        };
+        self.apply(draft, context, edit)
+    }
+
+    pub fn connect(
+        &mut self,
+        draft: &mut TreeDraft,
+        context: &EditContext,
+        node_id: NodeId,
+        input_id: NodeId,
+    ) -> Result<()> {
+        let edit = Edit::Connect {
+            idx: draft.edge_definitions().len(),
+            edge_def: EdgeDefinition { node_id, input_id },
+        };
+        self.apply(draft, context, edit)
+    }
+
+    pub fn disconnect(
+        &mut self,
+        draft: &mut TreeDraft,
+        context: &EditContext,
+        node_id: NodeId,
+        input_id: NodeId,
+    ) -> Result<()> {
+        let idx = draft
+            .edge_definitions()
+            .iter()
+            .position(|x| x.node_id == node_id && x.input_id == input_id)
+            .ok_or(anyhow!("no edge from {} to {}", input_id, node_id))?;
+        let edit = Edit::Disconnect {
+            idx,
+            edge_def: EdgeDefinition { node_id, input_id },
+        };
+        self.apply(draft, context, edit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::NodeOutput;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_history() {
+        let mut draft = TreeDraft::new();
+        let mut history = History::new();
+        let context = EditContext::default();
+        for (node_id, kind, value) in [(0, 0, "a"), (1, 0, "b"), (2, 1, "$0 + $1")] {
+            history
+                .add_node(
+                    &mut draft,
+                    &context,
+                    NodeDefinition {
+                        node_id,
+                        kind,
+                        value: value.into(),
+                        default: None,
+                    },
+                )
+                .unwrap();
+        }
+        history.connect(&mut draft, &context, 2, 0).unwrap();
+        history.connect(&mut draft, &context, 2, 1).unwrap();
+        draft.set_condition(0, "true").unwrap();
+        let before = draft.clone();
+
+        history
+            .set_value(&mut draft, &context, 2, "$1 * 2".into())
+            .unwrap();
+        history.remove_node(&mut draft, &context, 0).unwrap();
+        assert!(history.connect(&mut draft, &context, 2, 0).is_err());
+        let after = draft.clone();
+        let values = HashMap::from([(1, NodeOutput::Number(3.))]);
+        assert_eq!(
+            after.clone().freeze().unwrap().eval(2, &values).unwrap(),
+            NodeOutput::Number(6.)
+        );
+
+        assert!(history.undo(&mut draft, &context).unwrap());
+        assert!(history.undo(&mut draft, &context).unwrap());
+        assert_eq!(draft, before);
+        assert!(history.redo(&mut draft, &context).unwrap());
+        assert!(history.redo(&mut draft, &context).unwrap());
+        assert!(!history.redo(&mut draft, &context).unwrap());
+        assert_eq!(draft, after);
+
+        // Replaying the log from an empty draft ends where the draft is, the condition was set
+        // outside of the history
+        let mut replayed = TreeDraft::new();
+        for edit in history.take_log() {
+            edit.apply(&mut replayed, &context).unwrap();
+        }
+        assert_eq!(replayed.node_definitions(), draft.node_definitions());
+        assert_eq!(replayed.edge_definitions(), draft.edge_definitions());
+        assert!(history.take_log().is_empty());
+
+        history.undo(&mut draft, &context).unwrap();
+        history.disconnect(&mut draft, &context, 2, 0).unwrap();
+        assert!(!history.can_redo());
+        while history.undo(&mut draft, &context).unwrap() {}
+        assert_eq!(draft, TreeDraft::new());
+        assert_eq!(history.take_log().len(), 9);
+    }
+
+    #[test]
+    fn test_history_checks() {
+        let mut draft = TreeDraft::new();
+        let mut history = History::new();
+        let admin = EditContext::new(&["admin"]);
+        let guest = EditContext::default();
+        for (node_id, kind, value) in [(0, 0, "a"), (1, 1, "$0 * 2")] {
+            history
+                .add_node(
+                    &mut draft,
+                    &admin,
+                    NodeDefinition {
+                        node_id,
+                        kind,
+                        value: value.into(),
+                        default: None,
+                    },
+                )
+                .unwrap();
+        }
+        history.connect(&mut draft, &admin, 1, 0).unwrap();
+        draft.editor(&admin).set_owner(1, Some("admin")).unwrap();
+
+        // Adding a node back either adds all of it or nothing
+        let before = draft.clone();
+        let snapshot = NodeSnapshot {
+            idx: 2,
+            node_def: NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+            edges: vec![
+                (
+                    1,
+                    EdgeDefinition {
+                        node_id: 2,
+                        input_id: 1,
+                    },
+                ),
+                (
+                    2,
+                    EdgeDefinition {
+                        node_id: 2,
+                        input_id: 5,
+                    },
+                ),
+            ],
+            condition: None,
+            owner: None,
+        };
+        assert!(Edit::AddNode(snapshot.clone())
+            .apply(&mut draft, &admin)
+            .is_err());
+        let condition = Some("(a".to_string());
+        assert!(Edit::AddNode(NodeSnapshot {
+            edges: snapshot.edges[..1].to_vec(),
+            condition,
+            ..snapshot.clone()
+        })
+        .apply(&mut draft, &admin)
+        .is_err());
+        assert_eq!(draft, before);
+
+        // A value is only replaced if it is still the one the edit was made on
+        let edit = Edit::SetValue {
+            node_id: 0,
+            before: "b".into(),
+            after: "c".into(),
+        };
+        assert!(edit.apply(&mut draft, &admin).is_err());
+        assert_eq!(draft, before);
+
+        // Undo and redo need the roles of the nodes they change
+        history
+            .set_value(&mut draft, &admin, 1, "$0 * 3".into())
+            .unwrap();
+        assert!(history
+            .set_value(&mut draft, &guest, 1, "$0".into())
+            .is_err());
+        assert!(history.undo(&mut draft, &guest).is_err());
+        assert!(history.undo(&mut draft, &admin).unwrap());
+        assert!(history.redo(&mut draft, &guest).is_err());
+        assert!(history.can_redo());
+        assert!(history.remove_node(&mut draft, &guest, 0).is_err());
+        history.remove_node(&mut draft, &admin, 1).unwrap();
+        assert!(history.undo(&mut draft, &guest).is_err());
+        assert!(history.undo(&mut draft, &admin).unwrap());
+        assert_eq!(draft, before);
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::Result;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use crate::core::{NodeId, NodeOutput, Tree};
+
+// Integration code running around every evaluation of a tree, e.g. to refresh variables before
+// or to publish the results after it. An error of a hook fails the evaluation.
+pub trait EvalHook: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Gets the nodes about to be evaluated and may add or replace values
+    fn before(
+        &self,
+        _node_ids: &[NodeId],
+        _values: &mut HashMap<NodeId, NodeOutput>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn after(&self, _node_id: NodeId, _output: &NodeOutput) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<Arc<dyn EvalHook>>);
+
+impl Hooks {
+    // The values are only copied if there is a hook to change them
+    pub(crate) fn before<'a>(
+        &self,
+        node_ids: &[NodeId],
+        values: &'a HashMap<NodeId, NodeOutput>,
+    ) -> Result<Cow<'a, HashMap<NodeId, NodeOutput>>> {
+        if self.0.is_empty() {
+            return Ok(Cow::Borrowed(values));
+        }
+        let mut values = values.clone();
+        for hook in &self.0 {
+            hook.before(node_ids, &mut values)?;
+        }
+        Ok(Cow::Owned(values))
+    }
+
+    pub(crate) fn after(&self, node_id: NodeId, output: &NodeOutput) -> Result<()> {
+        for hook in &self.0 {
+            hook.after(node_id, output)?;
+        }
+        Ok(())
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|x| x.name()).collect()
+    }
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl PartialEq for Hooks {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+
+impl Tree {
+    // Hooks run in the order they were added, around every evaluation of the tree
+    pub fn with_hook(mut self, hook: impl EvalHook + 'static) -> Self {
+        self.hooks.0.push(Arc::new(hook));
+        self
+    }
+
+    // Runs the hooks around `eval` evaluating `node_id`
+    pub(crate) fn run_hooks(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        eval: impl FnOnce(&HashMap<NodeId, NodeOutput>) -> Result<NodeOutput>,
+    ) -> Result<NodeOutput> {
+        let values = self.hooks.before(&[node_id], values)?;
+        let output = eval(&values)?;
+        self.hooks.after(node_id, &output)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+    use anyhow::anyhow;
+    use std::sync::Mutex;
+
+    struct Refresh;
+
+    impl EvalHook for Refresh {
+        fn name(&self) -> &str {
+            "refresh"
+        }
+
+        fn before(
+            &self,
+            _node_ids: &[NodeId],
+            values: &mut HashMap<NodeId, NodeOutput>,
+        ) -> Result<()> {
+            values.entry(0).or_insert(NodeOutput::Number(1.));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Publish(Arc<Mutex<Vec<(NodeId, NodeOutput)>>>);
+
+    impl EvalHook for Publish {
+        fn name(&self) -> &str {
+            "publish"
+        }
+
+        fn after(&self, node_id: NodeId, output: &NodeOutput) -> Result<()> {
+            if *output == NodeOutput::Number(0.) {
+                return Err(anyhow!("node {} is zero", node_id));
+            }
+            self.0.lock().unwrap().push((node_id, output.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let tree = Tree::new(node_defs, edge_defs)
+            .unwrap()
+            .with_hook(Refresh)
+            .with_hook(Publish(Arc::clone(&published)));
+        assert_eq!(format!("{:?}", tree.hooks), r#"["refresh", "publish"]"#);
+
+        assert_eq!(
+            tree.eval(1, &HashMap::new()).unwrap(),
+            NodeOutput::Number(2.)
+        );
+        let values = HashMap::from([(0, NodeOutput::Number(3.))]);
+        tree.eval_many(&[0, 1], &values).unwrap();
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![
+                (1, NodeOutput::Number(2.)),
+                (0, NodeOutput::Number(3.)),
+                (1, NodeOutput::Number(6.))
+            ]
+        );
+
+        let values = HashMap::from([(0, NodeOutput::Number(0.))]);
+        assert!(tree.eval(1, &values).is_err());
+
+        // The other evaluations run the hooks as well
+        published.lock().unwrap().clear();
+        assert!(tree.eval_parallel(1, &values).is_err());
+        assert_eq!(
+            tree.eval_parallel(1, &HashMap::new()).unwrap(),
+            NodeOutput::Number(2.)
+        );
+        let outputs = tree.eval_all(1, &HashMap::new()).unwrap();
+        assert_eq!(outputs[&0], NodeOutput::Number(1.));
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![(1, NodeOutput::Number(2.)), (1, NodeOutput::Number(2.))]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::core::{EvalCache, NodeId, NodeKind, NodeOutput, Tree};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Convergence {
+    pub tolerance: f64,
+    pub max_iterations: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FixedPoint {
+    pub output: NodeOutput,
+    pub iterations: usize,
+}
+
+fn max_change(previous: &NodeOutput, output: &NodeOutput) -> Result<f64> {
+    match (previous, output) {
+        (NodeOutput::Number(a), NodeOutput::Number(b)) => Ok((a - b).abs()),
+        (NodeOutput::Integer(a), NodeOutput::Integer(b)) => Ok(a.abs_diff(*b) as f64),
+        (NodeOutput::Custom(_), NodeOutput::Custom(_))
+        | (NodeOutput::Bool(_), NodeOutput::Bool(_))
+        | (NodeOutput::String(_), NodeOutput::String(_))
+        | (NodeOutput::BoolArray(_), NodeOutput::BoolArray(_))
+        | (NodeOutput::StringArray(_), NodeOutput::StringArray(_)) => Ok(if previous == output {
+            0.
+        } else {
+            f64::INFINITY
+        }),
+        (NodeOutput::NumberArray(a), NodeOutput::NumberArray(b)) if a.len() == b.len() => Ok(a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f64::max)),
+        (
+            NodeOutput::Series {
+                name: a_name,
+                values: a,
+            },
+            NodeOutput::Series {
+                name: b_name,
+                values: b,
+            },
+        ) if a_name == b_name && a.len() == b.len() => Ok(a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f64::max)),
+        (NodeOutput::TimeSeries(a), NodeOutput::TimeSeries(b))
+            if a.len() == b.len() && a.iter().zip(b).all(|((s, _), (t, _))| s == t) =>
+        {
+            Ok(a.iter()
+                .zip(b)
+                .map(|((_, a), (_, b))| (a - b).abs())
+                .fold(0., f64::max))
+        }
+        (NodeOutput::Matrix(a), NodeOutput::Matrix(b))
+            if a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.len() == b.len()) =>
+        {
+            Ok(a.iter()
+                .flatten()
+                .zip(b.iter().flatten())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0., f64::max))
+        }
+        _ => Err(anyhow!("the fed back output changed its shape")),
+    }
+}
+
+impl Tree {
+    // Evaluates `node_id` and feeds its output back into the variable `feedback_id` until the
+    // largest change of an element drops below the tolerance. `values` holds the start value.
+    pub fn fixed_point(
+        &self,
+        node_id: NodeId,
+        feedback_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        convergence: Convergence,
+    ) -> Result<FixedPoint> {
+        if !matches!(self.node(feedback_id)?.kind(), NodeKind::Variable(_)) {
+            return Err(anyhow!("feedback node {} is not a variable", feedback_id));
+        }
+        if !self.leaves(node_id)?.contains(&feedback_id) {
+            return Err(anyhow!(
+                "node {} does not depend on the feedback node {}",
+                node_id,
+                feedback_id
+            ));
+        }
+
+        let mut values = values.clone();
+        let mut previous = values
+            .get(&feedback_id)
+            .ok_or(anyhow!("missing start value for node {}", feedback_id))?
+            .clone();
+        let mut cache = EvalCache::new();
+        let mut change = f64::INFINITY;
+        for iteration in 1..=convergence.max_iterations {
+            let output = self.eval_cached(node_id, &values, &mut cache)?;
+            change = max_change(&previous, &output)?;
+            if change < convergence.tolerance {
+                return Ok(FixedPoint {
+                    output,
+                    iterations: iteration,
+                });
+            }
+
+            // Only the nodes depending on the feedback have to be evaluated again
+            cache.invalidate(self, &[feedback_id]);
+            values.insert(feedback_id, output.clone());
+            previous = output;
+        }
+
+        Err(anyhow!(
+            "no convergence after {} iterations, the last change was {}",
+            convergence.max_iterations,
+            change
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_fixed_point() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "x".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "scale".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "math::cos($0) * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(1.)), (1, NodeOutput::Number(1.))]);
+
+        let convergence = Convergence {
+            tolerance: 1e-9,
+            max_iterations: 100,
+        };
+        let result = tree.fixed_point(2, 0, &values, convergence).unwrap();
+        let NodeOutput::Number(x) = result.output else {
+            unreachable!()
+        };
+        assert!((x - 0.7390851332).abs() < 1e-8);
+        assert!(result.iterations > 1);
+
+        let convergence = Convergence {
+            tolerance: 1e-9,
+            max_iterations: 3,
+        };
+        assert!(tree.fixed_point(2, 0, &values, convergence).is_err());
+        assert!(tree.fixed_point(2, 2, &values, convergence).is_err());
+    }
+}
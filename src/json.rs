@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::core::NodeOutput;
+use crate::registry::{CustomNode, NodeFactory};
+
+// Extracts a number or an array of numbers from a JSON document, e.g. a response held by a
+// variable. The node value is a JSON pointer like `/data/0/price`, empty for the whole document.
+// Nulls are missing values.
+pub struct JsonPointerFactory;
+
+impl NodeFactory for JsonPointerFactory {
+    fn name(&self) -> &str {
+        "json_pointer"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        if !value.is_empty() && !value.starts_with('/') {
+            return Err(anyhow!("the json pointer {} has to start with /", value));
+        }
+        Ok(Box::new(JsonPointer {
+            pointer: value.to_string(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct JsonPointer {
+    pointer: String,
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Null => Some(f64::NAN),
+        value => value.as_f64(),
+    }
+}
+
+impl CustomNode for JsonPointer {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        let [NodeOutput::String(document)] = inputs else {
+            return Err(anyhow!("json pointer takes a single json document"));
+        };
+        let document: Value = serde_json::from_str(document)?;
+        let value = document
+            .pointer(&self.pointer)
+            .ok_or(anyhow!("the document has no value at {}", self.pointer))?;
+
+        if let Value::Array(values) = value {
+            let values: Option<Vec<_>> = values.iter().map(number).collect();
+            return values.map(NodeOutput::NumberArray).ok_or(anyhow!(
+                "the array at {} does not only hold numbers",
+                self.pointer
+            ));
+        }
+        number(value)
+            .map(NodeOutput::Number)
+            .ok_or(anyhow!("the value at {} is not a number", self.pointer))
+    }
+
+    fn value(&self) -> String {
+        self.pointer.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, Tree};
+    use crate::registry::NodeRegistry;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_json_pointer() {
+        let tree = |pointer: &str| {
+            let mut registry = NodeRegistry::new();
+            registry.register(10, JsonPointerFactory).unwrap();
+            let node_defs = vec![
+                NodeDefinition {
+                    node_id: 0,
+                    kind: 0,
+                    value: "response".into(),
+                    default: None,
+                },
+                NodeDefinition {
+                    node_id: 1,
+                    kind: 10,
+                    value: pointer.into(),
+                    default: None,
+                },
+                NodeDefinition {
+                    node_id: 2,
+                    kind: 1,
+                    value: "$1 * 2".into(),
+                    default: None,
+                },
+            ];
+            let edge_defs = [(1, 0), (2, 1)]
+                .map(|(node_id, input_id)| EdgeDefinition { node_id, input_id })
+                .to_vec();
+            Tree::new_with_registry(node_defs, edge_defs, registry)
+        };
+        let document = r#"{
+            "data": [{"price": 2.5, "volume": 3}, {"price": null}],
+            "prices": [1, 2.5, null],
+            "name": "a/b",
+            "a/b": {"~": 4}
+        }"#;
+        let values = HashMap::from([(0, NodeOutput::String(document.into()))]);
+        let eval = |pointer: &str, node_id| tree(pointer)?.eval(node_id, &values);
+
+        assert_eq!(eval("/data/0/price", 2).unwrap(), NodeOutput::Number(5.));
+        assert_eq!(eval("/data/0/volume", 1).unwrap(), NodeOutput::Number(3.));
+        assert_eq!(eval("/a~1b/~0", 1).unwrap(), NodeOutput::Number(4.));
+        let NodeOutput::Number(missing) = eval("/data/1/price", 1).unwrap() else {
+            panic!("not a number");
+        };
+        assert!(missing.is_nan());
+        let NodeOutput::NumberArray(prices) = eval("/prices", 1).unwrap() else {
+            panic!("not an array");
+        };
+        assert_eq!(prices[..2], [1., 2.5]);
+        assert!(prices[2].is_nan());
+
+        assert!(eval("/data/2/price", 1).is_err());
+        assert!(eval("/name", 1).is_err());
+        assert!(eval("/data", 1).is_err());
+        assert!(eval("", 1).is_err());
+        assert!(tree("data").is_err());
+
+        let text = HashMap::from([(0, NodeOutput::String("{".into()))]);
+        assert!(tree("/data").unwrap().eval(1, &text).is_err());
+        let number = HashMap::from([(0, NodeOutput::Number(1.))]);
+        assert!(tree("/data").unwrap().eval(1, &number).is_err());
+    }
+}
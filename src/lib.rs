@@ -1,5 +1,100 @@
-pub mod core;
-pub use core::{Node, NodeOutput, Tree};
-pub mod database;
-pub use database::defintions_from_sqlite;
-
+mod aggregate;
+pub use aggregate::AggregateOp;
+mod analysis;
+pub use analysis::{Scenario, Sweep, SweepPoint, TornadoBar};
+mod asynchronous;
+mod binding;
+mod broadcast;
+pub use broadcast::BroadcastPolicy;
+#[cfg(feature = "sqlite-blocking")]
+mod blocking;
+#[cfg(feature = "sqlite-blocking")]
+pub use blocking::{
+    canonicalize_formulas_sqlite_blocking, definitions_from_sqlite_blocking,
+    docs_from_sqlite_blocking, draft_from_sqlite_blocking, edit_log_from_sqlite_blocking,
+    examples_from_sqlite_blocking, write_draft_sqlite_blocking, write_edit_log, write_example,
+    write_results, Conflict, ConflictingNode,
+};
+mod codegen;
+pub use codegen::SqlDialect;
+mod compile;
+pub use compile::CompiledTree;
+mod contract;
+pub use contract::{Contract, ContractInput, ContractOutput, ValueType};
+mod core;
+mod coverage;
+pub use core::{
+    CacheStats, EdgeDefinition, EvalCache, MemoryStats, Node, NodeDefinition, NodeId, NodeKind,
+    NodeOutput, ParameterDefinition, Tree, TreeDraft,
+};
+pub use coverage::{BranchCoverage, Coverage};
+#[cfg(feature = "sqlite")]
+mod database;
+#[cfg(feature = "sqlite")]
+pub use database::{
+    definitions_from_sqlite_verified, defintions_from_sqlite, verify_checksums, write_checksums,
+    DefinitionWatcher,
+};
+mod dialect;
+pub use dialect::{FormulaDialect, InputResolution};
+mod diff;
+pub use diff::OutputDifference;
+mod docs;
+mod examples;
+pub use examples::{Example, Mismatch};
+mod experiment;
+pub use experiment::{AllowList, Experiment, PercentageRollout, VariantStrategy};
+mod fingerprint;
+mod formula;
+pub use formula::{FormulaAliases, FormulaMetrics, Tolerance};
+mod functions;
+pub use functions::FunctionSet;
+mod group;
+pub use group::Group;
+mod history;
+pub use history::{Edit, History, NodeSnapshot};
+mod hooks;
+pub use hooks::EvalHook;
+mod iteration;
+pub use iteration::{Convergence, FixedPoint};
+mod json;
+pub use json::JsonPointerFactory;
+#[cfg(feature = "sqlite-blocking")]
+mod materialized;
+#[cfg(feature = "sqlite-blocking")]
+pub use materialized::MaterializedStore;
+mod missing;
+pub use missing::MissingPolicy;
+mod mutation;
+pub use mutation::{Mutant, MutationReport};
+mod partition;
+pub use partition::Partition;
+#[cfg(feature = "sqlite-blocking")]
+mod persistent;
+#[cfg(feature = "sqlite-blocking")]
+pub use persistent::PersistentCache;
+mod permissions;
+pub use permissions::{DraftEditor, EditContext};
+pub mod prelude;
+mod registry;
+pub use registry::{CustomKind, CustomNode, NodeFactory, NodeFuture, NodeRegistry, NodeState};
+mod remote;
+pub use remote::{
+    handle_connection, serve, RemoteExecutor, RemoteRequest, RemoteResponse, TcpExecutor,
+};
+#[cfg(feature = "sqlite-blocking")]
+mod rows;
+mod schema;
+mod shared;
+pub use shared::{Discrepancy, SharedTree};
+mod session;
+pub use session::Session;
+mod stateful;
+pub use stateful::{
+    DebounceFactory, DelayFactory, FirstOrderLagFactory, HysteresisFactory, PidFactory,
+    RateLimiterFactory, RateOfChangeFactory, StateMachineFactory, UnbatchFactory, WindowFactory,
+};
+mod trigger;
+pub use trigger::Trigger;
+mod value;
+pub use value::{Arithmetic, CustomValue};
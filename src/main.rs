@@ -1,6 +1,3 @@
-use graph::Node;
-
 fn main() {
     println!("Hello, world!");
 }
-
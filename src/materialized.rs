@@ -0,0 +1,488 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{NodeId, NodeKind, NodeOutput, Tree};
+
+struct Materialization {
+    fingerprint: u64,
+    input_hash: u64,
+    output: Option<NodeOutput>,
+    stale: bool,
+}
+
+// Keeps the last output of the materialized nodes in a SQLite file. A stored output is reused
+// until it is marked stale or the definition or input values of the node change.
+pub struct MaterializedStore {
+    conn: Connection,
+}
+
+impl MaterializedStore {
+    pub fn open(file_name: String) -> Result<Self> {
+        let conn = Connection::open(file_name)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS materialized (
+                node_id INTEGER NOT NULL PRIMARY KEY,
+                fingerprint INTEGER NOT NULL DEFAULT 0,
+                input_hash INTEGER NOT NULL DEFAULT 0,
+                output TEXT,
+                stale INTEGER NOT NULL DEFAULT 1
+            )
+            ",
+        )?;
+        Ok(Self { conn })
+    }
+
+    pub fn materialize(&self, node_id: NodeId) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO materialized (node_id) VALUES (?)",
+            [node_id as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn dematerialize(&self, node_id: NodeId) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM materialized WHERE node_id = ?",
+            [node_id as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn nodes(&self) -> Result<Vec<NodeId>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT node_id FROM materialized ORDER BY node_id")?;
+        let node_ids = stmt.query_map([], |row| Ok(row.get::<_, i64>(0)? as NodeId))?;
+        Ok(node_ids.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn mark_stale(&self, node_id: NodeId) -> Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE materialized SET stale = 1 WHERE node_id = ?",
+            [node_id as i64],
+        )?;
+        if updated == 0 {
+            return Err(anyhow!("node {} is not materialized", node_id));
+        }
+        Ok(())
+    }
+
+    pub fn is_stale(&self, node_id: NodeId) -> Result<bool> {
+        let materialization = self
+            .get(node_id)?
+            .ok_or(anyhow!("node {} is not materialized", node_id))?;
+        Ok(materialization.stale)
+    }
+
+    fn get(&self, node_id: NodeId) -> Result<Option<Materialization>> {
+        let row: Option<(i64, i64, Option<String>, bool)> = self
+            .conn
+            .query_row(
+                "SELECT fingerprint, input_hash, output, stale FROM materialized
+                WHERE node_id = ?",
+                [node_id as i64],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let Some((fingerprint, input_hash, output, stale)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Materialization {
+            fingerprint: fingerprint as u64,
+            input_hash: input_hash as u64,
+            output: match output {
+                Some(output) => Some(serde_json::from_str(&output)?),
+                None => None,
+            },
+            stale,
+        }))
+    }
+
+    fn store(
+        &self,
+        node_id: NodeId,
+        fingerprint: u64,
+        input_hash: u64,
+        output: &NodeOutput,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE materialized SET fingerprint = ?, input_hash = ?, output = ?, stale = 0
+            WHERE node_id = ?",
+            params![
+                fingerprint as i64,
+                input_hash as i64,
+                serde_json::to_string(output)?,
+                node_id as i64
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl Tree {
+    // Evaluates the node like `eval`, but takes the outputs of materialized nodes from the store
+    // while they are current, and stores them again after they had to be evaluated
+    pub fn eval_materialized(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        store: &MaterializedStore,
+    ) -> Result<NodeOutput> {
+        self.node(node_id)?;
+        self.run_hooks(node_id, values, |values| {
+            self.eval_materialized_nodes(node_id, values, store)
+        })
+    }
+
+    // Lists the materialized nodes that depend on one of the changed variables or on a node
+    // marked stale, every node after the materialized nodes it depends on
+    pub fn recalculation_plan(
+        &self,
+        store: &MaterializedStore,
+        changed: &[NodeId],
+    ) -> Result<Vec<NodeId>> {
+        let mut node_ids = Vec::new();
+        let mut stale = Vec::new();
+        for node_id in store.nodes()? {
+            if self.node(node_id).is_err() {
+                continue;
+            }
+            if store.is_stale(node_id)? {
+                stale.push(node_id);
+            }
+            node_ids.push(node_id);
+        }
+
+        // Nodes depending on a recalculated node have to follow, their stored output would be
+        // reused otherwise
+        let mut planned = Vec::new();
+        for node_id in node_ids {
+            if self
+                .eval_order(node_id)?
+                .iter()
+                .any(|x| changed.contains(x) || stale.contains(x))
+            {
+                planned.push(node_id);
+            }
+        }
+
+        let mut plan = Vec::with_capacity(planned.len());
+        for node_id in &planned {
+            for id in self.eval_order(*node_id)?.iter() {
+                if planned.contains(id) && !plan.contains(id) {
+                    plan.push(*id);
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    // Evaluates and stores the nodes of the plan in its order, earlier nodes are reused by later
+    // ones instead of being evaluated again
+    pub fn execute_plan(
+        &self,
+        plan: &[NodeId],
+        values: &HashMap<NodeId, NodeOutput>,
+        store: &MaterializedStore,
+    ) -> Result<HashMap<NodeId, NodeOutput>> {
+        let mut outputs = HashMap::new();
+        for node_id in plan {
+            store.mark_stale(*node_id)?;
+            outputs.insert(*node_id, self.eval_materialized(*node_id, values, store)?);
+        }
+        Ok(outputs)
+    }
+
+    // Reuses every stored output reached from the top once, the inputs of a reused node are not
+    // needed. The rest is evaluated along the evaluation order.
+    fn eval_materialized_nodes(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        store: &MaterializedStore,
+    ) -> Result<NodeOutput> {
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        let mut keys: HashMap<NodeId, (u64, u64)> = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![node_id];
+        while let Some(id) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let node = self.node(id)?;
+            if let NodeKind::Variable(_) = node.kind() {
+                continue;
+            }
+
+            if let Some(materialization) = store.get(id)? {
+                let fingerprint = self.fingerprint(id)?;
+                let input_hash = self.input_hash(id, values)?;
+                if !materialization.stale
+                    && materialization.fingerprint == fingerprint
+                    && materialization.input_hash == input_hash
+                {
+                    if let Some(output) = materialization.output {
+                        outputs.insert(id, output);
+                        continue;
+                    }
+                }
+                keys.insert(id, (fingerprint, input_hash));
+            }
+            stack.extend(node.inputs.iter());
+        }
+
+        for id in self.eval_order(node_id)?.iter() {
+            if !visited.contains(id) || outputs.contains_key(id) {
+                continue;
+            }
+            let node = self.node(*id)?;
+            if let NodeKind::Variable(_) = node.kind() {
+                outputs.insert(*id, node.eval(values)?);
+                continue;
+            }
+
+            let mut input_outputs = Vec::new();
+            for input in node.inputs.iter() {
+                let output = outputs
+                    .get(input)
+                    .ok_or(anyhow!("node {} was not evaluated", input))?;
+                input_outputs.push(output.clone());
+            }
+            let output = self.apply(node, input_outputs)?;
+            if let Some((fingerprint, input_hash)) = keys.get(id) {
+                store.store(*id, *fingerprint, *input_hash, &output)?;
+            }
+            outputs.insert(*id, output);
+        }
+
+        outputs
+            .remove(&node_id)
+            .ok_or(anyhow!("node {} was not evaluated", node_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_materialized() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_materialized.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(1.))]);
+
+        let store = MaterializedStore::open(file_name).unwrap();
+        store.materialize(1).unwrap();
+        assert_eq!(store.nodes().unwrap(), vec![1]);
+        assert!(store.is_stale(1).unwrap());
+        assert!(store.is_stale(2).is_err());
+        assert_eq!(
+            tree.eval_materialized(2, &values, &store).unwrap(),
+            NodeOutput::Number(3.)
+        );
+        assert!(!store.is_stale(1).unwrap());
+
+        // A stored output is taken as it is, even if it would be different by now
+        store
+            .store(
+                1,
+                tree.fingerprint(1).unwrap(),
+                tree.input_hash(1, &values).unwrap(),
+                &NodeOutput::Number(10.),
+            )
+            .unwrap();
+        assert_eq!(
+            tree.eval_materialized(2, &values, &store).unwrap(),
+            NodeOutput::Number(11.)
+        );
+
+        store.mark_stale(1).unwrap();
+        assert_eq!(
+            tree.eval_materialized(2, &values, &store).unwrap(),
+            NodeOutput::Number(3.)
+        );
+
+        let values = HashMap::from([(0, NodeOutput::Number(2.))]);
+        assert_eq!(
+            tree.eval_materialized(2, &values, &store).unwrap(),
+            NodeOutput::Number(5.)
+        );
+        store.dematerialize(1).unwrap();
+        assert!(store.nodes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recalculation_plan() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_recalculation.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$1 * 3".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 4,
+                kind: 1,
+                value: "$2 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 4,
+                input_id: 2,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(1.)), (1, NodeOutput::Number(2.))]);
+
+        let store = MaterializedStore::open(file_name).unwrap();
+        for node_id in [4, 3, 2] {
+            store.materialize(node_id).unwrap();
+        }
+        let plan = tree.recalculation_plan(&store, &[]).unwrap();
+        assert_eq!(plan, vec![2, 3, 4]);
+        let outputs = tree.execute_plan(&plan, &values, &store).unwrap();
+        assert_eq!(outputs[&4], NodeOutput::Number(3.));
+        assert!(tree.recalculation_plan(&store, &[]).unwrap().is_empty());
+
+        assert_eq!(tree.recalculation_plan(&store, &[0]).unwrap(), vec![2, 4]);
+        assert_eq!(tree.recalculation_plan(&store, &[1]).unwrap(), vec![3]);
+        store.mark_stale(2).unwrap();
+        assert_eq!(
+            tree.recalculation_plan(&store, &[1]).unwrap(),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_materialized_ladder() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_materialized_ladder.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        // Both nodes of a level read both nodes of the level below, the number of paths doubles
+        // with every level
+        let levels = 40;
+        let mut node_defs = vec![NodeDefinition {
+            node_id: 0,
+            kind: 0,
+            value: "a".into(),
+            default: None,
+        }];
+        let mut edge_defs = Vec::new();
+        for level in 0..levels {
+            for side in 0..2 {
+                let node_id = 1 + 2 * level + side;
+                let (inputs, value) = if level == 0 {
+                    (vec![0], "$0".to_string())
+                } else {
+                    let (a, b) = (2 * level - 1, 2 * level);
+                    (vec![a, b], format!("(${} + ${}) / 2", a, b))
+                };
+                node_defs.push(NodeDefinition {
+                    node_id,
+                    kind: 1,
+                    value,
+                    default: None,
+                });
+                for input_id in inputs {
+                    edge_defs.push(EdgeDefinition { node_id, input_id });
+                }
+            }
+        }
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(3.))]);
+
+        let store = MaterializedStore::open(file_name).unwrap();
+        for node_id in 1..2 * levels {
+            store.materialize(node_id).unwrap();
+        }
+        assert_eq!(
+            tree.eval_materialized(2 * levels, &values, &store).unwrap(),
+            NodeOutput::Number(3.)
+        );
+        for node_id in 1..2 * levels - 1 {
+            assert!(!store.is_stale(node_id).unwrap());
+        }
+
+        store.mark_stale(levels).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(5.))]);
+        assert_eq!(
+            tree.eval_materialized(2 * levels, &values, &store).unwrap(),
+            NodeOutput::Number(5.)
+        );
+    }
+}
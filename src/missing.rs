@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::core::{NodeId, NodeKind, NodeOutput, Tree};
+
+// What a formula node does with an element where one of its inputs is missing, i.e. NaN
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum MissingPolicy {
+    // The element of the output is missing as well
+    #[default]
+    Propagate,
+    // The element is left out of the output
+    Skip,
+    // The missing input is replaced by the constant
+    Substitute(f64),
+    // The missing input is replaced by its last earlier element that is not missing, which
+    // carries time series values forward
+    Previous,
+}
+
+// serde_json writes NaN as null, which has to be read back as NaN
+pub(crate) fn nullable<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(f64::NAN))
+}
+
+pub(crate) fn nullable_array<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<f64>, D::Error> {
+    let values = Vec::<Option<f64>>::deserialize(deserializer)?;
+    Ok(values.into_iter().map(|x| x.unwrap_or(f64::NAN)).collect())
+}
+
+pub(crate) fn nullable_matrix<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Vec<f64>>, D::Error> {
+    let rows = Vec::<Vec<Option<f64>>>::deserialize(deserializer)?;
+    Ok(rows
+        .into_iter()
+        .map(|x| x.into_iter().map(|x| x.unwrap_or(f64::NAN)).collect())
+        .collect())
+}
+
+pub(crate) fn nullable_time_series<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(i64, f64)>, D::Error> {
+    let values = Vec::<(i64, Option<f64>)>::deserialize(deserializer)?;
+    Ok(values
+        .into_iter()
+        .map(|(t, x)| (t, x.unwrap_or(f64::NAN)))
+        .collect())
+}
+
+// Leading missing values stay missing
+pub(crate) fn fill_previous(values: &mut [f64]) {
+    let mut previous = f64::NAN;
+    for x in values.iter_mut() {
+        if x.is_nan() {
+            *x = previous;
+        } else {
+            previous = *x;
+        }
+    }
+}
+
+impl NodeOutput {
+    pub fn missing() -> Self {
+        NodeOutput::Number(f64::NAN)
+    }
+
+    pub fn is_missing(&self) -> bool {
+        match self {
+            NodeOutput::Number(v) => v.is_nan(),
+            NodeOutput::NumberArray(v) | NodeOutput::Series { values: v, .. } => {
+                v.iter().all(|x| x.is_nan())
+            }
+            NodeOutput::Matrix(v) => v.iter().flatten().all(|x| x.is_nan()),
+            NodeOutput::TimeSeries(v) => v.iter().all(|(_, x)| x.is_nan()),
+            NodeOutput::Integer(_)
+            | NodeOutput::Bool(_)
+            | NodeOutput::String(_)
+            | NodeOutput::BoolArray(_)
+            | NodeOutput::StringArray(_)
+            | NodeOutput::Custom(_) => false,
+        }
+    }
+}
+
+impl Tree {
+    pub fn with_missing_policy(
+        mut self,
+        node_ids: &[NodeId],
+        policy: MissingPolicy,
+    ) -> Result<Self> {
+        for node_id in node_ids {
+            if !matches!(
+                self.node(*node_id)?.kind(),
+                NodeKind::Formula(_) | NodeKind::Aggregate(_)
+            ) {
+                return Err(anyhow!(
+                    "node {} is not a formula or aggregate node",
+                    node_id
+                ));
+            }
+        }
+        for node_id in node_ids {
+            self.missing.insert(*node_id, policy);
+        }
+        Ok(self)
+    }
+
+    pub fn missing_policy(&self, node_id: NodeId) -> MissingPolicy {
+        self.missing.get(&node_id).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_missing_policy() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "max($0, $1)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let values: HashMap<NodeId, NodeOutput> = serde_json::from_str(
+            r#"{"0": {"NumberArray": [1.0, null, 3.0]}, "1": {"Number": 2.0}}"#,
+        )
+        .unwrap();
+        let NodeOutput::NumberArray(output) = tree.eval(2, &values).unwrap() else {
+            panic!("expected an array");
+        };
+        assert_eq!(output[0], 2.);
+        assert!(output[1].is_nan());
+        assert_eq!(output[2], 3.);
+
+        let skipping = tree
+            .clone()
+            .with_missing_policy(&[2], MissingPolicy::Skip)
+            .unwrap();
+        assert_eq!(
+            skipping.eval(2, &values).unwrap(),
+            NodeOutput::NumberArray(vec![2., 3.])
+        );
+        let substituting = tree
+            .clone()
+            .with_missing_policy(&[2], MissingPolicy::Substitute(5.))
+            .unwrap();
+        assert_eq!(
+            substituting.eval(2, &values).unwrap(),
+            NodeOutput::NumberArray(vec![2., 5., 3.])
+        );
+
+        let values = HashMap::from([(0, NodeOutput::missing()), (1, NodeOutput::Integer(2))]);
+        assert!(tree.eval(2, &values).unwrap().is_missing());
+        assert!(skipping.eval(2, &values).unwrap().is_missing());
+        assert!(tree.with_missing_policy(&[0], MissingPolicy::Skip).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{NodeId, Tree};
+use crate::formula::mutants;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Mutant {
+    pub node_id: NodeId,
+    pub formula: String,
+    // Whether at least one example failed with the mutated formula
+    pub killed: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct MutationReport {
+    pub mutants: Vec<Mutant>,
+}
+
+impl MutationReport {
+    // Mutants the examples did not tell apart from the original formula
+    pub fn survivors(&self) -> Vec<&Mutant> {
+        self.mutants.iter().filter(|x| !x.killed).collect()
+    }
+
+    // Formula nodes with a surviving mutant, the examples do not pin their result down
+    pub fn untested(&self) -> Vec<NodeId> {
+        let mut node_ids: Vec<_> = self.survivors().iter().map(|x| x.node_id).collect();
+        node_ids.dedup();
+        node_ids
+    }
+
+    // Share of the mutants killed by the examples
+    pub fn score(&self) -> f64 {
+        if self.mutants.is_empty() {
+            return 1.;
+        }
+        let killed = self.mutants.iter().filter(|x| x.killed).count();
+        killed as f64 / self.mutants.len() as f64
+    }
+}
+
+impl Tree {
+    // Swaps single operators and changes single numbers of every formula and runs the examples
+    // against each of these mutants, a mutant is killed if `self_test` reports any mismatch
+    pub fn mutation_test(&self) -> Result<MutationReport> {
+        if self.examples().is_empty() {
+            return Err(anyhow!("the tree has no examples"));
+        }
+
+        let mut node_defs: Vec<_> = self
+            .node_definitions()
+            .iter()
+            .filter(|x| x.kind == 1)
+            .collect();
+        node_defs.sort_by_key(|x| x.node_id);
+
+        let mut report = MutationReport::default();
+        for node_def in node_defs {
+            for formula in mutants(&node_def.value)? {
+                let mut definitions = self.node_definitions().to_vec();
+                for definition in definitions.iter_mut() {
+                    if definition.node_id == node_def.node_id {
+                        definition.value = formula.clone();
+                    }
+                }
+                // A mutant that does not even build counts as killed
+                let killed = match self.with_node_definitions(definitions) {
+                    Ok(mutant) => !mutant.self_test().is_empty(),
+                    Err(_) => true,
+                };
+                report.mutants.push(Mutant {
+                    node_id: node_def.node_id,
+                    formula,
+                    killed,
+                });
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, NodeOutput};
+    use crate::examples::Example;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_mutation_test() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "if($1 > 3.0, $1, 0.0)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        assert!(tree.mutation_test().is_err());
+
+        let examples = vec![Example {
+            name: "two".into(),
+            inputs: HashMap::from([(0, NodeOutput::Number(2.))]),
+            expected: HashMap::from([(2, NodeOutput::Number(4.))]),
+        }];
+        let tree = tree.with_examples(examples).unwrap();
+        let report = tree.mutation_test().unwrap();
+        assert_eq!(report.mutants.len(), 5);
+        // Only `$1 >= 3.0` and `0.0` replaced by `1.0` still give 4 for an input of 2
+        let survivors: Vec<_> = report
+            .survivors()
+            .iter()
+            .map(|x| x.formula.as_str())
+            .collect();
+        assert_eq!(
+            survivors,
+            vec!["if($1 >= 3.0, $1, 0.0)", "if($1 > 3.0, $1, 1.0)"]
+        );
+        assert_eq!(report.untested(), vec![2]);
+        assert_eq!(report.score(), 3. / 5.);
+    }
+}
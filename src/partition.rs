@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{EdgeDefinition, NodeDefinition, NodeId, NodeKind, NodeOutput, Tree};
+use crate::functions::FunctionSet;
+
+// A self-contained part of a tree. Nodes computed by earlier partitions are imported as
+// variables under their original ids, so the formulas stay unchanged.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Partition {
+    pub nodes: Vec<NodeDefinition>,
+    pub edges: Vec<EdgeDefinition>,
+    pub imports: Vec<NodeId>,
+    pub exports: Vec<NodeId>,
+}
+
+impl Partition {
+    pub fn to_tree(&self) -> Result<Tree> {
+        Tree::new(self.nodes.clone(), self.edges.clone())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    // Evaluates every export, `values` has to contain the variables and all imports
+    pub fn eval(
+        &self,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<HashMap<NodeId, NodeOutput>> {
+        let tree = self.to_tree()?;
+        self.exports
+            .iter()
+            .map(|id| Ok((*id, tree.eval(*id, values)?)))
+            .collect()
+    }
+}
+
+impl Tree {
+    // Cuts the formulas below `node_id` into contiguous chunks of the evaluation order. Every
+    // partition only imports from the ones before it, so they can be evaluated in sequence with
+    // the exports of each passed on to the next. Variables are copied into every partition
+    // reading them instead of crossing partitions. Partitions only hold node and edge
+    // definitions, so trees with settings or custom nodes changing their outputs are refused.
+    pub fn partition(&self, node_id: NodeId, count: usize) -> Result<Vec<Partition>> {
+        if count == 0 {
+            return Err(anyhow!("cannot split a tree into 0 partitions"));
+        }
+        if self.function_set() != FunctionSet::default()
+            || self.tolerance().is_some()
+            || !self.missing.is_empty()
+            || !self.broadcast.is_empty()
+        {
+            return Err(anyhow!(
+                "cannot partition a tree with a function set, a tolerance or node policies"
+            ));
+        }
+
+        let mut formulas = Vec::new();
+        for id in self.eval_order(node_id)?.iter() {
+            match self.node(*id)?.kind() {
+                NodeKind::Variable(_) => {}
+                NodeKind::Formula(_) | NodeKind::Aggregate(_) => formulas.push(*id),
+                NodeKind::SqlQuery(_) | NodeKind::Custom(_) => {
+                    return Err(anyhow!("cannot partition custom node {}", id));
+                }
+            }
+        }
+        let chunk_len = formulas.len().div_ceil(count).max(1);
+
+        let definitions: HashMap<NodeId, &NodeDefinition> = self
+            .node_definitions()
+            .iter()
+            .map(|x| (x.node_id, x))
+            .collect();
+        let mut partitions = Vec::new();
+        let mut owner = HashMap::new();
+        for (idx, chunk) in formulas.chunks(chunk_len).enumerate() {
+            let members: HashSet<NodeId> = chunk.iter().copied().collect();
+            let edges: Vec<EdgeDefinition> = self
+                .edge_definitions()
+                .iter()
+                .filter(|x| members.contains(&x.node_id))
+                .cloned()
+                .collect();
+
+            let mut nodes: Vec<NodeDefinition> =
+                chunk.iter().map(|id| definitions[id].clone()).collect();
+            let mut imports = Vec::new();
+            let mut added = HashSet::new();
+            for edge in &edges {
+                let input_id = edge.input_id;
+                if members.contains(&input_id) || !added.insert(input_id) {
+                    continue;
+                }
+                match definitions[&input_id].kind {
+                    0 => nodes.push(definitions[&input_id].clone()),
+                    _ => {
+                        imports.push(input_id);
+                        nodes.push(NodeDefinition {
+                            node_id: input_id,
+                            value: format!("node_{}", input_id),
+                            kind: 0,
+                            default: None,
+                        });
+                    }
+                }
+            }
+
+            imports.sort();
+            for id in chunk {
+                owner.insert(*id, idx);
+            }
+            partitions.push(Partition {
+                nodes,
+                edges,
+                imports,
+                exports: Vec::new(),
+            });
+        }
+
+        // A node is exported when a later partition imports it, the root is always exported
+        let mut exports: Vec<HashSet<NodeId>> = vec![HashSet::new(); partitions.len()];
+        for partition in &partitions {
+            for id in &partition.imports {
+                exports[owner[id]].insert(*id);
+            }
+        }
+        if let Some(idx) = owner.get(&node_id) {
+            exports[*idx].insert(node_id);
+        }
+        for (partition, exports) in partitions.iter_mut().zip(exports) {
+            partition.exports = exports.into_iter().collect();
+            partition.exports.sort();
+        }
+
+        Ok(partitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::BroadcastPolicy;
+    use crate::registry::NodeRegistry;
+    use crate::stateful::DelayFactory;
+
+    #[test]
+    fn test_partition() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + $0".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 - 1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 4,
+                kind: 1,
+                value: "$3 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 4,
+                input_id: 3,
+            },
+            EdgeDefinition {
+                node_id: 4,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(3.))]);
+
+        let partitions = tree.partition(4, 2).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].exports, vec![1, 2]);
+        assert_eq!(partitions[1].imports, vec![1, 2]);
+        assert_eq!(partitions[1].exports, vec![4]);
+
+        let mut stitched = values.clone();
+        for partition in &partitions {
+            let partition = Partition::from_json(&partition.to_json().unwrap()).unwrap();
+            stitched.extend(partition.eval(&stitched).unwrap());
+        }
+        assert_eq!(stitched[&4], tree.eval(4, &values).unwrap());
+
+        // Partitions would be evaluated without the settings of the tree
+        let excel = tree.clone().with_function_set(FunctionSet::Excel);
+        assert!(excel.partition(4, 2).is_err());
+        let zipped = tree
+            .clone()
+            .with_broadcast_policy(&[2], BroadcastPolicy::ZipShortest)
+            .unwrap();
+        assert!(zipped.partition(4, 2).is_err());
+
+        let mut registry = NodeRegistry::new();
+        registry.register(10, DelayFactory).unwrap();
+        let mut node_defs = tree.node_definitions().to_vec();
+        node_defs.push(NodeDefinition {
+            node_id: 5,
+            kind: 10,
+            value: "1".into(),
+            default: None,
+        });
+        let mut edge_defs = tree.edge_definitions().to_vec();
+        edge_defs.push(EdgeDefinition {
+            node_id: 5,
+            input_id: 4,
+        });
+        let delayed = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        assert!(delayed.partition(4, 2).is_ok());
+        assert!(delayed.partition(5, 2).is_err());
+    }
+}
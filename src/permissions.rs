@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+
+use crate::core::{NodeDefinition, NodeId, TreeDraft};
+
+// The roles of whoever is editing. A node owned by a role can only be changed by callers holding
+// it, a node without an owner by everyone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditContext {
+    roles: HashSet<String>,
+}
+
+impl EditContext {
+    pub fn new(roles: &[&str]) -> Self {
+        Self {
+            roles: roles.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+
+    pub(crate) fn check(&self, node_id: NodeId, owner: Option<&str>) -> Result<()> {
+        match owner {
+            Some(role) if !self.has_role(role) => Err(anyhow!(
+                "node with id {} can only be edited with role {}",
+                node_id,
+                role
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// The edits of a draft checked against the owners of the nodes they change
+pub struct DraftEditor<'a> {
+    draft: &'a mut TreeDraft,
+    context: &'a EditContext,
+}
+
+impl DraftEditor<'_> {
+    pub fn add_node(&mut self, node_def: NodeDefinition) -> Result<()> {
+        self.draft.add_node(node_def)
+    }
+
+    // Also changes the nodes reading it, they lose an input
+    pub fn remove_node(&mut self, node_id: NodeId) -> Result<NodeDefinition> {
+        self.check(node_id)?;
+        let dependents: Vec<NodeId> = self
+            .draft
+            .edge_definitions()
+            .iter()
+            .filter(|x| x.input_id == node_id)
+            .map(|x| x.node_id)
+            .collect();
+        for dependent in dependents {
+            self.check(dependent)?;
+        }
+        self.draft.remove_node(node_id)
+    }
+
+    pub fn set_value(&mut self, node_id: NodeId, value: String) -> Result<()> {
+        self.check(node_id)?;
+        self.draft.set_value(node_id, value)
+    }
+
+    pub fn connect(&mut self, node_id: NodeId, input_id: NodeId) -> Result<()> {
+        self.check(node_id)?;
+        self.draft.connect(node_id, input_id)
+    }
+
+    pub fn disconnect(&mut self, node_id: NodeId, input_id: NodeId) -> Result<()> {
+        self.check(node_id)?;
+        self.draft.disconnect(node_id, input_id)
+    }
+
+    pub fn set_condition(&mut self, node_id: NodeId, condition: &str) -> Result<()> {
+        self.check(node_id)?;
+        self.draft.set_condition(node_id, condition)
+    }
+
+    pub fn clear_condition(&mut self, node_id: NodeId) -> Result<()> {
+        self.check(node_id)?;
+        self.draft.clear_condition(node_id);
+        Ok(())
+    }
+
+    // Handing a node to a role the caller does not hold locks them out of it
+    pub fn set_owner(&mut self, node_id: NodeId, role: Option<&str>) -> Result<()> {
+        self.check(node_id)?;
+        self.draft.set_owner(node_id, role)
+    }
+
+    fn check(&self, node_id: NodeId) -> Result<()> {
+        if !self.draft.has_node(node_id) {
+            return Err(anyhow!("no node with id {}", node_id));
+        }
+        self.context.check(node_id, self.draft.owner(node_id))
+    }
+}
+
+impl TreeDraft {
+    pub fn editor<'a>(&'a mut self, context: &'a EditContext) -> DraftEditor<'a> {
+        DraftEditor {
+            draft: self,
+            context,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_editor() {
+        let mut draft = TreeDraft::new();
+        for (node_id, kind, value) in [(0, 0, "a"), (1, 1, "$0 * 2"), (2, 1, "$1 + 1")] {
+            draft
+                .add_node(NodeDefinition {
+                    node_id,
+                    kind,
+                    value: value.into(),
+                    default: None,
+                })
+                .unwrap();
+        }
+        draft.connect(1, 0).unwrap();
+        draft.connect(2, 1).unwrap();
+
+        let admin = EditContext::new(&["admin", "process"]);
+        let process = EditContext::new(&["process"]);
+        let guest = EditContext::default();
+        draft.editor(&admin).set_owner(1, Some("admin")).unwrap();
+        draft
+            .editor(&process)
+            .set_owner(2, Some("process"))
+            .unwrap();
+        assert_eq!(draft.owner(1), Some("admin"));
+
+        let mut editor = draft.editor(&process);
+        assert!(editor.set_value(1, "$0 * 3".into()).is_err());
+        assert!(editor.disconnect(1, 0).is_err());
+        assert!(editor.set_owner(1, None).is_err());
+        editor.set_value(2, "$1 + 2".into()).unwrap();
+        editor.set_value(0, "b".into()).unwrap();
+
+        // Node 0 is read by node 1, which the guest may not change
+        let mut editor = draft.editor(&guest);
+        assert!(editor.remove_node(0).is_err());
+        assert!(editor.set_value(2, "$1".into()).is_err());
+        assert!(editor.set_value(5, "$1".into()).is_err());
+        editor
+            .add_node(NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 - 1".into(),
+                default: None,
+            })
+            .unwrap();
+        editor.connect(3, 2).unwrap();
+
+        draft.editor(&admin).remove_node(1).unwrap();
+        assert_eq!(draft.owner(1), None);
+        assert_eq!(draft.node_definitions()[0].value, "b");
+    }
+}
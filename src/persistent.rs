@@ -0,0 +1,285 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{CacheStats, NodeId, NodeKind, NodeOutput, Tree};
+
+// Keeps node outputs in a SQLite file, so they can be reused by other processes. Entries are
+// keyed by the fingerprint of the node and the hash of its input values, a changed definition
+// or input value therefore never hits an old entry.
+pub struct PersistentCache {
+    conn: Connection,
+    pub stats: CacheStats,
+}
+
+impl PersistentCache {
+    pub fn open(file_name: String) -> Result<Self> {
+        let conn = Connection::open(file_name)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS result_cache (
+                fingerprint INTEGER NOT NULL,
+                node_id INTEGER NOT NULL,
+                input_hash INTEGER NOT NULL,
+                output TEXT NOT NULL,
+                PRIMARY KEY (fingerprint, node_id, input_hash)
+            )
+            ",
+        )?;
+        Ok(Self {
+            conn,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.conn.execute("DELETE FROM result_cache", [])?;
+        Ok(())
+    }
+
+    fn get(&self, key: (u64, NodeId, u64)) -> Result<Option<NodeOutput>> {
+        let output: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT output FROM result_cache
+                WHERE fingerprint = ? AND node_id = ? AND input_hash = ?",
+                params![key.0 as i64, key.1 as i64, key.2 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match output {
+            Some(output) => Some(serde_json::from_str(&output)?),
+            None => None,
+        })
+    }
+
+    fn insert(&self, key: (u64, NodeId, u64), output: &NodeOutput) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO result_cache (fingerprint, node_id, input_hash, output)
+            VALUES (?, ?, ?, ?)",
+            params![
+                key.0 as i64,
+                key.1 as i64,
+                key.2 as i64,
+                serde_json::to_string(output)?
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl Tree {
+    pub fn eval_persistent(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        cache: &mut PersistentCache,
+    ) -> Result<NodeOutput> {
+        self.node(node_id)?;
+        self.run_hooks(node_id, values, |values| {
+            self.eval_persistent_nodes(node_id, values, cache)
+        })
+    }
+
+    // Looks up every node once from the top, a hit does not need its inputs. The misses are
+    // then evaluated along the evaluation order.
+    fn eval_persistent_nodes(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        cache: &mut PersistentCache,
+    ) -> Result<NodeOutput> {
+        let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+        let mut misses: HashMap<NodeId, (u64, NodeId, u64)> = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![node_id];
+        while let Some(id) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let node = self.node(id)?;
+            if let NodeKind::Variable(_) = node.kind() {
+                continue;
+            }
+
+            let key = (self.fingerprint(id)?, id, self.input_hash(id, values)?);
+            if let Some(output) = cache.get(key)? {
+                cache.stats.hits += 1;
+                outputs.insert(id, output);
+                continue;
+            }
+            cache.stats.misses += 1;
+            misses.insert(id, key);
+            stack.extend(node.inputs.iter());
+        }
+
+        for id in self.eval_order(node_id)?.iter() {
+            if !visited.contains(id) || outputs.contains_key(id) {
+                continue;
+            }
+            let node = self.node(*id)?;
+            let Some(key) = misses.get(id) else {
+                outputs.insert(*id, node.eval(values)?);
+                continue;
+            };
+
+            let mut input_outputs = Vec::new();
+            for input in node.inputs.iter() {
+                let output = outputs
+                    .get(input)
+                    .ok_or(anyhow!("node {} was not evaluated", input))?;
+                input_outputs.push(output.clone());
+            }
+            let output = self.apply(node, input_outputs)?;
+            cache.insert(*key, &output)?;
+            outputs.insert(*id, output);
+        }
+
+        outputs
+            .remove(&node_id)
+            .ok_or(anyhow!("node {} was not evaluated", node_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_persistent_cache() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_persistent.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2.]))]);
+
+        let mut cache = PersistentCache::open(file_name.clone()).unwrap();
+        let output = tree.eval_persistent(2, &values, &mut cache).unwrap();
+        assert_eq!(output, NodeOutput::NumberArray(vec![3., 5.]));
+        assert_eq!(cache.stats.misses, 2);
+
+        // A second process sees the results of the first one
+        let mut cache = PersistentCache::open(file_name.clone()).unwrap();
+        assert_eq!(
+            tree.eval_persistent(2, &values, &mut cache).unwrap(),
+            output
+        );
+        assert_eq!(cache.stats, CacheStats { hits: 1, misses: 0 });
+
+        let mut draft = tree.to_draft();
+        draft.set_value(2, "$1 + 2".into()).unwrap();
+        let tree = draft.freeze().unwrap();
+        assert_eq!(
+            tree.eval_persistent(2, &values, &mut cache).unwrap(),
+            NodeOutput::NumberArray(vec![4., 6.])
+        );
+        assert_eq!(cache.stats, CacheStats { hits: 2, misses: 1 });
+
+        cache.clear().unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(1.))]);
+        assert_eq!(
+            tree.eval_persistent(2, &values, &mut cache).unwrap(),
+            NodeOutput::Number(4.)
+        );
+        assert_eq!(cache.stats, CacheStats { hits: 2, misses: 3 });
+    }
+
+    #[test]
+    fn test_persistent_cache_ladder() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_persistent_ladder.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        // Both nodes of a level read both nodes of the level below, the number of paths doubles
+        // with every level
+        let levels = 40;
+        let mut node_defs = vec![NodeDefinition {
+            node_id: 0,
+            kind: 0,
+            value: "a".into(),
+            default: None,
+        }];
+        let mut edge_defs = Vec::new();
+        for level in 0..levels {
+            for side in 0..2 {
+                let node_id = 1 + 2 * level + side;
+                let (inputs, value) = if level == 0 {
+                    (vec![0], "$0".to_string())
+                } else {
+                    let (a, b) = (2 * level - 1, 2 * level);
+                    (vec![a, b], format!("(${} + ${}) / 2", a, b))
+                };
+                node_defs.push(NodeDefinition {
+                    node_id,
+                    kind: 1,
+                    value,
+                    default: None,
+                });
+                for input_id in inputs {
+                    edge_defs.push(EdgeDefinition { node_id, input_id });
+                }
+            }
+        }
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let values = HashMap::from([(0, NodeOutput::Number(3.))]);
+
+        let mut cache = PersistentCache::open(file_name.clone()).unwrap();
+        assert_eq!(
+            tree.eval_persistent(2 * levels, &values, &mut cache)
+                .unwrap(),
+            NodeOutput::Number(3.)
+        );
+        assert_eq!(
+            cache.stats,
+            CacheStats {
+                hits: 0,
+                misses: 2 * levels - 1
+            }
+        );
+
+        // The root is found, nothing under it is looked up
+        let mut cache = PersistentCache::open(file_name.clone()).unwrap();
+        assert_eq!(
+            tree.eval_persistent(2 * levels, &values, &mut cache)
+                .unwrap(),
+            NodeOutput::Number(3.)
+        );
+        assert_eq!(cache.stats, CacheStats { hits: 1, misses: 0 });
+    }
+}
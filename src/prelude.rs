@@ -0,0 +1,9 @@
+// The types most users need, meant to be glob imported
+#[cfg(feature = "sqlite-blocking")]
+pub use crate::definitions_from_sqlite_blocking;
+#[cfg(feature = "sqlite")]
+pub use crate::defintions_from_sqlite;
+pub use crate::{
+    CompiledTree, EdgeDefinition, EvalCache, FunctionSet, NodeDefinition, NodeId, NodeOutput,
+    NodeRegistry, Scenario, Tree, TreeDraft,
+};
@@ -0,0 +1,484 @@
+use anyhow::{anyhow, Result};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::core::{NodeId, NodeOutput};
+
+// Kind ids used by the built-in variable, formula and aggregate nodes
+const BUILTIN_KINDS: [usize; 3] = [0, 1, 2];
+
+pub type NodeFuture = Pin<Box<dyn Future<Output = Result<NodeOutput>>>>;
+
+// Whatever a custom node carries from one evaluation to the next, kept by a `Session`
+pub type NodeState = Box<dyn Any + Send + Sync>;
+
+// Trees are shared across threads and do not change after they are built, so nodes keeping
+// state across evaluations get it from the session of the evaluation instead of holding it
+pub trait CustomNode: Debug + Send + Sync {
+    // Gets the outputs of the node inputs in the order of its edges
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput>;
+
+    // The state is None on the first evaluation of a session, and on every evaluation without
+    // one. Nodes without state do not need to implement it.
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        _state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        self.eval(inputs)
+    }
+
+    // Stored as the value of the node definition, `NodeFactory::create` has to accept it again
+    fn value(&self) -> String;
+
+    // False for nodes with the given number of inputs whose output depends on more than their
+    // inputs and state, e.g. on the clock, see `Tree::with_strict_mode`
+    fn deterministic(&self, _inputs: usize) -> bool {
+        true
+    }
+
+    // Nodes waiting on I/O can hand out the future of their output instead, `Tree::eval_async`
+    // awaits it while every other evaluation calls `eval`
+    fn eval_future(&self, _inputs: &[NodeOutput]) -> Option<NodeFuture> {
+        None
+    }
+}
+
+pub trait NodeFactory: Send + Sync {
+    fn name(&self) -> &str;
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>>;
+
+    // Trees create their nodes with the input ids in the order of the edges, for nodes that
+    // read their inputs by id like formulas do
+    fn create_with_inputs(
+        &self,
+        value: &str,
+        _input_ids: &[NodeId],
+    ) -> Result<Box<dyn CustomNode>> {
+        self.create(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CustomKind {
+    pub kind: usize,
+    pub node: Arc<dyn CustomNode>,
+}
+
+impl PartialEq for CustomKind {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.node.value() == other.node.value()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct NodeRegistry {
+    factories: HashMap<usize, Arc<dyn NodeFactory>>,
+}
+
+impl NodeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, kind: usize, factory: impl NodeFactory + 'static) -> Result<()> {
+        if BUILTIN_KINDS.contains(&kind) {
+            return Err(anyhow!("node kind {} is reserved", kind));
+        }
+        if let Some(existing) = self.factories.get(&kind) {
+            return Err(anyhow!(
+                "node kind {} is already registered for {}",
+                kind,
+                existing.name()
+            ));
+        }
+        self.factories.insert(kind, Arc::new(factory));
+        Ok(())
+    }
+
+    pub fn kind(&self, name: &str) -> Option<usize> {
+        self.factories
+            .iter()
+            .find(|(_, factory)| factory.name() == name)
+            .map(|(kind, _)| *kind)
+    }
+
+    pub(crate) fn create(
+        &self,
+        kind: usize,
+        value: &str,
+        input_ids: &[NodeId],
+    ) -> Result<CustomKind> {
+        let factory = self
+            .factories
+            .get(&kind)
+            .ok_or(anyhow!("Invalid node type"))?;
+        Ok(CustomKind {
+            kind,
+            node: factory.create_with_inputs(value, input_ids)?.into(),
+        })
+    }
+
+    fn names(&self) -> Vec<(usize, &str)> {
+        let mut names: Vec<_> = self
+            .factories
+            .iter()
+            .map(|(kind, factory)| (*kind, factory.name()))
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+impl Debug for NodeRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.names()).finish()
+    }
+}
+
+impl PartialEq for NodeRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, EvalCache, NodeDefinition, Tree, TreeDraft};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct Scale(f64);
+
+    impl CustomNode for Scale {
+        fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+            let mut sum = 0.;
+            for input in inputs {
+                let NodeOutput::Number(v) = input else {
+                    return Err(anyhow!("scale only takes numbers"));
+                };
+                sum += v;
+            }
+            Ok(NodeOutput::Number(sum * self.0))
+        }
+
+        fn value(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    struct ScaleFactory;
+
+    #[derive(Debug)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl CustomNode for Counter {
+        fn eval(&self, _inputs: &[NodeOutput]) -> Result<NodeOutput> {
+            let runs = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+            Ok(NodeOutput::Number(runs as f64))
+        }
+
+        fn value(&self) -> String {
+            String::new()
+        }
+    }
+
+    struct CounterFactory(Arc<AtomicUsize>);
+
+    impl NodeFactory for CounterFactory {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn create(&self, _value: &str) -> Result<Box<dyn CustomNode>> {
+            Ok(Box::new(Counter(Arc::clone(&self.0))))
+        }
+    }
+
+    // Outputs an array of the given length without any inputs
+    #[derive(Debug)]
+    struct Ones(usize);
+
+    impl CustomNode for Ones {
+        fn eval(&self, _inputs: &[NodeOutput]) -> Result<NodeOutput> {
+            Ok(NodeOutput::NumberArray(vec![1.; self.0]))
+        }
+
+        fn value(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    struct OnesFactory;
+
+    impl NodeFactory for OnesFactory {
+        fn name(&self) -> &str {
+            "ones"
+        }
+
+        fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+            Ok(Box::new(Ones(value.parse()?)))
+        }
+    }
+
+    impl NodeFactory for ScaleFactory {
+        fn name(&self) -> &str {
+            "scale"
+        }
+
+        fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+            Ok(Box::new(Scale(value.parse()?)))
+        }
+    }
+
+    #[test]
+    fn test_draft_round_trip() {
+        let mut registry = NodeRegistry::new();
+        registry.register(7, ScaleFactory).unwrap();
+        let mut draft = TreeDraft::new_with_registry(registry);
+        for (node_id, kind, value) in [
+            (0, 0, "a"),
+            (1, 7, "3"),
+            (2, 1, "$1 * factor"),
+            (3, 1, "$2 + 1"),
+        ] {
+            draft
+                .add_node(NodeDefinition {
+                    node_id,
+                    kind,
+                    value: value.into(),
+                    default: None,
+                })
+                .unwrap();
+        }
+        draft.connect(1, 0).unwrap();
+        draft.connect(2, 1).unwrap();
+        draft.connect(3, 2).unwrap();
+        let mut plain = draft.clone();
+        draft.add_parameter("factor", 2.).unwrap();
+        draft.set_condition(3, "factor > 1").unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::Number(1.))]);
+        let tree = draft.instantiate(&HashMap::new()).unwrap();
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(7.));
+
+        let draft = tree.to_draft();
+        assert_eq!(draft.parameters().len(), 1);
+        assert_eq!(draft.condition(3), Some("factor > 1"));
+        let parameters = HashMap::from([("factor".to_string(), 0.5)]);
+        let tree = draft.instantiate(&parameters).unwrap();
+        assert!(tree.node(3).is_err());
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(1.5));
+
+        // Freezing would drop the parameters and conditions
+        assert!(tree.to_draft().freeze().is_err());
+
+        // Frozen trees keep the registry of their draft as well
+        plain.remove_node(3).unwrap();
+        plain.remove_node(2).unwrap();
+        let tree = plain.freeze().unwrap();
+        let tree = tree.to_draft().freeze().unwrap();
+        assert_eq!(tree.eval(1, &values).unwrap(), NodeOutput::Number(3.));
+    }
+
+    #[test]
+    fn test_node_registry() {
+        let mut registry = NodeRegistry::new();
+        registry.register(7, ScaleFactory).unwrap();
+        assert!(registry.register(7, ScaleFactory).is_err());
+        assert!(registry.register(1, ScaleFactory).is_err());
+        assert!(registry.register(2, ScaleFactory).is_err());
+        assert_eq!(registry.kind("scale"), Some(7));
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 + 1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 7,
+                value: "0.5".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$2 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 4,
+                kind: 1,
+                value: "$3 + 1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+            EdgeDefinition {
+                node_id: 4,
+                input_id: 3,
+            },
+        ];
+        let values = HashMap::from([(0, NodeOutput::Number(3.))]);
+
+        assert!(Tree::new(node_defs.clone(), edge_defs.clone()).is_err());
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        assert_eq!(tree.eval(2, &values).unwrap(), NodeOutput::Number(3.5));
+
+        assert!(tree.inline(1).is_err());
+        let tree = tree.inline(3).unwrap();
+        assert_eq!(tree.eval(4, &values).unwrap(), NodeOutput::Number(8.));
+    }
+
+    #[test]
+    fn test_rerun() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut registry = NodeRegistry::new();
+        registry
+            .register(7, CounterFactory(Arc::clone(&runs)))
+            .unwrap();
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 7,
+                value: "".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 * 10".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$1 + $2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+        ];
+        let values = HashMap::from([(0, NodeOutput::Number(0.))]);
+
+        // Shared nodes are evaluated once per evaluation
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(11.));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        runs.store(0, Ordering::Relaxed);
+        assert!(tree.clone().with_rerun(&[0]).is_err());
+        let tree = tree.with_rerun(&[1]).unwrap();
+        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(12.));
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+        runs.store(0, Ordering::Relaxed);
+        let outputs = tree.eval_many(&[2, 3], &values).unwrap();
+        assert_eq!(outputs[&2], NodeOutput::Number(10.));
+        assert_eq!(outputs[&3], NodeOutput::Number(12.));
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+        runs.store(0, Ordering::Relaxed);
+        let mut cache = EvalCache::new();
+        tree.eval_cached(3, &values, &mut cache).unwrap();
+        tree.eval_cached(3, &values, &mut cache).unwrap();
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_memory_of_custom_outputs() {
+        let mut registry = NodeRegistry::new();
+        registry.register(7, OnesFactory).unwrap();
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 7,
+                value: "10".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 7,
+                value: "10".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 + $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+
+        // Outputs larger than the inputs of their node are counted at their actual size
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        let (output, stats) = tree.eval_with_memory(2, &HashMap::new()).unwrap();
+        assert_eq!(output, NodeOutput::NumberArray(vec![2.; 10]));
+        assert_eq!(stats.current, 80);
+        assert_eq!(stats.peak, 240);
+    }
+}
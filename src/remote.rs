@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::core::{NodeId, NodeKind, NodeOutput, Tree};
+
+// The outputs of the node inputs are sent in the order of the node's edges
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct RemoteRequest {
+    pub node_id: NodeId,
+    pub inputs: Vec<(NodeId, NodeOutput)>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum RemoteResponse {
+    Output(NodeOutput),
+    Error(String),
+}
+
+pub trait RemoteExecutor {
+    fn execute(&self, request: &RemoteRequest) -> Result<NodeOutput>;
+}
+
+// Every request is one JSON line over a new connection, answered by one JSON line
+pub struct TcpExecutor {
+    address: String,
+}
+
+impl TcpExecutor {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+        }
+    }
+}
+
+impl RemoteExecutor for TcpExecutor {
+    fn execute(&self, request: &RemoteRequest) -> Result<NodeOutput> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        match serde_json::from_str(&line)? {
+            RemoteResponse::Output(output) => Ok(output),
+            RemoteResponse::Error(e) => Err(anyhow!(
+                "remote execution of node {} failed: {}",
+                request.node_id,
+                e
+            )),
+        }
+    }
+}
+
+pub fn handle_connection(
+    mut stream: TcpStream,
+    handler: impl Fn(&RemoteRequest) -> Result<NodeOutput>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => match handler(&request) {
+            Ok(output) => RemoteResponse::Output(output),
+            Err(e) => RemoteResponse::Error(e.to_string()),
+        },
+        Err(e) => RemoteResponse::Error(format!("invalid request: {}", e)),
+    };
+
+    writeln!(stream, "{}", serde_json::to_string(&response)?)?;
+    Ok(())
+}
+
+// Runs a worker answering requests one connection at a time
+pub fn serve(
+    listener: &TcpListener,
+    handler: impl Fn(&RemoteRequest) -> Result<NodeOutput>,
+) -> Result<()> {
+    for stream in listener.incoming() {
+        handle_connection(stream?, &handler)?;
+    }
+    Ok(())
+}
+
+impl Tree {
+    // Evaluates the designated nodes through the executor, everything else locally. Every node
+    // is evaluated once, however many nodes read it.
+    pub fn eval_remote(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        remote_nodes: &[NodeId],
+        executor: &dyn RemoteExecutor,
+    ) -> Result<NodeOutput> {
+        let remote_nodes: HashSet<NodeId> = remote_nodes.iter().copied().collect();
+        self.run_hooks(node_id, values, |values| {
+            let mut outputs: HashMap<NodeId, NodeOutput> = HashMap::new();
+            for id in self.eval_order(node_id)?.iter() {
+                let node = self.node(*id)?;
+                if let NodeKind::Variable(_) = node.kind() {
+                    outputs.insert(*id, node.eval(values)?);
+                    continue;
+                }
+
+                let mut inputs = Vec::new();
+                for input in node.inputs.iter() {
+                    let output = outputs
+                        .get(input)
+                        .ok_or(anyhow!("node {} was not evaluated", input))?;
+                    inputs.push((*input, output.clone()));
+                }
+                let request = RemoteRequest {
+                    node_id: *id,
+                    inputs,
+                };
+                let output = if remote_nodes.contains(id) {
+                    executor.execute(&request)?
+                } else {
+                    self.execute_request(&request)?
+                };
+                outputs.insert(*id, output);
+            }
+            outputs
+                .remove(&node_id)
+                .ok_or(anyhow!("node {} was not evaluated", node_id))
+        })
+    }
+
+    // The worker side, evaluates a single node from the input outputs in the request
+    pub fn execute_request(&self, request: &RemoteRequest) -> Result<NodeOutput> {
+        let node = self.node(request.node_id)?;
+        let input_ids = &node.inputs;
+        let request_ids: Vec<NodeId> = request.inputs.iter().map(|(id, _)| *id).collect();
+        if *input_ids != request_ids {
+            return Err(anyhow!(
+                "node {} expects the inputs {:?}, got {:?}",
+                request.node_id,
+                input_ids,
+                request_ids
+            ));
+        }
+
+        let outputs = request.inputs.iter().map(|(_, x)| x.clone()).collect();
+        self.apply(node, outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+    use std::cell::Cell;
+    use std::thread;
+
+    fn test_tree() -> Tree {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: "$0 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 + $0".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+        ];
+        Tree::new(node_defs, edge_defs).unwrap()
+    }
+
+    #[test]
+    fn test_eval_remote() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let worker = thread::spawn(move || {
+            let tree = test_tree();
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, |request| tree.execute_request(request)).unwrap();
+        });
+
+        let tree = test_tree();
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2.]))]);
+        let output = tree
+            .eval_remote(2, &values, &[1], &TcpExecutor::new(address))
+            .unwrap();
+        worker.join().unwrap();
+        assert_eq!(output, NodeOutput::NumberArray(vec![3., 6.]));
+
+        let request = RemoteRequest {
+            node_id: 2,
+            inputs: vec![(0, NodeOutput::Number(1.))],
+        };
+        assert!(tree.execute_request(&request).is_err());
+    }
+
+    struct CountingExecutor {
+        tree: Tree,
+        count: Cell<usize>,
+    }
+
+    impl RemoteExecutor for CountingExecutor {
+        fn execute(&self, request: &RemoteRequest) -> Result<NodeOutput> {
+            self.count.set(self.count.get() + 1);
+            self.tree.execute_request(request)
+        }
+    }
+
+    #[test]
+    fn test_eval_remote_ladder() {
+        // Every node of a level reads both nodes of the level before, so the number of paths
+        // doubles with every level
+        let levels = 5_000;
+        let mut node_defs = vec![NodeDefinition {
+            node_id: 0,
+            kind: 0,
+            value: "a".into(),
+            default: None,
+        }];
+        let mut edge_defs = Vec::new();
+        for level in 1..=levels {
+            let inputs = match level {
+                1 => vec![0],
+                _ => vec![2 * level - 3, 2 * level - 2],
+            };
+            let value = match level {
+                1 => "$0".to_string(),
+                _ => format!("(${} + ${}) / 2", inputs[0], inputs[1]),
+            };
+            for node_id in [2 * level - 1, 2 * level] {
+                node_defs.push(NodeDefinition {
+                    node_id,
+                    kind: 1,
+                    value: value.clone(),
+                    default: None,
+                });
+                for input_id in &inputs {
+                    edge_defs.push(EdgeDefinition {
+                        node_id,
+                        input_id: *input_id,
+                    });
+                }
+            }
+        }
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+        let executor = CountingExecutor {
+            tree: tree.clone(),
+            count: Cell::new(0),
+        };
+        let remote_nodes: Vec<NodeId> = (1..=2 * levels).filter(|x| x % 2 == 0).collect();
+
+        let values = HashMap::from([(0, NodeOutput::Number(3.))]);
+        let output = tree
+            .eval_remote(2 * levels, &values, &remote_nodes, &executor)
+            .unwrap();
+        assert_eq!(output, NodeOutput::Number(3.));
+        assert_eq!(executor.count.get(), levels);
+    }
+}
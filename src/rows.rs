@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+use crate::core::{EvalCache, NodeId, NodeOutput, Tree};
+
+impl Tree {
+    // Evaluates the nodes once per row of the query and hands the outputs to `sink` in the
+    // order of `node_ids`. Columns are matched to variables by name, other columns are ignored.
+    // Rows are read one at a time, so the result set never has to fit into memory.
+    pub fn eval_rows(
+        &self,
+        file_name: String,
+        query: &str,
+        node_ids: &[NodeId],
+        sink: impl FnMut(usize, Vec<NodeOutput>) -> Result<()>,
+    ) -> Result<usize> {
+        let conn = Connection::open(file_name)?;
+        self.eval_rows_with(&conn, query, node_ids, sink)
+    }
+
+    // Same as `eval_rows`, but writes every output element into `table` of the same database,
+    // which is created if it does not exist yet
+    pub fn eval_rows_into(
+        &self,
+        file_name: String,
+        query: &str,
+        node_ids: &[NodeId],
+        table: &str,
+    ) -> Result<usize> {
+        let conn = Connection::open(file_name)?;
+        conn.execute_batch(&format!(
+            "
+            CREATE TABLE IF NOT EXISTS \"{}\" (
+                row INTEGER NOT NULL,
+                node_id INTEGER NOT NULL,
+                \"index\" INTEGER NOT NULL,
+                value REAL NOT NULL
+            )
+            ",
+            table
+        ))?;
+
+        let tx = conn.unchecked_transaction()?;
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO \"{}\" (row, node_id, \"index\", value) VALUES (?, ?, ?, ?)",
+            table
+        ))?;
+        let count = self.eval_rows_with(&tx, query, node_ids, |row, outputs| {
+            for (node_id, output) in node_ids.iter().zip(outputs) {
+                let values = output.elements()?;
+                for (idx, value) in values.iter().enumerate() {
+                    insert.execute(params![row as i64, *node_id as i64, idx as i64, value])?;
+                }
+            }
+            Ok(())
+        })?;
+        drop(insert);
+        tx.commit()?;
+        Ok(count)
+    }
+
+    fn eval_rows_with(
+        &self,
+        conn: &Connection,
+        query: &str,
+        node_ids: &[NodeId],
+        mut sink: impl FnMut(usize, Vec<NodeOutput>) -> Result<()>,
+    ) -> Result<usize> {
+        let variables: HashMap<&str, NodeId> = self
+            .node_definitions()
+            .iter()
+            .filter(|x| x.kind == 0)
+            .map(|x| (x.value.as_str(), x.node_id))
+            .collect();
+
+        let mut stmt = conn.prepare(query)?;
+        let columns: Vec<(usize, NodeId)> = stmt
+            .column_names()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, name)| variables.get(name).map(|id| (idx, *id)))
+            .collect();
+        if columns.is_empty() {
+            return Err(anyhow!("no column of the query matches a variable"));
+        }
+
+        let mut rows = stmt.query([])?;
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            let mut values = HashMap::new();
+            for (idx, node_id) in &columns {
+                values.insert(*node_id, NodeOutput::Number(row.get(*idx)?));
+            }
+
+            let mut cache = EvalCache::new();
+            let mut outputs = Vec::with_capacity(node_ids.len());
+            for node_id in node_ids {
+                outputs.push(self.eval_cached(*node_id, &values, &mut cache)?);
+            }
+            sink(count, outputs)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+
+    #[test]
+    fn test_eval_rows() {
+        let file_name = std::env::temp_dir()
+            .join("delphy_test_rows.db")
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&file_name);
+
+        let conn = Connection::open(&file_name).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE "input" ("id" INTEGER, "a" REAL, "b" INTEGER);
+            INSERT INTO "input" VALUES (1, 1.5, 2);
+            INSERT INTO "input" VALUES (2, 2.5, 4);
+            INSERT INTO "input" VALUES (3, 3.5, 6);
+        "#,
+        )
+        .unwrap();
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$0 * $1".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+        ];
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let mut outputs = Vec::new();
+        let query = "SELECT id, a, b FROM input ORDER BY id";
+        let count = tree
+            .eval_rows(file_name.clone(), query, &[2], |row, x| {
+                outputs.push((row, x));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(outputs[2], (2, vec![NodeOutput::Number(21.)]));
+        let query_ids = "SELECT id FROM input";
+        let sink = |_, _| Ok(());
+        assert!(tree
+            .eval_rows(file_name.clone(), query_ids, &[2], sink)
+            .is_err());
+
+        tree.eval_rows_into(file_name.clone(), query, &[0, 2], "output")
+            .unwrap();
+        let values: Vec<(i64, i64, f64)> = conn
+            .prepare("SELECT row, node_id, value FROM output ORDER BY row, node_id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(values.len(), 6);
+        assert_eq!(values[3], (1, 2, 10.));
+    }
+}
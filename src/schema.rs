@@ -0,0 +1,180 @@
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::contract::{Contract, ValueType};
+
+// Values are sent the way `NodeOutput` is serialized, e.g. `{"Number": 1.0}`
+fn value_schema(value_type: ValueType, unit: &Option<String>) -> Value {
+    let (variant, schema) = match value_type {
+        ValueType::Number => ("Number", json!({ "type": "number" })),
+        ValueType::NumberArray => (
+            "NumberArray",
+            json!({ "type": "array", "items": { "type": "number" } }),
+        ),
+        ValueType::Integer => ("Integer", json!({ "type": "integer" })),
+        ValueType::Bool => ("Bool", json!({ "type": "boolean" })),
+        ValueType::String => ("String", json!({ "type": "string" })),
+        ValueType::BoolArray => (
+            "BoolArray",
+            json!({ "type": "array", "items": { "type": "boolean" } }),
+        ),
+        ValueType::StringArray => (
+            "StringArray",
+            json!({ "type": "array", "items": { "type": "string" } }),
+        ),
+        ValueType::Matrix => (
+            "Matrix",
+            json!({
+                "type": "array",
+                "items": { "type": "array", "items": { "type": "number" } },
+            }),
+        ),
+    };
+    let mut schema = json!({
+        "type": "object",
+        "properties": { variant: schema },
+        "required": [variant],
+        "additionalProperties": false,
+    });
+    if let Some(unit) = unit {
+        schema["description"] = json!(format!("unit: {}", unit));
+    }
+    schema
+}
+
+impl Contract {
+    // JSON Schema of the inputs `Tree::eval_contract` accepts
+    pub fn input_schema(&self) -> Result<Value> {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        for input in &self.inputs {
+            let mut schema = value_schema(input.value_type, &input.unit);
+            match &input.default {
+                Some(default) => schema["default"] = serde_json::to_value(default)?,
+                None => required.push(input.name.clone()),
+            }
+            properties.insert(input.name.clone(), schema);
+        }
+        Ok(json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false,
+        }))
+    }
+
+    // JSON Schema of the outputs `Tree::eval_contract` returns
+    pub fn output_schema(&self) -> Value {
+        let mut properties = Map::new();
+        for output in &self.outputs {
+            properties.insert(
+                output.name.clone(),
+                value_schema(output.value_type, &output.unit),
+            );
+        }
+        let required: Vec<_> = self.outputs.iter().map(|x| x.name.clone()).collect();
+        json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false,
+        })
+    }
+
+    // OpenAPI document with a single POST operation at `path` taking the inputs and answering
+    // with the outputs of the contract
+    pub fn openapi(&self, title: &str, path: &str) -> Result<Value> {
+        let mut input_schema = self.input_schema()?;
+        let mut output_schema = self.output_schema();
+        for schema in [&mut input_schema, &mut output_schema] {
+            if let Some(schema) = schema.as_object_mut() {
+                schema.remove("$schema");
+            }
+        }
+
+        Ok(json!({
+            "openapi": "3.1.0",
+            "info": { "title": title, "version": "1.0.0" },
+            "paths": {
+                path: {
+                    "post": {
+                        "requestBody": {
+                            "required": true,
+                            "content": { "application/json": { "schema": input_schema } },
+                        },
+                        "responses": {
+                            "200": {
+                                "description": "outputs of the graph",
+                                "content": { "application/json": { "schema": output_schema } },
+                            },
+                        },
+                    },
+                },
+            },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{ContractInput, ContractOutput};
+    use crate::core::NodeOutput;
+
+    #[test]
+    fn test_contract_schema() {
+        let contract = Contract {
+            inputs: vec![
+                ContractInput {
+                    name: "length".into(),
+                    node_id: 0,
+                    value_type: ValueType::NumberArray,
+                    unit: Some("m".into()),
+                    default: None,
+                },
+                ContractInput {
+                    name: "width".into(),
+                    node_id: 1,
+                    value_type: ValueType::Number,
+                    unit: None,
+                    default: Some(NodeOutput::Number(2.)),
+                },
+            ],
+            outputs: vec![ContractOutput {
+                name: "area".into(),
+                node_id: 2,
+                value_type: ValueType::NumberArray,
+                unit: None,
+            }],
+        };
+
+        let schema = contract.input_schema().unwrap();
+        assert_eq!(schema["required"], json!(["length"]));
+        assert_eq!(schema["properties"]["length"]["description"], "unit: m");
+        assert_eq!(
+            schema["properties"]["length"]["properties"]["NumberArray"]["type"],
+            "array"
+        );
+        assert_eq!(
+            schema["properties"]["width"]["default"],
+            json!({ "Number": 2.0 })
+        );
+
+        let schema = contract.output_schema();
+        assert_eq!(schema["required"], json!(["area"]));
+
+        let openapi = contract.openapi("area", "/area").unwrap();
+        let operation = &openapi["paths"]["/area"]["post"];
+        assert_eq!(
+            operation["requestBody"]["content"]["application/json"]["schema"]["required"],
+            json!(["length"])
+        );
+        assert!(
+            operation["requestBody"]["content"]["application/json"]["schema"]
+                .get("$schema")
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::time::Instant;
+
+use crate::core::{MemoryStats, NodeId, NodeOutput, Tree};
+use crate::registry::NodeState;
+
+// The state custom nodes carry from one evaluation to the next, e.g. the previous value of a
+// rate of change. Evaluations without a session start every node from scratch, so the tree can
+// be cloned, rebuilt and analysed without touching it. A session belongs to one tree, it is
+// keyed by node id.
+#[derive(Default)]
+pub struct Session {
+    states: HashMap<NodeId, Option<NodeState>>,
+    // The last output of the nodes with a trigger, see `Trigger`
+    outputs: HashMap<NodeId, (Instant, NodeOutput)>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Starts every node over, as if the session was new
+    pub fn reset(&mut self) {
+        self.states.clear();
+        self.outputs.clear();
+    }
+
+    pub fn reset_node(&mut self, node_id: NodeId) {
+        self.states.remove(&node_id);
+        self.outputs.remove(&node_id);
+    }
+
+    // The node is evaluated again on the next evaluation, whatever its trigger
+    pub fn refresh(&mut self, node_id: NodeId) {
+        self.outputs.remove(&node_id);
+    }
+
+    pub(crate) fn state(&mut self, node_id: NodeId) -> &mut Option<NodeState> {
+        self.states.entry(node_id).or_default()
+    }
+
+    pub(crate) fn output(&self, node_id: NodeId) -> Option<&(Instant, NodeOutput)> {
+        self.outputs.get(&node_id)
+    }
+
+    pub(crate) fn set_output(&mut self, node_id: NodeId, computed_at: Instant, output: NodeOutput) {
+        self.outputs.insert(node_id, (computed_at, output));
+    }
+
+    pub(crate) fn has_state(&self, node_id: NodeId) -> bool {
+        matches!(self.states.get(&node_id), Some(Some(_)))
+    }
+
+    fn node_ids(&self) -> Vec<NodeId> {
+        let mut node_ids: Vec<NodeId> = self
+            .states
+            .iter()
+            .filter(|(_, state)| state.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        node_ids.sort();
+        node_ids
+    }
+}
+
+impl Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.node_ids()).finish()
+    }
+}
+
+impl Tree {
+    // Evaluates like `eval`, with the custom nodes taking their state from the session and
+    // leaving the new one there for the next evaluation
+    pub fn eval_session(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+        session: &mut Session,
+    ) -> Result<NodeOutput> {
+        self.run_hooks(node_id, values, |values| {
+            let mut stats = MemoryStats::default();
+            self.eval_tracked(node_id, values, &mut stats, session)
+        })
+    }
+}
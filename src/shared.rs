@@ -0,0 +1,287 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::core::{lock, NodeId, NodeOutput, Tree};
+use crate::diff::compare;
+use crate::formula::Tolerance;
+
+// A tree shared between threads that can be replaced while it is being evaluated. Trees do not
+// change once they are built, so every evaluation works on the snapshot it started with and a
+// replacement only reaches the evaluations after it.
+#[derive(Debug, Clone)]
+pub struct SharedTree {
+    current: Arc<Mutex<Arc<Tree>>>,
+    // Evaluated next to the current tree on another thread, its outputs are only compared
+    candidate: Arc<Mutex<Option<Arc<Tree>>>>,
+    shadows: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    discrepancies: Arc<Mutex<Vec<Discrepancy>>>,
+}
+
+// An evaluation where the candidate disagreed with the current tree, errors by their message
+#[derive(Debug, PartialEq, Clone)]
+pub struct Discrepancy {
+    pub node_id: NodeId,
+    pub values: HashMap<NodeId, NodeOutput>,
+    pub active: Result<NodeOutput, String>,
+    pub candidate: Result<NodeOutput, String>,
+    // Largest absolute difference of the elements, infinite if the shapes or errors do not match
+    pub max_difference: f64,
+}
+
+impl SharedTree {
+    pub fn new(tree: Tree) -> Self {
+        Self {
+            current: Arc::new(Mutex::new(Arc::new(tree))),
+            candidate: Arc::new(Mutex::new(None)),
+            shadows: Arc::new(Mutex::new(Vec::new())),
+            discrepancies: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<Tree> {
+        Arc::clone(&lock(&self.current))
+    }
+
+    // Returns the tree it replaced
+    pub fn replace(&self, tree: Tree) -> Arc<Tree> {
+        std::mem::replace(&mut *lock(&self.current), Arc::new(tree))
+    }
+
+    // Builds the next tree from the current one, e.g. through a draft. Updates run one after
+    // the other, so none of them is lost, and the evaluations meanwhile keep their snapshot.
+    pub fn update(&self, build: impl FnOnce(&Tree) -> Result<Tree>) -> Result<()> {
+        let mut current = lock(&self.current);
+        let tree = build(&current)?;
+        *current = Arc::new(tree);
+        Ok(())
+    }
+
+    // None stops evaluating the candidate, the discrepancies found so far are kept
+    pub fn set_candidate(&self, candidate: Option<Tree>) {
+        *lock(&self.candidate) = candidate.map(Arc::new);
+    }
+
+    // Only ever returns the output of the current tree, the candidate runs in the background
+    pub fn eval(
+        &self,
+        node_id: NodeId,
+        values: &HashMap<NodeId, NodeOutput>,
+    ) -> Result<NodeOutput> {
+        let active = self.snapshot();
+        let output = active.eval(node_id, values);
+        let Some(candidate) = lock(&self.candidate).clone() else {
+            return output;
+        };
+
+        // Outputs equal within the tolerance of the current tree are no discrepancy
+        let tolerance = active.tolerance().unwrap_or(Tolerance {
+            absolute: 0.,
+            relative: 0.,
+        });
+        let active = match &output {
+            Ok(output) => Ok(output.clone()),
+            Err(error) => Err(error.to_string()),
+        };
+        let values = values.clone();
+        let discrepancies = Arc::clone(&self.discrepancies);
+        let shadow = thread::spawn(move || {
+            let candidate = candidate
+                .eval(node_id, &values)
+                .map_err(|error| error.to_string());
+            let max_difference = match (&active, &candidate) {
+                (Ok(active), Ok(candidate)) => match compare(active, candidate, tolerance) {
+                    (_, true) => return,
+                    (max_difference, false) => max_difference,
+                },
+                (Err(active), Err(candidate)) if active == candidate => return,
+                _ => f64::INFINITY,
+            };
+            lock(&discrepancies).push(Discrepancy {
+                node_id,
+                values,
+                active,
+                candidate,
+                max_difference,
+            });
+        });
+
+        let mut shadows = lock(&self.shadows);
+        shadows.retain(|shadow| !shadow.is_finished());
+        shadows.push(shadow);
+        output
+    }
+
+    // Waits for the candidate evaluations still running
+    pub fn take_discrepancies(&self) -> Vec<Discrepancy> {
+        let shadows = std::mem::take(&mut *lock(&self.shadows));
+        for shadow in shadows {
+            // A panicking candidate leaves no discrepancy but must not take the caller down
+            let _ = shadow.join();
+        }
+        std::mem::take(&mut *lock(&self.discrepancies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+    use std::thread;
+
+    // Every node adds `version` to its input, so node 3 is 0 for every consistent version
+    fn versioned(version: usize) -> Tree {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: format!("$0 + {}", version),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: format!("$1 + {}", version),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "($2 - $1) - ($1 - $0)".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = [(1, 0), (2, 1), (3, 2), (3, 1), (3, 0)]
+            .map(|(node_id, input_id)| EdgeDefinition { node_id, input_id })
+            .to_vec();
+        Tree::new(node_defs, edge_defs).unwrap()
+    }
+
+    #[test]
+    fn test_shared_tree() {
+        let shared = SharedTree::new(versioned(1));
+        let values = HashMap::from([(0, NodeOutput::Number(0.))]);
+
+        let snapshot = shared.snapshot();
+        assert_eq!(
+            shared.replace(versioned(2)).eval(1, &values).unwrap(),
+            NodeOutput::Number(1.)
+        );
+        assert_eq!(snapshot.eval(1, &values).unwrap(), NodeOutput::Number(1.));
+        assert_eq!(shared.eval(1, &values).unwrap(), NodeOutput::Number(2.));
+        assert!(shared.update(|_| versioned(3).inline(5)).is_err());
+        assert_eq!(shared.eval(1, &values).unwrap(), NodeOutput::Number(2.));
+
+        // Readers never see nodes of two versions in one evaluation while a writer swaps them
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let shared = shared.clone();
+                let values = values.clone();
+                scope.spawn(move || {
+                    for _ in 0..500 {
+                        let tree = shared.snapshot();
+                        assert_eq!(tree.eval(3, &values).unwrap(), NodeOutput::Number(0.));
+                        let NodeOutput::Number(version) = tree.eval(1, &values).unwrap() else {
+                            unreachable!()
+                        };
+                        assert!(version >= 2.);
+                        assert_eq!(tree.eval(1, &values).unwrap(), NodeOutput::Number(version));
+                    }
+                });
+            }
+            let shared = shared.clone();
+            scope.spawn(move || {
+                for version in 3..500 {
+                    match version % 2 {
+                        0 => {
+                            shared.replace(versioned(version));
+                        }
+                        _ => shared.update(|_| Ok(versioned(version))).unwrap(),
+                    }
+                }
+            });
+        });
+        assert_eq!(shared.eval(1, &values).unwrap(), NodeOutput::Number(499.));
+    }
+
+    fn doubled(formula: &str) -> Tree {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 1,
+                value: formula.into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        Tree::new(node_defs, edge_defs).unwrap()
+    }
+
+    #[test]
+    fn test_candidate() {
+        let shared = SharedTree::new(doubled("$0 * 2"));
+        let values = |a: f64| HashMap::from([(0, NodeOutput::Number(a))]);
+
+        // The squares only agree for 0 and 2, the candidate never shows up in the outputs
+        shared.set_candidate(Some(doubled("$0 * $0")));
+        for a in 0..4 {
+            let a = a as f64;
+            assert_eq!(
+                shared.eval(1, &values(a)).unwrap(),
+                NodeOutput::Number(a * 2.)
+            );
+        }
+        let mut discrepancies = shared.take_discrepancies();
+        discrepancies.sort_by(|a, b| a.max_difference.total_cmp(&b.max_difference));
+        assert_eq!(
+            discrepancies,
+            vec![
+                Discrepancy {
+                    node_id: 1,
+                    values: values(1.),
+                    active: Ok(NodeOutput::Number(2.)),
+                    candidate: Ok(NodeOutput::Number(1.)),
+                    max_difference: 1.,
+                },
+                Discrepancy {
+                    node_id: 1,
+                    values: values(3.),
+                    active: Ok(NodeOutput::Number(6.)),
+                    candidate: Ok(NodeOutput::Number(9.)),
+                    max_difference: 3.,
+                },
+            ]
+        );
+        assert!(shared.take_discrepancies().is_empty());
+
+        // Failing the same way is no discrepancy, failing where the current tree does not is
+        assert!(shared.eval(1, &HashMap::new()).is_err());
+        assert!(shared.take_discrepancies().is_empty());
+        shared.set_candidate(Some(doubled("$0 * 2 + b")));
+        assert_eq!(shared.eval(1, &values(1.)).unwrap(), NodeOutput::Number(2.));
+        let discrepancies = shared.take_discrepancies();
+        assert_eq!(discrepancies.len(), 1);
+        assert!(discrepancies[0].candidate.is_err());
+        assert_eq!(discrepancies[0].max_difference, f64::INFINITY);
+
+        shared.set_candidate(None);
+        assert_eq!(shared.eval(1, &values(1.)).unwrap(), NodeOutput::Number(2.));
+        assert!(shared.take_discrepancies().is_empty());
+    }
+}
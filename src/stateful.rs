@@ -0,0 +1,1480 @@
+use anyhow::{anyhow, Result};
+use evalexpr::{build_operator_tree, Value};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::binding::{BindingPlan, BoundContext};
+use crate::core::{NodeId, NodeOutput};
+use crate::formula::canonical_references;
+use crate::functions::FunctionSet;
+use crate::registry::{CustomNode, NodeFactory, NodeState};
+
+// Custom nodes keeping state from one evaluation of a tree to the next. The state lives in the
+// `Session` passed to `Tree::eval_session`, every other evaluation starts from scratch.
+
+// The state of the node in the session, made by `init` on the first evaluation
+fn state_of<T: Any + Send + Sync>(
+    state: &mut Option<NodeState>,
+    init: impl FnOnce() -> T,
+) -> Result<&mut T> {
+    state
+        .get_or_insert_with(|| Box::new(init()))
+        .downcast_mut()
+        .ok_or(anyhow!("the session holds the state of another node"))
+}
+
+// The state of nodes taking timestamps, which count from the first evaluation without one
+struct Timed<T> {
+    started: Instant,
+    state: T,
+}
+
+fn timed_state_of<T: Any + Send + Sync>(
+    state: &mut Option<NodeState>,
+    init: impl FnOnce() -> T,
+) -> Result<&mut Timed<T>> {
+    state_of(state, || Timed {
+        started: Instant::now(),
+        state: init(),
+    })
+}
+
+// The numbers of the value, which has to have at least one
+fn numbers(value: &NodeOutput, name: &str) -> Result<Vec<f64>> {
+    let values = value.elements()?;
+    if values.is_empty() {
+        return Err(anyhow!("{} got an empty value", name));
+    }
+    Ok(values)
+}
+
+// The values in the shape of the input they were taken from, scalars stay scalars and booleans
+// become numbers
+fn shaped(values: Vec<f64>, like: &NodeOutput) -> Result<NodeOutput> {
+    match like {
+        NodeOutput::Number(_) | NodeOutput::Integer(_) | NodeOutput::Bool(_) => values
+            .first()
+            .map(|x| NodeOutput::Number(*x))
+            .ok_or(anyhow!("no value to return")),
+        NodeOutput::Matrix(rows) => {
+            let mut values = values.into_iter();
+            Ok(NodeOutput::Matrix(
+                rows.iter()
+                    .map(|row| values.by_ref().take(row.len()).collect())
+                    .collect(),
+            ))
+        }
+        NodeOutput::Series { name, .. } => Ok(NodeOutput::Series {
+            name: name.clone(),
+            values,
+        }),
+        NodeOutput::TimeSeries(pairs) => Ok(NodeOutput::TimeSeries(
+            pairs.iter().map(|(t, _)| *t).zip(values).collect(),
+        )),
+        _ => Ok(NodeOutput::NumberArray(values)),
+    }
+}
+
+fn parse_or(value: &str, default: f64) -> Result<f64> {
+    match value.trim() {
+        "" => Ok(default),
+        value => Ok(value.parse()?),
+    }
+}
+
+// The value and its timestamp in seconds, taken from the second input if there is one and from
+// the time since `started` otherwise
+fn timestamped<'a>(
+    inputs: &'a [NodeOutput],
+    started: Instant,
+    name: &str,
+) -> Result<(&'a NodeOutput, f64)> {
+    match inputs {
+        [value] => Ok((value, started.elapsed().as_secs_f64())),
+        [value, NodeOutput::Number(time)] => Ok((value, *time)),
+        [value, NodeOutput::Integer(time)] => Ok((value, *time as f64)),
+        _ => Err(anyhow!("{} takes a value and an optional timestamp", name)),
+    }
+}
+
+// The time since the previous evaluation and its values, None on the first evaluation
+fn since_previous(
+    previous: &Option<(f64, Vec<f64>)>,
+    time: f64,
+    len: usize,
+) -> Result<Option<(f64, &[f64])>> {
+    let Some((previous_time, previous_values)) = previous else {
+        return Ok(None);
+    };
+    if time <= *previous_time {
+        return Err(anyhow!("timestamps have to increase"));
+    }
+    if previous_values.len() != len {
+        return Err(anyhow!("the length of the value changed"));
+    }
+    Ok(Some((time - previous_time, previous_values)))
+}
+
+// Time of the previous evaluation and its values
+type Previous = Option<(f64, Vec<f64>)>;
+
+#[derive(Debug)]
+struct RateOfChange {
+    time_unit: f64,
+}
+
+impl CustomNode for RateOfChange {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    // Without a timestamp input the node reads the clock
+    fn deterministic(&self, inputs: usize) -> bool {
+        inputs == 2
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let Timed {
+            started,
+            state: previous,
+        } = timed_state_of::<Previous>(state, || None)?;
+        let (value, time) = timestamped(inputs, *started, "rate of change")?;
+
+        let values = numbers(value, "rate of change")?;
+        // The first evaluation has nothing to compare with
+        let rates = match since_previous(previous, time, values.len())? {
+            Some((dt, previous_values)) => {
+                let dt = dt / self.time_unit;
+                values
+                    .iter()
+                    .zip(previous_values)
+                    .map(|(x, previous)| (x - previous) / dt)
+                    .collect()
+            }
+            None => vec![0.; values.len()],
+        };
+        *previous = Some((time, values));
+        shaped(rates, value)
+    }
+
+    fn value(&self) -> String {
+        self.time_unit.to_string()
+    }
+}
+
+// The change of the value per time unit between two evaluations. The node value is the time
+// unit in seconds, e.g. `60` for a change per minute, and defaults to one second.
+pub struct RateOfChangeFactory;
+
+impl NodeFactory for RateOfChangeFactory {
+    fn name(&self) -> &str {
+        "rate_of_change"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let time_unit = parse_or(value, 1.)?;
+        if time_unit <= 0. {
+            return Err(anyhow!("the time unit has to be positive"));
+        }
+        Ok(Box::new(RateOfChange { time_unit }))
+    }
+}
+
+#[derive(Debug)]
+struct Hysteresis {
+    low: f64,
+    high: f64,
+}
+
+impl CustomNode for Hysteresis {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let [value] = inputs else {
+            return Err(anyhow!("hysteresis takes a single value"));
+        };
+        let values = numbers(value, "hysteresis")?;
+        let on = state_of::<Vec<bool>>(state, Vec::new)?;
+        if on.len() != values.len() {
+            *on = vec![false; values.len()];
+        }
+
+        for (on, x) in on.iter_mut().zip(&values) {
+            if *x > self.high {
+                *on = true;
+            } else if *x < self.low {
+                *on = false;
+            }
+        }
+        let output = on.iter().map(|x| if *x { 1. } else { 0. }).collect();
+        shaped(output, value)
+    }
+
+    fn value(&self) -> String {
+        format!("{},{}", self.low, self.high)
+    }
+}
+
+// Switches on once the value rises above the upper threshold and only off again once it falls
+// below the lower one. The node value is `low,high`, the output 1 while on and 0 while off.
+pub struct HysteresisFactory;
+
+impl NodeFactory for HysteresisFactory {
+    fn name(&self) -> &str {
+        "hysteresis"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let Some((low, high)) = value.split_once(',') else {
+            return Err(anyhow!("hysteresis needs a lower and an upper threshold"));
+        };
+        let (low, high): (f64, f64) = (low.trim().parse()?, high.trim().parse()?);
+        if low > high {
+            return Err(anyhow!("the lower threshold is above the upper one"));
+        }
+        Ok(Box::new(Hysteresis { low, high }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct DebounceState {
+    on: bool,
+    // Since when the input differs from the output
+    changed_at: Option<f64>,
+}
+
+#[derive(Debug)]
+struct Debounce {
+    duration: f64,
+}
+
+impl CustomNode for Debounce {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    // Without a timestamp input the node reads the clock
+    fn deterministic(&self, inputs: usize) -> bool {
+        inputs == 2
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let Timed {
+            started,
+            state: states,
+        } = timed_state_of::<Vec<DebounceState>>(state, Vec::new)?;
+        let (value, time) = timestamped(inputs, *started, "debounce")?;
+        let values = numbers(value, "debounce")?;
+        if states.len() != values.len() {
+            *states = vec![DebounceState::default(); values.len()];
+        }
+
+        for (state, x) in states.iter_mut().zip(&values) {
+            let on = *x != 0.;
+            if on == state.on {
+                state.changed_at = None;
+                continue;
+            }
+            let changed_at = *state.changed_at.get_or_insert(time);
+            if time - changed_at >= self.duration {
+                state.on = on;
+                state.changed_at = None;
+            }
+        }
+        let output = states.iter().map(|x| if x.on { 1. } else { 0. }).collect();
+        shaped(output, value)
+    }
+
+    fn value(&self) -> String {
+        self.duration.to_string()
+    }
+}
+
+// Follows the input (anything but 0 is on) only after it kept its new state for at least the
+// duration in seconds given as node value. Timestamps work the same as for the rate of change.
+pub struct DebounceFactory;
+
+impl NodeFactory for DebounceFactory {
+    fn name(&self) -> &str {
+        "debounce"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let duration = parse_or(value, 0.)?;
+        if duration < 0. {
+            return Err(anyhow!("the duration cannot be negative"));
+        }
+        Ok(Box::new(Debounce { duration }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PidState {
+    integral: f64,
+    error: f64,
+}
+
+#[derive(Debug)]
+struct Pid {
+    gains: [f64; 3],
+    limits: Option<(f64, f64)>,
+}
+
+impl CustomNode for Pid {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    // Without a timestamp input the node reads the clock
+    fn deterministic(&self, inputs: usize) -> bool {
+        inputs == 2
+    }
+
+    // The state is the time of the previous evaluation and the state of every element
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let Timed { started, state } =
+            timed_state_of::<Option<(f64, Vec<PidState>)>>(state, || None)?;
+        let (value, time) = timestamped(inputs, *started, "pid")?;
+        let errors = numbers(value, "pid")?;
+        let dt = match state.as_ref() {
+            Some((previous_time, states)) => {
+                if time <= *previous_time {
+                    return Err(anyhow!("timestamps have to increase"));
+                }
+                if states.len() != errors.len() {
+                    return Err(anyhow!("the length of the value changed"));
+                }
+                time - previous_time
+            }
+            None => 0.,
+        };
+        let mut states = match state.take() {
+            Some((_, states)) => states,
+            None => vec![PidState::default(); errors.len()],
+        };
+
+        let [kp, ki, kd] = self.gains;
+        let mut output = Vec::with_capacity(errors.len());
+        for (state, error) in states.iter_mut().zip(&errors) {
+            let derivative = if dt > 0. {
+                (error - state.error) / dt
+            } else {
+                0.
+            };
+            let integral = state.integral + error * dt;
+            let unlimited = kp * error + ki * integral + kd * derivative;
+            let limited = match self.limits {
+                Some((min, max)) => unlimited.clamp(min, max),
+                None => unlimited,
+            };
+            // Anti-windup, the integral stops growing while it pushes the output past a limit
+            if limited == unlimited || (unlimited > limited) != (*error > 0.) {
+                state.integral = integral;
+            }
+            state.error = *error;
+            output.push(limited);
+        }
+        *state = Some((time, states));
+        shaped(output, value)
+    }
+
+    fn value(&self) -> String {
+        let [kp, ki, kd] = self.gains;
+        match self.limits {
+            Some((min, max)) => format!("{},{},{},{},{}", kp, ki, kd, min, max),
+            None => format!("{},{},{}", kp, ki, kd),
+        }
+    }
+}
+
+// PID controller on the control error as input, e.g. `$setpoint - $measurement` in a formula
+// node before it. The node value is `kp,ki,kd` with optional output limits as `kp,ki,kd,min,max`.
+// Timestamps work the same as for the rate of change.
+pub struct PidFactory;
+
+impl NodeFactory for PidFactory {
+    fn name(&self) -> &str {
+        "pid"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let parameters = value
+            .split(',')
+            .map(|x| x.trim().parse())
+            .collect::<std::result::Result<Vec<f64>, _>>()?;
+        let (gains, limits) = match parameters[..] {
+            [kp, ki, kd] => ([kp, ki, kd], None),
+            [kp, ki, kd, min, max] if min <= max => ([kp, ki, kd], Some((min, max))),
+            [_, _, _, _, _] => return Err(anyhow!("the lower limit is above the upper one")),
+            _ => {
+                return Err(anyhow!(
+                    "pid needs the gains kp,ki,kd and optionally min,max"
+                ))
+            }
+        };
+        Ok(Box::new(Pid { gains, limits }))
+    }
+}
+
+#[derive(Debug)]
+struct FirstOrderLag {
+    time_constant: f64,
+}
+
+impl CustomNode for FirstOrderLag {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    // Without a timestamp input the node reads the clock
+    fn deterministic(&self, inputs: usize) -> bool {
+        inputs == 2
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let Timed {
+            started,
+            state: previous,
+        } = timed_state_of::<Previous>(state, || None)?;
+        let (value, time) = timestamped(inputs, *started, "first order lag")?;
+        let values = numbers(value, "first order lag")?;
+        // The output starts at the first value
+        let output = match since_previous(previous, time, values.len())? {
+            Some((dt, outputs)) => {
+                let factor = 1. - (-dt / self.time_constant).exp();
+                outputs
+                    .iter()
+                    .zip(&values)
+                    .map(|(y, x)| y + (x - y) * factor)
+                    .collect()
+            }
+            None => values,
+        };
+        *previous = Some((time, output.clone()));
+        shaped(output, value)
+    }
+
+    fn value(&self) -> String {
+        self.time_constant.to_string()
+    }
+}
+
+// Follows the value with the delay of a first order system, the node value is its time
+// constant in seconds. Timestamps work the same as for the rate of change.
+pub struct FirstOrderLagFactory;
+
+impl NodeFactory for FirstOrderLagFactory {
+    fn name(&self) -> &str {
+        "first_order_lag"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let time_constant = parse_or(value, 1.)?;
+        if time_constant < 0. {
+            return Err(anyhow!("the time constant cannot be negative"));
+        }
+        Ok(Box::new(FirstOrderLag { time_constant }))
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+}
+
+impl CustomNode for RateLimiter {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    // Without a timestamp input the node reads the clock
+    fn deterministic(&self, inputs: usize) -> bool {
+        inputs == 2
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let Timed {
+            started,
+            state: previous,
+        } = timed_state_of::<Previous>(state, || None)?;
+        let (value, time) = timestamped(inputs, *started, "rate limiter")?;
+        let values = numbers(value, "rate limiter")?;
+        let output = match since_previous(previous, time, values.len())? {
+            Some((dt, outputs)) => {
+                let step = self.rate * dt;
+                outputs
+                    .iter()
+                    .zip(&values)
+                    .map(|(y, x)| y + (x - y).clamp(-step, step))
+                    .collect()
+            }
+            None => values,
+        };
+        *previous = Some((time, output.clone()));
+        shaped(output, value)
+    }
+
+    fn value(&self) -> String {
+        self.rate.to_string()
+    }
+}
+
+// Follows the value by at most the node value per second, up or down. Timestamps work the same
+// as for the rate of change.
+pub struct RateLimiterFactory;
+
+impl NodeFactory for RateLimiterFactory {
+    fn name(&self) -> &str {
+        "rate_limiter"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let rate: f64 = value.trim().parse()?;
+        if rate < 0. {
+            return Err(anyhow!("the rate cannot be negative"));
+        }
+        Ok(Box::new(RateLimiter { rate }))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DelayLength {
+    Evaluations(usize),
+    Seconds(f64),
+}
+
+#[derive(Debug)]
+struct Delay {
+    length: DelayLength,
+}
+
+impl CustomNode for Delay {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    // Without a timestamp input the node reads the clock
+    fn deterministic(&self, inputs: usize) -> bool {
+        inputs == 2
+    }
+
+    // The state holds the earlier values with their timestamps, the oldest one in front is the
+    // output
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let Timed {
+            started,
+            state: buffer,
+        } = timed_state_of(state, VecDeque::<(f64, NodeOutput)>::new)?;
+        let (value, time) = timestamped(inputs, *started, "delay")?;
+        match self.length {
+            DelayLength::Evaluations(count) => {
+                let len = count
+                    .checked_add(1)
+                    .ok_or(anyhow!("the delay of {} evaluations is too long", count))?;
+                if buffer.len() >= len {
+                    buffer.pop_front();
+                }
+                buffer.push_back((time, value.clone()));
+            }
+            DelayLength::Seconds(duration) => {
+                if buffer.back().is_some_and(|(previous, _)| time <= *previous) {
+                    return Err(anyhow!("timestamps have to increase"));
+                }
+                buffer.push_back((time, value.clone()));
+                // The front is kept until the value after it is old enough to take its place
+                while buffer.get(1).is_some_and(|(x, _)| time - x >= duration) {
+                    buffer.pop_front();
+                }
+            }
+        }
+        // Until there is a value old enough, the first value is the output
+        let (_, output) = buffer.front().ok_or(anyhow!("the delay has no values"))?;
+        Ok(output.clone())
+    }
+
+    fn value(&self) -> String {
+        match self.length {
+            DelayLength::Evaluations(count) => count.to_string(),
+            DelayLength::Seconds(duration) => format!("{}s", duration),
+        }
+    }
+}
+
+// Every delayed value is kept in the session, so the count has to stay reasonable
+const MAX_DELAY: usize = 1 << 20;
+
+// Outputs the value of a number of evaluations ago, e.g. `3`, or of a time ago with the node
+// value in seconds followed by `s`, e.g. `1.5s`. Timestamps work the same as for the rate of
+// change.
+pub struct DelayFactory;
+
+impl NodeFactory for DelayFactory {
+    fn name(&self) -> &str {
+        "delay"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let length = match value.trim().strip_suffix('s') {
+            Some(duration) => {
+                let duration: f64 = duration.trim().parse()?;
+                if duration < 0. {
+                    return Err(anyhow!("the duration cannot be negative"));
+                }
+                DelayLength::Seconds(duration)
+            }
+            None => {
+                let count: usize = value.trim().parse()?;
+                if count > MAX_DELAY {
+                    return Err(anyhow!(
+                        "the delay cannot be more than {} evaluations",
+                        MAX_DELAY
+                    ));
+                }
+                DelayLength::Evaluations(count)
+            }
+        };
+        Ok(Box::new(Delay { length }))
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    len: usize,
+}
+
+impl CustomNode for Window {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let value = match inputs {
+            [NodeOutput::Number(v)] => *v,
+            [NodeOutput::Integer(v)] => *v as f64,
+            _ => return Err(anyhow!("window takes a single scalar")),
+        };
+        let values = state_of(state, VecDeque::<f64>::new)?;
+        if values.len() == self.len {
+            values.pop_front();
+        }
+        values.push_back(value);
+        Ok(NodeOutput::NumberArray(values.iter().copied().collect()))
+    }
+
+    fn value(&self) -> String {
+        self.len.to_string()
+    }
+}
+
+// Collects the scalar of every evaluation into an array of the last values, oldest first. The
+// node value is the length of the array, it is shorter until that many evaluations ran.
+pub struct WindowFactory;
+
+impl NodeFactory for WindowFactory {
+    fn name(&self) -> &str {
+        "window"
+    }
+
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        let len: usize = value.trim().parse()?;
+        if len == 0 {
+            return Err(anyhow!("the window needs a length of at least 1"));
+        }
+        Ok(Box::new(Window { len }))
+    }
+}
+
+#[derive(Debug)]
+struct Unbatch;
+
+impl CustomNode for Unbatch {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let [value] = inputs else {
+            return Err(anyhow!("unbatch takes a single value"));
+        };
+        let values = value.elements()?;
+        if values.is_empty() {
+            return Err(anyhow!("unbatch got an empty array"));
+        }
+        let next = state_of::<usize>(state, || 0)?;
+        let idx = *next % values.len();
+        *next = idx + 1;
+        Ok(NodeOutput::Number(values[idx]))
+    }
+
+    fn value(&self) -> String {
+        String::new()
+    }
+}
+
+// Hands out the elements of the array one per evaluation and starts over after the last one
+pub struct UnbatchFactory;
+
+impl NodeFactory for UnbatchFactory {
+    fn name(&self) -> &str {
+        "unbatch"
+    }
+
+    fn create(&self, _value: &str) -> Result<Box<dyn CustomNode>> {
+        Ok(Box::new(Unbatch))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct Transition {
+    from: String,
+    to: String,
+    guard: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct StateMachineDefinition {
+    states: Vec<String>,
+    transitions: Vec<Transition>,
+}
+
+#[derive(Debug)]
+struct StateMachine {
+    definition: StateMachineDefinition,
+    // Index of the state and the parsed and bound guard of every transition
+    transitions: Vec<(usize, usize, evalexpr::Node, BindingPlan)>,
+}
+
+impl CustomNode for StateMachine {
+    fn eval(&self, inputs: &[NodeOutput]) -> Result<NodeOutput> {
+        self.eval_with_state(inputs, &mut None)
+    }
+
+    fn eval_with_state(
+        &self,
+        inputs: &[NodeOutput],
+        state: &mut Option<NodeState>,
+    ) -> Result<NodeOutput> {
+        let mut values = Vec::new();
+        for input in inputs {
+            let value = match input {
+                NodeOutput::Number(v) => Value::Float(*v),
+                NodeOutput::Integer(v) => Value::Int(*v),
+                NodeOutput::Bool(v) => Value::Boolean(*v),
+                NodeOutput::String(v) => Value::String(v.clone()),
+                NodeOutput::NumberArray(_)
+                | NodeOutput::BoolArray(_)
+                | NodeOutput::StringArray(_)
+                | NodeOutput::Matrix(_)
+                | NodeOutput::Series { .. }
+                | NodeOutput::TimeSeries(_)
+                | NodeOutput::Custom(_) => {
+                    return Err(anyhow!("state machine inputs have to be scalars"))
+                }
+            };
+            values.push(value);
+        }
+
+        // At most one transition per evaluation, the first one whose guard holds
+        let functions = FunctionSet::default().context()?;
+        let state = state_of::<usize>(state, || 0)?;
+        for (from, to, guard, plan) in &self.transitions {
+            if *from != *state {
+                continue;
+            }
+            let mut context = BoundContext::new(plan, functions.clone());
+            for (slot, input) in plan.inputs().iter().enumerate() {
+                let value = values
+                    .get(*input)
+                    .ok_or(anyhow!("the state machine got {} inputs", values.len()))?;
+                context.set_value(slot, value.clone());
+            }
+            if guard.eval_boolean_with_context(&context)? {
+                *state = *to;
+                break;
+            }
+        }
+        Ok(NodeOutput::Integer(*state as i64))
+    }
+
+    fn value(&self) -> String {
+        serde_json::to_string(&self.definition).unwrap_or_default()
+    }
+}
+
+// Moves between named states when the guard formula of a transition out of the current state
+// holds, and outputs the index of the current state. The node value is JSON like
+// `{"states": ["off", "on"], "transitions": [{"from": "off", "to": "on", "guard": "$4 > 1"}]}`
+// with the guards reading the inputs like formula nodes do. It starts in the first state.
+pub struct StateMachineFactory;
+
+impl NodeFactory for StateMachineFactory {
+    fn name(&self) -> &str {
+        "state_machine"
+    }
+
+    // Without inputs the guards cannot read any
+    fn create(&self, value: &str) -> Result<Box<dyn CustomNode>> {
+        self.create_with_inputs(value, &[])
+    }
+
+    fn create_with_inputs(&self, value: &str, input_ids: &[NodeId]) -> Result<Box<dyn CustomNode>> {
+        let definition: StateMachineDefinition = serde_json::from_str(value)?;
+        if definition.states.is_empty() {
+            return Err(anyhow!("a state machine needs at least one state"));
+        }
+        let index = |name: &str| {
+            definition
+                .states
+                .iter()
+                .position(|x| x == name)
+                .ok_or(anyhow!("unknown state {}", name))
+        };
+        let mut transitions = Vec::new();
+        for transition in &definition.transitions {
+            let guard = build_operator_tree(&canonical_references(&transition.guard, input_ids)?)?;
+            let plan = BindingPlan::new(&guard, input_ids);
+            if let Some(identifier) = guard
+                .iter_read_variable_identifiers()
+                .find(|x| plan.input(x).is_none())
+            {
+                return Err(anyhow!(
+                    "guard {} reads {}, which is no input of the node",
+                    transition.guard,
+                    identifier
+                ));
+            }
+            transitions.push((
+                index(&transition.from)?,
+                index(&transition.to)?,
+                guard,
+                plan,
+            ));
+        }
+        Ok(Box::new(StateMachine {
+            definition,
+            transitions,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, Tree};
+    use crate::registry::NodeRegistry;
+    use crate::session::Session;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_rate_of_change() {
+        let mut registry = NodeRegistry::new();
+        registry.register(10, RateOfChangeFactory).unwrap();
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "level".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "time".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 10,
+                value: "60".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 10,
+                value: "".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 0,
+            },
+        ];
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        let mut session = Session::new();
+        // Node 3 takes its timestamps from the clock
+        assert!(tree.clone().with_strict_mode().is_err());
+
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![1., 5.])),
+            (1, NodeOutput::Number(0.)),
+        ]);
+        assert_eq!(
+            tree.eval_session(2, &values, &mut session).unwrap(),
+            NodeOutput::NumberArray(vec![0., 0.])
+        );
+        assert_eq!(
+            tree.eval_session(3, &values, &mut session).unwrap(),
+            NodeOutput::NumberArray(vec![0., 0.])
+        );
+
+        let values = HashMap::from([
+            (0, NodeOutput::NumberArray(vec![2., 3.])),
+            (1, NodeOutput::Number(30.)),
+        ]);
+        assert_eq!(
+            tree.eval_session(2, &values, &mut session).unwrap(),
+            NodeOutput::NumberArray(vec![2., -4.])
+        );
+        assert!(tree.eval_session(2, &values, &mut session).is_err());
+
+        // Evaluations outside of the session neither see nor change its state
+        let zeros = NodeOutput::NumberArray(vec![0., 0.]);
+        assert_eq!(tree.eval(2, &values).unwrap(), zeros);
+        assert_eq!(tree.clone().eval(2, &values).unwrap(), zeros);
+        assert!(tree.eval_session(2, &values, &mut session).is_err());
+        session.reset_node(2);
+        assert_eq!(tree.eval_session(2, &values, &mut session).unwrap(), zeros);
+        assert!(RateOfChangeFactory.create("-1").is_err());
+    }
+
+    #[test]
+    fn test_rerun_in_session() {
+        let mut registry = NodeRegistry::new();
+        registry.register(10, RateOfChangeFactory).unwrap();
+
+        let mut node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "level".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "time".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 10,
+                value: "".into(),
+                default: None,
+            },
+        ];
+        for (node_id, value) in [(3, "$2 * 2"), (4, "$2 + $3")] {
+            node_defs.push(NodeDefinition {
+                node_id,
+                kind: 1,
+                value: value.into(),
+                default: None,
+            });
+        }
+        let edge_defs = [(2, 0), (2, 1), (3, 2), (4, 2), (4, 3)]
+            .map(|(node_id, input_id)| EdgeDefinition { node_id, input_id })
+            .to_vec();
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry)
+            .unwrap()
+            .with_rerun(&[2])
+            .unwrap()
+            .with_strict_mode()
+            .unwrap();
+
+        // Both consumers get the same rate, the state advances once per evaluation
+        let mut session = Session::new();
+        for (level, time, rate) in [(1., 0., 0.), (3., 1., 2.), (4., 3., 0.5)] {
+            let values = HashMap::from([
+                (0, NodeOutput::Number(level)),
+                (1, NodeOutput::Number(time)),
+            ]);
+            assert_eq!(
+                tree.eval_session(4, &values, &mut session).unwrap(),
+                NodeOutput::Number(rate * 3.)
+            );
+        }
+    }
+
+    #[test]
+    fn test_hysteresis_debounce() {
+        let hysteresis = HysteresisFactory.create("1, 2").unwrap();
+        let mut state = None;
+        let outputs: Vec<_> = [1.5, 2.5, 1.5, 0.5, 1.5]
+            .iter()
+            .map(|x| {
+                hysteresis
+                    .eval_with_state(&[NodeOutput::Number(*x)], &mut state)
+                    .unwrap()
+            })
+            .collect();
+        let expected = [0., 1., 1., 0., 0.].map(NodeOutput::Number);
+        assert_eq!(outputs, expected);
+        assert_eq!(hysteresis.value(), "1,2");
+        assert!(HysteresisFactory.create("2,1").is_err());
+        assert!(HysteresisFactory.create("2").is_err());
+
+        let debounce = DebounceFactory.create("10").unwrap();
+
+        let mut state = None;
+        let inputs = [
+            (1., 0.),
+            (0., 5.),
+            (1., 8.),
+            (1., 15.),
+            (1., 18.),
+            (0., 20.),
+        ];
+        let outputs: Vec<_> = inputs
+            .iter()
+            .map(|(x, time)| {
+                let inputs = [
+                    NodeOutput::NumberArray(vec![*x, 1.]),
+                    NodeOutput::Number(*time),
+                ];
+                debounce.eval_with_state(&inputs, &mut state).unwrap()
+            })
+            .collect();
+        let expected = [
+            vec![0., 0.],
+            vec![0., 0.],
+            vec![0., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+            vec![1., 1.],
+        ]
+        .map(NodeOutput::NumberArray);
+        assert_eq!(outputs, expected);
+    }
+
+    #[test]
+    fn test_control_blocks() {
+        let timed = |x: f64, time: f64| [NodeOutput::Number(x), NodeOutput::Number(time)];
+
+        let pid = PidFactory.create("2, 0.5, 1, -5, 5").unwrap();
+
+        let mut state = None;
+        assert_eq!(pid.value(), "2,0.5,1,-5,5");
+        assert_eq!(
+            pid.eval_with_state(&timed(1., 0.), &mut state).unwrap(),
+            NodeOutput::Number(2.)
+        );
+        // 2 * 2 + 0.5 * 2 + 1 * (2 - 1) is above the limit, so the integral stays at 0
+        assert_eq!(
+            pid.eval_with_state(&timed(2., 1.), &mut state).unwrap(),
+            NodeOutput::Number(5.)
+        );
+        assert!(pid.eval_with_state(&timed(2., 1.), &mut state).is_err());
+        assert_eq!(
+            pid.eval_with_state(&timed(4., 3.), &mut state).unwrap(),
+            NodeOutput::Number(5.)
+        );
+        // With the integral wound up to 10 this would still be 1
+        assert_eq!(
+            pid.eval_with_state(&timed(0., 4.), &mut state).unwrap(),
+            NodeOutput::Number(-4.)
+        );
+        assert!(PidFactory.create("1,2").is_err());
+        assert!(PidFactory.create("1,2,3,5,-5").is_err());
+
+        let lag = FirstOrderLagFactory.create("2").unwrap();
+
+        let mut state = None;
+        assert_eq!(
+            lag.eval_with_state(&timed(1., 0.), &mut state).unwrap(),
+            NodeOutput::Number(1.)
+        );
+        let NodeOutput::Number(y) = lag.eval_with_state(&timed(3., 2.), &mut state).unwrap() else {
+            panic!()
+        };
+        assert!((y - (3. - 2. * (-1f64).exp())).abs() < 1e-12);
+
+        let limiter = RateLimiterFactory.create("0.5").unwrap();
+
+        let mut state = None;
+        let values = [
+            NodeOutput::NumberArray(vec![0., 10.]),
+            NodeOutput::Number(0.),
+        ];
+        limiter.eval_with_state(&values, &mut state).unwrap();
+        let values = [
+            NodeOutput::NumberArray(vec![5., 10.5]),
+            NodeOutput::Number(4.),
+        ];
+        assert_eq!(
+            limiter.eval_with_state(&values, &mut state).unwrap(),
+            NodeOutput::NumberArray(vec![2., 10.5])
+        );
+        assert!(RateLimiterFactory.create("-1").is_err());
+    }
+
+    #[test]
+    fn test_shapes() {
+        let limiter = RateLimiterFactory.create("0.5").unwrap();
+        let timed = |x: NodeOutput, time: f64| [x, NodeOutput::Number(time)];
+
+        let mut state = None;
+        let matrix = NodeOutput::Matrix(vec![vec![0., 1.], vec![2., 3.]]);
+        assert_eq!(
+            limiter
+                .eval_with_state(&timed(matrix.clone(), 0.), &mut state)
+                .unwrap(),
+            matrix
+        );
+        let matrix = NodeOutput::Matrix(vec![vec![4., 1.], vec![2., 3.]]);
+        assert_eq!(
+            limiter
+                .eval_with_state(&timed(matrix, 2.), &mut state)
+                .unwrap(),
+            NodeOutput::Matrix(vec![vec![1., 1.], vec![2., 3.]])
+        );
+
+        let mut state = None;
+        let series = NodeOutput::Series {
+            name: "level".into(),
+            values: vec![1., 2.],
+        };
+        assert_eq!(
+            limiter
+                .eval_with_state(&timed(series.clone(), 0.), &mut state)
+                .unwrap(),
+            series
+        );
+        let time_series = NodeOutput::TimeSeries(vec![(10, 1.), (20, 2.)]);
+        assert_eq!(
+            limiter
+                .eval_with_state(&timed(time_series.clone(), 1.), &mut state)
+                .unwrap(),
+            time_series
+        );
+        assert_eq!(
+            limiter
+                .eval_with_state(
+                    &timed(NodeOutput::BoolArray(vec![true, false]), 2.),
+                    &mut state
+                )
+                .unwrap(),
+            NodeOutput::NumberArray(vec![1., 1.5])
+        );
+
+        // An empty value leaves the state as it was
+        let mut state = None;
+        limiter
+            .eval_with_state(&timed(NodeOutput::Bool(true), 0.), &mut state)
+            .unwrap();
+        assert!(limiter
+            .eval_with_state(&timed(NodeOutput::BoolArray(vec![]), 1.), &mut state)
+            .is_err());
+        assert!(limiter
+            .eval_with_state(&timed(NodeOutput::NumberArray(vec![]), 1.), &mut state)
+            .is_err());
+        assert_eq!(
+            limiter
+                .eval_with_state(&timed(NodeOutput::Integer(3), 2.), &mut state)
+                .unwrap(),
+            NodeOutput::Number(2.)
+        );
+
+        let hysteresis = HysteresisFactory.create("1, 2").unwrap();
+        assert!(hysteresis.eval(&[NodeOutput::BoolArray(vec![])]).is_err());
+    }
+
+    #[test]
+    fn test_delay() {
+        let delay = DelayFactory.create("2").unwrap();
+        let mut state = None;
+        assert_eq!(delay.value(), "2");
+        let outputs: Vec<_> = [1., 2., 3., 4.]
+            .iter()
+            .map(|x| {
+                delay
+                    .eval_with_state(&[NodeOutput::Number(*x)], &mut state)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(outputs, [1., 1., 1., 2.].map(NodeOutput::Number));
+
+        let delay = DelayFactory.create("1.5s").unwrap();
+
+        let mut state = None;
+        assert_eq!(delay.value(), "1.5s");
+        let timed =
+            |x: f64, time: f64| [NodeOutput::NumberArray(vec![x]), NodeOutput::Number(time)];
+        let outputs: Vec<_> = [(1., 0.), (2., 1.), (3., 2.), (4., 2.5), (5., 4.)]
+            .iter()
+            .map(|(x, time)| {
+                delay
+                    .eval_with_state(&timed(*x, *time), &mut state)
+                    .unwrap()
+            })
+            .collect();
+        let expected = [1., 1., 1., 2., 4.].map(|x| NodeOutput::NumberArray(vec![x]));
+        assert_eq!(outputs, expected);
+        assert!(delay.eval_with_state(&timed(6., 4.), &mut state).is_err());
+
+        assert!(DelayFactory.create("-1").is_err());
+        assert!(DelayFactory.create("-1s").is_err());
+        assert!(DelayFactory.create(&MAX_DELAY.to_string()).is_ok());
+        assert!(DelayFactory.create(&usize::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_window_unbatch() {
+        let mut registry = NodeRegistry::new();
+        registry.register(10, UnbatchFactory).unwrap();
+        registry.register(11, WindowFactory).unwrap();
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "batch".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 10,
+                value: "".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "$1 * 2".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 11,
+                value: "2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+        ];
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+
+        let values = HashMap::from([(0, NodeOutput::NumberArray(vec![1., 2., 3.]))]);
+        let mut session = Session::new();
+        let outputs: Vec<_> = (0..4)
+            .map(|_| tree.eval_session(3, &values, &mut session).unwrap())
+            .collect();
+        let expected = [vec![2.], vec![2., 4.], vec![4., 6.], vec![6., 2.]];
+        assert_eq!(outputs, expected.map(NodeOutput::NumberArray));
+
+        assert!(WindowFactory.create("0").is_err());
+        let empty = [NodeOutput::NumberArray(Vec::new())];
+        assert!(UnbatchFactory.create("").unwrap().eval(&empty).is_err());
+    }
+
+    #[test]
+    fn test_window_memory() {
+        let mut registry = NodeRegistry::new();
+        registry.register(11, WindowFactory).unwrap();
+
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 11,
+                value: "5".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 11,
+                value: "5".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$1 + $2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![
+            EdgeDefinition {
+                node_id: 1,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 2,
+                input_id: 0,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 1,
+            },
+            EdgeDefinition {
+                node_id: 3,
+                input_id: 2,
+            },
+        ];
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+
+        // The windows grow past the scalar they get as input. With windows of 5 elements, the
+        // formula holds 2 * 40 bytes of inputs and reserves 40 bytes for its output.
+        let tree = tree.with_memory_limit(120);
+        let mut session = Session::new();
+        for i in 1..=6 {
+            let values = HashMap::from([(0, NodeOutput::Number(i as f64))]);
+            let output = tree.eval_session(3, &values, &mut session).unwrap();
+            assert_eq!(output.elements().unwrap().len(), i.min(5));
+        }
+        let tree = tree.with_memory_limit(119);
+        let mut session = Session::new();
+        let outcomes: Vec<_> = (1..=5)
+            .map(|i| {
+                let values = HashMap::from([(0, NodeOutput::Number(i as f64))]);
+                tree.eval_session(3, &values, &mut session).is_ok()
+            })
+            .collect();
+        assert_eq!(outcomes, [true, true, true, true, false]);
+    }
+
+    #[test]
+    fn test_state_machine() {
+        let value = r#"{
+            "states": ["startup", "running", "shutdown"],
+            "transitions": [
+                {"from": "startup", "to": "running", "guard": "$4 >= 100.0"},
+                {"from": "running", "to": "shutdown", "guard": "$7 == 1"},
+                {"from": "shutdown", "to": "startup", "guard": "$4 < 10.0"}
+            ]
+        }"#;
+        let machine = StateMachineFactory
+            .create_with_inputs(value, &[4, 7])
+            .unwrap();
+        let mut state = None;
+        let inputs = [(50., 0), (120., 1), (120., 1), (50., 0), (5., 0)];
+        let states: Vec<_> = inputs
+            .iter()
+            .map(|(speed, stop)| {
+                let inputs = [NodeOutput::Number(*speed), NodeOutput::Integer(*stop)];
+                machine.eval_with_state(&inputs, &mut state).unwrap()
+            })
+            .collect();
+        let expected = [0, 1, 2, 2, 0].map(NodeOutput::Integer);
+        assert_eq!(states, expected);
+
+        let copy = StateMachineFactory
+            .create_with_inputs(&machine.value(), &[4, 7])
+            .unwrap();
+        assert_eq!(copy.value(), machine.value());
+        // Guards read the inputs of the node only
+        assert!(StateMachineFactory.create(value).is_err());
+        assert!(StateMachineFactory.create_with_inputs(value, &[4]).is_err());
+        let invalid =
+            r#"{"states": ["a"], "transitions": [{"from": "a", "to": "b", "guard": "true"}]}"#;
+        assert!(StateMachineFactory.create(invalid).is_err());
+        assert!(machine
+            .eval_with_state(&[NodeOutput::NumberArray(vec![1.])], &mut state)
+            .is_err());
+
+        // In a tree the guards read the inputs by node id, or by position in the old dialect
+        let mut registry = NodeRegistry::new();
+        registry.register(12, StateMachineFactory).unwrap();
+        let value = r#"{
+            "states": ["off", "on"],
+            "transitions": [
+                {"from": "off", "to": "on", "guard": "$0 > 1.0"},
+                {"from": "on", "to": "off", "guard": "id0 < 0.0"}
+            ]
+        }"#;
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "level".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 12,
+                value: value.into(),
+                default: None,
+            },
+        ];
+        let edge_defs = vec![EdgeDefinition {
+            node_id: 1,
+            input_id: 0,
+        }];
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        let mut session = Session::new();
+        let states: Vec<_> = [2., 0.5, -1.]
+            .iter()
+            .map(|x| {
+                let values = HashMap::from([(0, NodeOutput::Number(*x))]);
+                tree.eval_session(1, &values, &mut session).unwrap()
+            })
+            .collect();
+        assert_eq!(states, [1, 1, 0].map(NodeOutput::Integer));
+    }
+}
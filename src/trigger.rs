@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::core::{NodeId, NodeKind, NodeOutput, Tree};
+use crate::session::Session;
+
+// When a node is evaluated again in a session. Nodes refreshed on a timer or by hand keep their
+// output in the session until then, and the nodes below them are not evaluated meanwhile, e.g.
+// for an expensive query read by cheap formulas.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Trigger {
+    // Every evaluation, so the node follows its inputs
+    #[default]
+    OnChange,
+    // Once the output is older than the interval
+    Every(Duration),
+    // Only after `Session::refresh`
+    Manual,
+}
+
+impl Trigger {
+    fn fresh(&self, age: Duration) -> bool {
+        match self {
+            Trigger::OnChange => false,
+            Trigger::Every(interval) => age < *interval,
+            Trigger::Manual => true,
+        }
+    }
+}
+
+impl Tree {
+    // Evaluations outside of a session start from scratch and evaluate every node
+    pub fn with_trigger(mut self, node_ids: &[NodeId], trigger: Trigger) -> Result<Self> {
+        for node_id in node_ids {
+            if let NodeKind::Variable(_) = self.node(*node_id)?.kind() {
+                return Err(anyhow!("variable node {} has no trigger", node_id));
+            }
+            if self.rerun.contains(node_id) {
+                return Err(anyhow!("node {} is re-run on every read", node_id));
+            }
+        }
+        for node_id in node_ids {
+            match trigger {
+                Trigger::OnChange => self.triggers.remove(node_id),
+                trigger => self.triggers.insert(*node_id, trigger),
+            };
+        }
+        Ok(self)
+    }
+
+    pub fn trigger(&self, node_id: NodeId) -> Trigger {
+        self.triggers.get(&node_id).copied().unwrap_or_default()
+    }
+
+    // Finds the outputs the session keeps for the node and the nodes below it that are not due
+    // yet, and the nodes that have to be evaluated
+    pub(crate) fn triggered_nodes(
+        &self,
+        node_id: NodeId,
+        session: &Session,
+        now: Instant,
+    ) -> Result<TriggeredNodes> {
+        let mut kept = HashMap::new();
+        if self.triggers.is_empty() {
+            return Ok(TriggeredNodes { kept, needed: None });
+        }
+
+        let mut needed = HashSet::new();
+        let mut stack = vec![node_id];
+        while let Some(id) = stack.pop() {
+            if !needed.insert(id) {
+                continue;
+            }
+            if let Some((computed_at, output)) = session.output(id) {
+                if self
+                    .trigger(id)
+                    .fresh(now.saturating_duration_since(*computed_at))
+                {
+                    kept.insert(id, output.clone());
+                    continue;
+                }
+            }
+            stack.extend(self.node(id)?.inputs.iter());
+        }
+        Ok(TriggeredNodes {
+            kept,
+            needed: Some(needed),
+        })
+    }
+}
+
+pub(crate) struct TriggeredNodes {
+    pub(crate) kept: HashMap<NodeId, NodeOutput>,
+    // None if every node has to be evaluated
+    pub(crate) needed: Option<HashSet<NodeId>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition};
+    use crate::registry::NodeRegistry;
+    use crate::stateful::UnbatchFactory;
+
+    #[test]
+    fn test_trigger() {
+        // Node 1 hands out the next element on every evaluation, like a query seeing new rows
+        let mut registry = NodeRegistry::new();
+        registry.register(10, UnbatchFactory).unwrap();
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "rows".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 10,
+                value: "".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 0,
+                value: "factor".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "$1 * $2".into(),
+                default: None,
+            },
+        ];
+        let edge_defs = [(1, 0), (3, 1), (3, 2)]
+            .map(|(node_id, input_id)| EdgeDefinition { node_id, input_id })
+            .to_vec();
+        let tree = Tree::new_with_registry(node_defs, edge_defs, registry).unwrap();
+        let values = |factor: f64| {
+            HashMap::from([
+                (0, NodeOutput::NumberArray(vec![1., 2., 3.])),
+                (2, NodeOutput::Number(factor)),
+            ])
+        };
+        let eval = |tree: &Tree, session: &mut Session, factor: f64| {
+            tree.eval_session(3, &values(factor), session).unwrap()
+        };
+
+        let mut session = Session::new();
+        assert_eq!(eval(&tree, &mut session, 1.), NodeOutput::Number(1.));
+        assert_eq!(eval(&tree, &mut session, 1.), NodeOutput::Number(2.));
+
+        // The formula follows its variable while node 1 waits to be refreshed
+        let manual = tree.clone().with_trigger(&[1], Trigger::Manual).unwrap();
+        assert_eq!(manual.trigger(1), Trigger::Manual);
+        assert_eq!(manual.trigger(3), Trigger::OnChange);
+        let mut session = Session::new();
+        assert_eq!(eval(&manual, &mut session, 1.), NodeOutput::Number(1.));
+        assert_eq!(eval(&manual, &mut session, 10.), NodeOutput::Number(10.));
+        session.refresh(1);
+        assert_eq!(eval(&manual, &mut session, 10.), NodeOutput::Number(20.));
+        assert_eq!(eval(&manual, &mut session, 1.), NodeOutput::Number(2.));
+        // Outside of the session every node is evaluated
+        assert_eq!(manual.eval(3, &values(1.)).unwrap(), NodeOutput::Number(1.));
+
+        let hourly = tree
+            .clone()
+            .with_trigger(&[1], Trigger::Every(Duration::from_secs(3600)))
+            .unwrap();
+        let mut session = Session::new();
+        assert_eq!(eval(&hourly, &mut session, 1.), NodeOutput::Number(1.));
+        assert_eq!(eval(&hourly, &mut session, 2.), NodeOutput::Number(2.));
+        let always = tree
+            .clone()
+            .with_trigger(&[1], Trigger::Every(Duration::ZERO))
+            .unwrap();
+        let mut session = Session::new();
+        assert_eq!(eval(&always, &mut session, 1.), NodeOutput::Number(1.));
+        assert_eq!(eval(&always, &mut session, 1.), NodeOutput::Number(2.));
+
+        // A timer makes the output depend on when the tree is evaluated
+        assert!(hourly.with_strict_mode().is_err());
+        assert!(manual.with_strict_mode().is_ok());
+
+        assert!(tree.clone().with_trigger(&[0], Trigger::Manual).is_err());
+        let rerun = tree.with_rerun(&[1]).unwrap();
+        assert!(rerun.with_trigger(&[1], Trigger::Manual).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Result};
+use evalexpr::{Operator, Value};
+use std::any::Any;
+use std::fmt::{Debug, Display};
+
+use crate::binding::BindingPlan;
+use crate::core::NodeOutput;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// Values of a domain the built-in outputs do not cover, e.g. geometry objects. Variables and
+// custom nodes pass them along like any other output, `NodeOutput::Custom` shares them instead
+// of copying. Formulas can only apply a single arithmetic operator to them, e.g. `$1 * 2`,
+// which the value implements in `apply`.
+pub trait CustomValue: Debug + Display + Send + Sync {
+    fn type_name(&self) -> &str;
+
+    // For downcasting to the concrete type, see `NodeOutput::custom_value`
+    fn as_any(&self) -> &dyn Any;
+
+    fn eq_value(&self, other: &dyn CustomValue) -> bool;
+
+    // `other` is the other operand, on the left of the operator if `reversed`
+    fn apply(
+        &self,
+        operator: Arithmetic,
+        _other: &NodeOutput,
+        _reversed: bool,
+    ) -> Result<NodeOutput> {
+        Err(anyhow!(
+            "{} does not support {:?}",
+            self.type_name(),
+            operator
+        ))
+    }
+}
+
+impl PartialEq for dyn CustomValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_value(other)
+    }
+}
+
+impl NodeOutput {
+    pub fn custom_value<T: CustomValue + 'static>(&self) -> Option<&T> {
+        match self {
+            NodeOutput::Custom(value) => value.as_any().downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+// A formula with custom values as inputs, which has to be a single arithmetic operator on two
+// inputs or an input and a constant
+pub(crate) fn apply_custom(
+    formula: &evalexpr::Node,
+    plan: &BindingPlan,
+    input_outputs: &[NodeOutput],
+) -> Result<NodeOutput> {
+    let formula = unwrapped(formula);
+    let operator = match formula.operator() {
+        Operator::Add => Arithmetic::Add,
+        Operator::Sub => Arithmetic::Sub,
+        Operator::Mul => Arithmetic::Mul,
+        Operator::Div => Arithmetic::Div,
+        _ => {
+            return Err(anyhow!(
+                "formulas can only apply +, -, * or / to custom values"
+            ))
+        }
+    };
+    let [lhs, rhs] = formula.children() else {
+        return Err(anyhow!(
+            "formulas can only apply +, -, * or / to custom values"
+        ));
+    };
+
+    let operand = |node: &evalexpr::Node| match unwrapped(node).operator() {
+        Operator::VariableIdentifierRead { identifier } => plan
+            .input(identifier)
+            .and_then(|x| input_outputs.get(x))
+            .cloned()
+            .ok_or(anyhow!("variable {} is not an input", identifier)),
+        Operator::Const {
+            value: Value::Float(v),
+        } => Ok(NodeOutput::Number(*v)),
+        Operator::Const {
+            value: Value::Int(v),
+        } => Ok(NodeOutput::Integer(*v)),
+        _ => Err(anyhow!(
+            "custom values can only be combined with inputs and numbers"
+        )),
+    };
+    match (operand(lhs)?, operand(rhs)?) {
+        (NodeOutput::Custom(value), other) => value.apply(operator, &other, false),
+        (other, NodeOutput::Custom(value)) => value.apply(operator, &other, true),
+        _ => Err(anyhow!("the operands have no custom value")),
+    }
+}
+
+// Parentheses become root nodes with a single child
+fn unwrapped(node: &evalexpr::Node) -> &evalexpr::Node {
+    let mut node = node;
+    while let (Operator::RootNode, [child]) = (node.operator(), node.children()) {
+        node = child;
+    }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{EdgeDefinition, NodeDefinition, Tree};
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq)]
+    struct Point(f64, f64);
+
+    impl Display for Point {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "({}, {})", self.0, self.1)
+        }
+    }
+
+    impl CustomValue for Point {
+        fn type_name(&self) -> &str {
+            "point"
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn eq_value(&self, other: &dyn CustomValue) -> bool {
+            other.as_any().downcast_ref::<Self>() == Some(self)
+        }
+
+        fn apply(
+            &self,
+            operator: Arithmetic,
+            other: &NodeOutput,
+            _reversed: bool,
+        ) -> Result<NodeOutput> {
+            match (operator, other) {
+                (Arithmetic::Mul, NodeOutput::Number(v)) => {
+                    Ok(NodeOutput::Custom(Arc::new(Point(self.0 * v, self.1 * v))))
+                }
+                (Arithmetic::Add, NodeOutput::Custom(other)) => {
+                    let other = other
+                        .as_any()
+                        .downcast_ref::<Self>()
+                        .ok_or(anyhow!("points can only be added to points"))?;
+                    Ok(NodeOutput::Custom(Arc::new(Point(
+                        self.0 + other.0,
+                        self.1 + other.1,
+                    ))))
+                }
+                _ => Err(anyhow!(
+                    "point does not support {:?} with {:?}",
+                    operator,
+                    other
+                )),
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_value() {
+        let node_defs = vec![
+            NodeDefinition {
+                node_id: 0,
+                kind: 0,
+                value: "a".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 1,
+                kind: 0,
+                value: "b".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 2,
+                kind: 1,
+                value: "2.0 * $0".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 3,
+                kind: 1,
+                value: "($2 + $1)".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 4,
+                kind: 1,
+                value: "$0 - $1".into(),
+                default: None,
+            },
+            NodeDefinition {
+                node_id: 5,
+                kind: 1,
+                value: "sqrt($0)".into(),
+                default: None,
+            },
+        ];
+        let mut edge_defs = Vec::new();
+        for (node_id, input_id) in [(2, 0), (3, 2), (3, 1), (4, 0), (4, 1), (5, 0)] {
+            edge_defs.push(EdgeDefinition { node_id, input_id });
+        }
+        let tree = Tree::new(node_defs, edge_defs).unwrap();
+
+        let values = HashMap::from([
+            (0, NodeOutput::Custom(Arc::new(Point(1., 2.)))),
+            (1, NodeOutput::Custom(Arc::new(Point(0.5, 0.5)))),
+        ]);
+        let output = tree.eval(3, &values).unwrap();
+        assert_eq!(output.custom_value::<Point>(), Some(&Point(2.5, 4.5)));
+        assert_eq!(output, NodeOutput::Custom(Arc::new(Point(2.5, 4.5))));
+        assert!(tree.eval(4, &values).is_err());
+        assert!(tree.eval(5, &values).is_err());
+        assert!(output.elements().is_err());
+    }
+}